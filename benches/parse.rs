@@ -0,0 +1,88 @@
+//! Benchmarks for parsing large documents. Run with `cargo bench`
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+use kismesis::{
+	compiler::{lexer, parser},
+	Kismesis,
+};
+
+/// A document with `nodes` top-level tags, with a bit of everything in them
+fn generate_document(nodes: usize) -> String {
+	let mut output = String::from("const title = \"Benchmark\"\n<macro card name | <p | @name>>\n");
+	for i in 0..nodes {
+		match i % 4 {
+			0 => output.push_str(&format!(
+				"<div.item#n{} data-index=\"{}\" | text {}>\n",
+				i, i, i
+			)),
+			1 => output.push_str(&format!("<card! name=\"{}\">\n", i)),
+			2 => output.push_str("<ul:\n\t<li | @title>\n\t<li | <b | bold>>\n>\n"),
+			_ => output.push_str(&format!(
+				"<p | a paragraph with {{title}} and some words {}>\n",
+				i
+			)),
+		}
+	}
+	output
+}
+
+fn parse_large_document(c: &mut Criterion) {
+	let mut group = c.benchmark_group("parse");
+	group.sample_size(10);
+	for nodes in [1000, 5000] {
+		let source = generate_document(nodes);
+		let mut engine = Kismesis::new();
+		let id = engine.register_tokens(lexer::tokenize(&source), None);
+		group.bench_function(format!("{} top-level nodes", nodes), |b| {
+			b.iter(|| parser::file(black_box(id), &engine, None, None).is_ok())
+		});
+	}
+	group.finish();
+}
+
+/// Tags nested inside of each other, which the parser has to backtrack
+/// through at every level
+fn parse_nested_blocks(c: &mut Criterion) {
+	let mut group = c.benchmark_group("parse nested");
+	group.sample_size(10);
+	let block = concat!(
+		"<section:\n",
+		"\t<div.a | <div.b | <div.c | <p | x <b | y> z>>>>\n",
+		"\t<ul | <li | <a href=\"/\" | one>> <li | two>>\n",
+		"\t<div | <div | <div | <div | @{a or b}>>>>\n",
+		"\t<p | text <i | more <b | and more>>>\n",
+		"\t<footer | <small | end>>\n",
+		">\n"
+	);
+	let source = block.repeat(2000);
+	let mut engine = Kismesis::new();
+	let id = engine.register_tokens(lexer::tokenize(&source), None);
+	group.bench_function("2000 blocks", |b| {
+		b.iter(|| parser::file(black_box(id), &engine, None, None).is_ok())
+	});
+	group.finish();
+}
+
+/// Every line has an attribute without a value, which the parser recovers
+/// from and keeps going
+fn parse_with_recovered_errors(c: &mut Criterion) {
+	let mut group = c.benchmark_group("parse recovering");
+	group.sample_size(10);
+	for nodes in [500, 2000] {
+		let source = "<p a= b=\"1\" | x>\n".repeat(nodes);
+		let mut engine = Kismesis::new();
+		let id = engine.register_tokens(lexer::tokenize(&source), None);
+		group.bench_function(format!("{} errors", nodes), |b| {
+			b.iter(|| parser::file_recovering(black_box(id), &engine, None, None).is_ok())
+		});
+	}
+	group.finish();
+}
+
+criterion_group!(
+	benches,
+	parse_large_document,
+	parse_nested_blocks,
+	parse_with_recovered_errors
+);
+criterion_main!(benches);
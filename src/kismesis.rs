@@ -6,12 +6,15 @@
 //! every templating operation. It also works as an arena that holds
 //! templates and token strings.
 
-pub(crate) mod compiler;
+pub mod compiler;
 mod plugins;
 
 use std::{
-	collections::HashMap,
-	fs, io,
+	collections::{hash_map::DefaultHasher, HashMap},
+	fmt, fs,
+	hash::{Hash, Hasher},
+	io,
+	ops::Range,
 	path::{Path, PathBuf}, cell::RefCell, rc::Rc,
 };
 
@@ -19,22 +22,59 @@ use rhai::{Engine, AST, Scope, Array};
 
 use compiler::{
 	lexer::{self, Token},
-	parser::{self, errors::Err, types::{ParsedFile, TextPos, Ranged, HtmlNodes}},
+	errors::ErrorState,
+	parser::{self, errors::ParseError, state::TokenPos, types::{ParsedFile, TextPos, Ranged, HtmlNodes, Interner, Symbol}},
+	reporting::{draw_error, DrawingInfo, DEFAULT_TAB_WIDTH},
 };
 
 use self::plugins::EngineTag;
 
 pub type KisResult<T> = Result<T, KismesisError>;
 
+#[derive(Debug)]
 pub enum KismesisError {
 	IOError(io::Error, PathBuf),
-	ParseError(Err, KisID),
+	ParseError(Vec<ErrorState<ParseError>>, KisID),
+	InvalidKisID(KisID),
+	/// The name of a plugin whose script couldn't be compiled, and why
+	InvalidPlugin(String, String),
+}
+
+/// The parsing errors of a file along with how they look when they're drawn,
+/// so they can be shown without needing the engine that parsed the file
+#[derive(Debug)]
+pub struct RenderedError {
+	/// Never empty, and in the order they were found
+	pub errors: Vec<ErrorState<ParseError>>,
+	pub file: KisID,
+	pub text: String,
+}
+
+impl fmt::Display for RenderedError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}", self.text)
+	}
 }
 
 #[derive(Debug)]
 pub struct FileRef {
 	pub tokens: Vec<Token>,
 	pub path: Option<PathBuf>,
+	/// A hash of the tokens, which tells whether two files have the same
+	/// content
+	pub hash: u64,
+	/// The bytes of the source each token was lexed from, for files that
+	/// were registered from their source
+	pub offsets: Option<Vec<Range<usize>>>,
+}
+
+/// A file that was parsed before, which can be given back as long as it's
+/// parsed again from the same place with the same content
+#[derive(Debug)]
+struct CachedFile {
+	hash: u64,
+	tokens: Vec<Token>,
+	file: ParsedFile,
 }
 
 #[derive(Default, Debug)]
@@ -44,11 +84,29 @@ pub struct Kismesis {
 	plugin_engine: Engine,
 	plugins: HashMap<String, AST>,
 	id: usize,
+	max_parse_depth: usize,
+	parse_step_budget: Option<usize>,
+	/// The last file parsed from each path, by its path and its project
+	parse_cache: HashMap<(Option<PathBuf>, Option<PathBuf>), CachedFile>,
+	parse_count: usize,
+	/// How many columns a tab takes up, wherever columns are counted
+	tab_width: usize,
+	/// How many columns drawn errors are wrapped to, if not the terminal's
+	error_width: Option<usize>,
+	names: Interner,
 }
 
+/// Each level of nesting takes a fair amount of stack in the parser, up to
+/// about 40KiB in debug builds. 256 levels would fit in the main thread's
+/// 8MiB stack in release builds, but overflow it in debug builds, so the
+/// default is kept at 64, which fits in both
+pub const DEFAULT_MAX_PARSE_DEPTH: usize = 64;
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct KisID(usize);
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum KisTemplateID {
 	Input(usize),
 	File(PathBuf),
@@ -62,16 +120,73 @@ impl Kismesis {
 			templates: HashMap::new(),
 			plugins: HashMap::new(),
 			id: 0,
+			max_parse_depth: DEFAULT_MAX_PARSE_DEPTH,
+			parse_step_budget: None,
+			parse_cache: HashMap::new(),
+			parse_count: 0,
+			tab_width: DEFAULT_TAB_WIDTH,
+			error_width: None,
+			names: Interner::default(),
 		}
 	}
 
+	/// Sets how deeply tags can be nested before parsing fails with
+	/// `ParseError::MaxDepthExceeded`
+	pub fn set_max_parse_depth(&mut self, depth: usize) {
+		self.max_parse_depth = depth;
+	}
+
+	pub fn max_parse_depth(&self) -> usize {
+		self.max_parse_depth
+	}
+
+	/// Limits how many tokens the parser may step through in total, counting
+	/// the ones it backtracks over. Parsing fails with
+	/// `ParseError::StepBudgetExceeded` when the budget runs out. `None`, the
+	/// default, means there's no limit.
+	pub fn set_parse_step_budget(&mut self, budget: Option<usize>) {
+		self.parse_step_budget = budget;
+	}
+
+	pub fn parse_step_budget(&self) -> Option<usize> {
+		self.parse_step_budget
+	}
+
+	/// Sets how many columns a tab takes up when errors are drawn
+	pub fn set_tab_width(&mut self, tab_width: usize) {
+		self.tab_width = tab_width;
+	}
+
+	pub fn tab_width(&self) -> usize {
+		self.tab_width
+	}
+
+	/// Wraps drawn errors to a fixed width instead of the terminal's
+	pub fn set_error_width(&mut self, error_width: Option<usize>) {
+		self.error_width = error_width;
+	}
+
+	pub fn error_width(&self) -> Option<usize> {
+		self.error_width
+	}
+
+	/// Makes a symbol of `name`, which shares its storage with the other
+	/// symbols this engine made of the same name
+	pub fn intern(&self, name: &str) -> Symbol {
+		self.names.intern(name)
+	}
+
 	pub fn drop_id(&mut self, id: &KisID) {
 		self.tokens.remove(id);
 	}
 
-	pub fn register_plugin(&mut self, plugin: &str, name: &str) {
-		let ast = self.plugin_engine.compile(plugin).unwrap();
+	pub fn register_plugin(&mut self, plugin: &str, name: &str) -> KisResult<()> {
+		let ast = self
+			.plugin_engine
+			.compile(plugin)
+			.map_err(|x| KismesisError::InvalidPlugin(name.to_string(), x.to_string()))?;
 		self.plugins.insert(name.to_string(), ast);
+		Ok(())
 	}
 
 	pub fn run_plugin(
@@ -81,9 +196,11 @@ impl Kismesis {
 		params: Ranged<Vec<Token>>,
 		body: Option<Ranged<Vec<Token>>>,
 		project_path: Option<PathBuf>,
-	) -> Vec<HtmlNodes>
+	) -> Result<Vec<HtmlNodes>, ParseError>
 	{
-		let plugin = self.plugins.get(name).unwrap();
+		let Some(plugin) = self.plugins.get(name) else {
+			return Err(ParseError::UndefinedPlugin(name.to_string()));
+		};
 
 		let engine_tag = plugins::EngineTag {
 			project_path,
@@ -97,27 +214,120 @@ impl Kismesis {
 		drop(borrow);
 
 		
-		let string: Array = self.plugin_engine.call_fn(&mut Scope::new(), plugin, "token_call", (range, params.value, body.map(|x| x.value).unwrap_or(vec![]))).unwrap();
+		let string: Array = self.plugin_engine.call_fn(&mut Scope::new(), plugin, "token_call", (range, params.value, body.map(|x| x.value).unwrap_or(vec![])))
+			.map_err(|x| ParseError::PluginFailed(x.to_string()))?;
 		plugins::into_html_nodes(string)
+			.ok_or_else(|| ParseError::PluginFailed("The plugin returned something that isn't a tag".into()))
 	}
 
 	pub fn register_tokens(&mut self, tokens: Vec<Token>, path: Option<PathBuf>) -> KisID {
+		self.register_file_ref(tokens, path, None)
+	}
+
+	/// Lexes and registers a string, keeping the byte offsets of its tokens
+	/// so positions in it can be turned into offsets with
+	/// [`ParsedFile::byte_offset`]
+	pub fn register_source(&mut self, src: &str, path: Option<PathBuf>) -> KisID {
+		let (tokens, offsets) = lexer::tokenize_with_offsets(src);
+		self.register_file_ref(tokens, path, Some(offsets))
+	}
+
+	fn register_file_ref(
+		&mut self,
+		tokens: Vec<Token>,
+		path: Option<PathBuf>,
+		offsets: Option<Vec<Range<usize>>>,
+	) -> KisID {
 		let new_kis_id = KisID(self.id);
 		self.id += 1;
-		self.tokens.insert(new_kis_id.clone(), FileRef { tokens, path });
+		let mut hasher = DefaultHasher::new();
+		tokens.hash(&mut hasher);
+		let hash = hasher.finish();
+		self.tokens.insert(
+			new_kis_id.clone(),
+			FileRef {
+				tokens,
+				path,
+				hash,
+				offsets,
+			},
+		);
 		new_kis_id
 	}
 
+	/// Parses registered tokens, or gives back the file that was parsed from
+	/// the same tokens the last time something was parsed from the same path
+	/// and project. Parsing different tokens from the same path and project
+	/// replaces the file that was parsed before
+	fn parse_cached(
+		&mut self,
+		tokens: KisID,
+		project: Option<PathBuf>,
+	) -> Result<ParsedFile, Vec<ErrorState<ParseError>>> {
+		let Some(source) = self.get_file(tokens) else {
+			return parser::file(tokens, self, None, project);
+		};
+		let key = (source.path.clone(), project.clone());
+		if let Some(cached) = self.parse_cache.get(&key) {
+			// Different tokens can have the same hash, so the hash only
+			// saves comparing the tokens when they're different
+			if cached.hash == source.hash && cached.tokens == source.tokens {
+				let mut file = cached.file.clone();
+				// The new tokens are the same as the old ones, which may
+				// have been dropped since
+				file.file_id = tokens;
+				return Ok(file);
+			}
+		}
+		let (hash, source_tokens) = (source.hash, source.tokens.clone());
+		self.parse_count += 1;
+		let file = parser::file(tokens, self, None, project)?;
+		self.parse_cache.insert(
+			key,
+			CachedFile {
+				hash,
+				tokens: source_tokens,
+				file: file.clone(),
+			},
+		);
+		Ok(file)
+	}
+
+	/// How many times files have been parsed, not counting the times they
+	/// were taken from the cache
+	pub fn parse_count(&self) -> usize {
+		self.parse_count
+	}
+
 	pub fn register_file(&mut self, path: PathBuf, project: Option<PathBuf>) -> KisResult<ParsedFile> {
 		let text =
 			fs::read_to_string(&path).map_err(|x| KismesisError::IOError(x, path.clone()))?;
-		let tokens = lexer::tokenize(&text);
-		let tokens = self.register_tokens(tokens, Some(path));
+		let tokens = self.register_source(&text, Some(path));
 		let file =
-			parser::file(tokens, self, None, project).map_err(|x| KismesisError::ParseError(x, tokens))?;
+			self.parse_cached(tokens, project).map_err(|x| KismesisError::ParseError(x, tokens))?;
 		Ok(file)
 	}
 
+	/// Lexes and parses a string in one go. `path` is only used to tell
+	/// where the string came from. If parsing fails, the error is drawn the
+	/// same way the compiler draws it
+	pub fn compile_str(
+		&mut self,
+		src: &str,
+		path: Option<PathBuf>,
+	) -> Result<ParsedFile, RenderedError> {
+		let tokens = self.register_source(src, path);
+		self.parse_cached(tokens, None).map_err(|errors| {
+			let info = DrawingInfo::from(tokens, self, false);
+			let text = errors.iter().map(|x| draw_error(x, &info, self)).collect();
+			RenderedError {
+				errors,
+				file: tokens,
+				text,
+			}
+		})
+	}
+
 	pub fn register_template(&mut self, file: ParsedFile) -> KisTemplateID {
 		let output_id = match self.get_file(file.file_id).and_then(|x| x.path.clone()) {
 			Some(path) => KisTemplateID::File(path),
@@ -153,6 +363,60 @@ impl Kismesis {
 	pub fn get_file(&self, id: KisID) -> Option<&FileRef> {
 		self.tokens.get(&id)
 	}
+	pub fn get_file_or_err(&self, id: KisID) -> KisResult<&FileRef> {
+		self.get_file(id).ok_or(KismesisError::InvalidKisID(id))
+	}
+
+	/// Turns a token position into a 1-based line and character column, the
+	/// way editors count them. Indents take up `tab_width` columns, which
+	/// should match the width errors are drawn with.
+	pub fn resolve_position(
+		&self,
+		id: KisID,
+		pos: &TokenPos,
+		tab_width: usize,
+	) -> Option<(usize, usize)> {
+		let tokens = &self.get_file(id)?.tokens;
+		let mut line = 1;
+		let mut column = 1;
+		for token in tokens.get(..pos.get_idx())? {
+			match token {
+				Token::Newline(_) => {
+					line += 1;
+					column = 1;
+				}
+				Token::Indent(_) => column += tab_width,
+				Token::Word(word) => column += word.chars().count(),
+				Token::Space(_) | Token::Symbol(_) => column += 1,
+			}
+		}
+		Some((line, column))
+	}
+}
+
+/// Lexes and parses a string with an engine of its own, for when there's no
+/// engine around, like in tests. The engine is given back with the file,
+/// since the file's ID only means something to it. Errors are given as
+/// drawn text
+pub fn parse_standalone(src: &str) -> Result<(Kismesis, ParsedFile), String> {
+	let mut engine = Kismesis::new();
+	let file = engine.compile_str(src, None).map_err(|x| x.text)?;
+	Ok((engine, file))
+}
+
+impl fmt::Display for KisID {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "#{}", self.0)
+	}
+}
+
+impl fmt::Display for KisTemplateID {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Input(x) => write!(f, "input #{}", x),
+			Self::File(x) => write!(f, "{}", x.display()),
+		}
+	}
 }
 
 impl From<PathBuf> for KisTemplateID {
@@ -178,3 +442,246 @@ impl From<&KisTemplateID> for KisTemplateID {
 		val.clone()
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use std::collections::HashSet;
+
+	use super::*;
+
+	#[test]
+	fn ids_work_as_keys() {
+		let mut engine = Kismesis::new();
+		let a = engine.register_tokens(vec![], None);
+		let b = engine.register_tokens(vec![], None);
+		let ids: HashSet<_> = [a, b, a, b, a].into_iter().collect();
+		assert_eq!(ids.len(), 2);
+		assert!(ids.contains(&a) && ids.contains(&b));
+		assert_ne!(a.to_string(), b.to_string());
+		assert!(a.to_string().starts_with('#'));
+
+		let templates: HashSet<_> = [
+			KisTemplateID::Input(0),
+			KisTemplateID::File("a.ks".into()),
+			KisTemplateID::Input(0),
+		]
+		.into_iter()
+		.collect();
+		assert_eq!(templates.len(), 2);
+		assert_eq!(KisTemplateID::File("a.ks".into()).to_string(), "a.ks");
+		assert_eq!(KisTemplateID::Input(3).to_string(), "input #3");
+	}
+
+	#[test]
+	fn invalid_ids_are_named() {
+		let mut engine = Kismesis::new();
+		let id = engine.register_source("<p | a>\n", None);
+		assert!(engine.get_file_or_err(id).is_ok());
+		engine.drop_id(&id);
+		assert!(matches!(
+			engine.get_file_or_err(id),
+			Err(KismesisError::InvalidKisID(x)) if x == id
+		));
+		assert!(matches!(
+			DrawingInfo::from(id, &engine, false),
+			Err(compiler::reporting::ReportingError::InvalidKismesisID(x)) if x == id
+		));
+	}
+
+	#[test]
+	fn strings_compile_in_one_go() {
+		let mut engine = Kismesis::new();
+		let file = engine.compile_str("<p | hi>\n", None).unwrap();
+		assert_eq!(file.body.len(), 1);
+		assert_eq!(engine.get_file(file.file_id).unwrap().path, None);
+
+		let error = engine
+			.compile_str("<p | hi\n", Some(PathBuf::from("page.ks")))
+			.unwrap_err();
+		let info = DrawingInfo::from(error.file, &engine, false);
+		assert_eq!(error.text, draw_error(&error.errors[0], &info, &engine));
+		assert_eq!(error.to_string(), error.text);
+		assert!(error.text.contains("page.ks"));
+	}
+
+	#[test]
+	fn standalone_parsing() {
+		use compiler::{errors::ErrorKind, html::generate_html, options::Settings};
+
+		let (engine, file) = parse_standalone("<p | hi>\n").unwrap();
+		assert!(engine.get_file(file.file_id).is_some());
+		let output = generate_html(&file, vec![], &Settings::new(), &engine).unwrap();
+		assert_eq!(output.to_string().unwrap(), "<p>hi</p>\n");
+
+		let Err(text) = parse_standalone("<p | hi\n") else {
+			panic!("an unclosed tag should be an error")
+		};
+		assert!(
+			text.contains(&ParseError::ReachedEOF.get_text()),
+			"{}",
+			text
+		);
+		assert!(text.contains("<p | hi"), "{}", text);
+	}
+
+	#[test]
+	fn parsed_files_are_cached() {
+		let mut engine = Kismesis::new();
+		let page = || Some(PathBuf::from("page.ks"));
+		let other = || Some(PathBuf::from("other.ks"));
+		let project = || Some(PathBuf::from("project"));
+		engine.compile_str("<p | a>\n", page()).unwrap();
+		engine.compile_str("<p | a>\n", page()).unwrap();
+		assert_eq!(engine.parse_count(), 1);
+
+		// A different file, or the same one in another project, is parsed again
+		engine.compile_str("<p | a>\n", other()).unwrap();
+		assert_eq!(engine.parse_count(), 2);
+		let tokens = engine.register_source("<p | a>\n", page());
+		engine.parse_cached(tokens, project()).unwrap();
+		assert_eq!(engine.parse_count(), 3);
+		engine.parse_cached(tokens, project()).unwrap();
+		assert_eq!(engine.parse_count(), 3);
+
+		// Changing the content parses it again, and so does changing it back
+		engine.compile_str("<p | b>\n", page()).unwrap();
+		assert_eq!(engine.parse_count(), 4);
+		engine.compile_str("<p | a>\n", page()).unwrap();
+		assert_eq!(engine.parse_count(), 5);
+
+		// Files from the cache point to the tokens they were given
+		let file = engine.compile_str("<p | a>\n", page()).unwrap();
+		assert_eq!(engine.parse_count(), 5);
+		assert_eq!(engine.get_file(file.file_id).unwrap().path, page());
+	}
+
+	#[test]
+	fn cache_hits_compare_tokens() {
+		let mut engine = Kismesis::new();
+		let first = engine.compile_str("<p | a>\n", None).unwrap();
+		// A hash that collides with the first file's
+		let tokens = engine.register_source("<p | b>\n", None);
+		let hash = engine.get_file(first.file_id).unwrap().hash;
+		engine.tokens.get_mut(&tokens).unwrap().hash = hash;
+		let second = engine.parse_cached(tokens, None).unwrap();
+		assert_eq!(engine.parse_count(), 2);
+		assert_ne!(second.body, first.body);
+	}
+
+	#[test]
+	fn step_budgets() {
+		let src = "<div | <p | a @b> <ul | <li | c> <li | d>>>\n".repeat(20);
+		let mut engine = Kismesis::new();
+		engine.set_parse_step_budget(Some(100));
+		let error = engine.compile_str(&src, None).unwrap_err();
+		assert!(matches!(
+			error.errors[0].error,
+			ParseError::StepBudgetExceeded
+		));
+
+		let mut engine = Kismesis::new();
+		engine.set_parse_step_budget(Some(1_000_000));
+		assert_eq!(engine.compile_str(&src, None).unwrap().body.len(), 20);
+	}
+
+	#[test]
+	fn positions_resolve_to_lines_and_columns() {
+		let mut engine = Kismesis::new();
+		let id = engine.register_source("<p | ab cd>\n\t<b | x>", None);
+		let tokens = &engine.get_file(id).unwrap().tokens;
+		let at = |word: &str| {
+			let idx = tokens
+				.iter()
+				.position(|x| matches!(x, Token::Word(w) if w == word))
+				.unwrap();
+			TokenPos::new_at(idx, 0, 0)
+		};
+		let resolve = |pos: &TokenPos, width| engine.resolve_position(id, pos, width);
+		assert_eq!(resolve(&TokenPos::new(), 4), Some((1, 1)));
+		assert_eq!(resolve(&at("cd"), 4), Some((1, 9)));
+		assert_eq!(resolve(&at("b"), 4), Some((2, 6)));
+		assert_eq!(resolve(&at("b"), 2), Some((2, 4)));
+		assert_eq!(resolve(&TokenPos::new_at(1000, 0, 0), 4), None);
+	}
+
+	#[test]
+	fn broken_plugins_are_errors() {
+		let mut engine = Kismesis::new();
+		assert!(matches!(
+			engine.register_plugin("fn token_call(", "broken"),
+			Err(KismesisError::InvalidPlugin(x, _)) if x == "broken"
+		));
+	}
+
+	#[test]
+	fn adversarial_inputs_dont_panic() {
+		use compiler::{html::generate_html, options::Settings};
+
+		let mut engine = Kismesis::new();
+		let plugin = r#"
+			fn token_call(range, params, body) {
+				let text = io::read_to_string("/etc/hostname");
+				let attributes = parsers::get_attributes(parsers::tokenize("= = ="));
+				let parsed = new_parser(params).parse(|x| 42);
+				let mapped = new_parser(params).map(|x| x.nope());
+				let errors = new_parser(params).sequence([|x| 42]).map_err(|x| 42);
+				[]
+			}
+		"#;
+		engine.register_plugin(plugin, "hostile").unwrap();
+		engine.compile_str("<hostile? a b>\n", None).unwrap();
+		let inputs = [
+			"",
+			"<",
+			">",
+			"<<<<",
+			">>>>",
+			"<p",
+			"<p |",
+			"<p | <b",
+			"{",
+			"}",
+			"{{{{",
+			"@",
+			"@{",
+			"\"",
+			"<p a=\"",
+			"<p a=>",
+			"<p {...}>",
+			"<p .>",
+			"<p#>",
+			"<p+>",
+			"<-",
+			"<p->",
+			"<!",
+			"<?>",
+			"<hostile?>",
+			"<hostile? a b | c>",
+			"<nope? a>",
+			"<macro>",
+			"<macro m | <content!>",
+			"<m!>",
+			"<if {a}>",
+			"<for x in>",
+			"<include>",
+			"<include \"/etc/hostname\">",
+			"extends",
+			"extends \"",
+			"const",
+			"const x =",
+			"mut",
+			"<!- unterminated",
+			"\\",
+			"\t\t\n\r\n",
+			"<svg:>",
+			"<:p>",
+			"\u{0}<p | \u{FEFF}>",
+			"<p | ü 🦀 ∂>",
+		];
+		for input in inputs {
+			if let Ok(file) = engine.compile_str(input, None) {
+				let _ = generate_html(&file, vec![], &Settings::new(), &engine);
+			}
+		}
+	}
+}
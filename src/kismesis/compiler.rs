@@ -1,4 +1,6 @@
 use std::{
+	collections::HashMap,
+	fmt::Debug,
 	fs::{self, File},
 	io::{self, Write},
 	path::{Path, PathBuf},
@@ -7,46 +9,66 @@ use std::{
 use crate::kismesis::{KisID, Kismesis, KismesisError};
 
 use self::{
-	options::Settings,
-	parser::errors::Err,
-	reporting::{draw_error, DrawingInfo},
+	errors::{ErrorKind, ErrorState},
+	html::{ScopedError, SourceMapping},
+	options::{LineEnding, Settings},
+	parser::{errors::ParseError, types::ParsedFile},
+	reporting::{draw_all, draw_error, DrawingInfo},
 };
 
-mod errors;
+pub(crate) mod analysis;
+pub(crate) mod errors;
+pub(crate) mod eval;
+pub(crate) mod formatter;
 pub(crate) mod html;
-pub(crate) mod lexer;
+pub mod lexer;
 pub(crate) mod options;
-pub(crate) mod parser;
-mod reporting;
+pub mod parser;
+pub(crate) mod reporting;
 
 pub enum Error {
 	IOError(io::Error, PathBuf),
 	NoMainTemplate,
 	OutputNotInOutputFolder(PathBuf),
 	TemplateInOutputFolder(PathBuf),
-	ParseError(Err, KisID),
+	ParseError(Vec<ErrorState<ParseError>>, KisID),
 	TriedToGetNonExistentTemplate(KisID),
+	InvalidPlugin(String, String),
+	UnknownFlag(String),
+	MissingFlagValue(String),
+	InvalidFlagValue(String, String),
 }
 
 pub fn compile_project() {
 	let mut errors = Vec::new();
 	let mut engine = Kismesis::new();
+	let mut settings = Settings::new();
+	let mut lints = Lints::default();
+	let args = std::env::args().skip(1);
+	if let Err(x) = read_flags(args, &mut settings, &mut lints, &mut engine) {
+		report_errors(vec![x], &engine);
+		return;
+	}
 	let program_path =
 		directories::ProjectDirs::from("net.ampersandia", "ampersandia", "kismesis").unwrap();
 	let plugin_path = program_path.data_dir().join("plugins/helloworld.rhai");
 	println!("{}", &plugin_path.display());
 	let plugin = fs::read_to_string(&plugin_path).unwrap();
-	engine.register_plugin(&plugin, &plugin_path.file_stem().unwrap().to_string_lossy());
+	if let Err(x) = engine.register_plugin(&plugin, &plugin_path.file_stem().unwrap().to_string_lossy()) {
+		errors.push(x.into());
+	}
 
 	let project_path = std::env::current_dir().unwrap();
 
 	let main_template_path = PathBuf::from("templates/main.ks");
 	let template_paths = recursive_crawl(&PathBuf::from("templates")).0;
 
+	let mut template_ids = Vec::new();
 	for path in template_paths {
 		match engine.register_file(path, Some(project_path.clone())) {
-			Ok(x) => {
-				engine.register_template(x);
+			Ok(mut x) => {
+				eval::fold_constants(&mut x);
+				template_ids.push(engine.register_template(x));
 			}
 			Err(x) => errors.push(x.into()),
 		}
@@ -57,6 +79,12 @@ pub fn compile_project() {
 		return;
 	}
 
+	for id in template_ids.iter() {
+		if let Some(template) = engine.get_template(id) {
+			lint(template, &lints, &engine);
+		}
+	}
+
 	let Some(main_template_id) = engine.verify_template_id(main_template_path) else {
 		errors.push(Error::NoMainTemplate);
 		report_errors(errors, &engine);
@@ -64,11 +92,13 @@ pub fn compile_project() {
 	};
 	let input_paths = recursive_crawl(&PathBuf::from("input")).0;
 
-	let settings = Settings::new();
 	for path in input_paths {
 		let parsed_file = match engine.register_file(path, Some(project_path.clone())) {
 			Ok(mut x) => {
-				x.template = Some(main_template_id.clone());
+				eval::fold_constants(&mut x);
+				if x.template.is_none() {
+					x.template = Some(main_template_id.clone());
+				}
 				x
 			}
 			Err(x) => {
@@ -76,13 +106,14 @@ pub fn compile_project() {
 				continue
 			},
 		};
+		lint_input(&parsed_file, &lints, &engine);
 		match html::generate_html(&parsed_file, vec![], &settings, &engine) {
 			Ok(x) => {
 				let output_path = PathBuf::from("output");
-				let file = match engine.get_file(parsed_file.file_id) {
-					Some(x) => x,
-					None => {
-						errors.push(Error::TriedToGetNonExistentTemplate(parsed_file.file_id));
+				let file = match engine.get_file_or_err(parsed_file.file_id) {
+					Ok(x) => x,
+					Err(x) => {
+						errors.push(x.into());
 						return;
 					}
 				};
@@ -107,11 +138,27 @@ pub fn compile_project() {
 							continue;
 						}
 					};
-					let file_text = match x.to_string() {
-						Ok(x) => x,
-						Err(_) => {
-							errors.push(Error::TemplateInOutputFolder(path.clone()));
-							continue;
+					let file_text = if settings.has_source_map() {
+						match x.to_string_with_source_map() {
+							Ok((text, mappings)) => {
+								let map_path = output_path.with_extension("html.map");
+								if let Err(x) = write_source_map(&map_path, &mappings, &engine) {
+									errors.push(Error::IOError(x, map_path));
+								}
+								text
+							}
+							Err(_) => {
+								errors.push(Error::TemplateInOutputFolder(path.clone()));
+								continue;
+							}
+						}
+					} else {
+						match x.to_string() {
+							Ok(x) => x,
+							Err(_) => {
+								errors.push(Error::TemplateInOutputFolder(path.clone()));
+								continue;
+							}
 						}
 					};
 					match write!(file, "{}", file_text) {
@@ -127,8 +174,13 @@ pub fn compile_project() {
 				}
 			}
 			Err(errors) => {
+				// Errors tend to come in bunches from the same few files
+				let mut infos = HashMap::new();
 				for error in errors {
-					eprintln!("{}", reporting::draw_scoped_error(&error, &engine));
+					let info = infos
+						.entry(error.scope)
+						.or_insert_with(|| DrawingInfo::from(error.scope, &engine, false));
+					eprintln!("{}", draw_error(&error.error, info, &engine));
 				}
 			}
 		}
@@ -140,6 +192,128 @@ pub fn compile_project() {
 	}
 }
 
+/// Prints the files at `paths` the way the formatter writes them, leaving
+/// the files themselves as they are
+pub fn format_files(paths: impl Iterator<Item = String>) {
+	let mut engine = Kismesis::new();
+	let mut errors = Vec::new();
+	let project_path = std::env::current_dir().ok();
+	for path in paths {
+		match engine.register_file(PathBuf::from(path), project_path.clone()) {
+			Ok(file) => print!("{}", formatter::format(&file)),
+			Err(x) => errors.push(x.into()),
+		}
+	}
+	report_errors(errors, &engine);
+}
+
+/// Applies the command line flags to the settings of the output and of the
+/// engine
+fn read_flags(
+	mut args: impl Iterator<Item = String>,
+	settings: &mut Settings,
+	lints: &mut Lints,
+	engine: &mut Kismesis,
+) -> Result<(), Error> {
+	while let Some(flag) = args.next() {
+		match flag.as_str() {
+			"--env" => settings.add_environment(&flag_value(&flag, args.next())?),
+			"--minify" => settings.set_minify(true),
+			"--crlf" => settings.set_line_ending(LineEnding::CrLf),
+			"--no-final-newline" => settings.set_final_newline(false),
+			"--source-map" => settings.set_source_map(true),
+			"--check-indentation" => lints.indentation = true,
+			"--check-macro-names" => lints.macro_names = true,
+			"--check-recursion" => lints.recursion = true,
+			"--max-expansion-depth" => {
+				settings.set_max_expansion_depth(number_flag_value(&flag, args.next())?)
+			}
+			"--tab-width" => engine.set_tab_width(number_flag_value(&flag, args.next())?),
+			"--error-width" => engine.set_error_width(Some(number_flag_value(&flag, args.next())?)),
+			_ => return Err(Error::UnknownFlag(flag)),
+		}
+	}
+	Ok(())
+}
+
+fn flag_value(flag: &str, value: Option<String>) -> Result<String, Error> {
+	value.ok_or_else(|| Error::MissingFlagValue(flag.to_string()))
+}
+
+fn number_flag_value(flag: &str, value: Option<String>) -> Result<usize, Error> {
+	let value = flag_value(flag, value)?;
+	value
+		.parse()
+		.map_err(|_| Error::InvalidFlagValue(flag.to_string(), value))
+}
+
+/// Writes one line for each tag in the output, with the byte range it takes
+/// up in the output and the line and column its name is at in its source
+fn write_source_map(path: &Path, mappings: &[SourceMapping], engine: &Kismesis) -> io::Result<()> {
+	let mut file = File::create(path)?;
+	for mapping in mappings {
+		let source = engine
+			.get_file(mapping.file)
+			.and_then(|x| x.path.as_ref())
+			.map(|x| x.to_string_lossy().to_string())
+			.unwrap_or_else(|| mapping.file.to_string());
+		let (line, column) = engine
+			.resolve_position(
+				mapping.file,
+				&mapping.source.get_start(),
+				engine.tab_width(),
+			)
+			.unwrap_or((0, 0));
+		writeln!(
+			file,
+			"{}-{} {}:{}:{}",
+			mapping.output.start, mapping.output.end, source, line, column
+		)?;
+	}
+	Ok(())
+}
+
+/// The lints that only run when a flag asks for them
+#[derive(Default)]
+struct Lints {
+	/// Lines indented with both tabs and spaces
+	indentation: bool,
+	/// Macros named like HTML elements
+	macro_names: bool,
+	/// Macros that can end up calling themselves
+	recursion: bool,
+}
+
+/// Draws the mistakes in a file that don't stop it from compiling as
+/// warnings
+fn lint(file: &ParsedFile, lints: &Lints, engine: &Kismesis) {
+	report_warnings(&analysis::check_variables(file, engine), engine);
+	report_warnings(&analysis::check_macro_arguments(file), engine);
+	report_warnings(&analysis::check_pure_macros(file), engine);
+	if lints.indentation {
+		if let Some(source) = engine.get_file(file.file_id) {
+			let warnings = analysis::check_indentation(&source.tokens, file.file_id);
+			report_warnings(&warnings, engine);
+		}
+	}
+	if lints.macro_names {
+		report_warnings(&analysis::check_macro_names(file), engine);
+	}
+	if lints.recursion {
+		report_warnings(&analysis::find_recursive_macros(file), engine);
+	}
+}
+
+/// Like `lint`, plus the lints that only make sense for input files
+fn lint_input(file: &ParsedFile, lints: &Lints, engine: &Kismesis) {
+	lint(file, lints, engine);
+	report_warnings(&analysis::find_unused(file, engine), engine);
+}
+
+fn report_warnings<T: ErrorKind + Debug>(warnings: &[ScopedError<T>], engine: &Kismesis) {
+	eprint!("{}", draw_all(warnings, engine));
+}
+
 pub fn recursive_crawl(path: &Path) -> (Vec<PathBuf>, Vec<io::Error>) {
 	let mut errors = Vec::new();
 	let mut paths = Vec::new();
@@ -175,6 +349,8 @@ impl From<KismesisError> for Error {
 		match value {
 			KismesisError::IOError(x, y) => Error::IOError(x, y),
 			KismesisError::ParseError(x, y) => Error::ParseError(x, y),
+			KismesisError::InvalidKisID(x) => Error::TriedToGetNonExistentTemplate(x),
+			KismesisError::InvalidPlugin(x, y) => Error::InvalidPlugin(x, y),
 		}
 	}
 }
@@ -186,8 +362,100 @@ pub fn report_errors(errors: Vec<Error>, engine: &Kismesis) {
             Error::NoMainTemplate => eprintln!("Coudln't compile project because it doesn't have a template in templates/main.ks"),
             Error::OutputNotInOutputFolder(path) => eprintln!("Tried to output {} to a location outside the project's output folder.\n\nThis is meant to be impossible, please contact the developer at https://ampersandia.net/", path.to_string_lossy()),
             Error::TemplateInOutputFolder(path) => eprintln!("{} is a template, but it is in the input folder", path.to_string_lossy()),
-            Error::ParseError(error, id) => eprintln!("{}", draw_error(&error.unpack(), &DrawingInfo::from(id, engine, false), engine)),
-			Error::TriedToGetNonExistentTemplate(id) => eprintln!("Tried to get a non-existent kismesis template {:?}", id)
+            Error::ParseError(errors, id) => {
+				let info = DrawingInfo::from(id, engine, false);
+				for error in errors {
+					eprintln!("{}", draw_error(&error, &info, engine));
+				}
+			}
+			Error::TriedToGetNonExistentTemplate(id) => eprintln!("Tried to get a non-existent kismesis template {:?}", id),
+			Error::InvalidPlugin(name, error) => eprintln!("Couldn't compile the `{}` plugin: {}", name, error),
+			Error::UnknownFlag(flag) => eprintln!("There's no `{}` flag", flag),
+			Error::MissingFlagValue(flag) => eprintln!("The `{}` flag needs a value after it", flag),
+			Error::InvalidFlagValue(flag, value) => eprintln!("`{}` isn't a valid value for the `{}` flag, it has to be a number", value, flag),
         }
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn flags(args: &[&str]) -> Result<(Settings, Lints, Kismesis), Error> {
+		let mut settings = Settings::new();
+		let mut lints = Lints::default();
+		let mut engine = Kismesis::new();
+		let args = args.iter().map(|x| x.to_string());
+		read_flags(args, &mut settings, &mut lints, &mut engine).map(|_| (settings, lints, engine))
+	}
+
+	#[test]
+	fn flags_change_settings() {
+		let Ok((settings, lints, engine)) = flags(&[]) else {
+			panic!("no flags should be fine")
+		};
+		assert!(!settings.is_minified() && !settings.has_source_map());
+		assert!(!lints.indentation && !lints.macro_names && !lints.recursion);
+		assert!(!settings.has_environment("production"));
+		assert_eq!(engine.error_width(), None);
+
+		let Ok((settings, lints, engine)) = flags(&[
+			"--env",
+			"production",
+			"--minify",
+			"--crlf",
+			"--no-final-newline",
+			"--source-map",
+			"--check-indentation",
+			"--check-macro-names",
+			"--check-recursion",
+			"--max-expansion-depth",
+			"8",
+			"--tab-width",
+			"2",
+			"--error-width",
+			"60",
+		]) else {
+			panic!("the flags should be valid")
+		};
+		assert!(settings.has_environment("production"));
+		assert!(settings.is_minified());
+		assert_eq!(settings.line_ending(), LineEnding::CrLf);
+		assert!(!settings.has_final_newline());
+		assert!(settings.has_source_map());
+		assert!(lints.indentation && lints.macro_names && lints.recursion);
+		assert_eq!(settings.max_expansion_depth(), 8);
+		assert_eq!(engine.tab_width(), 2);
+		assert_eq!(engine.error_width(), Some(60));
+	}
+
+	#[test]
+	fn bad_flags_are_errors() {
+		assert!(
+			matches!(flags(&["--minify", "--fast"]), Err(Error::UnknownFlag(x)) if x == "--fast")
+		);
+		assert!(matches!(flags(&["--env"]), Err(Error::MissingFlagValue(x)) if x == "--env"));
+		assert!(matches!(
+			flags(&["--tab-width", "wide"]),
+			Err(Error::InvalidFlagValue(x, y)) if x == "--tab-width" && y == "wide"
+		));
+	}
+
+	#[test]
+	fn source_maps_have_a_line_per_tag() {
+		let (mut settings, _, mut engine) = flags(&["--source-map"]).ok().unwrap();
+		settings.set_final_newline(false);
+		let file = engine.compile_str("<p | <b | x>>", None).unwrap();
+		let output = html::generate_html(&file, vec![], &settings, &engine)
+			.ok()
+			.unwrap();
+		let (text, mappings) = output.to_string_with_source_map().unwrap();
+		assert_eq!(text, "<p><b>x</b></p>");
+		let path = std::env::temp_dir().join("kismesis-source-map-test.html.map");
+		write_source_map(&path, &mappings, &engine).unwrap();
+		let map = fs::read_to_string(&path).unwrap();
+		fs::remove_file(&path).unwrap();
+		let id = file.file_id;
+		assert_eq!(map, format!("0-15 {}:1:2\n3-11 {}:1:7\n", id, id));
+	}
+}
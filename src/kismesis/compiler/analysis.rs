@@ -0,0 +1,721 @@
+use std::collections::HashSet;
+
+use crate::kismesis::{KisID, Kismesis};
+
+use super::{
+	errors::{ErrorKind, Severity},
+	html::ScopedError,
+	lexer::Token,
+	parser::{
+		errors::{Hintable, Hints},
+		state::TokenPos,
+		types::{Expression, ForTag, HtmlNodes, Macro, ParsedFile, Ranged, TextPos, HTML_ELEMENTS},
+		visitor::{walk, walk_nodes, Visitor},
+	},
+};
+
+/// Reports every variable reference that isn't defined as a variable, a
+/// lambda, a macro argument or a `for` variable in the scope it's used in.
+/// The variables and lambdas of the file's template are in scope too.
+pub fn check_variables(file: &ParsedFile, engine: &Kismesis) -> Vec<ScopedError<Warning>> {
+	let mut checker = VariableChecker {
+		file,
+		scopes: vec![file.get_variable_scope(&[], engine).into_keys().collect()],
+		errors: vec![],
+	};
+	walk(file, &mut checker);
+	checker.errors
+}
+
+struct VariableChecker<'a> {
+	file: &'a ParsedFile,
+	scopes: Vec<Vec<String>>,
+	errors: Vec<ScopedError<Warning>>,
+}
+
+impl VariableChecker<'_> {
+	fn is_defined(&self, name: &str) -> bool {
+		self.scopes.iter().flatten().any(|x| x == name)
+	}
+
+	fn available_names(&self) -> Vec<String> {
+		let mut names: Vec<String> = self.scopes.iter().flatten().cloned().collect();
+		names.sort();
+		names.dedup();
+		names
+	}
+}
+
+impl Visitor for VariableChecker<'_> {
+	fn visit_macro_def(&mut self, mac: &Macro) {
+		self.scopes
+			.push(mac.arguments.iter().map(|x| x.name.value.clone()).collect());
+	}
+
+	fn leave_macro_def(&mut self, _mac: &Macro) {
+		self.scopes.pop();
+	}
+
+	fn visit_for(&mut self, tag: &ForTag) {
+		self.scopes.push(vec![tag.variable.value.clone()]);
+	}
+
+	fn leave_for(&mut self, _tag: &ForTag) {
+		self.scopes.pop();
+	}
+
+	fn visit_expression(&mut self, expression: &Ranged<Expression>) {
+		if let Expression::Variable(ref name) = expression.value {
+			if !self.is_defined(name) {
+				let error = Warning::UndefinedVariable(name.clone())
+					.with_scope_at(self.file.file_id, expression.range.clone())
+					.with_hint(Hints::AvailableNames(self.available_names()).stateless());
+				self.errors.push(error);
+			}
+		}
+	}
+}
+
+#[derive(Clone, Debug)]
+pub enum ArgumentError {
+	UnknownArgument { name: String },
+	MissingArgument { name: String },
+}
+
+impl ErrorKind for ArgumentError {
+	fn get_text(&self) -> String {
+		match self {
+			Self::UnknownArgument { name } => {
+				format!("The macro has no argument called `{}`", name)
+			}
+			Self::MissingArgument { name } => format!(
+				"The `{}` argument isn't set, and the macro has no default for it",
+				name
+			),
+		}
+	}
+}
+
+/// Reports macro calls that set arguments their macro doesn't have, or that
+/// leave out arguments their macro has no default for.
+///
+/// Only calls to macros defined in the file itself are checked, since the
+/// macros of a template aren't known here.
+pub fn check_macro_arguments(file: &ParsedFile) -> Vec<ScopedError<ArgumentError>> {
+	let mut checker = ArgumentChecker {
+		file,
+		errors: vec![],
+	};
+	walk(file, &mut checker);
+	checker.errors
+}
+
+struct ArgumentChecker<'a> {
+	file: &'a ParsedFile,
+	errors: Vec<ScopedError<ArgumentError>>,
+}
+
+impl Visitor for ArgumentChecker<'_> {
+	fn visit_macro_call(&mut self, mac: &Macro) {
+		let Some(definition) = self
+			.file
+			.defined_macros
+			.iter()
+			.find(|x| x.name.value == mac.name.value)
+		else {
+			return;
+		};
+		for argument in mac.arguments.iter() {
+			if definition.get_argument(&argument.name.value).is_none() {
+				let names = definition
+					.arguments
+					.iter()
+					.map(|x| x.name.value.clone())
+					.collect();
+				let error = ArgumentError::UnknownArgument {
+					name: argument.name.value.clone(),
+				}
+				.with_scope_at(self.file.file_id, argument.name.range.clone())
+				.with_hint(Hints::AvailableNames(names).stateless());
+				self.errors.push(error);
+			}
+		}
+		for argument in definition.arguments.iter() {
+			let is_set = mac
+				.get_argument(&argument.name.value)
+				.is_some_and(|x| x.value.is_some());
+			if argument.value.is_none() && !is_set {
+				let error = ArgumentError::MissingArgument {
+					name: argument.name.value.clone(),
+				}
+				.with_scope_at(self.file.file_id, mac.name.range.clone())
+				.with_hint(
+					Hints::ArgumentDefinedHere
+						.with_state_at(argument.name.range.clone(), self.file.file_id),
+				);
+				self.errors.push(error);
+			}
+		}
+	}
+}
+
+#[derive(Clone, Debug)]
+pub enum Warning {
+	UndefinedVariable(String),
+	UnusedVariable(String),
+	UnusedMacro(String),
+	MixedIndentation,
+	MacroNamedLikeElement(String),
+	PureMacroWithContent(String),
+	/// A macro and the macros it calls until it's called again
+	RecursiveMacro(Vec<String>),
+}
+
+impl ErrorKind for Warning {
+	fn get_text(&self) -> String {
+		match self {
+			Self::UndefinedVariable(name) => format!("The `{}` variable isn't defined here", name),
+			Self::UnusedVariable(name) => format!("The `{}` variable is never used", name),
+			Self::UnusedMacro(name) => format!("The `{}` macro is never used", name),
+			Self::MixedIndentation => "This line is indented with both tabs and spaces".into(),
+			Self::MacroNamedLikeElement(name) => {
+				format!("The `{}` macro has the same name as an HTML element", name)
+			}
+			Self::PureMacroWithContent(name) => format!(
+				"The `{}` macro can't be pure, since it has a content tag in it",
+				name
+			),
+			Self::RecursiveMacro(chain) => format!(
+				"The `{}` macro can end up calling itself: {}",
+				chain[0],
+				chain.join(" -> ")
+			),
+		}
+	}
+
+	fn severity(&self) -> Severity {
+		Severity::Warning
+	}
+}
+
+/// Reports every variable, lambda and macro definition that is never
+/// referenced. Things only referenced from inside of unused macros count as
+/// unused too.
+///
+/// This is meant for input files, since macros and lambdas in templates are
+/// often meant to be used by the files that use the template. Variables that
+/// have the name of one of their template's variables or lambdas fill it in,
+/// so they're never reported.
+pub fn find_unused(file: &ParsedFile, engine: &Kismesis) -> Vec<ScopedError<Warning>> {
+	let mut collector = ReferenceCollector {
+		owner: None,
+		scopes: vec![],
+		references: vec![],
+	};
+	walk(file, &mut collector);
+
+	let mut used_macros = HashSet::new();
+	let mut pending: Vec<Option<&str>> = vec![None];
+	while let Some(owner) = pending.pop() {
+		for (_, reference) in collector
+			.references
+			.iter()
+			.filter(|x| x.0.as_deref() == owner)
+		{
+			if let Reference::Macro(name) = reference {
+				if used_macros.insert(name.as_str()) {
+					pending.push(Some(name));
+				}
+			}
+		}
+	}
+	let used_variables: HashSet<&str> = collector
+		.references
+		.iter()
+		.filter(|(owner, _)| match owner {
+			Some(owner) => used_macros.contains(owner.as_str()),
+			None => true,
+		})
+		.filter_map(|(_, reference)| match reference {
+			Reference::Variable(name) => Some(name.as_str()),
+			Reference::Macro(_) => None,
+		})
+		.collect();
+
+	let template_variables: HashSet<String> = file
+		.template
+		.clone()
+		.and_then(|x| engine.get_template(x))
+		.map(|x| x.get_variable_scope(&[], engine).into_keys().collect())
+		.unwrap_or_default();
+	let unused_variable = |name: &Ranged<String>| -> Option<(Warning, TextPos)> {
+		let used = used_variables.contains(name.value.as_str())
+			|| template_variables.contains(&name.value);
+		(!used).then(|| {
+			(
+				Warning::UnusedVariable(name.value.clone()),
+				name.range.clone(),
+			)
+		})
+	};
+	let variables = file
+		.defined_variables
+		.iter()
+		.filter_map(|x| unused_variable(&x.name));
+	let lambdas = file
+		.defined_lambdas
+		.iter()
+		.filter_map(|x| unused_variable(&x.name));
+	let macros = file
+		.defined_macros
+		.iter()
+		.filter(|x| !used_macros.contains(x.name.value.as_str()))
+		.map(|x| {
+			(
+				Warning::UnusedMacro(x.name.value.clone()),
+				x.name.range.clone(),
+			)
+		});
+
+	variables
+		.chain(lambdas)
+		.chain(macros)
+		.map(|(warning, range)| warning.with_scope_at(file.file_id, range))
+		.collect()
+}
+
+/// Reports every line whose leading whitespace has both tabs and spaces in
+/// it. The leading whitespace of a line is every tab and space before its
+/// first other token, so lines with nothing but whitespace in them are
+/// never reported.
+pub fn check_indentation(tokens: &[Token], file: KisID) -> Vec<ScopedError<Warning>> {
+	let mut warnings = vec![];
+	let mut line = 0;
+	let mut line_start = 0;
+	// Whether the current line's leading whitespace has tabs and spaces in
+	// it, or `None` once the line's first other token has been seen
+	let mut leading = Some((false, false));
+	for (idx, token) in tokens.iter().enumerate() {
+		match (token, leading.as_mut()) {
+			(Token::Newline(_), _) => {
+				line += 1;
+				line_start = idx + 1;
+				leading = Some((false, false));
+			}
+			(Token::Indent(_), Some((tabs, _))) => *tabs = true,
+			(Token::Space(_), Some((_, spaces))) => *spaces = true,
+			(_, Some((tabs, spaces))) => {
+				if *tabs && *spaces {
+					let range = TextPos::Range((
+						TokenPos::new_at(line_start, line, 0),
+						TokenPos::new_at(idx, line, idx - line_start),
+					));
+					warnings.push(Warning::MixedIndentation.with_scope_at(file, range));
+				}
+				leading = None;
+			}
+			(_, None) => (),
+		}
+	}
+	warnings
+}
+
+/// Reports every macro definition named like an HTML element. Calls to
+/// them are written differently from tags, as `<name!>`, so they can't be
+/// confused by the parser, but they're easy to confuse when reading.
+pub fn check_macro_names(file: &ParsedFile) -> Vec<ScopedError<Warning>> {
+	file.defined_macros
+		.iter()
+		.filter(|x| {
+			HTML_ELEMENTS
+				.iter()
+				.any(|element| element.eq_ignore_ascii_case(&x.name.value))
+		})
+		.map(|x| {
+			Warning::MacroNamedLikeElement(x.name.value.clone())
+				.with_scope_at(file.file_id, x.name.range.clone())
+		})
+		.collect()
+}
+
+/// Reports every pure macro with a content tag in it. The content depends on
+/// where the macro is used, so these macros are never cached
+pub fn check_pure_macros(file: &ParsedFile) -> Vec<ScopedError<Warning>> {
+	file.defined_macros
+		.iter()
+		.filter(|x| x.pure && has_content(&x.body))
+		.map(|x| {
+			Warning::PureMacroWithContent(x.name.value.clone())
+				.with_scope_at(file.file_id, x.name.range.clone())
+		})
+		.collect()
+}
+
+/// Reports every macro defined in the file that can end up calling itself,
+/// along with the shortest chain of calls that gets back to it. Calls to
+/// macros from templates aren't followed. A macro that only calls itself
+/// behind an `if` may still stop, so these are only warnings.
+pub fn find_recursive_macros(file: &ParsedFile) -> Vec<ScopedError<Warning>> {
+	let mut collector = ReferenceCollector {
+		owner: None,
+		scopes: vec![],
+		references: vec![],
+	};
+	walk(file, &mut collector);
+
+	let calls = |caller: &str| -> Vec<&str> {
+		collector
+			.references
+			.iter()
+			.filter_map(|(owner, reference)| match reference {
+				Reference::Macro(name) if owner.as_deref() == Some(caller) => Some(name.as_str()),
+				_ => None,
+			})
+			.collect()
+	};
+	let find_chain = |name: &str| -> Option<Vec<String>> {
+		let mut seen = HashSet::new();
+		let mut chains = vec![vec![name]];
+		while !chains.is_empty() {
+			let mut next = vec![];
+			for chain in chains {
+				for callee in calls(chain[chain.len() - 1]) {
+					let mut chain = chain.clone();
+					chain.push(callee);
+					if callee == name {
+						return Some(chain.into_iter().map(String::from).collect());
+					}
+					if seen.insert(callee) {
+						next.push(chain);
+					}
+				}
+			}
+			chains = next;
+		}
+		None
+	};
+
+	file.defined_macros
+		.iter()
+		.filter_map(|x| {
+			find_chain(&x.name.value).map(|chain| {
+				Warning::RecursiveMacro(chain).with_scope_at(file.file_id, x.name.range.clone())
+			})
+		})
+		.collect()
+}
+
+/// Whether there's a content tag anywhere in the nodes
+pub(crate) fn has_content(nodes: &[HtmlNodes]) -> bool {
+	let mut finder = ContentFinder(false);
+	walk_nodes(nodes, &mut finder);
+	finder.0
+}
+
+struct ContentFinder(bool);
+
+impl Visitor for ContentFinder {
+	fn visit_content(&mut self, _slot: Option<&str>) {
+		self.0 = true;
+	}
+}
+
+enum Reference {
+	Variable(String),
+	Macro(String),
+}
+
+struct ReferenceCollector {
+	/// The macro whose definition is being walked, if any
+	owner: Option<String>,
+	/// Names that shadow file-level variables, like macro arguments
+	scopes: Vec<Vec<String>>,
+	references: Vec<(Option<String>, Reference)>,
+}
+
+impl Visitor for ReferenceCollector {
+	fn visit_macro_def(&mut self, mac: &Macro) {
+		self.owner = Some(mac.name.value.clone());
+		self.scopes
+			.push(mac.arguments.iter().map(|x| x.name.value.clone()).collect());
+	}
+
+	fn leave_macro_def(&mut self, _mac: &Macro) {
+		self.owner = None;
+		self.scopes.pop();
+	}
+
+	fn visit_for(&mut self, tag: &ForTag) {
+		self.scopes.push(vec![tag.variable.value.clone()]);
+	}
+
+	fn leave_for(&mut self, _tag: &ForTag) {
+		self.scopes.pop();
+	}
+
+	fn visit_macro_call(&mut self, mac: &Macro) {
+		self.references
+			.push((self.owner.clone(), Reference::Macro(mac.name.value.clone())));
+	}
+
+	fn visit_expression(&mut self, expression: &Ranged<Expression>) {
+		if let Expression::Variable(ref name) = expression.value {
+			if !self.scopes.iter().flatten().any(|x| x == name) {
+				self.references
+					.push((self.owner.clone(), Reference::Variable(name.clone())));
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::kismesis::compiler::parser::errors::Hint;
+
+	fn parse(engine: &mut Kismesis, src: &str) -> ParsedFile {
+		engine.compile_str(src, None).unwrap()
+	}
+
+	fn undefined_names(warnings: &[ScopedError<Warning>]) -> Vec<&str> {
+		warnings
+			.iter()
+			.map(|x| match &x.error.error {
+				Warning::UndefinedVariable(name) => name.as_str(),
+				x => panic!("expected an undefined variable, got {:?}", x),
+			})
+			.collect()
+	}
+
+	#[test]
+	fn defined_variables_are_fine() {
+		let mut engine = Kismesis::new();
+		let file = parse(
+			&mut engine,
+			"const a = \"x\"\nmut b\n<macro m c | <p | @c @a>>\n<for d in {a} | <p | @d @b>>\n",
+		);
+		assert_eq!(
+			undefined_names(&check_variables(&file, &engine)),
+			Vec::<&str>::new()
+		);
+	}
+
+	#[test]
+	fn undefined_variables_are_reported() {
+		let mut engine = Kismesis::new();
+		let file = parse(
+			&mut engine,
+			"const a = \"x\"\n<macro m c | <p | @c>>\n<p | @c>\n",
+		);
+		let warnings = check_variables(&file, &engine);
+		assert_eq!(undefined_names(&warnings), ["c"]);
+		let position = warnings[0].error.text_position.get_start();
+		assert_eq!((position.get_line(), position.get_column()), (2, 6));
+		let hint = &warnings[0].error.hints[0];
+		assert!(matches!(
+			hint,
+			Hint::Stateless(x) if matches!(&x.error, Hints::AvailableNames(names) if names == &["a"])
+		));
+	}
+
+	#[test]
+	fn template_variables_are_in_scope() {
+		let mut engine = Kismesis::new();
+		let template = parse(&mut engine, "const site = \"x\"\n<main | <content!>>\n");
+		let template = engine.register_template(template);
+		let mut file = parse(&mut engine, "<p | @site @page>\n");
+		assert_eq!(
+			undefined_names(&check_variables(&file, &engine)),
+			["site", "page"]
+		);
+		file.template = Some(template);
+		assert_eq!(undefined_names(&check_variables(&file, &engine)), ["page"]);
+	}
+
+	fn argument_errors(errors: &[ScopedError<ArgumentError>]) -> Vec<String> {
+		errors
+			.iter()
+			.map(|x| match &x.error.error {
+				ArgumentError::UnknownArgument { name } => format!("unknown {}", name),
+				ArgumentError::MissingArgument { name } => format!("missing {}", name),
+			})
+			.collect()
+	}
+
+	#[test]
+	fn valid_macro_calls_are_fine() {
+		let mut engine = Kismesis::new();
+		let file = parse(
+			&mut engine,
+			"<macro m a b=\"x\" | <p | @a @b>>\n<m! a=\"y\">\n<m! a=\"y\" b=\"z\">\n",
+		);
+		assert!(check_macro_arguments(&file).is_empty());
+	}
+
+	#[test]
+	fn unknown_macro_arguments_are_reported() {
+		let mut engine = Kismesis::new();
+		let file = parse(
+			&mut engine,
+			"<macro m a | <p | @a>>\n<m! a=\"y\" c=\"z\">\n",
+		);
+		let errors = check_macro_arguments(&file);
+		assert_eq!(argument_errors(&errors), ["unknown c"]);
+		let position = errors[0].error.text_position.get_start();
+		assert_eq!((position.get_line(), position.get_column()), (1, 10));
+		assert!(matches!(
+			&errors[0].error.hints[0],
+			Hint::Stateless(x) if matches!(&x.error, Hints::AvailableNames(names) if names == &["a"])
+		));
+	}
+
+	#[test]
+	fn missing_macro_arguments_are_reported() {
+		let mut engine = Kismesis::new();
+		let file = parse(
+			&mut engine,
+			"<macro m a b=\"x\" | <p | @a @b>>\n<m! b=\"z\">\n",
+		);
+		let errors = check_macro_arguments(&file);
+		assert_eq!(argument_errors(&errors), ["missing a"]);
+		assert!(matches!(
+			&errors[0].error.hints[0],
+			Hint::Stateful(x) if matches!(x.error.error, Hints::ArgumentDefinedHere)
+		));
+	}
+
+	fn unused_names(warnings: &[ScopedError<Warning>]) -> Vec<&str> {
+		warnings
+			.iter()
+			.map(|x| match &x.error.error {
+				Warning::UnusedVariable(name) | Warning::UnusedMacro(name) => name.as_str(),
+				x => panic!("expected an unused definition, got {:?}", x),
+			})
+			.collect()
+	}
+
+	#[test]
+	fn unused_definitions_are_reported() {
+		let mut engine = Kismesis::new();
+		let file = parse(
+			&mut engine,
+			"const a = \"x\"\nconst b = \"y\"\n<macro m | <p | @b>>\n<macro n | <p | @a>>\n<m!>\n",
+		);
+		let warnings = find_unused(&file, &engine);
+		assert_eq!(unused_names(&warnings), ["a", "n"]);
+		let position = warnings[0].error.text_position.get_start();
+		assert_eq!(position.get_line(), 0);
+	}
+
+	#[test]
+	fn variables_that_fill_the_template_are_used() {
+		let mut engine = Kismesis::new();
+		let template = parse(
+			&mut engine,
+			"mut title
+<title | @title>
+<content!>
+",
+		);
+		let template = engine.register_template(template);
+		let mut file = parse(&mut engine, "const title = \"x\"\nconst other = \"y\"\n");
+		assert_eq!(
+			unused_names(&find_unused(&file, &engine)),
+			["title", "other"]
+		);
+		file.template = Some(template);
+		assert_eq!(unused_names(&find_unused(&file, &engine)), ["other"]);
+	}
+
+	fn mixed_lines(src: &str) -> Vec<(usize, usize)> {
+		let mut engine = Kismesis::new();
+		let tokens = crate::kismesis::compiler::lexer::tokenize(src);
+		let id = engine.register_tokens(tokens.clone(), None);
+		check_indentation(&tokens, id)
+			.iter()
+			.map(|x| match (&x.error.error, &x.error.text_position) {
+				(Warning::MixedIndentation, TextPos::Range((start, end))) => {
+					(start.get_line(), end.get_column())
+				}
+				x => panic!("expected mixed indentation, got {:?}", x),
+			})
+			.collect()
+	}
+
+	#[test]
+	fn mixed_indentation_is_reported() {
+		assert_eq!(mixed_lines("<div |\n\t <p | a>\n\t\t<p | b>>\n"), [(1, 2)]);
+		assert_eq!(mixed_lines("<div |\n  \t<p | a>>\n"), [(1, 3)]);
+		// Only leading whitespace counts
+		assert!(mixed_lines("<div |\n    <p | a\t b>\n\t\t<p | c>>\n").is_empty());
+		assert!(mixed_lines("<div |\n\t \n  <p | a>>\n").is_empty());
+	}
+
+	#[test]
+	fn macros_named_like_elements_are_reported() {
+		let mut engine = Kismesis::new();
+		let file = parse(
+			&mut engine,
+			"<macro div | <p | x>>\n<macro Span | <p | y>>\n<macro card | <p | z>>\n",
+		);
+		let names: Vec<_> = check_macro_names(&file)
+			.into_iter()
+			.map(|x| match x.error.error {
+				Warning::MacroNamedLikeElement(name) => name,
+				x => panic!("expected a macro named like an element, got {:?}", x),
+			})
+			.collect();
+		assert_eq!(names, ["div", "Span"]);
+	}
+
+	#[test]
+	fn custom_macro_names_are_fine() {
+		let mut engine = Kismesis::new();
+		let file = parse(&mut engine, "<macro card | <p | x>>\n<card!>\n");
+		assert!(check_macro_names(&file).is_empty());
+	}
+
+	#[test]
+	fn pure_macros_with_content_are_reported() {
+		let mut engine = Kismesis::new();
+		let file = parse(
+			&mut engine,
+			"<macro pure a | <p | <content!>>>\n<macro pure b | <p | x>>\n<macro c | <content!>>\n",
+		);
+		let names: Vec<_> = check_pure_macros(&file)
+			.into_iter()
+			.map(|x| match x.error.error {
+				Warning::PureMacroWithContent(name) => name,
+				x => panic!("expected a pure macro with content, got {:?}", x),
+			})
+			.collect();
+		assert_eq!(names, ["a"]);
+	}
+
+	#[test]
+	fn recursive_macros_are_reported() {
+		let mut engine = Kismesis::new();
+		let file = parse(
+			&mut engine,
+			concat!(
+				"<macro a | <p | <a!>>>\n",
+				"<macro b | <c!>>\n",
+				"<macro c | <p | <b!>>>\n",
+				"<macro d | <a!>>\n",
+				"<macro e | <p | x>>\n",
+			),
+		);
+		let chains: Vec<_> = find_recursive_macros(&file)
+			.into_iter()
+			.map(|x| match x.error.error {
+				Warning::RecursiveMacro(chain) => chain,
+				x => panic!("expected a recursive macro, got {:?}", x),
+			})
+			.collect();
+		// `d` calls a recursive macro, but never gets back to itself
+		assert_eq!(
+			chains,
+			[vec!["a", "a"], vec!["b", "c", "b"], vec!["c", "b", "c"]]
+		);
+	}
+}
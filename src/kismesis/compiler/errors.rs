@@ -1,18 +1,52 @@
+use std::cmp::Ordering;
+
 use crate::kismesis::KisID;
 
 use super::{
 	html::ScopedError,
 	parser::{
 		errors::{Hint, Hintable},
+		state::TokenPos,
 		types::TextPos,
 	},
 };
 
+/// The broad kind of problem an error describes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorCategory {
+	/// The source couldn't be parsed
+	Syntax,
+	/// The source parsed, but doesn't make sense
+	Semantic,
+	/// An error couldn't be shown to the user
+	Reporting,
+}
+
+/// How bad a problem is, which decides how it's drawn
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+	Error,
+	/// Something that doesn't stop the file from being generated, but is
+	/// likely a mistake
+	Warning,
+}
+
 pub trait ErrorKind
 where
 	Self: Sized,
 {
 	fn get_text(&self) -> String;
+	/// A short, stable identifier for this error, like `E0012`. Errors without
+	/// one return an empty string
+	fn code(&self) -> &'static str {
+		""
+	}
+	fn category(&self) -> ErrorCategory {
+		ErrorCategory::Semantic
+	}
+	fn severity(&self) -> Severity {
+		Severity::Error
+	}
 	fn with_state_at(self, position: TextPos) -> ErrorState<Self> {
 		ErrorState {
 			error: self,
@@ -40,6 +74,13 @@ pub struct StatelessError<T> {
 	pub hints: Vec<Hint>,
 }
 
+impl<T> ErrorState<T> {
+	/// Where the error starts, for sorting errors by where they are
+	pub fn start_position(&self) -> TokenPos {
+		self.text_position.get_start()
+	}
+}
+
 impl<T> Hintable for ErrorState<T> {
 	fn add_hint(&mut self, hint: Hint) {
 		self.hints.push(hint);
@@ -54,9 +95,71 @@ impl<T> Hintable for StatelessError<T> {
 
 pub trait Reportable {}
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug)]
 pub struct ErrorState<T> {
 	pub error: T,
 	pub text_position: TextPos,
 	pub hints: Vec<Hint>,
 }
+
+/// Errors are ordered by where they start, then by their code, so sorting
+/// them puts them in the order they're in the file. Errors in the same
+/// place with the same code are told apart by their text, and are equal if
+/// that's the same too
+impl<T: ErrorKind> Ord for ErrorState<T> {
+	fn cmp(&self, other: &Self) -> Ordering {
+		(self.start_position(), self.error.code())
+			.cmp(&(other.start_position(), other.error.code()))
+			.then_with(|| self.error.get_text().cmp(&other.error.get_text()))
+	}
+}
+
+impl<T: ErrorKind> PartialOrd for ErrorState<T> {
+	fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+		Some(self.cmp(other))
+	}
+}
+
+impl<T: ErrorKind> PartialEq for ErrorState<T> {
+	fn eq(&self, other: &Self) -> bool {
+		self.cmp(other) == Ordering::Equal
+	}
+}
+
+impl<T: ErrorKind> Eq for ErrorState<T> {}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn errors_sort_by_where_they_start() {
+		use crate::kismesis::compiler::parser::errors::ParseError;
+
+		let at = |line, column| TokenPos::new_at(0, line, column);
+		let mut errors = vec![
+			ParseError::ExpectedTagName.with_state_at(TextPos::Single(at(2, 0))),
+			ParseError::EndlessString.with_state_at(TextPos::Range((at(1, 4), at(3, 0)))),
+			ParseError::TagCloserMismatch.with_state_at(TextPos::Single(at(0, 7))),
+			ParseError::EmptyString.with_state_at(TextPos::Multi(vec![TextPos::Single(at(2, 0))])),
+			ParseError::ExpectedTagName.with_state_at(TextPos::Single(at(1, 4))),
+		];
+		errors.sort();
+		let order: Vec<_> = errors
+			.iter()
+			.map(|x| (x.start_position(), x.error.code()))
+			.collect();
+		let mut expected = order.clone();
+		expected.sort();
+		assert_eq!(order, expected);
+		assert_eq!(order[0].0, at(0, 7));
+		assert_eq!(order[4].0, at(2, 0));
+		// In the same place, the code decides
+		assert!(errors[1].error.code() < errors[2].error.code());
+		assert!(errors[3].error.code() < errors[4].error.code());
+		assert!(at(0, 9) < at(1, 0));
+
+		let again = ParseError::ExpectedTagName.with_state_at(TextPos::Single(at(1, 4)));
+		assert!(errors.contains(&again));
+	}
+}
@@ -0,0 +1,324 @@
+use std::collections::HashMap;
+
+use super::parser::types::{
+	BinFunc, Expression, HtmlNodes, HtmlTag, Macro, ParsedFile, Ranged, Scoped, Section,
+	StringParts, TextPos, TopNodes, UniFunc,
+};
+
+pub type ValueRef<'a> = Scoped<'a, (Option<&'a Ranged<Expression>>, TextPos)>;
+
+/// The variables that can be seen from somewhere in a file, as given by
+/// [`ParsedFile::get_variable_scope`](super::parser::types::ParsedFile::get_variable_scope)
+pub type VariableScope<'a> = HashMap<String, ValueRef<'a>>;
+
+/// The result of evaluating an expression without generating any HTML
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+	None,
+	/// A truthy value with no contents, like the result of `not` on a falsy
+	/// value
+	Generic,
+	/// A string, whose interpolations haven't been evaluated
+	String(Vec<StringParts>),
+	Array(Vec<Ranged<Expression>>),
+}
+
+impl Value {
+	/// Only `None` is falsy. Strings and arrays are truthy even if they're
+	/// empty
+	pub fn is_truthy(&self) -> bool {
+		!matches!(self, Self::None)
+	}
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EvalError {
+	UndefinedVariable(String),
+	MissingField(String),
+}
+
+/// Evaluates an expression the same way the HTML generator does. Variables
+/// are looked up in `scope`, and variables without a value, like lambdas
+/// that haven't been set, evaluate to `None`.
+///
+/// `and` gives its second value if both are truthy, and `or` gives the
+/// first truthy value. Both give `None` otherwise. Their second operand is
+/// only evaluated if the first one doesn't decide the result, so
+/// `{"a" or x}` is `"a"` even if `x` is undefined
+pub fn eval_static(expr: &Expression, scope: &VariableScope) -> Result<Value, EvalError> {
+	match expr {
+		Expression::None => Ok(Value::None),
+		Expression::Literal(x) => Ok(Value::String(x.clone())),
+		Expression::Array(x) => Ok(Value::Array(x.clone())),
+		Expression::Variable(name) => match scope.get(name) {
+			Some(((Some(value), _), _)) => eval_static(&value.value, scope),
+			Some(((None, _), _)) => Ok(Value::None),
+			None => Err(EvalError::UndefinedVariable(name.clone())),
+		},
+		// None of the values an expression can have has fields
+		Expression::Member(object, field) => {
+			eval_static(&object.value, scope)?;
+			Err(EvalError::MissingField(field.clone()))
+		}
+		Expression::BinFunc(func, exp1, exp2) => {
+			let exp1 = eval_static(&exp1.value, scope)?;
+			match (func, exp1.is_truthy()) {
+				(BinFunc::And, false) => Ok(Value::None),
+				(BinFunc::Or, true) => Ok(exp1),
+				(BinFunc::And | BinFunc::Or, _) => match eval_static(&exp2.value, scope)? {
+					exp2 if exp2.is_truthy() => Ok(exp2),
+					_ => Ok(Value::None),
+				},
+			}
+		}
+		Expression::UniFunc(UniFunc::Not, exp) => {
+			if eval_static(&exp.value, scope)?.is_truthy() {
+				Ok(Value::None)
+			} else {
+				Ok(Value::Generic)
+			}
+		}
+	}
+}
+
+/// Whether an expression uses a variable anywhere, including inside of the
+/// interpolations of its strings
+pub(crate) fn has_references(expr: &Expression) -> bool {
+	match expr {
+		Expression::None => false,
+		Expression::Variable(_) | Expression::Member(..) => true,
+		Expression::Literal(parts) => parts.iter().any(|part| match part {
+			StringParts::Expression(x) => has_references(&x.value),
+			StringParts::String(_) | StringParts::Entity(_) => false,
+		}),
+		Expression::Array(values) => values.iter().any(|x| has_references(&x.value)),
+		Expression::BinFunc(_, exp1, exp2) => {
+			has_references(&exp1.value) || has_references(&exp2.value)
+		}
+		Expression::UniFunc(_, exp) => has_references(&exp.value),
+	}
+}
+
+/// Whether an expression is truthy, if that doesn't depend on any variable
+fn known_truthiness(expr: &Expression) -> Option<bool> {
+	if has_references(expr) {
+		return None;
+	}
+	eval_static(expr, &VariableScope::new())
+		.ok()
+		.map(|x| x.is_truthy())
+}
+
+/// Replaces the parts of an expression that don't depend on any variable
+/// with their result. `and` and `or` are folded when their first operand
+/// decides the result, the same way they short-circuit when generating, so
+/// `{"a" or x}` becomes `"a"`, but `{x and y}` is left alone
+pub fn fold_expression(expr: &mut Ranged<Expression>) {
+	match expr.value {
+		Expression::BinFunc(ref func, ref mut exp1, ref mut exp2) => {
+			fold_expression(exp1);
+			fold_expression(exp2);
+			let folded = match (func, known_truthiness(&exp1.value)) {
+				(BinFunc::And, Some(false)) | (BinFunc::Or, Some(true)) => exp1,
+				(BinFunc::And, Some(true)) | (BinFunc::Or, Some(false)) => exp2,
+				_ => return,
+			};
+			expr.value = std::mem::replace(&mut folded.value, Expression::None);
+		}
+		Expression::UniFunc(UniFunc::Not, ref mut exp) => {
+			fold_expression(exp);
+			match known_truthiness(&exp.value) {
+				Some(true) => expr.value = Expression::None,
+				// `{not !}` is the simplest truthy expression there is
+				Some(false) => exp.value = Expression::None,
+				None => (),
+			}
+		}
+		Expression::Literal(ref mut parts) => fold_string(parts),
+		Expression::Array(ref mut values) => values.iter_mut().for_each(fold_expression),
+		Expression::None | Expression::Variable(_) | Expression::Member(..) => (),
+	}
+}
+
+/// Folds every expression in a file
+pub fn fold_constants(file: &mut ParsedFile) {
+	for variable in file.defined_variables.iter_mut() {
+		fold_expression(&mut variable.value);
+	}
+	for lambda in file.defined_lambdas.iter_mut() {
+		lambda.value.iter_mut().for_each(fold_expression);
+	}
+	for mac in file.defined_macros.iter_mut() {
+		fold_macro(mac);
+	}
+	for node in file.body.iter_mut() {
+		match node {
+			TopNodes::HtmlTag(tag) => fold_tag(tag),
+			TopNodes::MacroCall(mac) => fold_macro(mac),
+			TopNodes::PlugCall(call) => fold_nodes(&mut call.body),
+			TopNodes::Section(section) => fold_section(section),
+			TopNodes::Slot(slot) => fold_nodes(&mut slot.body),
+			TopNodes::Include(path) => fold_string(&mut path.value),
+			TopNodes::If(tag) => {
+				fold_expression(&mut tag.condition);
+				fold_nodes(&mut tag.body);
+				tag.else_body.iter_mut().for_each(|x| fold_nodes(x));
+			}
+			TopNodes::For(tag) => {
+				fold_expression(&mut tag.iterator);
+				fold_nodes(&mut tag.body);
+			}
+			TopNodes::Env(tag) => fold_nodes(&mut tag.body),
+			TopNodes::Content(_) | TopNodes::Doctype(_) => (),
+		}
+	}
+}
+
+fn fold_nodes(nodes: &mut [HtmlNodes]) {
+	for node in nodes.iter_mut() {
+		match node {
+			HtmlNodes::HtmlTag(tag) => fold_tag(tag),
+			HtmlNodes::MacroCall(mac) => fold_macro(mac),
+			HtmlNodes::String(parts) => fold_string(parts),
+			HtmlNodes::PlugCall(call) => fold_nodes(&mut call.body),
+			HtmlNodes::Section(section) => fold_section(section),
+			HtmlNodes::Include(path) => fold_string(&mut path.value),
+			HtmlNodes::If(tag) => {
+				fold_expression(&mut tag.condition);
+				fold_nodes(&mut tag.body);
+				tag.else_body.iter_mut().for_each(|x| fold_nodes(x));
+			}
+			HtmlNodes::For(tag) => {
+				fold_expression(&mut tag.iterator);
+				fold_nodes(&mut tag.body);
+			}
+			HtmlNodes::Env(tag) => fold_nodes(&mut tag.body),
+			HtmlNodes::Content(_) => (),
+		}
+	}
+}
+
+fn fold_tag(tag: &mut HtmlTag) {
+	for attribute in tag.attributes.iter_mut() {
+		fold_expression(&mut attribute.value);
+		attribute.condition.iter_mut().for_each(fold_expression);
+	}
+	tag.spreads.iter_mut().for_each(fold_expression);
+	tag.subtags.iter_mut().for_each(fold_tag);
+	fold_nodes(&mut tag.body);
+}
+
+/// Works for both macro definitions and macro calls
+fn fold_macro(mac: &mut Macro) {
+	for argument in mac.arguments.iter_mut() {
+		argument.value.iter_mut().for_each(fold_expression);
+	}
+	fold_nodes(&mut mac.body);
+}
+
+fn fold_section(section: &mut Section) {
+	fold_string(&mut section.name);
+	section.subtitle.iter_mut().for_each(|x| fold_string(x));
+	section.content.iter_mut().for_each(|x| fold_nodes(x));
+}
+
+fn fold_string(parts: &mut [StringParts]) {
+	for part in parts.iter_mut() {
+		if let StringParts::Expression(expr) = part {
+			// Writing `None` is an error, but writing `{!}` isn't, so
+			// interpolations that would fold into `{!}` are left alone
+			let original = expr.value.clone();
+			fold_expression(expr);
+			if matches!(expr.value, Expression::None) {
+				expr.value = original;
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::kismesis::{
+		compiler::{
+			lexer,
+			parser::{parse_expression, state::TokenPos, types::SpanlessEq},
+		},
+		Kismesis,
+	};
+
+	fn expression(src: &str) -> Expression {
+		parse_expression(&lexer::tokenize(src), &Kismesis::new()).unwrap()
+	}
+
+	#[test]
+	fn static_evaluation() {
+		let mut engine = Kismesis::new();
+		let file = engine
+			.compile_str("const a = \"x\"\nconst none = {!}\nmut b\n", None)
+			.unwrap();
+		let scope = file.get_variable_scope(&[], &engine);
+		let eval = |src: &str| eval_static(&expression(src), &scope);
+		let string = |x: &str| Ok(Value::String(vec![StringParts::String(x.into())]));
+
+		assert_eq!(eval("{!}"), Ok(Value::None));
+		assert_eq!(eval("\"y\""), string("y"));
+		assert!(matches!(eval("{\"y\", a}"), Ok(Value::Array(x)) if x.len() == 2));
+		assert_eq!(eval("a"), string("x"));
+		assert_eq!(eval("b"), Ok(Value::None));
+		assert_eq!(eval("none"), Ok(Value::None));
+		assert_eq!(eval("c"), Err(EvalError::UndefinedVariable("c".into())));
+		assert_eq!(eval("a.len"), Err(EvalError::MissingField("len".into())));
+		assert_eq!(eval("c.len"), Err(EvalError::UndefinedVariable("c".into())));
+		assert_eq!(eval("{a and \"y\"}"), string("y"));
+		assert_eq!(eval("{b and a}"), Ok(Value::None));
+		assert_eq!(eval("{b or a}"), string("x"));
+		// The second operand isn't needed, so it isn't evaluated
+		assert_eq!(eval("{a or c}"), string("x"));
+		assert_eq!(eval("{b and c}"), Ok(Value::None));
+		assert_eq!(
+			eval("{b or c}"),
+			Err(EvalError::UndefinedVariable("c".into()))
+		);
+		assert_eq!(eval("{not b}"), Ok(Value::Generic));
+		assert_eq!(eval("{not a}"), Ok(Value::None));
+		assert!(Value::String(vec![]).is_truthy());
+		assert!(Value::Array(vec![]).is_truthy());
+		assert!(!Value::None.is_truthy());
+	}
+
+	fn folded(src: &str) -> Expression {
+		let mut expr = Ranged {
+			value: expression(src),
+			range: TextPos::Single(TokenPos::new()),
+		};
+		fold_expression(&mut expr);
+		expr.value
+	}
+
+	#[test]
+	fn constant_folding() {
+		assert_eq!(folded("{\"a\" or \"b\"}"), expression("\"a\""));
+		assert_eq!(folded("{\"a\" and \"b\"}"), expression("\"b\""));
+		assert_eq!(folded("{{!} and \"b\"}"), Expression::None);
+		assert_eq!(folded("{not {not {!}}}"), Expression::None);
+		assert_eq!(folded("{not \"a\"}"), Expression::None);
+		assert_eq!(folded("{\"a\" and x}"), expression("x"));
+		assert_eq!(folded("{{!} or x}"), expression("x"));
+		assert_eq!(folded("{x and y}"), expression("{x and y}"));
+		assert_eq!(folded("{x or \"a\"}"), expression("{x or \"a\"}"));
+		// `{not {!}}` is as close to `true` as it gets
+		assert!(folded("{{not {!}} or x}").eq_ignoring_span(&expression("{not {!}}")));
+		assert_eq!(folded("{\"a\" or x}"), expression("\"a\""));
+		assert_eq!(folded("{{!} and x.y}"), Expression::None);
+		assert_eq!(
+			folded("{\"@x\" and \"b\"}"),
+			expression("{\"@x\" and \"b\"}")
+		);
+		assert_eq!(folded("{not \"@x\"}"), expression("{not \"@x\"}"));
+		let Expression::Array(values) = folded("{\"a\", {\"b\" or \"c\"}}") else {
+			panic!("Arrays should stay arrays")
+		};
+		assert_eq!(values[1].value, expression("\"b\""));
+	}
+}
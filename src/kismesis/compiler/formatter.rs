@@ -0,0 +1,196 @@
+use super::parser::types::{
+	EnvTag, Expression, ForTag, HtmlNodes, HtmlTag, IfTag, InlineBody, ParsedFile, StringParts,
+	TopNodes,
+};
+use super::reporting::DEFAULT_TAB_WIDTH;
+
+/// Re-emits a parsed file as canonical Kismesis source.
+///
+/// Tags whose bodies only contain other tags are written as indented blocks,
+/// with one child per line. Bodies that contain text are kept on a single
+/// line, since splitting them would change the text. Tag heads that would
+/// be wider than [`MAX_HEAD_WIDTH`] get one attribute or subtag per line.
+pub fn format(file: &ParsedFile) -> String {
+	let mut formatter = Formatter::default();
+	if let Some(extends) = file.extends_statement() {
+		formatter.line(&extends);
+	}
+	for variable in file.defined_variables.iter() {
+		formatter.line(&variable.to_string());
+	}
+	for lambda in file.defined_lambdas.iter() {
+		formatter.line(&lambda.to_string());
+	}
+	for mac in file.defined_macros.iter() {
+		formatter.block(mac.display_definition().head(), &mac.body, ">");
+	}
+	for node in file.body.iter() {
+		match node {
+			TopNodes::HtmlTag(tag) => formatter.tag(tag),
+			TopNodes::If(x) => formatter.if_tag(x),
+			TopNodes::Env(x) => formatter.env_tag(x),
+			TopNodes::For(x) => formatter.for_tag(x),
+			_ => formatter.line(&node.to_string()),
+		}
+	}
+	formatter.output
+}
+
+/// The widest a tag head can be before its attributes are split into lines
+pub const MAX_HEAD_WIDTH: usize = 80;
+
+#[derive(Default)]
+struct Formatter {
+	output: String,
+	depth: usize,
+}
+
+impl Formatter {
+	fn line(&mut self, text: &str) {
+		for line in text.lines() {
+			if !line.is_empty() {
+				self.output.push_str(&"\t".repeat(self.depth));
+			}
+			self.output.push_str(line);
+			self.output.push('\n');
+		}
+	}
+
+	fn node(&mut self, node: &HtmlNodes) {
+		match node {
+			HtmlNodes::HtmlTag(tag) => self.tag(tag),
+			HtmlNodes::If(x) => self.if_tag(x),
+			HtmlNodes::Env(x) => self.env_tag(x),
+			HtmlNodes::For(x) => self.for_tag(x),
+			_ => self.line(&node.to_string()),
+		}
+	}
+
+	fn tag(&mut self, tag: &HtmlTag) {
+		let mut parts: Vec<String> = tag.attributes.iter().map(|x| x.to_string()).collect();
+		parts.extend(tag.spreads.iter().map(|x| format!("{{...{}}}", x)));
+		for subtag in tag.subtags.iter() {
+			let mut part = format!("+{}", subtag.qualified_name());
+			for attribute in subtag.attributes.iter() {
+				part.push_str(&format!(" {}", attribute));
+			}
+			parts.push(part);
+		}
+		let trim_before = if tag.trim_before { "-" } else { "" };
+		let mut head = format!("<{}{}", trim_before, tag.qualified_name());
+		let width = self.depth * DEFAULT_TAB_WIDTH
+			+ head.len()
+			+ parts.iter().map(|x| x.len() + 1).sum::<usize>();
+		let separator = if width > MAX_HEAD_WIDTH && parts.len() > 1 {
+			"\n\t"
+		} else {
+			" "
+		};
+		for part in parts {
+			head.push_str(separator);
+			head.push_str(&part);
+		}
+		let closer = if tag.trim_after { "->" } else { ">" };
+		self.block(head, &tag.body, closer);
+	}
+
+	fn if_tag(&mut self, tag: &IfTag) {
+		self.block(format!("<if {}", tag.condition), &tag.body, ">");
+		if let Some(else_body) = &tag.else_body {
+			self.block("<else".to_string(), else_body, ">");
+		}
+	}
+
+	fn env_tag(&mut self, tag: &EnvTag) {
+		let name = Expression::Literal(vec![StringParts::String(tag.name.value.clone())]);
+		self.block(format!("<env {}", name), &tag.body, ">");
+	}
+
+	fn for_tag(&mut self, tag: &ForTag) {
+		self.block(
+			format!("<for {} in {}", tag.variable, tag.iterator),
+			&tag.body,
+			">",
+		);
+	}
+
+	fn block(&mut self, head: String, body: &[HtmlNodes], closer: &str) {
+		let has_text = body.iter().any(|x| matches!(x, HtmlNodes::String(_)));
+		if body.is_empty() || has_text {
+			self.line(&format!("{}{}{}", head, InlineBody(body), closer));
+			return;
+		}
+		self.line(&format!("{}:", head));
+		self.depth += 1;
+		for node in body {
+			self.node(node);
+		}
+		self.depth -= 1;
+		self.line(closer);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::kismesis::Kismesis;
+
+	fn reformat(src: &str) -> String {
+		let mut engine = Kismesis::new();
+		let file = engine.compile_str(src, None).unwrap();
+		format(&file)
+	}
+
+	#[test]
+	fn formatting_is_idempotent() {
+		let inputs = [
+			"<div | <p | hello @name> <ul | <li | a> <li | b>>>\n",
+			"<macro card title | <div | <h1 | @title> <content!>>>\n<card! title=\"x\">\n",
+			"<li +a href=\"/\" | home>\n",
+			"<div class=\"a very long class list\" id=\"and-a-long-id\" data-one=\"1\" data-two=\"2\" data-three=\"3\" | x>\n",
+			"const name = \"a\"\n<if {name} | <p | @name>>\n",
+		];
+		for src in inputs {
+			let once = reformat(src);
+			assert_eq!(reformat(&once), once, "{}", src);
+		}
+	}
+
+	#[test]
+	fn pure_macros_stay_pure() {
+		let src = "<macro pure card title | <b | @title>>\n";
+		let formatted = reformat(src);
+		assert_eq!(formatted, "<macro pure card title:\n\t<b | @title>\n>\n");
+		let mut engine = Kismesis::new();
+		let file = engine.compile_str(&formatted, None).unwrap();
+		assert!(file.defined_macros[0].pure);
+	}
+
+	#[test]
+	fn templates_are_kept() {
+		use std::path::PathBuf;
+
+		let mut engine = Kismesis::new();
+		let base = engine
+			.compile_str("<main | <content!>>\n", Some(PathBuf::from("base.ks")))
+			.unwrap();
+		let base = engine.register_template(base);
+		let file = engine
+			.compile_str("extends \"base\"\n<p | hi>\n", None)
+			.unwrap();
+		let formatted = format(&file);
+		assert_eq!(formatted, "extends \"base.ks\"\n<p | hi>\n");
+		assert_eq!(file.to_string(), formatted);
+		let reformatted = engine.compile_str(&formatted, None).unwrap();
+		assert_eq!(reformatted.template, Some(base));
+		assert_eq!(format(&reformatted), formatted);
+	}
+
+	#[test]
+	fn long_heads_are_split() {
+		let formatted = reformat(
+			"<div class=\"a very long class list\" id=\"and-a-long-id\" data-one=\"1\" data-two=\"2\" data-three=\"3\" | x>\n",
+		);
+		assert!(formatted.starts_with("<div\n\tclass="), "{}", formatted);
+	}
+}
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::{cell::RefCell, collections::HashMap, ops::Range, path::PathBuf, rc::Rc};
 
 use crate::kismesis::{
 	compiler::parser::types::ParsedFile,
@@ -6,25 +6,54 @@ use crate::kismesis::{
 };
 
 use super::{
+	analysis::has_content,
 	errors::{ErrorKind, ErrorState},
-	options::Settings,
+	eval::{has_references, VariableScope},
+	options::{LineEnding, Settings},
 	parser::{
 		errors::{Hint, Hintable, Hints},
 		types::{
-			Attribute, BinFunc, Expression, ForTag, HtmlNodes, HtmlTag, IfTag, Macro, PlugCall,
-			Ranged, Scoped, StringParts, TextPos, TopNodes, UniFunc,
+			Attribute, BinFunc, EnvTag, Expression, ForTag, HtmlNodes, HtmlTag, IfTag, Macro,
+			PlugCall, Ranged, Scoped, StringParts, TextPos, TopNodes, UniFunc,
 		},
 	},
 };
 
 type CompileResult<'a, T> = Result<T, Vec<ScopedError<CompilerError>>>;
 
+/// A macro's name, the files it's defined and called in, the indentation
+/// it's called at, and the text of each of its arguments, none of which use
+/// variables
+type PureMacroKey = (String, KisID, KisID, usize, Vec<(String, Option<String>)>);
+
 #[derive(Clone, Debug)]
 enum OutputTypes {
-	ContentMark(usize),
+	ContentMark(usize, Option<String>),
+	/// Removes the whitespace around it, left by `<-tag>` and `<tag->`
+	TrimMark,
+	/// Where the output of a tag starts, and the name of the tag in the file
+	/// it comes from. Only left when source maps are enabled
+	SourceStart(KisID, TextPos),
+	/// Where the output of the last tag whose start hasn't been closed ends
+	SourceEnd,
 	Html(String),
 }
 
+impl OutputTypes {
+	fn is_source_mark(&self) -> bool {
+		matches!(self, Self::SourceStart(..) | Self::SourceEnd)
+	}
+}
+
+/// The range of the output that a tag was generated into, and the position
+/// of the tag's name in its file
+#[derive(Clone, Debug, PartialEq)]
+pub struct SourceMapping {
+	pub output: Range<usize>,
+	pub file: KisID,
+	pub source: TextPos,
+}
+
 #[derive(Clone, Debug)]
 pub struct HtmlOutput {
 	val: Vec<OutputTypes>,
@@ -34,9 +63,9 @@ impl HtmlOutput {
 	pub fn new() -> Self {
 		Self { val: Vec::new() }
 	}
-	pub fn new_content(indents: usize) -> Self {
+	pub fn new_content(indents: usize, slot: Option<String>) -> Self {
 		Self {
-			val: vec![OutputTypes::ContentMark(indents)],
+			val: vec![OutputTypes::ContentMark(indents, slot)],
 		}
 	}
 	pub fn is_empty(&self) -> bool {
@@ -57,15 +86,94 @@ impl HtmlOutput {
 		}
 	}
 
+	/// Makes every line break use `line_ending`, and replaces the ones at the
+	/// end of the output with a single one if `final_newline` is set, or with
+	/// none otherwise. Empty output is left empty
+	fn normalize_line_endings(&mut self, line_ending: LineEnding, final_newline: bool) {
+		for x in self.val.iter_mut() {
+			if let OutputTypes::Html(string) = x {
+				if string.contains('\r') {
+					*string = string.replace("\r\n", "\n");
+				}
+			}
+		}
+		for previous in self.val.iter_mut().rev() {
+			match previous {
+				OutputTypes::Html(string) => {
+					string.truncate(string.trim_end_matches('\n').len());
+					if !string.is_empty() {
+						break;
+					}
+				}
+				x if x.is_source_mark() => (),
+				_ => break,
+			}
+		}
+		self.val
+			.retain(|x| !matches!(x, OutputTypes::Html(string) if string.is_empty()));
+		if final_newline && !self.is_empty() {
+			self.push_string('\n');
+		}
+		if line_ending != LineEnding::Lf {
+			for x in self.val.iter_mut() {
+				if let OutputTypes::Html(string) = x {
+					*string = string.replace('\n', line_ending.as_str());
+				}
+			}
+		}
+	}
+
 	fn push_output(&mut self, new: &mut HtmlOutput) {
 		self.val.append(&mut new.val)
 	}
 
+	/// Removes the whitespace around every trim mark, along with the marks.
+	/// Content marks are left alone, so this should only be done once the
+	/// templates have been filled
+	fn apply_trims(&mut self) {
+		let mut out: Vec<OutputTypes> = Vec::with_capacity(self.val.len());
+		let mut trim_next = false;
+		for x in self.val.drain(..) {
+			match x {
+				OutputTypes::TrimMark => {
+					for previous in out.iter_mut().rev() {
+						match previous {
+							OutputTypes::Html(string) => {
+								string.truncate(string.trim_end().len());
+								if !string.is_empty() {
+									break;
+								}
+							}
+							x if x.is_source_mark() => (),
+							_ => break,
+						}
+					}
+					trim_next = true;
+				}
+				OutputTypes::Html(string) if trim_next => {
+					let string = string.trim_start();
+					trim_next = string.is_empty();
+					out.push(OutputTypes::Html(string.to_string()));
+				}
+				x if x.is_source_mark() => out.push(x),
+				x => {
+					trim_next = false;
+					out.push(x);
+				}
+			}
+		}
+		self.val = out;
+	}
+
 	pub fn to_string_forced(&self) -> String {
 		let mut output = String::new();
 		for x in self.val.iter() {
 			match x {
-				OutputTypes::ContentMark(_) => output.push_str("<content!>"),
+				OutputTypes::ContentMark(_, None) => output.push_str("<content!>"),
+				OutputTypes::ContentMark(_, Some(slot)) => {
+					output.push_str(&format!("<content:{}!>", slot))
+				}
+				OutputTypes::TrimMark | OutputTypes::SourceStart(..) | OutputTypes::SourceEnd => (),
 				OutputTypes::Html(string) => output.push_str(string),
 			}
 		}
@@ -76,12 +184,45 @@ impl HtmlOutput {
 		let mut output = String::new();
 		for x in self.val.iter() {
 			match x {
-				OutputTypes::ContentMark(_) => return Err(CompilerError::ContentTagInOutput),
+				OutputTypes::ContentMark(..) => return Err(CompilerError::ContentTagInOutput),
+				OutputTypes::TrimMark | OutputTypes::SourceStart(..) | OutputTypes::SourceEnd => (),
 				OutputTypes::Html(string) => output.push_str(string),
 			}
 		}
 		Ok(output)
 	}
+
+	/// The same as [`to_string`](Self::to_string), along with the byte range
+	/// of every tag in the output and where the tag is in its source. A tag's
+	/// range goes from its `<` to the end of its closing tag, so it has the
+	/// tags in its body inside of it. The output only has this information if
+	/// it was generated with source maps enabled in its settings
+	pub fn to_string_with_source_map(&self) -> Result<(String, Vec<SourceMapping>), CompilerError> {
+		let mut output = String::new();
+		let mut open = Vec::new();
+		let mut mappings = Vec::new();
+		for x in self.val.iter() {
+			match x {
+				OutputTypes::ContentMark(..) => return Err(CompilerError::ContentTagInOutput),
+				OutputTypes::TrimMark => (),
+				OutputTypes::SourceStart(file, source) => {
+					open.push((output.len(), *file, source.clone()))
+				}
+				OutputTypes::SourceEnd => {
+					if let Some((start, file, source)) = open.pop() {
+						mappings.push(SourceMapping {
+							output: start..output.len(),
+							file,
+							source,
+						});
+					}
+				}
+				OutputTypes::Html(string) => output.push_str(string),
+			}
+		}
+		mappings.sort_by_key(|x| x.output.start);
+		Ok((output, mappings))
+	}
 }
 
 #[derive(Clone)]
@@ -91,42 +232,110 @@ struct GenerationState<'a> {
 	macro_templates: HashMap<String, Scoped<'a, &'a Macro>>,
 	indent: usize,
 	scope: KisID,
+	minify: bool,
+	engine: &'a Kismesis,
+	/// The files being generated, from the outermost one to the current one
+	includes: Vec<KisID>,
+	/// The macros being expanded, from the outermost call to the current one
+	macro_chain: Vec<String>,
+	/// The outputs of pure macros, shared by every state of the file
+	pure_outputs: Rc<RefCell<HashMap<PureMacroKey, HtmlOutput>>>,
 }
 
-type ValueRef<'a> = Scoped<'a, (Option<&'a Ranged<Expression>>, TextPos)>;
-
-type VariableScope<'a> = HashMap<String, ValueRef<'a>>;
-
 impl<'a> GenerationState<'a> {
 	pub(crate) fn from(
 		file: &'a ParsedFile,
 		sub_scopes: &[&'a ParsedFile],
 		options: &'a Settings,
 		engine: &'a Kismesis,
+		includes: &[KisID],
 	) -> Self {
+		let mut includes = includes.to_vec();
+		includes.push(file.file_id);
 		Self {
 			options,
 			variable_scopes: file.get_variable_scope(sub_scopes, engine),
 			macro_templates: file.get_macro_scope(engine),
 			indent: 0,
 			scope: file.file_id,
+			minify: options.is_minified(),
+			engine,
+			includes,
+			macro_chain: vec![],
+			pure_outputs: Rc::default(),
 		}
 	}
+
+	/// Pushes the line break that goes between two nodes. Minified output
+	/// leaves it out, but keeps a single space if there's no block on either
+	/// side of it, so that text on both sides isn't merged into one word
+	fn push_break(&self, output: &mut HtmlOutput, around_block: bool) {
+		if !self.minify {
+			output.push_string('\n');
+		} else if !around_block {
+			output.push_string(' ');
+		}
+	}
+
+	fn is_block(&self, node: &HtmlNodes) -> bool {
+		matches!(node, HtmlNodes::HtmlTag(x) if !self.options.is_inline(&x.name.value))
+	}
 }
 
 pub fn generate_html<'a>(
 	file: &'a ParsedFile,
 	sub_scopes: Vec<&'a ParsedFile>,
 	options: &'a Settings,
-	engine: &Kismesis,
+	engine: &'a Kismesis,
+) -> CompileResult<'a, HtmlOutput> {
+	let mut output = generate_file(file, sub_scopes, options, engine, &[])?;
+	output.apply_trims();
+	output.normalize_line_endings(options.line_ending(), options.has_final_newline());
+	Ok(output)
+}
+
+/// Generates a file that was reached through the `include` tags of the files
+/// in `includes`
+fn generate_file<'a>(
+	file: &'a ParsedFile,
+	sub_scopes: Vec<&'a ParsedFile>,
+	options: &'a Settings,
+	engine: &'a Kismesis,
+	includes: &[KisID],
 ) -> CompileResult<'a, HtmlOutput> {
-	let state = GenerationState::from(file, &sub_scopes, options, engine);
+	let state = GenerationState::from(file, &sub_scopes, options, engine, includes);
 	let mut errors = Vec::new();
 	let mut output = HtmlOutput::new();
+	let mut slots: HashMap<&str, HtmlOutput> = HashMap::new();
+	let mut previous_block = true;
 	for node in file.body.iter() {
+		if let TopNodes::Slot(slot) = node {
+			let slot_output = slots
+				.entry(&slot.name.value)
+				.or_insert_with(HtmlOutput::new);
+			let mut previous_block = true;
+			for child in slot.body.iter() {
+				let block = state.is_block(child);
+				if !slot_output.is_empty() {
+					state.push_break(slot_output, previous_block || block);
+				}
+				previous_block = block;
+				match parse_html_child(child, &state) {
+					Ok(mut string) => slot_output.push_output(&mut string),
+					Err(mut error) => errors.append(&mut error),
+				}
+			}
+			continue;
+		}
+		let block = match node {
+			TopNodes::HtmlTag(x) => !options.is_inline(&x.name.value),
+			TopNodes::Doctype(_) => true,
+			_ => false,
+		};
 		if !output.is_empty() {
-			output.push_string('\n');
+			state.push_break(&mut output, previous_block || block);
 		}
+		previous_block = block;
 		match parse_node(node, &state) {
 			Ok(mut string) => output.push_output(&mut string),
 			Err(mut error) => errors.append(&mut error),
@@ -144,33 +353,17 @@ pub fn generate_html<'a>(
 	{
 		let mut next_scopes = sub_scopes;
 		next_scopes.push(file);
-		let template_output = generate_html(template, next_scopes, options, engine)?;
+		let template_output =
+			generate_file(template, next_scopes, options, engine, &state.includes)?;
 		output.val = template_output
 			.val
 			.into_iter()
 			.flat_map(|x| match x {
-				OutputTypes::ContentMark(indents) => {
-					let mut out = Vec::new();
-					let mut is_first_text = true;
-					for x in output.val.iter() {
-						match x.clone() {
-							OutputTypes::ContentMark(x) => {
-								out.push(OutputTypes::ContentMark(x + 1))
-							}
-							OutputTypes::Html(mut output_string) => {
-								if is_first_text {
-									output_string =
-										format!("{}{}", make_indents(indents), output_string);
-									is_first_text = false;
-								}
-								let output_string = output_string
-									.replace('\n', &format!("\n{}", make_indents(indents)));
-								out.push(OutputTypes::Html(output_string));
-							}
-						}
-					}
-					out
-				}
+				OutputTypes::ContentMark(indents, None) => fill_content(&output, indents),
+				OutputTypes::ContentMark(indents, Some(slot)) => match slots.get(slot.as_str()) {
+					Some(slot_output) => fill_content(slot_output, indents),
+					None => vec![],
+				},
 				x => vec![x],
 			})
 			.collect();
@@ -179,6 +372,34 @@ pub fn generate_html<'a>(
 	Ok(output)
 }
 
+/// Indents the output that goes in place of a template's content mark.
+/// Unnamed marks are filled with the whole output of the file, and named
+/// marks with the matching `content:name!` slot, or nothing if it's missing
+fn fill_content(content: &HtmlOutput, indents: usize) -> Vec<OutputTypes> {
+	let mut out = Vec::new();
+	let mut is_first_text = true;
+	for x in content.val.iter() {
+		match x.clone() {
+			OutputTypes::ContentMark(x, slot) => out.push(OutputTypes::ContentMark(x + 1, slot)),
+			x @ (OutputTypes::TrimMark | OutputTypes::SourceStart(..) | OutputTypes::SourceEnd) => {
+				out.push(x)
+			}
+			OutputTypes::Html(mut output_string) => {
+				if is_first_text {
+					// The indentation goes before the tags that start here
+					let marks = out.iter().rev().take_while(|x| x.is_source_mark()).count();
+					out.insert(out.len() - marks, OutputTypes::Html(make_indents(indents)));
+					is_first_text = false;
+				}
+				let output_string =
+					output_string.replace('\n', &format!("\n{}", make_indents(indents)));
+				out.push(OutputTypes::Html(output_string));
+			}
+		}
+	}
+	out
+}
+
 fn parse_node<'a>(
 	node: &'a TopNodes,
 	state: &GenerationState<'a>,
@@ -187,9 +408,12 @@ fn parse_node<'a>(
 		TopNodes::HtmlTag(t) => tag(t, state),
 		TopNodes::MacroCall(t) => mac_call(t, state),
 		TopNodes::PlugCall(t) => plug_call(t, state),
-		TopNodes::Content => Ok(HtmlOutput::new_content(state.indent)),
+		TopNodes::Content(slot) => Ok(HtmlOutput::new_content(state.indent, slot.clone())),
+		TopNodes::Slot(_) => Ok(HtmlOutput::new()),
+		TopNodes::Include(path) => include(path, state),
 		TopNodes::Section(_) => Ok(HtmlOutput { val: vec![] }),
 		TopNodes::If(x) => if_tag(x, state),
+		TopNodes::Env(x) => env_tag(x, state),
 		TopNodes::For(x) => for_tag(x, state),
 		TopNodes::Doctype(string) => {
 			let mut htmlo = HtmlOutput::new();
@@ -201,19 +425,43 @@ fn parse_node<'a>(
 
 fn if_tag<'a>(tag: &'a IfTag, state: &GenerationState<'a>) -> CompileResult<'a, HtmlOutput> {
 	let value = calculate_expression(&tag.condition, state)?;
+	if value.is_truthy(state)? {
+		gated_body(&tag.body, state)
+	} else if let Some(else_body) = &tag.else_body {
+		gated_body(else_body, state)
+	} else {
+		Ok(HtmlOutput::new())
+	}
+}
+
+/// Environments that weren't enabled in the settings, including ones that
+/// don't exist anywhere, just leave their content out
+fn env_tag<'a>(tag: &'a EnvTag, state: &GenerationState<'a>) -> CompileResult<'a, HtmlOutput> {
+	if state.options.has_environment(&tag.name.value) {
+		gated_body(&tag.body, state)
+	} else {
+		Ok(HtmlOutput::new())
+	}
+}
+
+/// Writes the body of a tag that only decides whether its body is written,
+/// like `if` or `env`
+fn gated_body<'a>(
+	body: &'a [HtmlNodes],
+	state: &GenerationState<'a>,
+) -> CompileResult<'a, HtmlOutput> {
 	let mut output = HtmlOutput::new();
 	let mut errors = Vec::new();
-	if value.is_truthy(state)? {
-		for child in tag.body.iter() {
-			output.push_string('\n');
-			match parse_html_child(child, state) {
-				Ok(mut string) => output.push_output(&mut string),
-				Err(mut error) => errors.append(&mut error),
-			}
+	for child in body.iter() {
+		state.push_break(&mut output, state.is_block(child));
+		match parse_html_child(child, state) {
+			Ok(mut string) => output.push_output(&mut string),
+			Err(mut error) => errors.append(&mut error),
 		}
-
-		output.push_string('\n');
 	}
+
+	let last_block = body.last().is_some_and(|x| state.is_block(x));
+	state.push_break(&mut output, last_block);
 	if errors.is_empty() {
 		Ok(output)
 	} else {
@@ -227,11 +475,13 @@ fn to_iterator<'a>(
 ) -> CompileResult<'a, Vec<Ranged<Expression>>> {
 	match expr.value {
 		Expression::Array(ref x) => Ok(x.clone()),
-		Expression::Variable(_) => match calculate_expression(expr, state)? {
-			ExpressionValues::Array(x) => Ok(x),
-			ExpressionValues::Reference(x, _, _) => to_iterator(&x, state),
-			_ => Ok(vec![expr.clone()]),
-		},
+		Expression::Variable(_) | Expression::Member(..) => {
+			match calculate_expression(expr, state)? {
+				ExpressionValues::Array(x) => Ok(x),
+				ExpressionValues::Reference(x, _, _) => to_iterator(&x, state),
+				_ => Ok(vec![expr.clone()]),
+			}
+		}
 		_ => Ok(vec![expr.clone()]),
 	}
 }
@@ -253,7 +503,7 @@ fn for_tag<'a>(tag: &'a ForTag, state: &GenerationState<'a>) -> CompileResult<'a
 		};
 
 		for child in tag.body.iter() {
-			output.push_string('\n');
+			state.push_break(&mut output, state.is_block(child));
 			match parse_html_child(child, &state) {
 				Ok(mut string) => output.push_output(&mut string),
 				Err(mut error) => errors.append(&mut error),
@@ -276,8 +526,10 @@ fn parse_html_child<'a>(
 		HtmlNodes::HtmlTag(t) => tag(t, state),
 		HtmlNodes::MacroCall(t) => mac_call(t, state),
 		HtmlNodes::PlugCall(t) => plug_call(t, state),
-		HtmlNodes::Content => Ok(HtmlOutput::new_content(state.indent)),
+		HtmlNodes::Content(slot) => Ok(HtmlOutput::new_content(state.indent, slot.clone())),
+		HtmlNodes::Include(path) => include(path, state),
 		HtmlNodes::If(t) => if_tag(t, state),
+		HtmlNodes::Env(t) => env_tag(t, state),
 		HtmlNodes::For(t) => for_tag(t, state),
 		HtmlNodes::String(t) => match parse_kis_string(t, state) {
 			Ok(mut x) => {
@@ -293,6 +545,33 @@ fn parse_html_child<'a>(
 	}
 }
 
+/// Generates the template an `include` tag points to, in place of the tag.
+/// Including a file that is already being generated is an error, since it
+/// would never finish
+fn include<'a>(
+	path: &'a Ranged<Vec<StringParts>>,
+	state: &GenerationState<'a>,
+) -> CompileResult<'a, HtmlOutput> {
+	let mut file_path = PathBuf::from(parse_kis_string(&path.value, state)?.to_string_forced());
+	file_path.set_extension("ks");
+	let Some(file) = state.engine.get_template(file_path.clone()) else {
+		return Err(vec![CompilerError::UndefinedInclude(
+			file_path.to_string_lossy().into(),
+		)
+		.with_scope_at(state.scope, path.range.clone())]);
+	};
+	if state.includes.contains(&file.file_id) {
+		return Err(vec![CompilerError::RecursiveInclude(
+			file_path.to_string_lossy().into(),
+		)
+		.with_scope_at(state.scope, path.range.clone())]);
+	}
+	let output = generate_file(file, vec![], state.options, state.engine, &state.includes)?;
+	Ok(HtmlOutput {
+		val: fill_content(&output, state.indent),
+	})
+}
+
 fn mac_call<'a>(mac: &'a Macro, state: &GenerationState<'a>) -> CompileResult<'a, HtmlOutput> {
 	let mut errors = Vec::new();
 	let template =
@@ -301,11 +580,51 @@ fn mac_call<'a>(mac: &'a Macro, state: &GenerationState<'a>) -> CompileResult<'a
 			.get(&mac.name.value)
 			.ok_or(vec![CompilerError::UndefinedMacroCall
 				.with_scope_at(state.scope, mac.name.range.clone())])?;
+	let mut base = template.0.get_argument_scope(template.1);
+	base.extend(mac.get_argument_scope(state.scope));
+
+	// Macro bodies only see their arguments, so a pure macro gives the same
+	// output for arguments that are written the same, as long as none of them
+	// uses a variable, whose value could be different from call to call
+	let cacheable = template.0.pure
+		&& !has_content(&template.0.body)
+		&& base
+			.values()
+			.all(|((value, _), _)| value.is_none_or(|x| !has_references(&x.value)));
+	let pure_key = cacheable.then(|| {
+		let mut arguments: Vec<_> = base
+			.iter()
+			.map(|(name, ((value, _), _))| (name.clone(), value.map(|x| x.value.to_string())))
+			.collect();
+		arguments.sort();
+		(
+			mac.name.value.clone(),
+			template.1,
+			state.scope,
+			state.indent,
+			arguments,
+		)
+	});
+	if let Some(output) = pure_key
+		.as_ref()
+		.and_then(|key| state.pure_outputs.borrow().get(key).cloned())
+	{
+		return Ok(output);
+	}
+
+	if state.macro_chain.len() >= state.options.max_expansion_depth() {
+		let mut chain = state.macro_chain.clone();
+		chain.push(mac.name.value.clone());
+		return Err(vec![
+			CompilerError::RecursionLimit(chain).with_scope_at(state.scope, mac.name.range.clone())
+		]);
+	}
+
 	let mut new_state = state.clone();
+	// The body is in the file the macro is defined in
+	new_state.scope = template.1;
+	new_state.macro_chain.push(mac.name.value.clone());
 	new_state.variable_scopes = {
-		let mut base = template.0.get_argument_scope(template.1);
-
-		base.extend(mac.get_argument_scope(state.scope));
 		let mut output = VariableScope::new();
 		for arg in base.iter() {
 			match arg.1 .0 .0 {
@@ -335,9 +654,11 @@ fn mac_call<'a>(mac: &'a Macro, state: &GenerationState<'a>) -> CompileResult<'a
 	}
 
 	let mut output = HtmlOutput::new();
+	let mut previous_block = true;
 	for child in template.0.body.iter() {
+		let block = state.is_block(child);
 		if !output.is_empty() {
-			output.push_string('\n');
+			state.push_break(&mut output, previous_block || block);
 			for _ in 0..state.indent {
 				output.push_string('\t');
 			}
@@ -346,9 +667,13 @@ fn mac_call<'a>(mac: &'a Macro, state: &GenerationState<'a>) -> CompileResult<'a
 			Ok(mut string) => output.push_output(&mut string),
 			Err(mut error) => errors.append(&mut error),
 		}
+		previous_block = block;
 	}
 
 	if errors.is_empty() {
+		if let Some(key) = pure_key {
+			state.pure_outputs.borrow_mut().insert(key, output.clone());
+		}
 		Ok(output)
 	} else {
 		Err(errors)
@@ -358,7 +683,7 @@ fn plug_call<'a>(plugin: &'a PlugCall, state: &GenerationState) -> CompileResult
 	let mut output = HtmlOutput::new();
 	let mut errors = Vec::new();
 	for child in plugin.body.iter() {
-		output.push_string('\n');
+		state.push_break(&mut output, state.is_block(child));
 		match parse_html_child(child, state) {
 			Ok(mut string) => output.push_output(&mut string),
 			Err(mut error) => errors.append(&mut error),
@@ -375,22 +700,34 @@ fn plug_call<'a>(plugin: &'a PlugCall, state: &GenerationState) -> CompileResult
 fn tag<'a>(tag: &'a HtmlTag, state: &GenerationState<'a>) -> CompileResult<'a, HtmlOutput> {
 	let mut errors = Vec::new();
 	let mut output = HtmlOutput::new();
+	if tag.trim_before {
+		output.val.push(OutputTypes::TrimMark);
+	}
 	for _ in 0..state.indent {
 		output.push_string('\t');
 	}
+	if state.options.has_source_map() {
+		output.val.push(OutputTypes::SourceStart(
+			state.scope,
+			tag.name.range.clone(),
+		));
+	}
 	output.push_string('<');
-	output.push_string(&tag.name.value);
-	match attribute_string(&tag.attributes, state) {
+	let name = tag.qualified_name();
+	output.push_string(&name);
+	match attribute_string(&tag.attributes, &tag.spreads, state) {
 		Ok(string) => output.push_string(&string),
 		Err(mut error) => errors.append(&mut error),
 	}
-	if state.options.is_only_closer(&tag.name.value) {
+	// Void elements are written like `<br>`, whatever the settings say
+	let is_void = tag.is_void_element();
+	if state.options.is_only_closer(&tag.name.value) && !is_void {
 		output.push_string(" />");
 	} else {
 		output.push_string('>');
 	}
 
-	if state.options.has_body(&tag.name.value) {
+	if !is_void && state.options.has_body(&tag.name.value) {
 		let mut inline = state.options.is_inline(&tag.name.value) || tag.body.is_empty();
 		for child in tag.body.iter() {
 			match child {
@@ -409,17 +746,26 @@ fn tag<'a>(tag: &'a HtmlTag, state: &GenerationState<'a>) -> CompileResult<'a, H
 		}
 
 		let mut new_state = state.clone();
+		if state.options.is_preformatted(&tag.name.value) {
+			new_state.minify = false;
+		}
 
-		if !inline {
+		if !inline && !new_state.minify {
 			new_state.indent += 1;
 		} else {
 			new_state.indent = 0;
 		}
 
+		// Whitespace at the edges of a block's body doesn't show, so minified
+		// output can drop it
+		let is_block = !state.options.is_inline(&tag.name.value);
+		let mut previous_block = is_block;
 		for child in tag.body.iter() {
+			let block = new_state.is_block(child);
 			if !inline {
-				output.push_string('\n');
+				new_state.push_break(&mut output, previous_block || block);
 			}
+			previous_block = block;
 			match parse_html_child(child, &new_state) {
 				Ok(mut string) => output.push_output(&mut string),
 				Err(mut error) => errors.append(&mut error),
@@ -427,12 +773,19 @@ fn tag<'a>(tag: &'a HtmlTag, state: &GenerationState<'a>) -> CompileResult<'a, H
 		}
 
 		if !inline {
-			output.push_string('\n');
+			new_state.push_break(&mut output, previous_block || is_block);
 			for _ in 0..state.indent {
 				output.push_string('\t');
 			}
 		}
-		output.push_string(&format!("</{}>", tag.name.value))
+		output.push_string(&format!("</{}>", name))
+	}
+	if state.options.has_source_map() {
+		output.val.push(OutputTypes::SourceEnd);
+	}
+
+	if tag.trim_after {
+		output.val.push(OutputTypes::TrimMark);
 	}
 
 	if errors.is_empty() {
@@ -442,24 +795,65 @@ fn tag<'a>(tag: &'a HtmlTag, state: &GenerationState<'a>) -> CompileResult<'a, H
 	}
 }
 
+/// Writes the attributes in the order they're given, so the output doesn't
+/// change between runs. Spread attributes come after the written ones, and
+/// are left out if an attribute with the same name was written
 fn attribute_string<'a>(
 	attrs: &Vec<Attribute>,
+	spreads: &'a [Ranged<Expression>],
 	state: &GenerationState<'a>,
 ) -> CompileResult<'a, String> {
-	let mut output = String::new();
+	let mut written: Vec<(String, String)> = Vec::new();
 	let mut errors = Vec::new();
 	for attr in attrs {
-		output.push(' ');
-		match calculate_expression(&attr.value, state) {
-			Ok(value_string) => {
-				let string =
-					value_string.to_string(attr.value.range.clone(), state.scope, state)?;
-				output.push_str(&format!("{}='{}'", attr.name.value, string))
+		if let Some(ref condition) = attr.condition {
+			match calculate_expression(condition, state).and_then(|x| x.is_truthy(state)) {
+				Ok(true) => (),
+				Ok(false) => continue,
+				Err(mut error) => {
+					errors.append(&mut error);
+					continue;
+				}
 			}
+		}
+		let string = calculate_expression(&attr.value, state)
+			.and_then(|x| x.to_attribute_string(attr.value.range.clone(), state.scope, state));
+		match string {
+			Ok(string) => written.push((attr.name.value.to_string(), string)),
 			Err(mut error) => errors.append(&mut error),
 		}
 	}
 
+	let explicit_count = written.len();
+	for spread in spreads {
+		let pairs = match calculate_expression(spread, state) {
+			Ok(value) => value.to_spread_attributes(spread.range.clone(), state.scope, state),
+			Err(error) => Err(error),
+		};
+		match pairs {
+			Ok(pairs) => {
+				for (name, value) in pairs {
+					let (explicit, spread) = written.split_at_mut(explicit_count);
+					if explicit.iter().any(|x| x.0.eq_ignore_ascii_case(&name)) {
+						continue;
+					}
+					match spread.iter_mut().find(|x| x.0.eq_ignore_ascii_case(&name)) {
+						Some(previous) => previous.1 = value,
+						None => written.push((name, value)),
+					}
+				}
+			}
+			Err(mut error) => errors.append(&mut error),
+		}
+	}
+
+	let mut output = String::new();
+	for (name, value) in written {
+		// Values are always written between single quotes, whatever quotes
+		// they were written with
+		output.push_str(&format!(" {}='{}'", name, value.replace('\'', "&#39;")));
+	}
+
 	if errors.is_empty() {
 		Ok(output)
 	} else {
@@ -475,7 +869,13 @@ fn parse_kis_string<'a>(
 	let mut errors = Vec::new(); // TODO actually use this vector, remove elvis operators below
 	for parse in string {
 		match parse {
-			StringParts::String(x) => output.push_string(x),
+			StringParts::String(x) => output.push_string(x.replace('&', "&amp;")),
+			StringParts::Entity(x) => output.push_string(format!("&{};", x)),
+			// `{!}` writes nothing, but other `None` values are still errors
+			StringParts::Expression(Ranged {
+				value: Expression::None,
+				..
+			}) => (),
 			StringParts::Expression(expr) => match calculate_expression(expr, state) {
 				Ok(calculated_expression) => {
 					match calculated_expression.to_string(expr.range.clone(), state.scope, state) {
@@ -504,12 +904,18 @@ enum ExpressionValues {
 	Reference(Ranged<Expression>, KisID, TextPos),
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug)]
 pub struct ScopedError<T> {
 	pub error: ErrorState<T>,
 	pub scope: KisID,
 }
 
+impl<T: ErrorKind> PartialEq for ScopedError<T> {
+	fn eq(&self, other: &Self) -> bool {
+		self.scope == other.scope && self.error == other.error
+	}
+}
+
 impl<T> Hintable for ScopedError<T> {
 	fn add_hint(&mut self, hint: Hint) {
 		self.error.hints.push(hint);
@@ -525,6 +931,97 @@ impl ExpressionValues {
 		}
 	}
 
+	/// Attribute values can also be arrays, which are written as their items
+	/// separated by spaces, like a list of classes. Nested arrays are
+	/// flattened, and items that are `None` are left out, so
+	/// `class={"a", {x and "b"}}` gives `a` or `a b`
+	fn to_attribute_string<'a>(
+		&'a self,
+		range: TextPos,
+		scope: KisID,
+		state: &GenerationState<'a>,
+	) -> CompileResult<'a, String> {
+		match self {
+			ExpressionValues::Array(items) => {
+				let mut output = Vec::new();
+				for item in items {
+					let value = calculate_expression(item, state)?;
+					if value.is_truthy(state)? {
+						output.push(value.to_attribute_string(item.range.clone(), scope, state)?);
+					}
+				}
+				Ok(output.join(" "))
+			}
+			ExpressionValues::Reference(x, _, _) => match calculate_expression(x, state)? {
+				value @ (ExpressionValues::Array(_) | ExpressionValues::Reference(..)) => {
+					value.to_attribute_string(range, scope, state)
+				}
+				_ => self.to_string(range, scope, state),
+			},
+			_ => self.to_string(range, scope, state),
+		}
+	}
+
+	/// Spread attributes are lists of `{name, value}` pairs, like
+	/// `{{"class", "big"}, {"id", "main"}}`. Pairs whose value is `None` are
+	/// left out, and `None` spreads nothing at all
+	fn to_spread_attributes<'a>(
+		&'a self,
+		range: TextPos,
+		scope: KisID,
+		state: &GenerationState<'a>,
+	) -> CompileResult<'a, Vec<(String, String)>> {
+		let invalid = |range: &TextPos| {
+			vec![CompilerError::InvalidSpread.with_scope_at(scope, range.clone())]
+		};
+		match self {
+			ExpressionValues::None => Ok(vec![]),
+			ExpressionValues::Reference(x, _, _) => {
+				calculate_expression(x, state)?.to_spread_attributes(range, scope, state)
+			}
+			ExpressionValues::Array(pairs) => {
+				let mut output = Vec::new();
+				for pair in pairs {
+					let items = match calculate_expression(pair, state)?.dereference(state)? {
+						ExpressionValues::Array(items) => items,
+						_ => return Err(invalid(&pair.range)),
+					};
+					let [name, value] = items.as_slice() else {
+						return Err(invalid(&pair.range));
+					};
+					let value_range = value.range.clone();
+					let value = calculate_expression(value, state)?;
+					if !value.is_truthy(state)? {
+						continue;
+					}
+					let name_range = name.range.clone();
+					let name = calculate_expression(name, state)?.to_string(
+						name_range.clone(),
+						scope,
+						state,
+					)?;
+					let is_valid_name = |x: char| !x.is_whitespace() && !"\"'<>/=".contains(x);
+					if name.is_empty() || !name.chars().all(is_valid_name) {
+						return Err(invalid(&name_range));
+					}
+					output.push((name, value.to_attribute_string(value_range, scope, state)?));
+				}
+				Ok(output)
+			}
+			_ => Err(invalid(&range)),
+		}
+	}
+
+	/// Follows references until there's a value that isn't one
+	fn dereference<'a>(self, state: &GenerationState<'a>) -> CompileResult<'a, ExpressionValues> {
+		match self {
+			ExpressionValues::Reference(x, _, _) => {
+				calculate_expression(&x, state)?.dereference(state)
+			}
+			value => Ok(value),
+		}
+	}
+
 	fn to_string<'a>(
 		&'a self,
 		range: TextPos,
@@ -563,21 +1060,16 @@ fn calculate_expression<'a>(
 	state: &GenerationState<'a>,
 ) -> CompileResult<'a, ExpressionValues> {
 	match &expr.value {
+		// The second operand is only evaluated if the first one doesn't
+		// decide the result
 		Expression::BinFunc(func, exp1, exp2) => {
 			let exp1 = calculate_expression(exp1, state)?;
-			let exp2 = calculate_expression(exp2, state)?;
-			match func {
-				BinFunc::And => {
-					if exp1.is_truthy(state)? && exp2.is_truthy(state)? {
-						Ok(exp2)
-					} else {
-						Ok(ExpressionValues::None)
-					}
-				}
-				BinFunc::Or => {
-					if exp1.is_truthy(state)? {
-						Ok(exp1)
-					} else if exp2.is_truthy(state)? {
+			match (func, exp1.is_truthy(state)?) {
+				(BinFunc::And, false) => Ok(ExpressionValues::None),
+				(BinFunc::Or, true) => Ok(exp1),
+				(BinFunc::And | BinFunc::Or, _) => {
+					let exp2 = calculate_expression(exp2, state)?;
+					if exp2.is_truthy(state)? {
 						Ok(exp2)
 					} else {
 						Ok(ExpressionValues::None)
@@ -621,6 +1113,12 @@ fn calculate_expression<'a>(
 				])
 			}
 		}
+		// None of the values an expression can have has fields
+		Expression::Member(object, field) => {
+			calculate_expression(object, state)?;
+			Err(vec![CompilerError::MissingField(field.clone())
+				.with_scope_at(state.scope, expr.range.clone())])
+		}
 		Expression::Literal(x) => Ok(ExpressionValues::String(x.clone())),
 		Expression::Array(x) => Ok(ExpressionValues::Array(x.clone())),
 	}
@@ -631,10 +1129,16 @@ pub enum CompilerError {
 	CantWriteArray,
 	ContentTagInOutput,
 	UndefinedVariable,
+	MissingField(String),
 	CantWriteNoneValue,
 	CantWriteGenericValue,
 	UnsetArgNoDefault(String),
 	UndefinedMacroCall,
+	UndefinedInclude(String),
+	RecursiveInclude(String),
+	/// The chain of macro calls that went past the expansion depth
+	RecursionLimit(Vec<String>),
+	InvalidSpread,
 }
 
 impl ErrorKind for CompilerError {
@@ -647,6 +1151,7 @@ impl ErrorKind for CompilerError {
 				"Can't write this file to output due to having a <content!> tag".into()
 			}
 			Self::UndefinedVariable => "This variable isn't defined".into(),
+			Self::MissingField(field) => format!("This value has no `{}` field", field),
 			Self::CantWriteNoneValue => {
 				"This computes to a Nothing value, which cannot be written into content".into()
 			}
@@ -658,6 +1163,17 @@ impl ErrorKind for CompilerError {
 				arg
 			),
 			Self::UndefinedMacroCall => "This macro isn't defined".to_string(),
+			Self::UndefinedInclude(path) => format!("There is no template at `{}`", path),
+			Self::RecursiveInclude(path) => {
+				format!("`{}` ends up including itself", path)
+			}
+			Self::RecursionLimit(chain) => format!(
+				"Macro calls were expanded too deep inside of each other: {}",
+				chain.join(" -> ")
+			),
+			Self::InvalidSpread => {
+				"Spread attributes have to be lists of `{name, value}` pairs".into()
+			}
 		}
 	}
 }
@@ -665,3 +1181,460 @@ impl ErrorKind for CompilerError {
 fn make_indents(indents: usize) -> String {
 	"\t".repeat(indents)
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn render_with(src: &str, settings: &Settings) -> String {
+		let mut engine = Kismesis::new();
+		let file = engine.compile_str(src, None).unwrap();
+		match generate_html(&file, vec![], settings, &engine) {
+			Ok(x) => x.to_string().unwrap(),
+			Err(x) => panic!("{:?}", x.into_iter().map(|x| x.error).collect::<Vec<_>>()),
+		}
+	}
+
+	fn render(src: &str) -> String {
+		render_with(src, &Settings::new())
+	}
+
+	#[test]
+	fn comments_anywhere_but_quotes() {
+		assert_eq!(
+			render(concat!(
+				"<!- at the top ->\n",
+				"<p <!- between attributes -> class=\"a\" title=\"<!- kept ->\" | x <!- in a body -> y>\n",
+				"<!- between tags ->\n",
+				"<b | z>\n"
+			)),
+			"<p class='a' title='<!- kept ->'>x  y</p>\n<b>z</b>\n"
+		);
+	}
+
+	#[test]
+	fn entities_pass_through() {
+		assert_eq!(
+			render("<p | a &amp; b &#169;>\n"),
+			"<p>a &amp; b &#169;</p>\n"
+		);
+	}
+
+	#[test]
+	fn bare_ampersands_are_escaped() {
+		assert_eq!(render("<p | a & b &foo>\n"), "<p>a &amp; b &amp;foo</p>\n");
+	}
+
+	#[test]
+	fn falsy_conditions_drop_attributes() {
+		assert_eq!(
+			render("<a class=\"a\"?{\"yes\"} id=\"b\"?{not \"yes\"}>\n"),
+			"<a class='a'></a>\n"
+		);
+	}
+
+	#[test]
+	fn every_attribute_reports_its_errors() {
+		let mut engine = Kismesis::new();
+		let file = engine
+			.compile_str("const a = {u}\n<p x=\"1\"?{a} y=\"2\"?{a} z={v}>\n", None)
+			.unwrap();
+		let errors = generate_html(&file, vec![], &Settings::new(), &engine).unwrap_err();
+		// Both conditions fail on `u`, and the last value on `v`
+		assert_eq!(errors.len(), 3);
+	}
+
+	#[test]
+	fn and_and_or_short_circuit() {
+		assert_eq!(
+			render("<p | @{\"a\" or x}>\n<if {{!} and y.z} | <p | b>>\n"),
+			"<p>a</p>\n"
+		);
+	}
+
+	#[test]
+	fn quote_styles_mix() {
+		assert_eq!(
+			render("const name = \"Bo\"\n<a title=`She said \"hi\" to @name` alt='it\\'s' id=\"`x`\">\n"),
+			"<a title='She said \"hi\" to Bo' alt='it&#39;s' id='`x`'></a>\n"
+		);
+	}
+
+	#[test]
+	fn named_slots_are_filled() {
+		let mut engine = Kismesis::new();
+		let template = engine
+			.compile_str("<main | <content!>>\n<footer | <content:footer!>>\n", None)
+			.unwrap();
+		let template = engine.register_template(template);
+		let mut file = engine
+			.compile_str("<content:footer! | <p | bye>>\n<p | hi>\n", None)
+			.unwrap();
+		file.template = Some(template);
+		let html = generate_html(&file, vec![], &Settings::new(), &engine)
+			.unwrap()
+			.to_string()
+			.unwrap();
+		assert_eq!(
+			html,
+			"<main>\n\t<p>hi</p>\n</main>\n<footer>\n\t<p>bye</p>\n</footer>\n"
+		);
+	}
+
+	#[test]
+	fn source_maps_point_at_each_tag() {
+		let mut engine = Kismesis::new();
+		let template = engine
+			.compile_str("<macro card | <b | x>>\n<main | <content!>>\n", None)
+			.unwrap();
+		let template_id = template.file_id;
+		let template = engine.register_template(template);
+		let mut file = engine.compile_str("<p | hi>\n<card!>\n", None).unwrap();
+		file.template = Some(template);
+		let mut settings = Settings::new();
+		settings.set_source_map(true);
+		let (html, mappings) = generate_html(&file, vec![], &settings, &engine)
+			.unwrap()
+			.to_string_with_source_map()
+			.unwrap();
+		assert_eq!(html, "<main>\n\t<p>hi</p>\n\t<b>x</b>\n</main>\n");
+		let tags: Vec<_> = mappings
+			.iter()
+			.map(|x| {
+				let source = engine.get_file(x.file).unwrap();
+				let name = source.tokens[x.source.get_start().get_idx()].get_as_string();
+				let output = &html[x.output.clone()];
+				assert!(output.starts_with(&format!("<{}>", name)), "{}", output);
+				assert!(output.ends_with(&format!("</{}>", name)), "{}", output);
+				(name, x.file)
+			})
+			.collect();
+		// The tags in a macro's body map to the file the macro is defined in
+		assert_eq!(
+			tags,
+			[
+				("main".to_string(), template_id),
+				("p".to_string(), file.file_id),
+				("b".to_string(), template_id)
+			]
+		);
+	}
+
+	#[test]
+	fn errors_in_macro_bodies_point_at_their_definition() {
+		let mut engine = Kismesis::new();
+		let template = engine
+			.compile_str("<macro card | <b | @nope>>\n<content!>\n", None)
+			.unwrap();
+		let template_id = template.file_id;
+		let template = engine.register_template(template);
+		let mut file = engine.compile_str("<p | hi>\n<card!>\n", None).unwrap();
+		file.template = Some(template);
+		let errors = generate_html(&file, vec![], &Settings::new(), &engine).unwrap_err();
+		assert!(matches!(
+			errors[0].error.error,
+			CompilerError::UndefinedVariable
+		));
+		// The position is in the template, not in the file calling the macro
+		assert_eq!(errors[0].scope, template_id);
+		let idx = errors[0].error.text_position.get_start().get_idx();
+		let tokens = &engine.get_file(template_id).unwrap().tokens;
+		assert_eq!(tokens[idx].get_as_string(), "nope");
+	}
+
+	#[test]
+	fn doubled_ats_are_literal() {
+		assert_eq!(
+			render(
+				"const name = \"Bo\"\n<p title=\"@@name @name \\@name\" | @@name @name \\@name>\n"
+			),
+			"<p title='@name Bo @name'>@name Bo @name</p>\n"
+		);
+	}
+
+	#[test]
+	fn minified_output() {
+		let src =
+			"<main |\n\t<section |\n\t\t<p | a <b | b> c>\n\t\t<div | d>\n\t\t<pre | <i | x>>>>\n";
+		let mut settings = Settings::new();
+		settings.set_minify(true);
+		assert_eq!(
+			render(src),
+			"<main>\n\t<section>\n\t\t<p>a <b>b</b> c</p>\n\t\t<div>\n\t\t\td\n\t\t</div>\n\t\t<pre>\n\t\t\t<i>x</i>\n\t\t</pre>\n\t</section>\n</main>\n"
+		);
+		assert_eq!(
+			render_with(src, &settings),
+			"<main><section><p>a <b>b</b> c</p><div>d</div><pre>\n\t<i>x</i>\n</pre></section></main>\n"
+		);
+	}
+
+	#[test]
+	fn class_shorthands() {
+		assert_eq!(render("<div.card>\n"), "<div class='card'></div>\n");
+		assert_eq!(
+			render("<div.card.active>\n"),
+			"<div class='card active'></div>\n"
+		);
+		assert_eq!(
+			render("<div.card class=\"wide\" +p.note>\n"),
+			"<div class='card wide'>\n\t<p class='note'></p>\n</div>\n"
+		);
+	}
+
+	#[test]
+	fn id_shorthands() {
+		assert_eq!(
+			render("<section#hero>\n"),
+			"<section id='hero'></section>\n"
+		);
+		assert_eq!(
+			render("<div#main.card>\n"),
+			"<div id='main' class='card'></div>\n"
+		);
+	}
+
+	#[test]
+	fn includes() {
+		let mut engine = Kismesis::new();
+		for (path, src) in [
+			("nav.ks", "<p | hi>\n"),
+			("a.ks", "<include \"b\">\n"),
+			("b.ks", "<include \"a\">\n"),
+			("c.ks", "<div | <include \"c\">>\n"),
+		] {
+			let file = engine.compile_str(src, Some(PathBuf::from(path))).unwrap();
+			engine.register_template(file);
+		}
+		let mut generate = |src: &str| {
+			let file = engine.compile_str(src, None).unwrap();
+			generate_html(&file, vec![], &Settings::new(), &engine)
+				.map(|x| x.to_string().unwrap())
+				.map_err(|x| x.into_iter().map(|x| x.error.error).collect::<Vec<_>>())
+		};
+		assert_eq!(generate("<include \"nav\">\n").unwrap(), "<p>hi</p>\n");
+		for cycle in ["<include \"a\">\n", "<include \"c\">\n"] {
+			assert!(matches!(
+				&generate(cycle).unwrap_err()[..],
+				[CompilerError::RecursiveInclude(_)]
+			));
+		}
+		assert!(matches!(
+			&generate("<include \"nope\">\n").unwrap_err()[..],
+			[CompilerError::UndefinedInclude(x)] if x == "nope.ks"
+		));
+	}
+
+	#[test]
+	fn trim_marks_remove_whitespace() {
+		assert_eq!(
+			render("<p | a <b | b> <-i-> c <i | d->>\n"),
+			"<p>a <b>b</b><i></i>c <i>d-</i></p>\n"
+		);
+	}
+
+	#[test]
+	fn void_elements_have_no_closer() {
+		assert_eq!(render("<br>\n"), "<br>\n");
+		assert_eq!(
+			render("<div | <hr><img src=\"x.png\">>\n"),
+			"<div>\n\t<hr>\n\t<img src='x.png'>\n</div>\n"
+		);
+	}
+
+	#[test]
+	fn attributes_keep_their_order() {
+		assert_eq!(
+			render("<a title=\"w\" href=\"x\" class=\"z\" id=\"y\">\n"),
+			"<a title='w' href='x' class='z' id='y'></a>\n"
+		);
+		assert_eq!(
+			render("<div.c z=\"1\" a=\"2\"+p d=\"3\" c=\"4\">\n"),
+			"<div class='c' z='1' a='2'>\n\t<p d='3' c='4'></p>\n</div>\n"
+		);
+		// Spreads come after the written attributes and don't move the names
+		// they repeat
+		assert_eq!(
+			render(concat!(
+				"const props = {{\"c\", \"1\"}, {\"a\", \"2\"}, {\"c\", \"3\"}, {\"b\", \"4\"}}\n",
+				"<p z=\"0\" a=\"x\" {...props}>\n"
+			)),
+			"<p z='0' a='x' c='3' b='4'></p>\n"
+		);
+	}
+
+	#[test]
+	fn written_attributes_win_over_spreads() {
+		assert_eq!(
+			render(concat!(
+				"const props = {{\"ID\", \"spread\"}, {\"title\", \"t\"}, {\"hidden\", {!}}}\n",
+				"<p id=\"written\" {...props}>\n"
+			)),
+			"<p id='written' title='t'></p>\n"
+		);
+		assert_eq!(render("<p {...{!}}>\n"), "<p></p>\n");
+	}
+
+	#[test]
+	fn invalid_spreads_are_errors() {
+		for src in [
+			"<p {...\"x\"}>\n",
+			"<p {...{\"x\"}}>\n",
+			"<p {...{{\"x\", \"y\", \"z\"},}}>\n",
+			"<p {...{{\"a b\", \"y\"},}}>\n",
+		] {
+			let mut engine = Kismesis::new();
+			let file = engine.compile_str(src, None).unwrap();
+			let errors = generate_html(&file, vec![], &Settings::new(), &engine).unwrap_err();
+			assert!(
+				matches!(errors[0].error.error, CompilerError::InvalidSpread),
+				"{}",
+				src
+			);
+		}
+	}
+
+	#[test]
+	fn empty_expressions_write_nothing() {
+		assert_eq!(render("<p | a@{!}b@{ ! }c>\n"), "<p>abc</p>\n");
+	}
+
+	#[test]
+	fn none_variables_cant_be_written() {
+		let mut engine = Kismesis::new();
+		let file = engine
+			.compile_str("<const x = {!}>\n<p | @{x}>\n", None)
+			.unwrap();
+		let errors = generate_html(&file, vec![], &Settings::new(), &engine).unwrap_err();
+		assert!(matches!(
+			errors[0].error.error,
+			CompilerError::CantWriteNoneValue
+		));
+	}
+
+	#[test]
+	fn pure_macros_with_variables_in_their_arguments() {
+		let src = concat!(
+			"<macro pure m a | <b | @a>>\n",
+			"<macro pure n a b | <b | @a>>\n",
+			"<n! a={b} b=\"1\">\n",
+			"<n! a={b} b=\"2\">\n",
+			"<m! a=\"3\">\n",
+			"<m! a=\"3\">\n",
+		);
+		let lines: Vec<_> = render(src)
+			.lines()
+			.map(str::trim)
+			.filter(|x| !x.is_empty())
+			.map(String::from)
+			.collect();
+		assert_eq!(lines, ["<b>1</b>", "<b>2</b>", "<b>3</b>", "<b>3</b>"]);
+	}
+
+	#[test]
+	fn recursive_macros_hit_the_expansion_limit() {
+		let mut settings = Settings::new();
+		settings.set_max_expansion_depth(4);
+		for (src, chain) in [
+			("<macro a | <p | <a!>>>\n<a!>\n", "a a a a a"),
+			(
+				"<macro b | <c!>>\n<macro c | <p | <b!>>>\n<b!>\n",
+				"b c b c b",
+			),
+		] {
+			let mut engine = Kismesis::new();
+			let file = engine.compile_str(src, None).unwrap();
+			let errors = generate_html(&file, vec![], &settings, &engine).unwrap_err();
+			assert_eq!(errors.len(), 1);
+			let CompilerError::RecursionLimit(found) = &errors[0].error.error else {
+				panic!(
+					"expected a recursion limit, got {:?}",
+					errors[0].error.error
+				)
+			};
+			assert_eq!(found.join(" "), chain);
+		}
+		// Macros that stop before the limit are fine
+		assert_eq!(
+			render_with("<macro a | <p | x>>\n<macro b | <a!>>\n<b!>\n", &settings),
+			"<p>x</p>\n"
+		);
+	}
+
+	#[test]
+	fn env_tags_need_their_environment() {
+		// Gated bodies are written on lines of their own, so only the lines
+		// with something on them are compared
+		let lines = |x: String| -> Vec<String> {
+			x.lines()
+				.filter(|x| !x.trim().is_empty())
+				.map(str::to_string)
+				.collect()
+		};
+		let src = "<p | a>\n<env \"production\" | <b | b>>\n<env \"nowhere\" | <i | c>>\n";
+		assert_eq!(lines(render(src)), ["<p>a</p>"]);
+		let mut settings = Settings::new();
+		settings.add_environment("production");
+		assert_eq!(lines(render_with(src, &settings)), ["<p>a</p>", "<b>b</b>"]);
+	}
+
+	#[test]
+	fn line_endings_and_final_newlines() {
+		let src = "<div | <hr><img src=\"x.png\">>\r\n<p | a>\r\n\r\n";
+		let mut settings = Settings::new();
+		let lf = "<div>\n\t<hr>\n\t<img src='x.png'>\n</div>\n<p>a</p>";
+		assert_eq!(render_with(src, &settings), format!("{}\n", lf));
+
+		settings.set_line_ending(LineEnding::CrLf);
+		let crlf = lf.replace('\n', "\r\n");
+		assert_eq!(render_with(src, &settings), format!("{}\r\n", crlf));
+
+		settings.set_final_newline(false);
+		assert_eq!(render_with(src, &settings), crlf);
+
+		settings.set_line_ending(LineEnding::Lf);
+		assert_eq!(render_with(src, &settings), lf);
+		assert_eq!(render_with("", &Settings::new()), "");
+	}
+
+	#[test]
+	fn else_bodies_are_written_when_the_condition_is_falsy() {
+		let src = |x: &str| {
+			format!(
+				"const a = {}\n<div | <if {{a}} | <b | yes>><else | <i | no>>>\n",
+				x
+			)
+		};
+		assert!(render(&src("\"x\"")).contains("<b>yes</b>"));
+		assert!(!render(&src("\"x\"")).contains("<i>"));
+		assert!(render(&src("{!}")).contains("<i>no</i>"));
+		assert!(!render(&src("{!}")).contains("<b>"));
+	}
+
+	#[test]
+	fn array_attributes_are_space_separated() {
+		assert_eq!(
+			render(
+				"const classes = {\"a\", {{!} and \"b\"}, {\"c\", \"d\"}}\n<p class={classes}>\n"
+			),
+			"<p class='a c d'></p>\n"
+		);
+		assert_eq!(render("<p class={\"a\",}>\n"), "<p class='a'></p>\n");
+		assert_eq!(render("<p class={\"a\"}>\n"), "<p class='a'></p>\n");
+	}
+
+	#[test]
+	fn hyphenated_attributes_pass_through() {
+		assert_eq!(
+			render("<div data-user-id=\"5\" aria-hidden=\"true\">\n"),
+			"<div data-user-id='5' aria-hidden='true'></div>\n"
+		);
+	}
+
+	#[test]
+	fn doctypes_are_written_as_they_are() {
+		assert_eq!(
+			render("doctype html\n<p | a>\n"),
+			"<!DOCTYPE html>\n<p>a</p>\n"
+		);
+	}
+}
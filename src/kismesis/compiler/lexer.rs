@@ -1,6 +1,7 @@
-use std::fmt;
+use std::{fmt, ops::Range};
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 
 /// The different tokens that can be in an input string
 pub enum Token {
@@ -35,32 +36,101 @@ impl Token {
 	}
 }
 
-/// Converts a string into a `Vec<Token>`, ignoring `\r` characters.
+/// Turns tokens back into the text they were lexed from. If `indent_width`
+/// is set, indents are expanded into that many spaces instead of being
+/// written as tabs.
+///
+/// Since [`tokenize`] drops comments and `\r` characters, those won't be in
+/// the output.
+pub fn tokens_to_source(tokens: &[Token], indent_width: Option<usize>) -> String {
+	let mut output = String::new();
+	for token in tokens {
+		match (token, indent_width) {
+			(Token::Indent(_), Some(width)) => output.push_str(&" ".repeat(width)),
+			(token, _) => token.push_to_string(&mut output),
+		}
+	}
+	output
+}
+
+/// Converts a string into a `Vec<Token>`, ignoring `\r` characters and the
+/// byte-order mark some editors put at the start of files.
 pub fn tokenize(s: &str) -> Vec<Token> {
-	let s: String = {
-		let mut buffer = Vec::new();
-		let mut buffer2 = Vec::new();
-		let mut comments = false;
-		for x in s.chars() {
-			if !comments {
-				buffer.push(x);
-				if let [.., '<', '!', '-'] = buffer.as_slice() {
-					buffer.pop();
-					buffer.pop();
-					buffer.pop();
-					comments = true;
-				}
-			} else {
-				buffer2.push(x);
-				if let [.., '-', '>'] = buffer2.as_slice() {
-					buffer2.clear();
-					comments = false;
+	lex(&strip_comments(s).0)
+}
+
+/// Tokenizes a string like [`tokenize`], also giving the range of bytes in
+/// `s` each token was lexed from
+pub fn tokenize_with_offsets(s: &str) -> (Vec<Token>, Vec<Range<usize>>) {
+	let (stripped, origins) = strip_comments(s);
+	let tokens = lex(&stripped);
+	let mut offsets = Vec::with_capacity(tokens.len());
+	let mut idx = 0;
+	for token in tokens.iter() {
+		// `\r`s are dropped without a token of their own
+		while stripped[idx..].starts_with('\r') {
+			idx += 1;
+		}
+		let end = idx + token.get_as_string().len();
+		offsets.push(origins[idx]..origins[end - 1] + 1);
+		idx = end;
+	}
+	(tokens, offsets)
+}
+
+/// Removes the byte-order mark and comments from a string. Also gives where
+/// each byte of the result was in `s`.
+///
+/// Since comments never reach the parser, they're ignored the same way
+/// anywhere they're written, except inside of quoted values, so
+/// `title="<!- x ->"` keeps its text. A quote only starts a quoted value
+/// right after an `=`, so quotes and apostrophes in text don't hide the
+/// comments after them, and a quoted value that isn't closed ends with its
+/// line.
+fn strip_comments(s: &str) -> (String, Vec<usize>) {
+	let without_bom = s.strip_prefix('\u{feff}').unwrap_or(s);
+	let bom = s.len() - without_bom.len();
+	let mut buffer = Vec::new();
+	let mut buffer2 = Vec::new();
+	let mut origins = Vec::new();
+	let mut comments = false;
+	let mut quote: Option<char> = None;
+	let mut escape = false;
+	for (idx, x) in without_bom.char_indices() {
+		if !comments {
+			let previous = buffer.iter().rev().find(|x| !matches!(x, ' ' | '\t'));
+			let after_equals = previous == Some(&'=');
+			buffer.push(x);
+			origins.extend((0..x.len_utf8()).map(|byte| bom + idx + byte));
+			match (quote, x) {
+				(Some(_), _) if escape => escape = false,
+				(Some(_), '\\') => escape = true,
+				(Some(_), '\n') => quote = None,
+				(Some(open), x) if x == open => quote = None,
+				(Some(_), _) => (),
+				(None, '"' | '\'' | '`') if after_equals => quote = Some(x),
+				(None, _) => {
+					if let [.., '<', '!', '-'] = buffer.as_slice() {
+						buffer.pop();
+						buffer.pop();
+						buffer.pop();
+						origins.truncate(origins.len() - 3);
+						comments = true;
+					}
 				}
 			}
+		} else {
+			buffer2.push(x);
+			if let [.., '-', '>'] = buffer2.as_slice() {
+				buffer2.clear();
+				comments = false;
+			}
 		}
-		buffer.into_iter().collect()
-	};
+	}
+	(buffer.into_iter().collect(), origins)
+}
 
+fn lex(s: &str) -> Vec<Token> {
 	let mut output: Vec<Token> = vec![];
 
 	let mut current_word: usize = 0;
@@ -83,7 +153,7 @@ pub fn tokenize(s: &str) -> Vec<Token> {
 					&mut output,
 					&mut current_word,
 					idx,
-					&s,
+					s,
 				);
 			}
 			'\n' => {
@@ -92,7 +162,7 @@ pub fn tokenize(s: &str) -> Vec<Token> {
 					&mut output,
 					&mut current_word,
 					idx,
-					&s,
+					s,
 				);
 			}
 			'\t' => {
@@ -101,7 +171,7 @@ pub fn tokenize(s: &str) -> Vec<Token> {
 					&mut output,
 					&mut current_word,
 					idx,
-					&s,
+					s,
 				);
 			}
 			x if !x.is_alphanumeric() => {
@@ -110,7 +180,7 @@ pub fn tokenize(s: &str) -> Vec<Token> {
 					&mut output,
 					&mut current_word,
 					idx,
-					&s,
+					s,
 				);
 			}
 			_ => (),
@@ -134,6 +204,116 @@ fn push_token(
 	if !word.is_empty() {
 		list.push(Token::Word(word.to_string()))
 	}
+	// Symbols like a stray byte-order mark take more than one byte
+	let token_length = match &token {
+		Token::Word(word) => word.len(),
+		Token::Space(c) | Token::Newline(c) | Token::Indent(c) | Token::Symbol(c) => c.len_utf8(),
+	};
 	list.push(token);
-	*current_word_start = current_word_end + 1
+	*current_word_start = current_word_end + token_length
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn tokens_round_trip() {
+		let src = "<p class=\"a b\" |\n\tx  y, @z!\n\t\t<b | w>>\n";
+		assert_eq!(tokens_to_source(&tokenize(src), None), src);
+		assert_eq!(
+			tokens_to_source(&tokenize(src), Some(2)),
+			src.replace('\t', "  ")
+		);
+	}
+
+	#[test]
+	fn round_trips_drop_carriage_returns_and_comments() {
+		let src = "<p | a\r\n<!- note ->b>\r\n";
+		assert_eq!(tokens_to_source(&tokenize(src), None), "<p | a\nb>\n");
+	}
+
+	#[test]
+	fn offsets_cover_multibyte_characters() {
+		let src = "<p title=\"ñandú\" | héllo 🎉\r\n\tw <!- ☃ -> x>\n";
+		let (tokens, offsets) = tokenize_with_offsets(src);
+		assert_eq!(tokens.len(), offsets.len());
+		for (token, range) in tokens.iter().zip(offsets.iter()) {
+			match token {
+				// The `\r` before it is dropped, but still in the source
+				Token::Newline(_) => assert_eq!(&src[range.clone()], "\n"),
+				token => assert_eq!(&src[range.clone()], token.get_as_string()),
+			}
+		}
+		let find = |text: &str| {
+			let idx = tokens
+				.iter()
+				.position(|x| x.get_as_string() == text)
+				.unwrap();
+			offsets[idx].clone()
+		};
+		assert_eq!(find("🎉"), 28..32);
+		// The comment is skipped over
+		assert_eq!(find("w").end, 36);
+		assert_eq!(find("x").start, 48);
+	}
+
+	#[test]
+	fn comments_inside_quotes_are_kept() {
+		let text = |src: &str| tokens_to_source(&tokenize(src), None);
+		assert_eq!(text("<p title=\"<!- x ->\">\n"), "<p title=\"<!- x ->\">\n");
+		assert_eq!(text("<p title='a<!- x ->'>\n"), "<p title='a<!- x ->'>\n");
+		assert_eq!(
+			text("<p title=`\\`<!- x ->`>\n"),
+			"<p title=`\\`<!- x ->`>\n"
+		);
+		// After the string closes, comments are comments again
+		assert_eq!(
+			text("<p title=\"a\"<!- x -> | b>\n"),
+			"<p title=\"a\" | b>\n"
+		);
+		assert_eq!(text("const a = \"<!- x ->\"\n"), "const a = \"<!- x ->\"\n");
+		// Quotes in text aren't values, so they don't keep comments
+		assert_eq!(text("<p | don't <!- x ->stop>\n"), "<p | don't stop>\n");
+		assert_eq!(text("<p | 'a <!- x ->b>\n"), "<p | 'a b>\n");
+		assert_eq!(text("<p | say \"<!- x ->hi\">\n"), "<p | say \"hi\">\n");
+		// And values that aren't closed end with their line
+		assert_eq!(
+			text("<p title='a <!- x ->\n<!- y ->b>\n"),
+			"<p title='a <!- x ->\nb>\n"
+		);
+	}
+
+	#[test]
+	fn offsets_after_quoted_comments() {
+		let src = "<p title=\"<!- x ->\" <!- y -> id=\"z\">\n";
+		let (tokens, offsets) = tokenize_with_offsets(src);
+		for (token, range) in tokens.iter().zip(offsets.iter()) {
+			assert_eq!(&src[range.clone()], token.get_as_string());
+		}
+		let idx = tokens
+			.iter()
+			.position(|x| x.get_as_string() == "id")
+			.unwrap();
+		assert_eq!(offsets[idx], 29..31);
+	}
+
+	#[test]
+	fn leading_byte_order_marks_are_dropped() {
+		use crate::kismesis::{compiler::parser::types::SpanlessEq, Kismesis};
+
+		let src = "<p class=\"a\" | hi>\n";
+		let with_bom = format!("\u{feff}{}", src);
+		assert_eq!(tokenize(&with_bom), tokenize(src));
+		let (_, offsets) = tokenize_with_offsets(&with_bom);
+		assert_eq!(offsets[0], 3..4);
+		assert_eq!(&with_bom[offsets[1].clone()], "p");
+		// Only the first character can be a byte-order mark
+		assert_ne!(tokenize("a\u{feff}"), tokenize("a"));
+
+		let mut engine = Kismesis::new();
+		let plain = engine.compile_str(src, None).unwrap();
+		let marked = engine.compile_str(&with_bom, None).unwrap();
+		assert!(marked.body.eq_ignoring_span(&plain.body));
+	}
 }
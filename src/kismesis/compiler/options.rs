@@ -1,7 +1,36 @@
+/// The line break used in the output
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum LineEnding {
+	#[default]
+	Lf,
+	CrLf,
+}
+
+impl LineEnding {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			Self::Lf => "\n",
+			Self::CrLf => "\r\n",
+		}
+	}
+}
+
+/// How many macro calls can be expanded inside of each other before
+/// generation fails, so a macro that calls itself forever is caught
+pub const DEFAULT_MAX_EXPANSION_DEPTH: usize = 64;
+
 pub struct Settings {
 	inline: Vec<String>,
 	only_closer: Vec<String>,
 	only_opener: Vec<String>,
+	preformatted: Vec<String>,
+	minify: bool,
+	/// The build environments `<env>` tags are written for
+	environments: Vec<String>,
+	line_ending: LineEnding,
+	final_newline: bool,
+	source_map: bool,
+	max_expansion_depth: usize,
 }
 
 impl Settings {
@@ -13,9 +42,78 @@ impl Settings {
 			]),
 			only_opener: string_vec(&["meta", "img", "link"]),
 			only_closer: string_vec(&["br"]),
+			preformatted: string_vec(&["pre", "textarea"]),
+			minify: false,
+			environments: vec![],
+			line_ending: LineEnding::Lf,
+			final_newline: true,
+			source_map: false,
+			max_expansion_depth: DEFAULT_MAX_EXPANSION_DEPTH,
 		}
 	}
 
+	/// Makes `<env>` tags with this name write their content
+	pub fn add_environment(&mut self, name: &str) {
+		self.environments.push(name.to_string());
+	}
+
+	pub fn has_environment(&self, n: &str) -> bool {
+		self.environments.iter().any(|x| x == n)
+	}
+
+	/// Makes the output leave out the line breaks and indentation between
+	/// tags. The contents of preformatted tags like `pre` are left as they are
+	pub fn set_minify(&mut self, minify: bool) {
+		self.minify = minify;
+	}
+
+	pub fn is_minified(&self) -> bool {
+		self.minify
+	}
+
+	/// Makes every line break in the output use `line_ending`, including the
+	/// ones written in the source
+	pub fn set_line_ending(&mut self, line_ending: LineEnding) {
+		self.line_ending = line_ending;
+	}
+
+	pub fn line_ending(&self) -> LineEnding {
+		self.line_ending
+	}
+
+	/// Whether the output ends with a single line break, or with none at all
+	pub fn set_final_newline(&mut self, final_newline: bool) {
+		self.final_newline = final_newline;
+	}
+
+	pub fn has_final_newline(&self) -> bool {
+		self.final_newline
+	}
+
+	/// Keeps track of where each tag in the output comes from, for
+	/// [`HtmlOutput::to_string_with_source_map`](super::html::HtmlOutput::to_string_with_source_map)
+	pub fn set_source_map(&mut self, source_map: bool) {
+		self.source_map = source_map;
+	}
+
+	pub fn has_source_map(&self) -> bool {
+		self.source_map
+	}
+
+	/// Sets how many macro calls can be expanded inside of each other before
+	/// generation fails with `CompilerError::RecursionLimit`
+	pub fn set_max_expansion_depth(&mut self, depth: usize) {
+		self.max_expansion_depth = depth;
+	}
+
+	pub fn max_expansion_depth(&self) -> usize {
+		self.max_expansion_depth
+	}
+
+	pub fn is_preformatted(&self, n: &str) -> bool {
+		self.preformatted.iter().any(|x| x == n)
+	}
+
 	pub fn is_only_closer(&self, n: &str) -> bool {
 		self.only_closer.iter().any(|x| x == n)
 	}
@@ -2,20 +2,20 @@ mod combinators;
 pub(crate) mod errors;
 pub(crate) mod state;
 pub(crate) mod types;
+pub(crate) mod visitor;
 
 use combinators::*;
-use std::fmt::Debug;
-use std::path::PathBuf;
+use std::{path::PathBuf, rc::Rc};
 
 use crate::kismesis::compiler::lexer::Token;
 use crate::kismesis::{KisID, KisTemplateID, Kismesis};
 
-use self::errors::{Err, ParseError};
-use self::state::ParserState;
+use self::errors::{Err, Hintable, Hints, ParseError};
+use self::state::{ParserState, TokenPos};
 use self::types::{
-	paragraph_str_to_p, Argument, Attribute, BinFunc, BodyNodes, BodyTags, Expression, ForTag,
-	HtmlNodes, HtmlTag, IfTag, Lambda, Macro, ParsedFile, PlugCall, Ranged, Section, StringParts,
-	Tag, TopNodes, UniFunc, Variable,
+	paragraph_str_to_p, Argument, Attribute, BinFunc, BodyNodes, BodyTags, EnvTag, Expression,
+	ForTag, HtmlNodes, HtmlTag, IfTag, Lambda, Macro, ParsedFile, PlugCall, Ranged, Section, Slot,
+	StringParts, Symbol, Tag, TopNodes, UniFunc, Variable,
 };
 
 use super::errors::ErrorState;
@@ -53,12 +53,21 @@ pub(crate) trait Parser<'a, Output> {
 		BoxedParser::new(is(self, fun))
 	}
 
-	fn dbg(self) -> BoxedParser<'a, Output>
+	fn verify<F>(self, fun: F, error: ParseError) -> BoxedParser<'a, Output>
 	where
 		Self: Sized + 'a,
-		Output: Debug + 'a,
+		F: Fn(&Output) -> bool + 'a,
+		Output: 'a,
+	{
+		BoxedParser::new(verify(self, fun, error))
+	}
+
+	fn trace(self, label: &str) -> BoxedParser<'a, Output>
+	where
+		Self: Sized + 'a,
+		Output: 'a,
 	{
-		BoxedParser::new(dbg(self))
+		BoxedParser::new(trace(self, label))
 	}
 	fn or<P>(self, other: P) -> BoxedParser<'a, Output>
 	where
@@ -96,23 +105,39 @@ pub(crate) trait Parser<'a, Output> {
 	{
 		BoxedParser::new(and_also(self, other))
 	}
-	fn maybe_until<P, O2>(self, terminator: P) -> BoxedParser<'a, Vec<Output>>
+	fn and_maybe<P, O2>(self, other: P) -> BoxedParser<'a, (Output, Option<O2>)>
 	where
 		Self: Sized + 'a,
 		P: Parser<'a, O2> + 'a,
 		Output: 'a,
 		O2: 'a,
 	{
-		BoxedParser::new(maybe_until(self, terminator))
+		BoxedParser::new(and_maybe(self, other))
 	}
-	fn and_maybe<P, O2>(self, other: P) -> BoxedParser<'a, (Output, Option<O2>)>
+	/// The same as [`maybe`], for when it reads better at the end of a chain
+	fn opt(self) -> BoxedParser<'a, Option<Output>>
 	where
 		Self: Sized + 'a,
-		P: Parser<'a, O2> + 'a,
 		Output: 'a,
-		O2: 'a,
 	{
-		BoxedParser::new(and_maybe(self, other))
+		BoxedParser::new(maybe(self))
+	}
+	/// The same as [`after_spaces`], for chains like
+	/// `literal.after_spaces().and_also(...)`
+	fn after_spaces(self) -> BoxedParser<'a, Output>
+	where
+		Self: Sized + 'a,
+		Output: 'a,
+	{
+		BoxedParser::new(after_spaces(self))
+	}
+	/// Skips the spaces after the parser instead of the ones before it
+	fn before_spaces(self) -> BoxedParser<'a, Output>
+	where
+		Self: Sized + 'a,
+		Output: 'a,
+	{
+		BoxedParser::new(self.followed_by(skip_spaces()))
 	}
 }
 
@@ -150,6 +175,7 @@ impl<'a, T> Parser<'a, T> for BoxedParser<'a, T> {
 fn quote_mark(state: ParserState) -> ParserResult<&char> {
 	match specific_symbol('\'')
 		.or(specific_symbol('"'))
+		.or(specific_symbol('`'))
 		.parse(state.clone())
 	{
 		Err(_) => Err(ParseError::NotQuoteMark.error_at(&state)),
@@ -209,6 +235,8 @@ fn plugin_mark(state: ParserState) -> ParserResult<&char> {
 	}
 }
 
+/// Only one symbol is taken, so a body can start with a `|` of its own, as
+/// in `<p | | text>`. Writing it as `\|` works too, like any other escape
 fn body_opener(state: ParserState) -> ParserResult<&char> {
 	match specific_symbol('|')
 		.or(specific_symbol(':'))
@@ -219,10 +247,17 @@ fn body_opener(state: ParserState) -> ParserResult<&char> {
 	}
 }
 
+/// Words that mean something of their own at the start of a tag, so tags,
+/// macros and plugins can't be named after them
+const RESERVED: &[&str] = &["macro", "content", "include", "if", "else", "for", "env"];
+
 fn macro_name(state: ParserState) -> ParserResult<&str> {
 	match literal.parse(state.clone()) {
-		Ok(ok) if ok.0 != "content" && ok.0 != "if" && ok.0 != "for" => Ok(ok),
-		_ => Err(ParseError::ExpectedTagName.error_at(&state)),
+		Ok((name, _)) if RESERVED.contains(&name) => {
+			Err(ParseError::ReservedName(name.to_string()).error_at(&state))
+		}
+		Ok(ok) => Ok(ok),
+		Err(_) => Err(ParseError::ExpectedTagName.error_at(&state)),
 	}
 }
 
@@ -233,29 +268,51 @@ fn set_starter(state: ParserState) -> ParserResult<&str> {
 	}
 }
 
-fn set_stmt(state: ParserState) -> ParserResult<(String, String)> {
-	let parser =
-		set_starter.preceding(cut(after_spaces(literal)
-			.and_also(after_spaces(equals).preceding(after_spaces(attr_string)))));
+fn set_stmt(state: ParserState) -> ParserResult<(Ranged<String>, Ranged<String>)> {
+	let parser = set_starter.preceding(cut(after_spaces(get_range(literal))
+		.and_also(after_spaces(equals).preceding(after_spaces(get_range(attr_string))))));
 
-	match parser.parse(state.clone()) {
-		Ok(((name, value), next_state)) => {
-			let value = {
-				let mut output = Vec::new();
-				for part in value.iter() {
-					match part {
-						StringParts::String(x) => output.push(x.clone()),
-						StringParts::Expression(_) => {
-							return Err(ParseError::ExpressionInSetStmt.error_at(&state))
-						}
-					}
-				}
-				output.into_iter().collect()
-			};
-			Ok(((name.to_string(), value), next_state))
+	let ((name, value), state) = parser.parse(state)?;
+	let value = plain_string(value, ParseError::ExpressionInSetStmt)?;
+	Ok(((name.to_own(), value), state))
+}
+
+/// Turns a string that can't have interpolations, like a path, into its
+/// text, with its entities written as they were. An interpolation in it is
+/// a failure with `error`, pointing at the interpolation
+fn plain_string(
+	string: Ranged<Vec<StringParts>>,
+	error: ParseError,
+) -> Result<Ranged<String>, Err> {
+	let mut output = String::new();
+	for part in string.value.iter() {
+		match part {
+			StringParts::String(x) => output.push_str(x),
+			StringParts::Entity(x) => output.push_str(&format!("&{};", x)),
+			StringParts::Expression(x) => {
+				return Err(Err::Failure(ErrorState {
+					error,
+					text_position: x.range.clone(),
+					hints: vec![],
+				}))
+			}
 		}
-		Err(x) => Err(x),
 	}
+	Ok(Ranged {
+		value: output,
+		range: string.range,
+	})
+}
+
+/// Parses an `extends "path"` statement, which makes the file fill the
+/// slots of the template at that path. Everything in the file's body that
+/// isn't a `content:name!` slot goes in the template's unnamed `content!`.
+fn extends_stmt(state: ParserState) -> ParserResult<Ranged<PathBuf>> {
+	let parser = specific_literal("extends").preceding(cut(after_spaces(get_range(attr_string))));
+
+	let (path, next_state) = parser.parse(state)?;
+	let path = plain_string(path, ParseError::ExpressionInPath)?;
+	Ok((path.map(PathBuf::from), next_state))
 }
 
 fn equals(state: ParserState) -> ParserResult<&char> {
@@ -282,25 +339,30 @@ fn check_tag_mismatch(state: ParserState) -> ParserResult<()> {
 	Ok(((), state))
 }
 
+/// Parses the inside of `{a, b}`. An array needs at least one comma, so that
+/// `{a}` is just `a`, and `{}` is an empty array
 fn expr_array(state: ParserState) -> ParserResult<Expression> {
-	let parser = zero_or_more(get_range(expression).followed_by(after_blanks(
-		specific_symbol(',').followed_by(skipped_blanks()),
-	)))
+	let items = repeated(
+		get_range(expression).followed_by(after_blanks(
+			specific_symbol(',').followed_by(skipped_blanks()),
+		)),
+		1..,
+	)
 	.and_maybe(get_range(expression))
 	.map(|(mut vec, maybe)| {
 		if let Some(last) = maybe {
 			vec.push(last)
 		}
 		vec
-	})
-	.map(Expression::Array);
+	});
+	let empty = peek(expr_closer).map(|_| vec![]);
+	let parser = items.or(empty).map(Expression::Array);
 
 	parser.parse(state)
 }
 
 fn expression(state: ParserState) -> ParserResult<Expression> {
-	let parser = variable_name
-		.map(|x| Expression::Variable(x.to_owned()))
+	let parser = member_access
 		.or(attr_string.map(Expression::Literal))
 		.or(wrapped_expr);
 	parser.parse(state)
@@ -310,10 +372,9 @@ fn binary_func(state: ParserState) -> ParserResult<BinFunc> {
 	let (val, next_state) = literal
 		.parse(state.clone())
 		.map_err(|_x| ParseError::ExpectedBinFunc.error_at(&state))?;
-	match val {
-		"and" => Ok((BinFunc::And, next_state)),
-		"or" => Ok((BinFunc::Or, next_state)),
-		_ => Err(ParseError::ExpectedBinFunc.error_at(&state)),
+	match val.parse() {
+		Ok(func) => Ok((func, next_state)),
+		Err(()) => Err(ParseError::ExpectedBinFunc.error_at(&state)),
 	}
 }
 
@@ -321,16 +382,16 @@ fn unary_func(state: ParserState) -> ParserResult<UniFunc> {
 	let (val, next_state) = literal
 		.parse(state.clone())
 		.map_err(|_x| ParseError::ExpectedUniFunc.error_at(&state))?;
-	match val {
-		"not" => Ok((UniFunc::Not, next_state)),
-		_ => Err(ParseError::ExpectedUniFunc.error_at(&state)),
+	match val.parse() {
+		Ok(func) => Ok((func, next_state)),
+		Err(()) => Err(ParseError::ExpectedUniFunc.error_at(&state)),
 	}
 }
 
 fn binary_func_expr(state: ParserState) -> ParserResult<Expression> {
 	let parser = get_range(expression)
 		.and_also(after_spaces(binary_func))
-		.and_also(cut(after_spaces(get_range(expression))));
+		.and_also(cut_before_eof(after_spaces(get_range(expression))));
 	let (((expr1, fun), expr2), next_state) = parser.parse(state)?;
 	Ok((
 		Expression::BinFunc(fun, Box::new(expr1), Box::new(expr2)),
@@ -344,16 +405,39 @@ fn unary_func_expr(state: ParserState) -> ParserResult<Expression> {
 	Ok((Expression::UniFunc(fun, Box::new(expr)), next_state))
 }
 
+/// Once the `{` is found, an expression that isn't closed is an error with
+/// a hint pointing back at the `{`
 fn wrapped_expr(state: ParserState) -> ParserResult<Expression> {
 	let internal_parser = binary_func_expr
 		.or(unary_func_expr)
 		.or(expr_array)
 		.or(expression)
-		.or(specific_symbol('!').map(|_x| Expression::None));
-	let parser = expr_opener
-		.preceding(cut(after_blanks(internal_parser)).followed_by(after_blanks(expr_closer)));
+		.or(empty_expr);
+	let (opener, next_state) = get_range(expr_opener).parse(state)?;
+	let (expression, next_state) = cut(after_blanks(internal_parser)).parse(next_state)?;
+	let scope = next_state.scope;
+
+	match cut(after_blanks(expr_closer)).parse(next_state) {
+		Ok((_, next_state)) => Ok((expression, next_state)),
+		Err(error) => {
+			let mut error = error.unpack();
+			if let Some(scope) = scope {
+				error.add_hint(Hints::ExprOpenedHere.with_state_at(opener.range, scope));
+			}
+			Err(Err::Failure(error))
+		}
+	}
+}
 
-	parser.parse(state)
+/// `{!}` is the empty expression, which evaluates to `None` and writes
+/// nothing. The `!` has to be the only thing in it
+fn empty_expr(state: ParserState) -> ParserResult<Expression> {
+	let (_, next_state) = specific_symbol('!').parse(state)?;
+	match after_blanks(expr_closer).parse(next_state.clone()) {
+		Ok(_) => Ok((Expression::None, next_state)),
+		Err(error) if next_state.remaining().iter().all(is_blank) => Err(error),
+		Err(_) => Err(ParseError::ContentInEmptyExpr.error_at(&next_state)),
+	}
 }
 
 fn variable_definition(state: ParserState) -> ParserResult<Variable> {
@@ -390,18 +474,51 @@ fn lambda_definition(state: ParserState) -> ParserResult<Lambda> {
 fn if_tag(state: ParserState) -> ParserResult<IfTag> {
 	let parser = specific_literal("if")
 		.preceding(after_spaces(get_range(expression)))
-		.and_also(maybe(tag_body).map(|x| x.unwrap_or(vec![])))
-		.map(|(condition, body)| IfTag { condition, body });
+		.and_also(tag_body.opt().map(Option::unwrap_or_default))
+		.map(|(condition, body)| IfTag {
+			condition,
+			body,
+			else_body: None,
+		});
 
 	parser.parse(state)
 }
 
+/// Parses an `<else | ...>` tag, which may only come right after an `if`
+/// tag, with nothing but blanks between them
+fn else_tag(state: ParserState) -> ParserResult<Vec<HtmlNodes>> {
+	let parser = skipped_blanks()
+		.preceding(tag_opener)
+		.preceding(after_spaces(specific_literal("else")))
+		.preceding(cut(maybe(tag_body).followed_by(tag_closer)))
+		.map(|x| x.unwrap_or(vec![]));
+
+	parser.parse(state)
+}
+
+/// Gives an `if` tag the `else` tag that follows it, if there is one
+fn with_else(mut tag: IfTag, state: ParserState) -> ParserResult<IfTag> {
+	let (else_body, state) = maybe(else_tag).parse(state)?;
+	tag.else_body = else_body;
+	Ok((tag, state))
+}
+
+fn env_tag(state: ParserState) -> ParserResult<EnvTag> {
+	let parser = specific_literal("env")
+		.preceding(cut(after_spaces(get_range(attr_string))))
+		.and_also(tag_body.opt().map(Option::unwrap_or_default));
+
+	let ((name, body), state) = parser.parse(state)?;
+	let name = plain_string(name, ParseError::ExpressionInEnvName)?;
+	Ok((EnvTag { name, body }, state))
+}
+
 fn for_tag(state: ParserState) -> ParserResult<ForTag> {
 	let parser = specific_literal("for").preceding(
 		cut(after_spaces(get_range(literal)))
 			.followed_by(after_spaces(specific_literal("in")))
 			.and_also(after_spaces(get_range(expression)))
-			.and_also(maybe(tag_body).map(|x| x.unwrap_or(vec![])))
+			.and_also(tag_body.opt().map(Option::unwrap_or_default))
 			.map(|((variable, iterator), body)| ForTag {
 				variable: variable.to_own(),
 				iterator,
@@ -414,51 +531,120 @@ fn for_tag(state: ParserState) -> ParserResult<ForTag> {
 
 fn some_tag(state: ParserState) -> ParserResult<Tag> {
 	let parser = tag_opener.preceding(cut(after_spaces(
-		tag.map(Tag::HtmlTag)
+		include_tag
+			.map(Tag::Include)
+			.or(tag.map(Tag::HtmlTag))
 			.or(macro_call.map(Tag::MacroCall))
 			.or(macro_def.map(Tag::MacroDef))
 			.or(plug_call.map(Tag::PlugCall))
-			.or(content_macro.map(|_| Tag::Content))
+			.or(slot.map(Tag::Slot))
+			.or(content_macro.map(Tag::Content))
+			.or(nameless_macro)
 			.or(doctype.map(Tag::Doctype))
+			.or(env_tag.map(Tag::Env))
 			.or(if_tag.map(Tag::If))
 			.or(for_tag.map(Tag::For))
-			.followed_by(tag_closer),
+			.or(reserved_tag)
+			.followed_by(cut_before_eof(tag_closer)),
 	)));
 
-	parser.parse(state)
+	match parser.parse(state)? {
+		(Tag::If(tag), state) => with_else(tag, state).map(|(x, state)| (Tag::If(x), state)),
+		x => Ok(x),
+	}
 }
 
 fn some_child_tag(state: ParserState) -> ParserResult<BodyTags> {
 	let parser = tag_opener
 		.preceding(cut(after_spaces(
-			tag.map(|x| BodyTags::HtmlTag(x.merge_subtags()))
+			include_tag
+				.map(BodyTags::Include)
+				.or(tag.map(|x| BodyTags::HtmlTag(x.merge_subtags())))
 				.or(macro_call.map(BodyTags::MacroCall))
-				.or(content_macro.map(|_| BodyTags::Content))
+				.or(content_macro.map(BodyTags::Content))
+				.or(nameless_macro)
+				.or(env_tag.map(BodyTags::Env))
 				.or(if_tag.map(BodyTags::If))
 				.or(for_tag.map(BodyTags::For))
-				.followed_by(tag_closer),
+				.or(reserved_tag)
+				.followed_by(cut_before_eof(tag_closer)),
 		)))
 		.or(section_block.map(BodyTags::Section));
 
-	parser.parse(state)
+	match parser.parse(state)? {
+		(BodyTags::If(tag), state) => {
+			with_else(tag, state).map(|(x, state)| (BodyTags::If(x), state))
+		}
+		x => Ok(x),
+	}
 }
 
-fn tag(state: ParserState<'_>) -> ParserResult<'_, HtmlTag> {
-	let parser = tag_head.and_maybe(tag_body);
+/// Always fails. Tags that start with a reserved word but couldn't be parsed
+/// as what that word starts get an error saying the word is reserved
+fn reserved_tag<T>(state: ParserState) -> ParserResult<T> {
+	let (name, _) = literal.parse(state.clone())?;
+	let error = match name {
+		"else" => ParseError::ElseWithoutIf,
+		name if RESERVED.contains(&name) => ParseError::ReservedName(name.to_string()),
+		_ => ParseError::ExpectedTagName,
+	};
+	Err(error.error_at(&state))
+}
+
+/// Always fails. A `!` where a tag name should be, as in `<!>`, is a macro
+/// call missing its name, unless it's the `!` of `<!doctype>`
+fn nameless_macro<T>(state: ParserState) -> ParserResult<T> {
+	macro_mark
+		.followed_by(not(after_spaces(specific_literal("doctype"))))
+		.parse(state.clone())?;
+	Err(ParseError::EmptyMacroName.error_at(&state).cut())
+}
 
-	let (((name, attributes, subtags), body), state) = parser.parse(state)?;
+/// Parses a tag. The `-`s that trim the whitespace around it have to be right
+/// next to its head, as in `<-p>` or `<p class="a"->`. A tag with a body
+/// can't trim the whitespace after it, so that in `<p:a->` the `-` is text
+fn tag(state: ParserState<'_>) -> ParserResult<'_, HtmlTag> {
+	let ending = trim_closer_mark
+		.map(|_| (None, true))
+		.or(maybe(tag_body).map(|x| (x, false)));
+	let parser = maybe(specific_symbol('-'))
+		.and_also(tag_head)
+		.and_also(ending);
+
+	let (((trim_before, tag), (body, trim_after)), state) = parser.parse(state)?;
+	// Every subtag is the body of the tag before it, and the last one gets
+	// the actual body
+	let chain: Vec<&HtmlTag> = std::iter::once(&tag).chain(tag.subtags.iter()).collect();
+	for (i, element) in chain.iter().enumerate() {
+		let has_body = i + 1 < chain.len() || body.is_some();
+		if has_body && element.is_void_element() {
+			return Err(Err::Failure(ErrorState {
+				error: ParseError::VoidElementWithBody(element.qualified_name()),
+				text_position: element.name.range.clone(),
+				hints: vec![],
+			}));
+		}
+	}
 	Ok((
 		HtmlTag {
-			name,
-			attributes,
 			body: body.unwrap_or(vec![]),
-			subtags,
+			trim_before: trim_before.is_some(),
+			trim_after,
+			..tag
 		}
 		.merge_subtags(),
 		state,
 	))
 }
 
+/// Parses the `-` of a `->` that closes a tag and asks for the whitespace
+/// after it to be removed
+fn trim_closer_mark(state: ParserState) -> ParserResult<&char> {
+	specific_symbol('-')
+		.followed_by(peek(specific_symbol('>')))
+		.parse(state)
+}
+
 fn section_block(state: ParserState) -> ParserResult<Section> {
 	let ((depth, title), state) = repeated(specific_symbol('#'), 1..=state.section_depth + 1)
 		.map(|x| x.len())
@@ -499,10 +685,43 @@ fn section_block(state: ParserState) -> ParserResult<Section> {
 	Ok((section, state))
 }
 
-fn content_macro(state: ParserState<'_>) -> ParserResult<'_, ()> {
-	let parser = specific_literal("content").followed_by(after_spaces(macro_mark));
-	let (_, state) = parser.parse(state)?;
-	Ok(((), state))
+/// Parses a `content!` placeholder. A name may be given with `content:name!`
+/// to make a named slot; the unnamed placeholder is the default slot, which
+/// receives everything that isn't given to a named one.
+fn content_macro(state: ParserState<'_>) -> ParserResult<'_, Option<String>> {
+	let parser = specific_literal("content")
+		.preceding(maybe(
+			specific_symbol(':').preceding(literal.map(|x| x.to_string())),
+		))
+		.followed_by(after_spaces(macro_mark));
+	parser.parse(state)
+}
+
+/// Parses a `content:name!` tag with a body, which fills the template's slot
+/// of that name
+fn slot(state: ParserState<'_>) -> ParserResult<'_, Slot> {
+	let parser = specific_literal("content")
+		.preceding(specific_symbol(':'))
+		.preceding(get_range(literal))
+		.followed_by(after_spaces(macro_mark))
+		.and_also(tag_body);
+
+	let ((name, body), state) = parser.parse(state)?;
+	Ok((
+		Slot {
+			name: name.to_own(),
+			body,
+		},
+		state,
+	))
+}
+
+/// Parses an `include "path"` tag, which embeds the output of the template
+/// at that path. The path may have interpolations in it
+fn include_tag(state: ParserState<'_>) -> ParserResult<'_, Ranged<Vec<StringParts>>> {
+	specific_literal("include")
+		.preceding(cut(after_spaces(get_range(attr_string))))
+		.parse(state)
 }
 
 fn doctype(state: ParserState<'_>) -> ParserResult<'_, String> {
@@ -512,18 +731,37 @@ fn doctype(state: ParserState<'_>) -> ParserResult<'_, String> {
 		.parse(state)
 }
 
+/// Parses a top-level `doctype html` line, which is the same as writing
+/// `<!doctype html>`. Anything else after the type on the same line makes
+/// it a paragraph instead
+fn doctype_stmt(state: ParserState<'_>) -> ParserResult<'_, String> {
+	specific_literal("doctype")
+		.preceding(after_spaces(literal.map(|x| x.to_string())))
+		.followed_by(peek(after_spaces(ignore(newline).or(eof))))
+		.parse(state)
+}
+
 fn plug_call(state: ParserState<'_>) -> ParserResult<'_, Box<PlugCall>> {
 	let parser = plugin_head.and_maybe(plugin_body);
 
 	let (((name, arguments), body), state) = parser.parse(state)?;
 
-	let body = state.engine.run_plugin(
-		&name.value,
-		name.range.clone(),
-		arguments.clone(),
-		body.clone(),
-		state.project_path.clone(),
-	);
+	let body = state
+		.engine
+		.run_plugin(
+			&name.value,
+			name.range.clone(),
+			arguments.clone(),
+			body.clone(),
+			state.project_path.as_deref().cloned(),
+		)
+		.map_err(|error| {
+			Err::Failure(ErrorState {
+				error,
+				text_position: name.range.clone(),
+				hints: vec![],
+			})
+		})?;
 
 	Ok((Box::new(PlugCall { name, body }), state))
 }
@@ -537,6 +775,7 @@ fn macro_call(state: ParserState<'_>) -> ParserResult<'_, Macro> {
 			name,
 			arguments,
 			body: vec![],
+			pure: false,
 		},
 		state,
 	))
@@ -545,42 +784,43 @@ fn macro_call(state: ParserState<'_>) -> ParserResult<'_, Macro> {
 fn macro_def(state: ParserState<'_>) -> ParserResult<'_, Macro> {
 	let parser = macro_def_head.and_maybe(tag_body);
 
-	let (((name, arguments), body), state) = parser.parse(state)?;
+	let (((pure, name, arguments), body), state) = parser.parse(state)?;
 	Ok((
 		Macro {
 			name,
 			arguments,
 			body: body.unwrap_or(vec![]),
+			pure,
 		},
 		state,
 	))
 }
 
 fn space(state: ParserState) -> ParserResult<&char> {
-	match any.parse(state)? {
+	match any.parse(state.clone())? {
 		(Token::Space(space), next_state) => Ok((space, next_state)),
-		(_, next_state) => Err(ParseError::NotASpace.error_at(&next_state)),
+		_ => Err(ParseError::NotASpace.error_at(&state)),
 	}
 }
 
 fn indent(state: ParserState) -> ParserResult<&char> {
-	match any.parse(state)? {
+	match any.parse(state.clone())? {
 		(Token::Indent(indent), next_state) => Ok((indent, next_state)),
-		(_, error_state) => Err(ParseError::NotAnIndent.error_at(&error_state)),
+		_ => Err(ParseError::NotAnIndent.error_at(&state)),
 	}
 }
 
 fn newline(state: ParserState) -> ParserResult<&char> {
-	match any.parse(state)? {
+	match any.parse(state.clone())? {
 		(Token::Newline(newline), next_state) => ParserResult::Ok((newline, next_state)),
-		(_, error_state) => Err(ParseError::NotANewline.error_at(&error_state)),
+		_ => Err(ParseError::NotANewline.error_at(&state)),
 	}
 }
 
 fn some_symbol(state: ParserState) -> ParserResult<&char> {
-	match any.parse(state)? {
+	match any.parse(state.clone())? {
 		(Token::Symbol(x), next_state) => Ok((x, next_state)),
-		(_, state) => Err(ParseError::NotSymbol.error_at(&state)),
+		_ => Err(ParseError::NotSymbol.error_at(&state)),
 	}
 }
 
@@ -592,18 +832,17 @@ fn eof(state: ParserState) -> ParserResult<()> {
 }
 
 fn literal(state: ParserState) -> ParserResult<&str> {
-	match any.parse(state)? {
+	match any.parse(state.clone())? {
 		(Token::Word(x), next_state) => Ok((x, next_state)),
-		(_, state) => Err(ParseError::NotLiteral.error_at(&state)),
+		_ => Err(ParseError::NotLiteral.error_at(&state)),
 	}
 }
 
 fn non_macro_starter(state: ParserState) -> ParserResult<&str> {
-	literal
-		.set_err(|| ParseError::ExpectedTagName)
-		.is(|x| x != &"macro" && x != &"if" && x != &"for")
-		.set_err(|| ParseError::UnexpectedMacroDef)
-		.parse(state)
+	match literal.parse(state.clone()) {
+		Ok(("macro", _)) => Err(ParseError::UnexpectedMacroDef.error_at(&state)),
+		_ => macro_name.parse(state),
+	}
 }
 
 fn var_def_starter(state: ParserState) -> ParserResult<&str> {
@@ -621,28 +860,230 @@ fn lambda_def_starter(state: ParserState) -> ParserResult<&str> {
 fn macro_starter(state: ParserState) -> ParserResult<&str> {
 	literal
 		.set_err(|| ParseError::ExpectedTagNameOrMacroDef)
-		.is(|x| x == &"macro")
-		.set_err(|| ParseError::NotMacroStart)
+		.verify(|x| x == &"macro", ParseError::NotMacroStart)
 		.parse(state)
 }
 
-fn tag_head(state: ParserState) -> ParserResult<(Ranged<String>, Vec<Attribute>, Vec<HtmlTag>)> {
+/// Parses a tag's name, attributes and subtags, leaving its body empty.
+///
+/// Attributes and subtags may be put on lines of their own, so long heads can
+/// be written vertically. A body can't open at the start of a line, so the
+/// `|` or `:` has to follow the last attribute or subtag on the same line.
+/// Comments are removed by the lexer, so they can go between attributes too,
+/// and since they end at `->` rather than at the end of the line, they don't
+/// swallow the attributes after them.
+fn tag_head(state: ParserState) -> ParserResult<HtmlTag> {
 	let cut_cond = space
 		.or(indent)
+		.or(newline)
 		.or(body_opener)
 		.or(tag_closer)
 		.or(tag_opener)
-		.or(subtag_opener);
-	let parser = get_range(non_macro_starter)
+		.or(subtag_opener)
+		.or(specific_symbol('.'))
+		.or(specific_symbol('#'))
+		.or(trim_closer_mark)
+		.map(|_| ())
+		.or(eof);
+	let parser = maybe(namespace_prefix)
+		.and_also(get_range(non_macro_starter))
+		.and_also(shorthands)
 		.followed_by(peek(cut_cond))
-		.and_also(cut(zero_or_more(after_spaces(attribute))))
-		.and_also(zero_or_more(after_spaces(subtag)));
+		.and_also(zero_or_more(after_blanks(
+			spread_attribute
+				.map(HeadAttribute::Spread)
+				.or(recovering_attribute.map(HeadAttribute::Attribute)),
+		)))
+		.and_also(zero_or_more(after_blanks(subtag)));
+
+	let (((((namespace, name), shorthands), head_attributes), subtags), state) =
+		parser.parse(state)?;
+	let mut attributes = vec![];
+	let mut spreads = vec![];
+	for head_attribute in head_attributes {
+		match head_attribute {
+			HeadAttribute::Attribute(Some(attribute)) => attributes.push(attribute),
+			HeadAttribute::Attribute(None) => (),
+			HeadAttribute::Spread(spread) => spreads.push(spread),
+		}
+	}
+
+	Ok((
+		HtmlTag {
+			namespace,
+			name: name.to_symbol(state.engine),
+			attributes: add_shorthands(shorthands, attributes, state.engine)?,
+			spreads,
+			body: vec![],
+			subtags,
+			trim_before: false,
+			trim_after: false,
+		},
+		state,
+	))
+}
+
+/// Parses a variable along with the `.field`s written after it, so
+/// `user.name.first` is the `first` field of the `name` field of `user`
+fn member_access(state: ParserState) -> ParserResult<Expression> {
+	let start = state.position;
+	let (root, mut state) = get_range(variable_name).parse(state)?;
+	let mut object = Ranged {
+		value: Expression::Variable(root.value.to_owned()),
+		range: root.range,
+	};
+	let field = specific_symbol('.').preceding(literal);
+	while let Ok((name, next_state)) = field.parse(state.clone()) {
+		object = Ranged {
+			value: Expression::Member(Box::new(object), name.to_owned()),
+			range: types::TextPos::Range((start, next_state.position)),
+		};
+		state = next_state;
+	}
+	Ok((object.value, state))
+}
+
+/// An attribute or a `{...spread}` in a tag's head. Attributes are `None`
+/// when they were malformed and skipped
+enum HeadAttribute {
+	Attribute(Option<Attribute>),
+	Spread(Ranged<Expression>),
+}
+
+/// Parses a `{...props}` spread attribute, giving the expression of `props`
+fn spread_attribute(state: ParserState) -> ParserResult<Ranged<Expression>> {
+	let spread_mark = specific_symbol('.')
+		.followed_by(specific_symbol('.'))
+		.followed_by(specific_symbol('.'));
+	expr_opener
+		.followed_by(spread_mark)
+		.preceding(cut(
+			after_blanks(get_range(expression)).followed_by(after_blanks(expr_closer))
+		))
+		.parse(state)
+}
+
+/// A `.class` or `#id` written right after a tag's name
+enum Shorthand<'a> {
+	Class(&'a str),
+	Id(&'a str),
+}
+
+/// Parses the shorthands that can follow a tag's name, like the ones in
+/// `<div#main.card.active>`
+fn shorthands(state: ParserState) -> ParserResult<Vec<Ranged<Shorthand>>> {
+	let class = specific_symbol('.').preceding(cut(literal).map(Shorthand::Class));
+	let id = specific_symbol('#').preceding(cut(literal).map(Shorthand::Id));
+	zero_or_more(get_range(class.or(id))).parse(state)
+}
+
+/// Turns the shorthands after a tag's name into `id` and `class` attributes.
+/// If the tag also has an unconditional `class` attribute, its value is added
+/// after the shorthand classes and the two become one. Since a tag can only
+/// have one id, an `#id` together with another one or with an `id` attribute
+/// is an error.
+fn add_shorthands(
+	shorthands: Vec<Ranged<Shorthand>>,
+	mut attributes: Vec<Attribute>,
+	engine: &Kismesis,
+) -> Result<Vec<Attribute>, Err> {
+	let mut classes = Vec::new();
+	let mut class_ranges = Vec::new();
+	let mut id: Option<Ranged<&str>> = None;
+	for shorthand in shorthands {
+		match shorthand.value {
+			Shorthand::Class(name) => {
+				classes.push(name);
+				class_ranges.push(shorthand.range);
+			}
+			Shorthand::Id(_) if id.is_some() => {
+				return Err(duplicate_id(shorthand.range));
+			}
+			Shorthand::Id(name) => {
+				id = Some(Ranged {
+					value: name,
+					range: shorthand.range,
+				})
+			}
+		}
+	}
+
+	if !classes.is_empty() {
+		let range = match class_ranges.len() {
+			1 => class_ranges.remove(0),
+			_ => types::TextPos::Multi(class_ranges),
+		};
+		let mut value = vec![StringParts::String(classes.join(" "))];
+		let explicit = attributes
+			.iter()
+			.position(|x| x.name.value == "class" && x.condition.is_none());
+		if let Some(idx) = explicit {
+			let explicit = attributes.remove(idx);
+			value.push(StringParts::String(" ".into()));
+			match explicit.value.value {
+				Expression::Literal(parts) => value.extend(parts),
+				_ => value.push(StringParts::Expression(explicit.value)),
+			}
+		}
+		attributes.insert(0, shorthand_attribute(engine.intern("class"), value, range));
+	}
+
+	if let Some(id) = id {
+		if let Some(explicit) = attributes.iter().find(|x| x.name.value == "id") {
+			return Err(duplicate_id(explicit.name.range.clone()));
+		}
+		let value = vec![StringParts::String(id.value.to_string())];
+		attributes.insert(0, shorthand_attribute(engine.intern("id"), value, id.range));
+	}
+
+	Ok(attributes)
+}
 
-	let (((name, attributes), subtags), state) = parser.parse(state)?;
+fn shorthand_attribute(name: Symbol, value: Vec<StringParts>, range: types::TextPos) -> Attribute {
+	Attribute {
+		name: Ranged {
+			value: name,
+			range: range.clone(),
+		},
+		value: Ranged {
+			value: Expression::Literal(value),
+			range,
+		},
+		condition: None,
+	}
+}
+
+fn duplicate_id(range: types::TextPos) -> Err {
+	Err::Failure(ErrorState {
+		error: ParseError::DuplicateId,
+		text_position: range,
+		hints: vec![],
+	})
+}
 
-	Ok(((name.to_own(), attributes, subtags), state))
+/// The prefixes that can come before a `:` in a tag's name. Any other name
+/// followed by a `:` is a tag with a body, like `<p:text>`
+const NAMESPACE_PREFIXES: &[&str] = &["svg", "math", "xlink", "xml", "xmlns"];
+
+/// Parses the `prefix:` of a namespaced name like `svg:rect`. The prefix has
+/// to be one of `NAMESPACE_PREFIXES`, and has to be directly followed by the
+/// rest of the name, since a `:` followed by anything else opens a body.
+fn namespace_prefix(state: ParserState) -> ParserResult<Ranged<Symbol>> {
+	get_range(literal)
+		.verify(
+			|x| NAMESPACE_PREFIXES.contains(&x.value),
+			ParseError::NotLiteral,
+		)
+		.followed_by(specific_symbol(':'))
+		.followed_by(peek(literal))
+		.map(|x| x.to_symbol(state.engine))
+		.parse(state)
 }
 
+/// Parses the name of a plugin call and the raw tokens of its head.
+///
+/// The head ends at a `>`, a `|` or a newline. A backslash makes the next
+/// token part of the head no matter what it is, and isn't kept itself.
 fn plugin_head(state: ParserState) -> ParserResult<(Ranged<String>, Ranged<Vec<Token>>)> {
 	let parser = get_range(non_macro_starter)
 		.followed_by(plugin_mark)
@@ -688,6 +1129,7 @@ fn plugin_head(state: ParserState) -> ParserResult<(Ranged<String>, Ranged<Vec<T
 			tok => {
 				tokens.push(tok.clone());
 				state = state.next_state();
+				escape = false;
 			}
 		}
 	}
@@ -707,14 +1149,29 @@ fn macro_call_head(state: ParserState) -> ParserResult<(Ranged<String>, Vec<Argu
 	Ok(((name.to_own(), attributes), state))
 }
 
-fn macro_def_head(state: ParserState) -> ParserResult<(Ranged<String>, Vec<Argument>)> {
-	let parser = after_spaces(macro_starter).preceding(
-		cut(after_spaces(get_range(literal))).and_also(zero_or_more(after_spaces(argument))),
+/// The `pure` in `<macro pure name>`. A macro can still be called `pure`,
+/// since the modifier is only taken if there's a name after it that isn't
+/// an argument with a default, like in `<macro pure x="1">`
+fn pure_modifier(state: ParserState) -> ParserResult<&str> {
+	specific_literal("pure")
+		.followed_by(space.or(indent))
+		.followed_by(peek(after_spaces(macro_name)))
+		.followed_by(not(after_spaces(literal).preceding(after_spaces(equals))))
+		.parse(state)
+}
+
+fn macro_def_head(state: ParserState) -> ParserResult<(bool, Ranged<String>, Vec<Argument>)> {
+	let parser = macro_starter.after_spaces().preceding(
+		pure_modifier
+			.after_spaces()
+			.opt()
+			.and_also(cut(get_range(macro_name).after_spaces()))
+			.and_also(zero_or_more(argument.after_spaces())),
 	);
 
-	let ((name, attributes), state) = parser.parse(state)?;
+	let (((pure, name), attributes), state) = parser.parse(state)?;
 
-	Ok(((name.to_own(), attributes), state))
+	Ok(((pure.is_some(), name.to_own(), attributes), state))
 }
 
 fn skip_spaces<'a>() -> impl Parser<'a, Vec<&'a char>> {
@@ -750,6 +1207,10 @@ fn skip_newline_blanks<'a>() -> impl Parser<'a, Vec<&'a char>> {
 }
 
 fn tag_body(state: ParserState) -> ParserResult<Vec<HtmlNodes>> {
+	let inner_state = match state.deeper() {
+		Ok(x) => x,
+		Err(x) => return Err(x.error_at(&state).cut()),
+	};
 	let parser = skip_spaces().preceding(body_opener).preceding(
 		cut(skipped_blanks()).preceding(zero_or_more(
 			skip_newline_blanks()
@@ -759,54 +1220,145 @@ fn tag_body(state: ParserState) -> ParserResult<Vec<HtmlNodes>> {
 		)),
 	);
 
-	parser.parse(state)
-}
-
-fn plugin_body(state: ParserState) -> ParserResult<Ranged<Vec<Token>>> {
+	let (body, state) = parser.parse(inner_state)?;
+	Ok((body, state.shallower()))
+}
+
+/// Parses the body of a plugin call as raw tokens.
+///
+/// The body ends at the first `>` that doesn't close a `<` opened inside of
+/// it. Opening the body with extra pipes (like `||`) makes it end only at the
+/// same number of consecutive `>`s, so a lone `>` can appear in the body.
+/// Backslashes escape the next token the same way they do in
+/// [`plugin_head`].
+///
+/// If the body never ends, the error points at the first `<` that is never
+/// closed, or else at the last `<` that took a `>` which would've ended the
+/// body
+fn plugin_body<'a>(state: ParserState<'a>) -> ParserResult<'a, Ranged<Vec<Token>>> {
 	let parser = skip_spaces()
 		.preceding(body_opener)
+		.preceding(count(specific_symbol('|')))
 		.followed_by(skipped_blanks());
-	let (_, mut state) = parser.parse(state)?;
+	let (fence, state) = parser.parse(state)?;
+	let closer_count = fence + 1;
+	let closer = move |state: ParserState<'a>| {
+		if is_plugin_closer(&state, closer_count) {
+			Ok(((), state))
+		} else {
+			Err(ParseError::ExpectedTagCloser.error_at(&state))
+		}
+	};
 
 	let start = state.position;
-	let mut tokens = Vec::new();
-	let mut escape = false;
-
-	while let Some(token) = state.first_token() {
-		match token {
-			Token::Symbol(symbol) if symbol == &'\\' && !escape => {
-				escape = true;
-				state = state.next_state();
+	let body = many_till(plugin_body_part(closer_count), peek(closer));
+	let (parts, mut state) = match body.parse(state.clone()) {
+		Ok(x) => x,
+		Err(Err::Error(x)) if matches!(x.error, ParseError::ReachedEOF) => {
+			// Every `<` was closed, so one of them probably took the `>` that
+			// was meant to end the body
+			let (parts, state) = zero_or_more(plugin_body_part(closer_count)).parse(state)?;
+			if let Some(opener) = parts.iter().rev().find_map(|(_, suspect)| *suspect) {
+				return Err(Err::Failure(ErrorState {
+					error: ParseError::TagOpenerMismatch,
+					text_position: types::TextPos::Single(opener),
+					hints: vec![],
+				}));
 			}
-			Token::Symbol(x) if !escape && (x == &'>') => {
-				let end = state.position;
-				return Ok((
-					Ranged {
-						value: tokens,
-						range: types::TextPos::Range((start, end)),
-					},
-					state,
-				));
+			let (_, state) = check_tag_mismatch.parse(state)?;
+			return Err(ParseError::EndlessString.error_at(&state).cut());
+		}
+		Err(x) => return Err(x),
+	};
+	let end = state.position;
+	for _ in 0..closer_count {
+		state = state.next_state();
+	}
+	Ok((
+		Ranged {
+			value: parts.into_iter().flat_map(|(tokens, _)| tokens).collect(),
+			range: types::TextPos::Range((start, end)),
+		},
+		state,
+	))
+}
+
+/// Parses a token of a plugin's body, or a `<` along with everything up to
+/// the `>` that closes it. Backslashes are replaced by the token after them.
+///
+/// A `<` that is never closed means the body never ends, so it fails right
+/// away, pointing at that `<`. A `<` whose `>` could have ended the body is
+/// given along with its tokens, in case the body turns out to never end
+fn plugin_body_part<'a>(closer_count: usize) -> impl Parser<'a, (Vec<Token>, Option<TokenPos>)> {
+	move |mut state: ParserState<'a>| {
+		let opener = state.position;
+		let mut tokens = Vec::new();
+		let mut depth = 0;
+		let mut escape = false;
+		while let Some(token) = state.first_token() {
+			match token {
+				Token::Symbol('\\') if !escape => {
+					escape = true;
+					state = state.next_state();
+					continue;
+				}
+				Token::Symbol('<') if !escape => depth += 1,
+				Token::Symbol('>') if !escape && depth == 1 => {
+					let suspect = is_plugin_closer(&state, closer_count).then_some(opener);
+					tokens.push(token.clone());
+					return Ok(((tokens, suspect), state.next_state()));
+				}
+				Token::Symbol('>') if !escape && depth > 1 => depth -= 1,
+				_ => (),
 			}
-			tok => {
-				tokens.push(tok.clone());
-				state = state.next_state();
+			tokens.push(token.clone());
+			state = state.next_state();
+			if depth == 0 {
+				return Ok(((tokens, None), state));
 			}
+			escape = false;
+		}
+		match depth {
+			0 => Err(ParseError::ReachedEOF.error_at(&state)),
+			_ => Err(Err::Failure(ErrorState {
+				error: ParseError::TagOpenerMismatch,
+				text_position: types::TextPos::Single(opener),
+				hints: vec![],
+			})),
 		}
 	}
+}
 
-	let (_, state) = check_tag_mismatch.parse(state)?;
-
-	Err(ParseError::EndlessString.error_at(&state).cut())
+/// Whether the state is at the `>`s that close a plugin's body
+fn is_plugin_closer(state: &ParserState, closer_count: usize) -> bool {
+	state
+		.remaining()
+		.get(..closer_count)
+		.is_some_and(|x| x.iter().all(|x| matches!(x, Token::Symbol('>'))))
 }
+
 fn string(mut state: ParserState) -> ParserResult<Vec<StringParts>> {
 	let mut output = Vec::<StringParts>::new();
 	let mut escape = false;
 	while let Some(token) = state.first_token() {
+		// A `&` that doesn't start an entity is just text
+		if !escape && *token == Token::Symbol('&') {
+			if let Ok((name, next_state)) = entity.parse(state.clone()) {
+				output.push(StringParts::Entity(name));
+				state = next_state;
+				continue;
+			}
+		}
 		match token {
+			Token::Symbol(sym) if *sym == '@' && !escape && is_escaped_at(&state) => {
+				match output.last_mut() {
+					Some(StringParts::String(string)) => string.push('@'),
+					_ => output.push(StringParts::String("@".into())),
+				}
+				state = state.next_state().next_state();
+			}
 			Token::Symbol(sym) if *sym == '@' && !escape => {
-				state = state.next_state();
-				let (val, next_state) = get_range(expression).parse(state)?;
+				let (val, next_state) = interpolation.parse(state)?;
 				output.push(StringParts::Expression(val));
 				state = next_state;
 			}
@@ -831,7 +1383,7 @@ fn string(mut state: ParserState) -> ParserResult<Vec<StringParts>> {
 			Token::Newline(_) => {
 				if !output.is_empty()
 					&& output.iter().all(|x| match x {
-						StringParts::Expression(_) => true,
+						StringParts::Expression(_) | StringParts::Entity(_) => true,
 						StringParts::String(x) => x.chars().any(|x| !x.is_whitespace()),
 					}) {
 					return Ok((output, state));
@@ -839,29 +1391,32 @@ fn string(mut state: ParserState) -> ParserResult<Vec<StringParts>> {
 					return Err(ParseError::EmptyString.error_at(&state));
 				}
 			}
-			tok => match output.pop() {
-				Some(StringParts::String(mut string)) => {
-					tok.push_to_string(&mut string);
-					output.push(StringParts::String(string));
-					state = state.next_state();
-				}
-				Some(StringParts::Expression(var)) => {
-					output.push(StringParts::Expression(var));
-					output.push(StringParts::String(tok.get_as_string()));
-					state = state.next_state();
-				}
-				None => {
-					output.push(StringParts::String(tok.get_as_string()));
-					state = state.next_state();
+			tok => {
+				escape = false;
+				match output.pop() {
+					Some(StringParts::String(mut string)) => {
+						tok.push_to_string(&mut string);
+						output.push(StringParts::String(string));
+						state = state.next_state();
+					}
+					Some(part) => {
+						output.push(part);
+						output.push(StringParts::String(tok.get_as_string()));
+						state = state.next_state();
+					}
+					None => {
+						output.push(StringParts::String(tok.get_as_string()));
+						state = state.next_state();
+					}
 				}
-			},
+			}
 		}
 	}
 
 	let (_, state) = check_tag_mismatch.parse(state)?;
 	if !output.is_empty()
 		&& output.iter().all(|x| match x {
-			StringParts::Expression(_) => true,
+			StringParts::Expression(_) | StringParts::Entity(_) => true,
 			StringParts::String(x) => x.chars().any(|x| !x.is_whitespace()),
 		}) {
 		Ok((output, state))
@@ -873,21 +1428,68 @@ fn string(mut state: ParserState) -> ParserResult<Vec<StringParts>> {
 fn string_tagless_content<'a>() -> impl Parser<'a, StringParts> {
 	specific_symbol('\\')
 		.preceding(any.map(|x| StringParts::String(x.get_as_string())))
-		.or(specific_symbol('@').preceding(get_range(expression).map(StringParts::Expression)))
+		.or(specific_symbol('@')
+			.preceding(specific_symbol('@'))
+			.map(|_| StringParts::String("@".into())))
+		.or(interpolation.map(StringParts::Expression))
+		.or(entity.map(StringParts::Entity))
 		.or(any.map(|x| StringParts::String(x.get_as_string())))
 }
 
+/// Whether the state is at an `@@`, which stands for a literal `@` rather
+/// than an interpolation
+fn is_escaped_at(state: &ParserState) -> bool {
+	matches!(
+		(state.nth_token(0), state.nth_token(1)),
+		(Some(Token::Symbol('@')), Some(Token::Symbol('@')))
+	)
+}
+
+/// Parses an `@` followed by an expression. If no expression follows, the
+/// error points at the `@` itself.
+fn interpolation(state: ParserState) -> ParserResult<Ranged<Expression>> {
+	let (_, next_state) = specific_symbol('@').parse(state.clone())?;
+	match get_range(expression).parse(next_state) {
+		Err(Err::Error(_)) => Err(ParseError::DanglingInterpolation.error_at(&state).cut()),
+		x => x,
+	}
+}
+
+/// Parses an HTML character reference like `&amp;` or `&#169;`, returning
+/// the text between the `&` and the `;`.
+fn entity(state: ParserState) -> ParserResult<String> {
+	let parser = specific_symbol('&')
+		.preceding(maybe(specific_symbol('#')).and_also(literal))
+		.followed_by(specific_symbol(';'))
+		.map(|(hash, name)| match hash {
+			Some(_) => format!("#{}", name),
+			None => name.to_string(),
+		});
+
+	parser.parse(state)
+}
+
+/// Parses text up to `terminator`, which is left unparsed. Failures inside
+/// of the text, like an `@` with nothing after it, aren't swallowed
+fn text_until<'a, P, T>(terminator: P) -> impl Parser<'a, Vec<StringParts>>
+where
+	P: Parser<'a, T> + 'a,
+	T: 'a,
+{
+	zero_or_more(not(terminator).preceding(string_tagless_content()))
+		.verify(|x| !x.is_empty(), ParseError::EmptyString)
+}
+
 fn string_tagless(state: ParserState) -> ParserResult<Vec<StringParts>> {
 	let terminator = newline.or(tag_opener).or(tag_closer);
-	let parser = maybe_until(string_tagless_content(), terminator);
+	let parser = text_until(terminator);
 	parser.parse(state)
 }
 
 fn attr_string(state: ParserState) -> ParserResult<Vec<StringParts>> {
 	let (quote_mark, state) = quote_mark.parse(state)?;
 	let terminator = newline.or(specific_symbol(*quote_mark));
-	let parser =
-		maybe_until(string_tagless_content(), terminator).followed_by(specific_symbol(*quote_mark));
+	let parser = text_until(terminator).followed_by(specific_symbol(*quote_mark));
 	parser.parse(state)
 }
 
@@ -898,119 +1500,310 @@ fn paragraph_string(state: ParserState) -> ParserResult<Vec<HtmlNodes>> {
 
 	let terminator = newline;
 
-	inside.maybe_until(terminator).parse(state)
+	zero_or_more(not(terminator).preceding(inside))
+		.verify(|x| !x.is_empty(), ParseError::EmptyString)
+		.parse(state)
 }
 
 fn subtag(state: ParserState) -> ParserResult<HtmlTag> {
 	let parser = subtag_opener.preceding(
-		cut(after_spaces(get_range(literal)))
-			.and_also(zero_or_more(skip_spaces().preceding(attribute))),
+		cut(after_spaces(
+			maybe(namespace_prefix).and_also(get_range(literal)),
+		))
+		.and_also(shorthands)
+		.and_also(zero_or_more(skip_spaces().preceding(attribute))),
 	);
-	let ((name, attributes), state) = parser.parse(state)?;
+	let ((((namespace, name), shorthands), attributes), state) = parser.parse(state)?;
 	Ok((
 		HtmlTag {
-			name: name.to_own(),
-			attributes,
+			namespace,
+			name: name.to_symbol(state.engine),
+			attributes: add_shorthands(shorthands, attributes, state.engine)?,
+			spreads: vec![],
 			subtags: vec![],
 			body: vec![],
+			trim_before: false,
+			trim_after: false,
 		},
 		state,
 	))
 }
 
 fn attribute(state: ParserState) -> ParserResult<Attribute> {
-	let parser = get_range(literal).followed_by(skip_spaces()).and_also(cut(
-		equals.preceding(zero_or_more(space.or(indent)).preceding(get_range(expression)))
-	));
-	let ((name, value), state) = parser.parse(state)?;
+	let (name, state) = attribute_name.before_spaces().parse(state)?;
+	let (value, state) =
+		cut(equals.preceding(zero_or_more(space.or(indent)).preceding(get_range(attribute_value))))
+			.parse(state)?;
+	// In `a= b="c"`, `b` is the next attribute rather than the value of `a`
+	if matches!(value.value, Expression::Variable(_))
+		&& after_spaces(equals).parse(state.clone()).is_ok()
+	{
+		return Err(Err::Failure(ErrorState {
+			error: ParseError::MissingAttributeValue(name.value),
+			text_position: name.range,
+			hints: vec![],
+		}));
+	}
+	let (condition, state) = maybe(attribute_condition).parse(state)?;
 	Ok((
 		Attribute {
-			name: name.to_own(),
+			name: name.to_symbol(state.engine),
 			value,
+			condition,
 		},
 		state,
 	))
 }
 
+/// Parses an attribute's value. Quoted values can be split into several
+/// quoted segments, which are joined together, so `class="btn " "primary"`
+/// is the same as `class="btn primary"`. Segments are separated by spaces or
+/// tabs, which aren't part of the value
+fn attribute_value(state: ParserState) -> ParserResult<Expression> {
+	let (value, state) = expression.parse(state)?;
+	let Expression::Literal(mut parts) = value else {
+		return Ok((value, state));
+	};
+	let (segments, state) = zero_or_more(after_spaces(attr_string)).parse(state)?;
+	parts.extend(segments.into_iter().flatten());
+	Ok((Expression::Literal(parts), state))
+}
+
+/// Parses an attribute, or skips a malformed one. The error of a malformed
+/// attribute is kept in the state, and the parser carries on from the next
+/// blank or `>`, so one bad attribute doesn't hide the ones after it
+fn recovering_attribute(state: ParserState) -> ParserResult<Option<Attribute>> {
+	match attribute.parse(state.clone()) {
+		Ok((attribute, state)) => Ok((Some(attribute), state)),
+		Err(Err::Failure(error)) => {
+			let mut state = state.next_state();
+			while let Some(token) = state.first_token() {
+				match token {
+					Token::Space(_) | Token::Indent(_) | Token::Newline(_) | Token::Symbol('>') => {
+						break
+					}
+					_ => state = state.next_state(),
+				}
+			}
+			state.push_error(error);
+			Ok((None, state))
+		}
+		Err(error) => Err(error),
+	}
+}
+
+/// Parses an attribute or argument name, whose words may be joined by
+/// hyphens, like `data-user-id`. It may have a namespace prefix, like
+/// `xml:lang` or `xlink:show-mode`, which is kept as part of the name, so
+/// `xml:lang` and `lang` are different names. The hyphens can't have
+/// anything around them, so `a - b` is not a name.
+/// Reserved words only mean something at the start of a tag, so attributes
+/// can still be named after them, as in `<div let="x" for="y">`
+fn attribute_name(state: ParserState) -> ParserResult<Ranged<String>> {
+	get_range(hyphenated_word.and_maybe(specific_symbol(':').preceding(hyphenated_word)))
+		.map(|x| {
+			x.map(|x| match x {
+				(prefix, Some(name)) => format!("{}:{}", prefix, name),
+				(name, None) => name,
+			})
+		})
+		.parse(state)
+}
+
+fn hyphenated_word(state: ParserState) -> ParserResult<String> {
+	literal
+		.and_also(zero_or_more(specific_symbol('-').preceding(literal)))
+		.map(|(first, rest)| {
+			let mut word = first.to_owned();
+			for part in rest {
+				word.push('-');
+				word.push_str(part);
+			}
+			word
+		})
+		.parse(state)
+}
+
+/// Parses the `?{condition}` that can follow an attribute's value
+fn attribute_condition(state: ParserState) -> ParserResult<Ranged<Expression>> {
+	specific_symbol('?')
+		.preceding(cut(get_range(wrapped_expr)))
+		.parse(state)
+}
+
 fn argument(state: ParserState) -> ParserResult<Argument> {
-	let parser = get_range(literal)
+	let parser = attribute_name
 		.followed_by(zero_or_more(space.or(indent)))
 		.and_maybe(
 			equals.preceding(zero_or_more(space.or(indent)).preceding(get_range(expression))),
 		);
 	let ((name, value), state) = parser.parse(state)?;
-	Ok((
-		Argument {
-			name: name.to_own(),
-			value,
-		},
-		state,
-	))
+	Ok((Argument { name, value }, state))
+}
+
+/// Parses tokens that make up a single expression, like `{a and b}` or a
+/// bare variable name. Blanks around the expression are allowed, but
+/// anything else after it is an error.
+pub(crate) fn parse_expression(tokens: &[Token], engine: &Kismesis) -> Result<Expression, Err> {
+	let parser = skipped_blanks()
+		.preceding(expression)
+		.followed_by(skipped_blanks())
+		.followed_by(cut(eof));
+	let state = ParserState::new(tokens, None, engine);
+	let (expression, _) = parser.parse(state)?;
+	Ok(expression)
 }
 
-pub(crate) fn file(
+/// Parses a file. If the parser had to skip any malformed parts of it, every
+/// error it found is returned, in the order they were found
+pub fn file(
 	tokens_id: KisID,
 	engine: &Kismesis,
 	default_template: Option<KisTemplateID>,
 	project_path: Option<PathBuf>,
-) -> Result<ParsedFile, Err> {
-	let parser = zero_or_more(
-		skipped_blanks().preceding(
-			some_tag
-				.map(|x| x.into())
-				.or(lambda_definition.map(BodyNodes::LambdaDef))
-				.or(variable_definition.map(BodyNodes::VarDef))
-				.or(set_stmt.map(|(x, y)| BodyNodes::SetStmt(x, y)))
-				.or(section_block.map(|x| BodyNodes::HtmlTag(Section::to_tag(x))))
-				.or(paragraph_string
-					.map(paragraph_str_to_p)
-					.map(BodyNodes::HtmlTag)),
-		),
-	)
-	.followed_by(check_tag_mismatch)
-	.followed_by(skipped_blanks())
-	.followed_by(eof.or(ignore(tag_closer)));
-
-	let state = ParserState::new(&engine.get_file(tokens_id).unwrap().tokens, project_path, engine);
-	let ast_nodes = match parser.parse(state) {
-		Ok((val, _)) => val,
-		Err(err) => {
-			drop(parser);
-			return Err(err);
+) -> Result<ParsedFile, Vec<ErrorState<ParseError>>> {
+	let (file, errors) = file_recovering(tokens_id, engine, default_template, project_path)?;
+	if errors.is_empty() {
+		Ok(file)
+	} else {
+		Err(errors)
+	}
+}
+
+/// Parses a file, skipping the parts of it that can be skipped when they're
+/// malformed, like attributes. Returns what could be parsed along with the
+/// errors of the skipped parts, in the order they were found.
+///
+/// If the file can't be parsed at all, the errors that were skipped before
+/// that are returned along with the error that stopped it
+pub fn file_recovering(
+	tokens_id: KisID,
+	engine: &Kismesis,
+	default_template: Option<KisTemplateID>,
+	project_path: Option<PathBuf>,
+) -> Result<(ParsedFile, Vec<ErrorState<ParseError>>), Vec<ErrorState<ParseError>>> {
+	let node = skipped_blanks().preceding(
+		some_tag
+			.map(|x| x.into())
+			.or(lambda_definition.map(BodyNodes::LambdaDef))
+			.or(variable_definition.map(BodyNodes::VarDef))
+			.or(set_stmt.map(|(x, y)| BodyNodes::SetStmt(x, y)))
+			.or(extends_stmt.map(BodyNodes::Extends))
+			.or(doctype_stmt.map(BodyNodes::Doctype))
+			.or(section_block.map(|x| BodyNodes::HtmlTag(Section::to_tag(x))))
+			.or(paragraph_string
+				.map(paragraph_str_to_p)
+				.map(BodyNodes::HtmlTag))
+			.trace("top level node"),
+	);
+	let end = check_tag_mismatch
+		.followed_by(skipped_blanks())
+		.followed_by(eof.or(ignore(tag_closer)));
+
+	let Some(tokens) = engine.get_file(tokens_id) else {
+		return Err(vec![ErrorState {
+			error: ParseError::TriedToParseInvalidID(tokens_id),
+			text_position: types::TextPos::Single(TokenPos::new()),
+			hints: vec![],
+		}]);
+	};
+	let mut state = ParserState::new(&tokens.tokens, project_path, engine);
+	state.scope = Some(tokens_id);
+	let steps = state.steps.clone();
+	// Nodes are parsed one by one instead of with `zero_or_more`, so the
+	// state before a node that fails still has the errors skipped so far
+	let mut ast_nodes = Vec::new();
+	let result = loop {
+		match node.parse(state.clone()) {
+			Ok((val, next_state)) => {
+				ast_nodes.push(val);
+				state = next_state;
+			}
+			Err(Err::Failure(error)) => break Err(error),
+			Err(Err::Error(_)) => break end.parse(state.clone()).map_err(Err::unpack),
 		}
 	};
-	drop(parser);
+	drop(node);
+	drop(end);
+	if let Some(position) = steps.exceeded_at() {
+		return Err(vec![ErrorState {
+			error: ParseError::StepBudgetExceeded,
+			text_position: types::TextPos::Single(position),
+			hints: vec![],
+		}]);
+	}
+	let mut errors = Rc::unwrap_or_clone(state.errors);
+	if let Err(error) = result {
+		errors.push(error);
+		return Err(errors);
+	}
 	let mut output = ParsedFile::new(tokens_id);
 	output.template = default_template;
+	let mut extends = false;
 	for node in ast_nodes {
 		match node {
 			BodyNodes::HtmlTag(tag) => output.body.push(TopNodes::HtmlTag(tag)),
 			BodyNodes::MacroDef(mac) => output.defined_macros.push(mac),
 			BodyNodes::MacroCall(mac) => output.body.push(TopNodes::MacroCall(mac)),
-			BodyNodes::String(_string) => todo!("Markup syntax"),
+			BodyNodes::String(string) => {
+				let paragraph = paragraph_str_to_p(vec![HtmlNodes::String(string)]);
+				output.body.push(TopNodes::HtmlTag(paragraph))
+			}
 			BodyNodes::LambdaDef(lambda) => output.defined_lambdas.push(lambda),
 			BodyNodes::VarDef(var) => output.defined_variables.push(var),
 			BodyNodes::PlugCall(plug) => output.body.push(TopNodes::PlugCall(plug)),
-			BodyNodes::Content => output.body.push(TopNodes::Content),
-			BodyNodes::Section(_) => todo!("Add sections"),
+			BodyNodes::Content(x) => output.body.push(TopNodes::Content(x)),
+			BodyNodes::Slot(x) => output.body.push(TopNodes::Slot(x)),
+			BodyNodes::Include(x) => output.body.push(TopNodes::Include(x)),
+			BodyNodes::Section(x) => output.body.push(TopNodes::HtmlTag(Section::to_tag(x))),
 			BodyNodes::Doctype(x) => output.body.push(TopNodes::Doctype(x)),
 			BodyNodes::If(x) => output.body.push(TopNodes::If(x)),
+			BodyNodes::Env(x) => output.body.push(TopNodes::Env(x)),
 			BodyNodes::For(x) => output.body.push(TopNodes::For(x)),
-			BodyNodes::SetStmt(config, value) => match config.as_str() {
+			BodyNodes::SetStmt(config, value) => match config.value.as_str() {
 				"template" => {
-					let mut value = PathBuf::from(value);
-					value.set_extension("ks");
-					match engine.verify_template_id(value) {
+					let mut path = PathBuf::from(value.value);
+					path.set_extension("ks");
+					match engine.verify_template_id(path.clone()) {
 						Some(template) => output.template = Some(template),
-						None => panic!("Template does not exist"),
+						None => errors.push(ErrorState {
+							error: ParseError::TemplateNotFound(path.to_string_lossy().into()),
+							text_position: value.range,
+							hints: vec![],
+						}),
 					}
 				}
-				_ => panic!("Non-existant config"),
+				_ => errors.push(ErrorState {
+					error: ParseError::UnknownSetting(config.value),
+					text_position: config.range,
+					hints: vec![],
+				}),
 			},
+			BodyNodes::Extends(path) => {
+				if extends {
+					errors.push(ErrorState {
+						error: ParseError::DuplicateExtends,
+						text_position: path.range,
+						hints: vec![],
+					});
+					continue;
+				}
+				let mut value = path.value;
+				value.set_extension("ks");
+				match engine.verify_template_id(value.clone()) {
+					Some(template) => output.template = Some(template),
+					None => errors.push(ErrorState {
+						error: ParseError::TemplateNotFound(value.to_string_lossy().into()),
+						text_position: path.range,
+						hints: vec![],
+					}),
+				}
+				extends = true;
+			}
 		}
 	}
 
-	Ok(output)
+	Ok((output, errors))
 }
 // Generators
 pub(super) fn specific_symbol<'a>(chr: char) -> impl Parser<'a, &'a char> {
@@ -1046,3 +1839,926 @@ fn any(state: ParserState) -> ParserResult<&Token> {
 pub(crate) fn multiple_attributes(state: ParserState) -> ParserResult<Vec<Attribute>> {
 	zero_or_more(after_spaces(attribute)).parse(state)
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::kismesis::compiler::{errors::ErrorKind, lexer};
+	use errors::Hint;
+
+	fn parse(src: &str) -> ParsedFile {
+		let mut engine = Kismesis::new();
+		let id = engine.register_tokens(lexer::tokenize(src), None);
+		match file(id, &engine, None, None) {
+			Ok(x) => x,
+			Err(x) => panic!("{:?}", x[0].error),
+		}
+	}
+
+	/// The first error in `src`
+	fn parse_err(src: &str) -> ErrorState<ParseError> {
+		let mut engine = Kismesis::new();
+		let id = engine.register_tokens(lexer::tokenize(src), None);
+		match file(id, &engine, None, None) {
+			Ok(_) => panic!("`{}` parsed without errors", src),
+			Err(mut x) => x.remove(0),
+		}
+	}
+
+	/// Runs `f` on a state at the start of `src`
+	pub(super) fn with_state<T>(src: &str, f: impl FnOnce(ParserState) -> T) -> T {
+		let engine = Kismesis::new();
+		let tokens = lexer::tokenize(src);
+		f(ParserState::new(&tokens, None, &engine))
+	}
+
+	pub(super) fn tokens_text(tokens: &[Token]) -> String {
+		tokens.iter().map(|x| x.get_as_string()).collect()
+	}
+
+	fn first_tag(file: &ParsedFile) -> &HtmlTag {
+		match &file.body[0] {
+			TopNodes::HtmlTag(x) => x,
+			x => panic!("expected a tag, got {}", x),
+		}
+	}
+
+	fn first_body(file: &ParsedFile) -> &[HtmlNodes] {
+		&first_tag(file).body
+	}
+
+	#[test]
+	fn space_methods_match_the_free_functions() {
+		for src in [
+			"macro m a b=\"x\" | <p>>",
+			"  macro  pure   m\t a  ?b |",
+			"macro\tpure",
+			"macro |",
+			"mac m",
+		] {
+			with_state(src, |state| {
+				// `macro_def_head` as it was written before the methods existed
+				let free = after_spaces(macro_starter).preceding(
+					after_spaces(pure_modifier)
+						.opt()
+						.and_also(cut(after_spaces(get_range(macro_name))))
+						.and_also(zero_or_more(after_spaces(argument))),
+				);
+				let method = macro_def_head.parse(state.clone());
+				match (free.parse(state), method) {
+					(
+						Ok((((pure, name), args), a)),
+						Ok(((method_pure, method_name, method_args), b)),
+					) => {
+						assert_eq!(pure.is_some(), method_pure, "{}", src);
+						assert_eq!(name.value, method_name.value, "{}", src);
+						assert_eq!(args, method_args, "{}", src);
+						assert_eq!(a.position, b.position, "{}", src);
+					}
+					(Err(a), Err(b)) => assert_eq!(
+						a.unpack().text_position,
+						b.unpack().text_position,
+						"{}",
+						src
+					),
+					_ => panic!("only one of them parsed `{}`", src),
+				}
+			});
+		}
+		with_state("class  =", |state| {
+			let (a, a_state) = literal
+				.followed_by(skip_spaces())
+				.parse(state.clone())
+				.unwrap();
+			let (b, b_state) = literal.before_spaces().parse(state).unwrap();
+			assert_eq!(a, b);
+			assert_eq!(a_state.position, b_state.position);
+		});
+	}
+
+	#[test]
+	fn there_are_no_comparisons_yet() {
+		// Comparing needs typing rules for numbers and strings, which don't
+		// exist until numbers and comparison operators do
+		for src in ["<p | @{2 < 10}>\n", "<p | @{\"a\" < \"b\"}>\n"] {
+			let error = parse_err(src);
+			assert!(
+				matches!(error.error, ParseError::ExpectedExprEnd),
+				"{:?}",
+				error.error
+			);
+		}
+	}
+
+	#[test]
+	fn pure_macros() {
+		let file = parse(concat!(
+			"<macro pure card title | <p | @title>>\n",
+			"<macro plain | <p | x>>\n",
+			"<macro pure | <p | y>>\n",
+			"<macro pure x=\"1\" | <p | @x>>\n",
+		));
+		let macros: Vec<_> = file
+			.defined_macros
+			.iter()
+			.map(|x| (x.name.value.as_str(), x.pure, x.arguments.len()))
+			.collect();
+		assert_eq!(
+			macros,
+			[
+				("card", true, 1),
+				("plain", false, 0),
+				("pure", false, 0),
+				("pure", false, 1)
+			]
+		);
+	}
+
+	#[test]
+	fn empty_files() {
+		for src in ["", "\n", "  \t\n\n", "\r\n"] {
+			let file = parse(src);
+			assert!(file.body.is_empty(), "{:?}", src);
+			assert!(file.defined_macros.is_empty() && file.defined_variables.is_empty());
+		}
+	}
+
+	#[test]
+	fn macro_calls_without_a_name() {
+		for src in ["<!>\n", "< !foo>\n", "<p | <!>>\n", "<p | < ! x=\"1\">>\n"] {
+			let error = parse_err(src);
+			assert!(
+				matches!(error.error, ParseError::EmptyMacroName),
+				"{}: {:?}",
+				src,
+				error.error
+			);
+			let at = error.text_position.get_start().get_idx();
+			assert_eq!(lexer::tokenize(src)[at], Token::Symbol('!'), "{}", src);
+		}
+		// `content` isn't a macro name, but it's still the content macro
+		let file = parse("<p | <content!>>\n<content!>\n");
+		assert!(matches!(file.body[1], TopNodes::Content(None)));
+		assert!(matches!(first_body(&file)[0], HtmlNodes::Content(None)));
+		parse("<!doctype html>\n");
+	}
+
+	#[test]
+	fn unclosed_expressions_point_at_their_brace() {
+		for src in ["<p | @{a and b>\n", "<p title={a and b>\n"] {
+			let tokens = lexer::tokenize(src);
+			let error = parse_err(src);
+			assert!(
+				matches!(error.error, ParseError::ExpectedExprEnd),
+				"{}: {:?}",
+				src,
+				error.error
+			);
+			let stalled = error.text_position.get_start().get_idx();
+			assert_eq!(tokens[stalled], Token::Symbol('>'), "{}", src);
+			let [Hint::Stateful(hint)] = &error.hints[..] else {
+				panic!("expected a single hint, got {:?}", error.hints)
+			};
+			assert_eq!(hint.error.error, Hints::ExprOpenedHere);
+			let opened = hint.error.text_position.get_start().get_idx();
+			assert_eq!(tokens[opened], Token::Symbol('{'), "{}", src);
+
+			let mut engine = Kismesis::new();
+			let text = engine.compile_str(src, None).unwrap_err().text;
+			assert!(text.contains(&Hints::ExprOpenedHere.get_text()), "{}", text);
+			assert!(
+				text.contains(&ParseError::ExpectedExprEnd.get_text()),
+				"{}",
+				text
+			);
+		}
+	}
+
+	#[test]
+	fn nested_member_access() {
+		let (expression, end) = with_state("{user.name.first}", |state| {
+			let (expression, state) = wrapped_expr(state).unwrap();
+			(expression, state.position.get_idx())
+		});
+		let Expression::Member(name, first) = expression else {
+			panic!("expected a field, got {:?}", expression)
+		};
+		assert_eq!(first, "first");
+		let Expression::Member(user, field) = name.value else {
+			panic!("expected a field, got {:?}", name.value)
+		};
+		assert_eq!(field, "name");
+		assert_eq!(user.value, Expression::Variable("user".into()));
+		assert_eq!(end, 7);
+
+		let expression = with_state("{user}", |state| wrapped_expr(state).unwrap().0);
+		assert_eq!(expression, Expression::Variable("user".into()));
+	}
+
+	#[test]
+	fn empty_expressions_cant_have_content() {
+		parse("<p | @{!}>\n");
+		for src in ["<p | @{! x}>\n", "<p | @{!x}>\n", "<p | @{!!}>\n"] {
+			let error = parse_err(src);
+			assert!(
+				matches!(error.error, ParseError::ContentInEmptyExpr),
+				"{}: {:?}",
+				src,
+				error.error
+			);
+		}
+		let error = parse_err("<p | @{! x}>\n");
+		assert_eq!(error.text_position.get_start().get_column(), 8);
+	}
+
+	#[test]
+	fn entities_are_told_apart_from_ampersands() {
+		let file = parse("<p | &amp; & &foo>\n");
+		let HtmlNodes::String(parts) = &first_body(&file)[0] else {
+			panic!("expected text")
+		};
+		assert!(matches!(&parts[0], StringParts::Entity(x) if x == "amp"));
+		let rest: String = parts[1..]
+			.iter()
+			.map(|x| match x {
+				StringParts::String(x) => x.as_str(),
+				x => panic!("expected text, got {:?}", x),
+			})
+			.collect();
+		assert_eq!(rest, " & &foo");
+	}
+
+	#[test]
+	fn plugin_bodies_keep_closing_brackets() {
+		let body = |src: &str| {
+			with_state(src, |state| match plugin_body(state) {
+				Ok((x, _)) => tokens_text(&x.value),
+				Err(x) => panic!("{:?}", x.unpack().error),
+			})
+		};
+		assert_eq!(body("| a b>"), "a b");
+		assert_eq!(body("| <b | c> d>"), "<b | c> d");
+		assert_eq!(body("|| a > b>>"), "a > b");
+		assert_eq!(body("| a \\> b>"), "a > b");
+	}
+
+	#[test]
+	fn unclosed_bracket_in_plugin_body() {
+		let error = |src: &str| with_state(src, |state| plugin_body(state).unwrap_err().unpack());
+		let unclosed = error("| a < b>");
+		assert!(matches!(unclosed.error, ParseError::TagOpenerMismatch));
+		assert_eq!(unclosed.text_position.get_start().get_column(), 4);
+		let unclosed = error("| <b | c> a < b");
+		assert!(matches!(unclosed.error, ParseError::TagOpenerMismatch));
+		assert_eq!(unclosed.text_position.get_start().get_column(), 12);
+		// The `>` of the `<b` could've ended the body
+		let suspect = error("| a <b | c> d");
+		assert!(matches!(suspect.error, ParseError::TagOpenerMismatch));
+		assert_eq!(suspect.text_position.get_start().get_column(), 4);
+		let suspect = error("|| <b>> a");
+		assert!(matches!(suspect.error, ParseError::TagOpenerMismatch));
+		assert_eq!(suspect.text_position.get_start().get_column(), 3);
+		assert!(matches!(error("| a b").error, ParseError::EndlessString));
+		assert!(matches!(error("|| <b> a").error, ParseError::EndlessString));
+	}
+
+	#[test]
+	fn dangling_interpolations_point_at_the_at() {
+		for src in ["<p | {\"text @\"}>\n", "text @<\n", "<p | x @>\n"] {
+			let error = parse_err(src);
+			let dangling = matches!(error.error, ParseError::DanglingInterpolation);
+			assert!(dangling, "{} {:?}", src, error);
+			let tokens = lexer::tokenize(src);
+			let at = &tokens[error.text_position.get_start().get_idx()];
+			assert!(matches!(at, Token::Symbol('@')), "{}", src);
+		}
+	}
+
+	#[test]
+	fn conditional_attributes() {
+		let file = parse("<a class=\"active\"?{on} href=x>\n");
+		let attributes = &first_tag(&file).attributes;
+		let condition = attributes[0].condition.as_ref().map(|x| &x.value);
+		assert!(matches!(condition, Some(Expression::Variable(x)) if x.as_str() == "on"));
+		assert!(attributes[1].condition.is_none());
+		let error = parse_err("<a class=\"active\"?>\n");
+		assert!(matches!(error.error, ParseError::ExpectedExprStart));
+	}
+
+	/// `depth` tags nested inside of each other
+	fn nested_tags(depth: usize) -> String {
+		format!("{}{}\n", "<a | ".repeat(depth), ">".repeat(depth))
+	}
+
+	/// Runs `f` on a thread with a stack big enough for deeply nested input
+	fn with_big_stack<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> T {
+		std::thread::Builder::new()
+			.stack_size(32 << 20)
+			.spawn(f)
+			.unwrap()
+			.join()
+			.unwrap()
+	}
+
+	#[test]
+	fn nesting_past_the_depth_limit_fails() {
+		with_big_stack(|| {
+			let depth = crate::kismesis::DEFAULT_MAX_PARSE_DEPTH;
+			parse(&nested_tags(depth - 1));
+			let error = parse_err(&nested_tags(depth * 4));
+			assert!(matches!(error.error, ParseError::MaxDepthExceeded(x) if x == depth));
+
+			let mut engine = Kismesis::new();
+			engine.set_max_parse_depth(4);
+			let id = engine.register_tokens(lexer::tokenize(&nested_tags(5)), None);
+			let error = file(id, &engine, None, None).unwrap_err().remove(0);
+			assert!(matches!(error.error, ParseError::MaxDepthExceeded(4)));
+		});
+	}
+
+	fn body_text(file: &ParsedFile) -> String {
+		first_body(file).iter().map(|x| x.to_string()).collect()
+	}
+
+	#[test]
+	fn bodies_can_start_with_a_pipe() {
+		assert_eq!(body_text(&parse("<div | text>\n")), "text");
+		assert_eq!(body_text(&parse("<div | \\| literal>\n")), "| literal");
+		assert_eq!(body_text(&parse("<div | | literal>\n")), "| literal");
+	}
+
+	#[test]
+	fn namespaced_tags() {
+		let file = parse("<svg:rect x=\"1\"> <svg:g +svg:circle>\n");
+		let tag = first_tag(&file);
+		assert_eq!(tag.namespace.as_ref().unwrap().value.as_str(), "svg");
+		assert_eq!(tag.name.value.as_str(), "rect");
+		assert_eq!(tag.attributes[0].name.value.as_str(), "x");
+		match &file.body[1] {
+			TopNodes::HtmlTag(HtmlTag { body, .. }) => match &body[0] {
+				HtmlNodes::HtmlTag(x) => {
+					assert_eq!(x.namespace.as_ref().unwrap().value.as_str(), "svg");
+					assert_eq!(x.name.value.as_str(), "circle");
+				}
+				x => panic!("expected a subtag, got {}", x),
+			},
+			x => panic!("expected a tag, got {}", x),
+		}
+
+		let tag = first_tag(&parse("<use xlink:href=\"#a\">\n")).clone();
+		assert_eq!(tag.attributes[0].name.value.as_str(), "xlink:href");
+	}
+
+	#[test]
+	fn colons_after_other_names_open_bodies() {
+		for (src, text) in [
+			("<p:text>\n", "text"),
+			("<p:Hello>\n", "Hello"),
+			("<p:hello world>\n", "hello world"),
+			("<svg: text>\n", "text"),
+		] {
+			let file = parse(src);
+			let tag = first_tag(&file);
+			assert!(tag.namespace.is_none(), "{}", src);
+			assert_eq!(body_text(&file), text, "{}", src);
+		}
+		parse_err("<:>\n");
+	}
+
+	#[test]
+	fn namespaced_attribute_names() {
+		let file = parse("<html xml:lang=\"en\" lang=\"en\" aria-label=\"x\">\n");
+		let names: Vec<_> = first_tag(&file)
+			.attributes
+			.iter()
+			.map(|x| x.name.value.as_str())
+			.collect();
+		assert_eq!(names, ["xml:lang", "lang", "aria-label"]);
+
+		let file = parse("<m! xml:lang=\"en\">\n");
+		match &file.body[0] {
+			TopNodes::MacroCall(x) => assert_eq!(x.arguments[0].name.value, "xml:lang"),
+			x => panic!("expected a macro call, got {}", x),
+		}
+
+		// Arguments are named the same way as attributes
+		let file = parse("<macro m data-x aria:y-z=\"1\" | a>\n<m! data-x=\"2\" aria:y-z=\"3\">\n");
+		let names: Vec<_> = file.defined_macros[0]
+			.arguments
+			.iter()
+			.map(|x| x.name.value.as_str())
+			.collect();
+		assert_eq!(names, ["data-x", "aria:y-z"]);
+		match &file.body[0] {
+			TopNodes::MacroCall(x) => {
+				let names: Vec<_> = x.arguments.iter().map(|x| x.name.value.as_str()).collect();
+				assert_eq!(names, ["data-x", "aria:y-z"]);
+			}
+			x => panic!("expected a macro call, got {}", x),
+		}
+
+		parse_err("<p xml:=\"x\">\n");
+		// Anywhere else in the head, a `:` opens the body
+		assert_eq!(body_text(&parse("<p :foo=\"x\">\n")), "foo=\"x\"");
+		assert_eq!(body_text(&parse("<p a=\"x\" :foo>\n")), "foo");
+	}
+
+	#[test]
+	fn single_expressions() {
+		let engine = Kismesis::new();
+		let expression = |src: &str| parse_expression(&lexer::tokenize(src), &engine);
+		assert!(matches!(
+			expression("{a and b}"),
+			Ok(Expression::BinFunc(BinFunc::And, x, y))
+				if x.value == Expression::Variable("a".into())
+					&& y.value == Expression::Variable("b".into())
+		));
+		assert!(matches!(expression(" a\n"), Ok(Expression::Variable(x)) if x == "a"));
+		assert!(matches!(expression("a b"), Err(Err::Failure(_))));
+		assert!(expression("{a} >").is_err());
+	}
+
+	#[test]
+	fn content_placeholders() {
+		let file = parse("<main | <content!> <footer | <content:footer!>>>\n<content!>\n");
+		let body = first_body(&file);
+		assert!(matches!(body[0], HtmlNodes::Content(None)));
+		assert!(matches!(
+			&body[2],
+			HtmlNodes::HtmlTag(HtmlTag { body, .. })
+				if matches!(&body[0], HtmlNodes::Content(Some(x)) if x == "footer")
+		));
+		assert!(matches!(file.body[1], TopNodes::Content(None)));
+
+		let file = parse("<content:footer! | <p | bye>>\n");
+		assert!(matches!(
+			&file.body[0],
+			TopNodes::Slot(Slot { name, body }) if name.value == "footer" && body.len() == 1
+		));
+	}
+
+	#[test]
+	fn plugin_heads_drop_escapes() {
+		let head = |src: &str| {
+			with_state(src, |state| match plugin_head(state) {
+				Ok(((name, head), _)) => (name.value, tokens_text(&head.value)),
+				Err(x) => panic!("{:?}", x.unpack().error),
+			})
+		};
+		assert_eq!(head("plug? a \\> b | c>"), ("plug".into(), "a > b ".into()));
+		assert_eq!(
+			head("plug? a \\| b \\\\ c>"),
+			("plug".into(), "a | b \\ c".into())
+		);
+		assert_eq!(head("plug? \\>> b>").1, ">");
+	}
+
+	#[test]
+	fn attributes_on_their_own_lines() {
+		let file = parse("<a\n\thref=\"/\"\n\tclass=\"x\"\n\ttitle=\"y\" | home>\n");
+		let names: Vec<_> = first_tag(&file)
+			.attributes
+			.iter()
+			.map(|x| x.name.value.as_str())
+			.collect();
+		assert_eq!(names, ["href", "class", "title"]);
+		assert_eq!(body_text(&file), "home");
+		parse_err("<a href=\"/\"\n\t| home>\n");
+	}
+
+	#[test]
+	fn comments_between_attributes() {
+		let file = parse(concat!(
+			"<a <!- where it goes ->\n",
+			"\thref=\"/\" <!- how it looks -> class=\"x\"\n",
+			"\t<!- a comment\n\tover two lines ->\n",
+			"\ttitle=\"y\" | home>\n"
+		));
+		let names: Vec<_> = first_tag(&file)
+			.attributes
+			.iter()
+			.map(|x| x.name.value.as_str())
+			.collect();
+		assert_eq!(names, ["href", "class", "title"]);
+		assert_eq!(body_text(&file), "home");
+	}
+
+	#[test]
+	fn values_in_several_segments() {
+		let values = |src: &str| -> Vec<String> {
+			first_tag(&parse(src))
+				.attributes
+				.iter()
+				.map(|x| match &x.value.value {
+					Expression::Literal(parts) => types::plain_text(parts, Some("_")),
+					x => panic!("expected a string, got {:?}", x),
+				})
+				.collect()
+		};
+		assert_eq!(values("<a class=\"btn\">\n"), ["btn"]);
+		assert_eq!(
+			values("<a class=\"btn \" \"primary\" id=\"x\">\n"),
+			["btn primary", "x"]
+		);
+		assert_eq!(values("<a title=\"a\"  \t\"b@{c}\" \"d\">\n"), ["ab_d"]);
+		// Segments can't go on lines of their own
+		parse_err("<a title=\"a\"\n\t\"b\">\n");
+	}
+
+	#[test]
+	fn duplicate_ids() {
+		for src in ["<div#a#b>\n", "<div#a id=\"b\">\n"] {
+			assert!(
+				matches!(parse_err(src).error, ParseError::DuplicateId),
+				"{}",
+				src
+			);
+		}
+	}
+
+	#[test]
+	fn extends_statements() {
+		let mut engine = Kismesis::new();
+		let base = engine
+			.compile_str("<main | <content!>>\n", Some(PathBuf::from("base.ks")))
+			.unwrap();
+		let base = engine.register_template(base);
+		let file = engine
+			.compile_str("extends \"base\"\n<p | hi>\n", None)
+			.unwrap();
+		assert_eq!(file.template, Some(base));
+		assert_eq!(file.body.len(), 1);
+
+		let mut error = |src: &str| match engine.compile_str(src, None) {
+			Ok(_) => panic!("`{}` parsed without errors", src),
+			Err(mut x) => x.errors.remove(0).error,
+		};
+		assert!(matches!(error("extends <p>\n"), ParseError::NotQuoteMark));
+		error("extends\n");
+		assert!(matches!(
+			error("extends \"nope\"\n"),
+			ParseError::TemplateNotFound(x) if x == "nope.ks"
+		));
+		assert!(matches!(
+			error("extends \"base\"\nextends \"base\"\n"),
+			ParseError::DuplicateExtends
+		));
+	}
+
+	#[test]
+	fn include_tags() {
+		let file = parse("<include \"nav\">\n<div | <include \"parts/@name\">>\n");
+		assert!(matches!(
+			&file.body[0],
+			TopNodes::Include(path) if matches!(&path.value[..], [StringParts::String(x)] if x == "nav")
+		));
+		match &file.body[1] {
+			TopNodes::HtmlTag(HtmlTag { body, .. }) => assert!(matches!(
+				&body[0],
+				HtmlNodes::Include(path)
+					if matches!(path.value.last(), Some(StringParts::Expression(_)))
+			)),
+			x => panic!("expected a tag, got {}", x),
+		}
+		parse_err("<include>\n");
+	}
+
+	#[test]
+	fn trim_marks_are_next_to_the_head() {
+		let trims = |src: &str| {
+			let file = parse(src);
+			let tag = first_tag(&file);
+			(tag.trim_before, tag.trim_after, body_text(&file))
+		};
+		assert_eq!(trims("<-p>\n"), (true, false, "".into()));
+		assert_eq!(trims("<p->\n"), (false, true, "".into()));
+		assert_eq!(trims("<-p class=\"a\"->\n"), (true, true, "".into()));
+		assert_eq!(trims("<p:a->\n"), (false, false, "a-".into()));
+		assert_eq!(trims("<p | a ->\n"), (false, false, "a -".into()));
+		parse_err("<p ->\n");
+	}
+
+	#[test]
+	fn void_elements_with_bodies() {
+		for src in ["<br|x>\n", "<br+p>\n", "<p+img|x>\n"] {
+			assert!(matches!(
+				parse_err(src).error,
+				ParseError::VoidElementWithBody(_)
+			));
+		}
+		parse("<svg:br|x>\n");
+	}
+
+	#[test]
+	fn array_expressions() {
+		let engine = Kismesis::new();
+		let expression = |src: &str| parse_expression(&lexer::tokenize(src), &engine).unwrap();
+		let length = |src: &str| match expression(src) {
+			Expression::Array(x) => x.len(),
+			x => panic!("expected an array, got {}", x),
+		};
+		assert_eq!(expression("{a}"), Expression::Variable("a".into()));
+		assert_eq!(length("{}"), 0);
+		assert_eq!(length("{a,}"), 1);
+		assert_eq!(length("{a, \"b\"}"), 2);
+		assert_eq!(length("{a, b,}"), 2);
+
+		let file = parse("<p class=\"x\" id={y} title={y, \"z\"}>\n");
+		let values: Vec<_> = first_tag(&file)
+			.attributes
+			.iter()
+			.map(|x| x.value.value.clone())
+			.collect();
+		assert!(matches!(&values[0], Expression::Literal(_)));
+		assert_eq!(values[1], Expression::Variable("y".into()));
+		assert!(matches!(&values[2], Expression::Array(x) if x.len() == 2));
+	}
+
+	#[test]
+	fn hyphenated_attribute_names() {
+		let file = parse("<div data-user-id=\"5\" aria-hidden=\"true\" xlink:show-mode=\"a\">\n");
+		let names: Vec<_> = first_tag(&file)
+			.attributes
+			.iter()
+			.map(|x| x.name.value.as_str())
+			.collect();
+		assert_eq!(names, ["data-user-id", "aria-hidden", "xlink:show-mode"]);
+		for src in [
+			"<div data- =\"5\">\n",
+			"<div data - id=\"5\">\n",
+			"<div -id=\"5\">\n",
+		] {
+			parse_err(src);
+		}
+	}
+
+	#[test]
+	fn deeply_nested_plugin_bodies() {
+		with_big_stack(|| {
+			let depth = 50_000;
+			let src = format!("| {}{} >", "<".repeat(depth), ">".repeat(depth));
+			with_state(&src, |state| match plugin_body(state) {
+				Ok((body, state)) => {
+					assert_eq!(body.value.len(), depth * 2 + 1);
+					assert!(state.remaining().is_empty());
+				}
+				Err(x) => panic!("{:?}", x.unpack().error),
+			});
+			let src = format!("| {} >", "<".repeat(depth));
+			with_state(&src, |state| {
+				assert!(matches!(
+					plugin_body(state).map_err(|x| x.unpack().error),
+					Err(ParseError::TagOpenerMismatch)
+				))
+			});
+		})
+	}
+
+	fn parse_recovering(src: &str) -> Result<(ParsedFile, Vec<ParseError>), Vec<ParseError>> {
+		let mut engine = Kismesis::new();
+		let id = engine.register_tokens(lexer::tokenize(src), None);
+		let errors = |x: Vec<ErrorState<ParseError>>| x.into_iter().map(|x| x.error).collect();
+		file_recovering(id, &engine, None, None)
+			.map(|(file, x)| (file, errors(x)))
+			.map_err(errors)
+	}
+
+	#[test]
+	fn malformed_attributes_are_skipped() {
+		let (parsed, errors) = parse_recovering("<div a=< d=\"2\" e=< b=\"ok\">\n").unwrap();
+		let names: Vec<_> = first_tag(&parsed)
+			.attributes
+			.iter()
+			.map(|x| x.name.value.as_str())
+			.collect();
+		assert_eq!(names, ["d", "b"]);
+		assert_eq!(errors.len(), 2, "{:?}", errors);
+
+		let mut engine = Kismesis::new();
+		let id = engine.register_tokens(lexer::tokenize("<div a=< b=< c=\"ok\">\n"), None);
+		assert_eq!(file(id, &engine, None, None).unwrap_err().len(), 2);
+		let error = engine
+			.compile_str("<div a=< b=< c=\"ok\">\n", None)
+			.unwrap_err();
+		assert_eq!(error.errors.len(), 2);
+
+		// The errors before one that can't be skipped aren't lost
+		let errors = parse_recovering("<div a=< b=\"ok\">\n<p | a\n").unwrap_err();
+		assert_eq!(errors.len(), 2, "{:?}", errors);
+	}
+
+	#[test]
+	fn spread_attributes() {
+		let file = parse("<div id=\"a\" {...props} {... extra.more } class=\"b\">\n");
+		let tag = first_tag(&file);
+		let names: Vec<_> = tag
+			.attributes
+			.iter()
+			.map(|x| x.name.value.as_str())
+			.collect();
+		assert_eq!(names, ["id", "class"]);
+		let spreads: Vec<_> = tag.spreads.iter().map(|x| x.value.clone()).collect();
+		assert_eq!(spreads[0], Expression::Variable("props".into()));
+		assert!(
+			matches!(&spreads[1], Expression::Member(x, field) if field == "more"
+				&& x.value == Expression::Variable("extra".into()))
+		);
+		parse_err("<div {...}>\n");
+		parse_err("<div {...props>\n");
+	}
+
+	#[test]
+	fn env_tags() {
+		let file = parse("<env \"production\" | <p | a> <b | c>>\n<env 'dev'>\n");
+		let TopNodes::Env(env) = &file.body[0] else {
+			panic!("expected an env tag, got {}", file.body[0])
+		};
+		assert_eq!(env.name.value, "production");
+		assert_eq!(env.body.len(), 3);
+		assert!(matches!(&env.body[0], HtmlNodes::HtmlTag(x) if x.name.value == "p"));
+		assert!(
+			matches!(&file.body[1], TopNodes::Env(x) if x.name.value == "dev" && x.body.is_empty())
+		);
+		let nested = parse("<div | <env \"x\" | y>>\n");
+		assert!(matches!(&first_body(&nested)[0], HtmlNodes::Env(x) if x.name.value == "x"));
+		assert!(matches!(
+			parse_err("<env \"a@b\" | c>\n").error,
+			ParseError::ExpressionInEnvName
+		));
+		parse_err("<env production | c>\n");
+	}
+
+	#[test]
+	fn reserved_words_arent_names() {
+		let reserved = |src: &str| match parse_err(src).error {
+			ParseError::ReservedName(x) => x,
+			x => panic!("expected a reserved name error for `{}`, got {:?}", src, x),
+		};
+		for word in RESERVED {
+			assert_eq!(reserved(&format!("<macro {} | a>\n", word)), *word);
+			// The words that start tags of their own give the errors of
+			// those tags instead
+			parse_err(&format!("<div | <{}.a>>\n", word));
+		}
+		for word in ["macro", "content", "if"] {
+			assert_eq!(reserved(&format!("<div | <{}.a>>\n", word)), word);
+		}
+		assert!(matches!(
+			parse_err("<else.a>\n").error,
+			ParseError::ElseWithoutIf
+		));
+		assert_eq!(parse("<contents>\n").body.len(), 1);
+	}
+
+	#[test]
+	fn attributes_named_after_reserved_words() {
+		let names = |src: &str| -> Vec<String> {
+			first_tag(&parse(src))
+				.attributes
+				.iter()
+				.map(|x| x.name.value.to_string())
+				.collect()
+		};
+		assert_eq!(names("<div let=\"x\">\n"), ["let"]);
+		for word in RESERVED.iter().chain(&["let", "lambda"]) {
+			let src = format!("<div {}=\"x\" id=\"y\">\n", word);
+			assert_eq!(names(&src), [*word, "id"]);
+		}
+		// They're still reserved as tag and macro names
+		assert!(matches!(
+			parse_err("<macro for let=\"x\" | a>\n").error,
+			ParseError::ReservedName(_)
+		));
+		assert!(matches!(
+			parse_err("<div | <macro.a let=\"x\">>\n").error,
+			ParseError::ReservedName(_)
+		));
+	}
+
+	#[test]
+	fn backtracking_doesnt_change_the_tree() {
+		use types::SpanlessEq;
+
+		// Clones of the state share what they can, so parsing every piece of
+		// a file from its own fresh state has to give the same tree as
+		// parsing them all from one state that gets cloned at every branch
+		let pieces = [
+			"<macro card name title=\"x\" | <div.card | <h2 | @title> <p | @name &amp; co>>>\n",
+			"<main#top lang=\"en\" | <card! name=\"a\"> <card! name={b} title=\"c\">>\n",
+			"<ul:\n\t<li | <a href=\"/\" class=\"x\"?{y} | home>>\n\t<li | @{not z}>\n>\n",
+			"<if {a and b} | <p | yes>>\n<else | <p | no <b | really>>>\n",
+			"<for x in {{\"a\", \"b\"}} | <section | <div | <div | <i | @x>>>>>\n",
+			"<env \"production\" | <p | live>>\n",
+			"<p | text with a \\@ and &#169; and @{x or \"y\"}>\n",
+		];
+		let whole = parse(&pieces.concat());
+		let parsed: Vec<_> = pieces.iter().map(|x| parse(x)).collect();
+		let body: Vec<_> = parsed.iter().flat_map(|x| x.body.iter().cloned()).collect();
+		let macros: Vec<_> = parsed
+			.iter()
+			.flat_map(|x| x.defined_macros.iter().cloned())
+			.collect();
+		assert_eq!(whole.body.len(), body.len());
+		assert!(whole.body.eq_ignoring_span(&body));
+		assert!(whole.defined_macros.eq_ignoring_span(&macros));
+	}
+
+	#[test]
+	fn strings_without_interpolations() {
+		let error = |src: &str| {
+			let error = parse_err(src);
+			let tokens = lexer::tokenize(src);
+			let at = &tokens[error.text_position.get_start().get_idx()];
+			(error.error, at.get_as_string())
+		};
+		assert!(matches!(
+			error("set template = \"a@{b}\"\n"),
+			(ParseError::ExpressionInSetStmt, x) if x == "{"
+		));
+		assert!(matches!(
+			error("extends \"a@{b}\"\n"),
+			(ParseError::ExpressionInPath, x) if x == "{"
+		));
+		assert!(matches!(
+			error("<env \"a@{b}\" | c>\n"),
+			(ParseError::ExpressionInEnvName, x) if x == "{"
+		));
+		let file = parse("<env \"a&amp;b\" | c>\n");
+		let TopNodes::Env(env) = &file.body[0] else {
+			panic!("expected an env tag, got {}", file.body[0])
+		};
+		assert_eq!(env.name.value, "a&amp;b");
+	}
+
+	#[test]
+	fn if_tags_take_the_else_after_them() {
+		let file = parse("<if {a} | <p | x>>\n<else | <p | y>>\n");
+		assert_eq!(file.body.len(), 1);
+		let TopNodes::If(tag) = &file.body[0] else {
+			panic!("expected an if tag, got {}", file.body[0])
+		};
+		assert_eq!(tag.condition.value, Expression::Variable("a".into()));
+		assert!(matches!(&tag.body[..], [HtmlNodes::HtmlTag(x)] if x.name.value == "p"));
+		assert!(matches!(
+			tag.else_body.as_deref(),
+			Some([HtmlNodes::HtmlTag(_)])
+		));
+
+		let file = parse("<if {a} | <p | x>>\n<p | y>\n");
+		assert_eq!(file.body.len(), 2);
+		assert!(matches!(&file.body[0], TopNodes::If(x) if x.else_body.is_none()));
+
+		let nested = parse("<div | <if {a} | x><else | y>>\n");
+		assert!(matches!(&first_body(&nested)[..], [HtmlNodes::If(x)] if x.else_body.is_some()));
+
+		for src in [
+			"<else | y>\n",
+			"<p | x>\n<else | y>\n",
+			"<div | <else | y>>\n",
+		] {
+			assert!(
+				matches!(parse_err(src).error, ParseError::ElseWithoutIf),
+				"{}",
+				src
+			);
+		}
+	}
+
+	#[test]
+	fn running_out_of_input_mid_tag_or_expression() {
+		let expected = |error: &ErrorState<ParseError>| match &error.hints[..] {
+			[Hint::Stateless(x)] => match &x.error {
+				Hints::Expected(x) => x.clone(),
+				x => panic!("expected an `Expected` hint, got {:?}", x),
+			},
+			x => panic!("expected one hint, got {:?}", x),
+		};
+		for (src, idx) in [("<div ", 2), ("<div", 2), ("<div a=\"b\"\n", 8)] {
+			let error = parse_err(src);
+			assert!(matches!(error.error, ParseError::ReachedEOF), "{}", src);
+			assert_eq!(error.text_position.get_start().get_idx(), idx, "{}", src);
+			assert_eq!(expected(&error), ParseError::ExpectedTagCloser.get_text());
+		}
+		for (src, idx) in [("<p x={a and", 9), ("<p | @{a and\n", 10)] {
+			let error = parse_err(src);
+			assert!(matches!(error.error, ParseError::ReachedEOF), "{}", src);
+			assert_eq!(error.text_position.get_start().get_idx(), idx, "{}", src);
+		}
+		// Other cuts keep their own errors at the end of the input
+		assert!(matches!(parse_err("<for \n").error, ParseError::NotLiteral));
+		assert!(matches!(
+			parse_err("extends\n").error,
+			ParseError::NotQuoteMark
+		));
+	}
+
+	#[test]
+	fn doctype_statements() {
+		let file = parse("doctype html\n<p | a>\n");
+		assert!(matches!(&file.body[0], TopNodes::Doctype(x) if x == "html"));
+		assert!(matches!(&parse("<!doctype html>\n").body[0], TopNodes::Doctype(x) if x == "html"));
+		// Anything else on the line makes it text
+		let file = parse("doctype html please\n");
+		assert!(matches!(&file.body[0], TopNodes::HtmlTag(x) if x.name.value == "p"));
+	}
+}
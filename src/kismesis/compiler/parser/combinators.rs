@@ -1,10 +1,11 @@
-use std::{
-	fmt::Debug,
-	ops::{Bound, RangeBounds},
-};
+use std::ops::{Bound, RangeBounds};
+
+use crate::kismesis::compiler::lexer::Token;
+
+use crate::kismesis::compiler::errors::{ErrorKind, ErrorState};
 
 use super::{
-	errors::{Err, ParseError},
+	errors::{Err, Hints, ParseError},
 	state::ParserState,
 	types::{Ranged, TextPos},
 	Parser, ParserResult,
@@ -30,30 +31,28 @@ where
 	}
 }
 
-pub(super) fn maybe_until<'a, P1, O1, P2, O2>(p1: P1, p2: P2) -> impl Parser<'a, Vec<O1>>
+/// Parses `item` until `end` parses, and consumes `end` too. Reaching the end
+/// of the input before `end` is a `ReachedEOF` error. Errors from `item` are
+/// returned as they are, and so are failures from `end`
+pub(super) fn many_till<'a, P, T, E, U>(item: P, end: E) -> impl Parser<'a, Vec<T>>
 where
-	P1: Parser<'a, O1>,
-	P2: Parser<'a, O2>,
+	P: Parser<'a, T>,
+	E: Parser<'a, U>,
 {
 	move |mut state: ParserState<'a>| {
 		let mut found = Vec::new();
 		loop {
-			match p2.parse(state.clone()) {
-				Ok((_, next_state)) => {
-					if found.is_empty() {
-						return Err(ParseError::EmptyString.error_at(&next_state));
-					} else {
-						return Ok((found, state));
-					}
-				}
-				Err(_) => match p1.parse(state.clone()) {
-					Ok((val, next_state)) => {
-						found.push(val);
-						state = next_state;
-					}
-					Err(_) => return Err(ParseError::ConditionUnmet.error_at(&state)),
-				},
+			match end.parse(state.clone()) {
+				Ok((_, next_state)) => return Ok((found, next_state)),
+				Err(Err::Failure(x)) => return Err(Err::Failure(x)),
+				Err(Err::Error(_)) => (),
 			}
+			if state.first_token().is_none() {
+				return Err(ParseError::ReachedEOF.error_at(&state));
+			}
+			let (val, next_state) = item.parse(state)?;
+			found.push(val);
+			state = next_state;
 		}
 	}
 }
@@ -116,6 +115,30 @@ where
 	repeated(parser, 0..)
 }
 
+/// Like `zero_or_more`, but only counts how many times the parser matched
+/// instead of collecting what it returned
+pub(super) fn count<'a, P, T>(parser: P) -> impl Parser<'a, usize>
+where
+	P: Parser<'a, T>,
+{
+	move |state: ParserState<'a>| {
+		let mut state = state;
+		let mut found = 0;
+		let mut attempt = state.clone();
+		loop {
+			match parser.parse(attempt) {
+				Ok((_, next_state)) => {
+					state.catch_up(&next_state);
+					attempt = next_state;
+					found += 1;
+				}
+				Err(Err::Failure(x)) => return Err(Err::Failure(x)),
+				Err(Err::Error(_)) => return Ok((found, state)),
+			}
+		}
+	}
+}
+
 pub(super) fn repeated<'a, P, T>(
 	parser: P,
 	range: impl RangeBounds<usize>,
@@ -123,17 +146,26 @@ pub(super) fn repeated<'a, P, T>(
 where
 	P: Parser<'a, T>,
 {
+	let min = match range.start_bound() {
+		Bound::Included(x) => *x,
+		Bound::Excluded(x) => x + 1,
+		Bound::Unbounded => 0,
+	};
 	move |state: ParserState<'a>| {
+		// One clone to go back to when the parser stops matching, which is
+		// moved along with every match instead of cloning the state each time
 		let mut state = state;
-		let mut found = Vec::<T>::new();
+		let mut found = Vec::<T>::with_capacity(min);
+		let mut attempt = state.clone();
 		loop {
-			match parser.parse(state.clone()) {
+			match parser.parse(attempt) {
 				Ok((token, next_state)) => {
-					state = next_state;
+					state.catch_up(&next_state);
+					attempt = next_state;
 					found.push(token);
 				}
 				Err(Err::Failure(x)) => return Err(Err::Failure(x)),
-				_ => break,
+				Err(Err::Error(_)) => break,
 			}
 		}
 		if range.contains(&found.len()) {
@@ -164,17 +196,54 @@ where
 	}
 }
 
-pub(super) fn dbg<'a, P, T: Debug>(parser: P) -> impl Parser<'a, T>
+/// Logs the label, the position the parser started at and whether it
+/// succeeded at the debug level, under the `kismesis::parser` target. Does
+/// nothing else when that level is disabled.
+pub(super) fn trace<'a, P, T>(parser: P, label: &str) -> impl Parser<'a, T>
 where
 	P: Parser<'a, T>,
 {
+	let label = label.to_owned();
 	move |state: ParserState<'a>| {
-		let r = parser.parse(state);
-		println!("{:#?}", r);
-		r
+		if !log::log_enabled!(target: "kismesis::parser", log::Level::Debug) {
+			return parser.parse(state);
+		}
+		let (result, message) = run_traced(&parser, &label, state);
+		log::debug!(target: "kismesis::parser", "{}", message);
+		result
 	}
 }
 
+/// Runs a parser and describes how it went, the way [`trace`] logs it
+fn run_traced<'a, P, T>(
+	parser: &P,
+	label: &str,
+	state: ParserState<'a>,
+) -> (ParserResult<'a, T>, String)
+where
+	P: Parser<'a, T>,
+{
+	let start = state.position;
+	let result = parser.parse(state);
+	let outcome = match result {
+		Ok((_, ref next_state)) => format!(
+			"ok, ended at {}:{}",
+			next_state.position.get_line() + 1,
+			next_state.position.get_column() + 1
+		),
+		Err(Err::Error(ref x)) => format!("error: {:?}", x.error),
+		Err(Err::Failure(ref x)) => format!("failure: {:?}", x.error),
+	};
+	let message = format!(
+		"{} at {}:{}: {}",
+		label,
+		start.get_line() + 1,
+		start.get_column() + 1,
+		outcome
+	);
+	(result, message)
+}
+
 pub(super) fn cut<'a, P, T>(parser: P) -> impl Parser<'a, T>
 where
 	P: Parser<'a, T>,
@@ -185,6 +254,50 @@ where
 	}
 }
 
+/// Like [`cut`], but errors with nothing but blanks after them become
+/// `ReachedEOF` failures, with what was expected there as a hint. For the
+/// places where running out of input is the likeliest reason to fail
+pub(super) fn cut_before_eof<'a, P, T>(parser: P) -> impl Parser<'a, T>
+where
+	P: Parser<'a, T>,
+{
+	move |state: ParserState<'a>| {
+		let start = state.position.get_idx();
+		let tokens = state.remaining();
+		match parser.parse(state) {
+			Err(Err::Error(x)) => {
+				let rest = tokens.get(x.start_position().get_idx().saturating_sub(start)..);
+				if rest.is_none_or(|x| x.iter().all(is_blank)) {
+					Err(Err::Failure(reached_eof(x)))
+				} else {
+					Err(Err::Failure(x))
+				}
+			}
+			pat => pat,
+		}
+	}
+}
+
+pub(super) fn is_blank(token: &Token) -> bool {
+	matches!(
+		token,
+		Token::Space(_) | Token::Indent(_) | Token::Newline(_)
+	)
+}
+
+fn reached_eof(error: ErrorState<ParseError>) -> ErrorState<ParseError> {
+	if matches!(error.error, ParseError::ReachedEOF) {
+		return error;
+	}
+	let mut hints = vec![Hints::Expected(error.error.get_text()).stateless()];
+	hints.extend(error.hints);
+	ErrorState {
+		error: ParseError::ReachedEOF,
+		text_position: error.text_position,
+		hints,
+	}
+}
+
 pub(super) fn not<'a, P, T>(parser: P) -> impl Parser<'a, ()>
 where
 	P: Parser<'a, T>,
@@ -254,6 +367,24 @@ where
 	}
 }
 
+/// Fails with `error` at the state the parser started at if what it parsed
+/// doesn't satisfy `fun`
+pub(super) fn verify<'a, P, F, T1>(parser: P, fun: F, error: ParseError) -> impl Parser<'a, T1>
+where
+	P: Parser<'a, T1>,
+	F: Fn(&T1) -> bool,
+	T1: 'a,
+{
+	move |state: ParserState<'a>| {
+		let (val, next_state) = parser.parse(state.clone())?;
+		if fun(&val) {
+			Ok((val, next_state))
+		} else {
+			Err(error.clone().error_at(&state))
+		}
+	}
+}
+
 pub(super) fn get_range<'a, P, T1>(parser: P) -> impl Parser<'a, Ranged<T1>>
 where
 	P: Parser<'a, T1>,
@@ -272,3 +403,221 @@ where
 		Ok((Ranged { value: val, range }, next_state))
 	}
 }
+
+/// Runs `parser` and also returns a copy of the tokens it consumed
+pub(super) fn with_tokens<'a, P, T1>(parser: P) -> impl Parser<'a, (T1, Vec<Token>)>
+where
+	P: Parser<'a, T1>,
+{
+	move |state: ParserState<'a>| {
+		let start = state.position.get_idx();
+		let tokens = state.tokens;
+		let (val, next_state) = parser.parse(state)?;
+		let consumed = next_state.position.get_idx() - start;
+		Ok(((val, tokens[..consumed].to_vec()), next_state))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::super::{
+		after_spaces, attribute, literal, skip_spaces, some_tag, specific_symbol,
+		tests::{tokens_text, with_state},
+	};
+	use super::*;
+
+	#[test]
+	fn with_tokens_captures_what_was_parsed() {
+		let (name, tokens) = with_state("class=\"a b\" id=c", |state| {
+			let ((attribute, tokens), _) = with_tokens(attribute).parse(state).unwrap();
+			(attribute.name.to_string(), tokens)
+		});
+		assert_eq!(name, "class");
+		assert_eq!(tokens_text(&tokens), "class=\"a b\"");
+	}
+
+	#[test]
+	fn opt_only_catches_recoverable_errors() {
+		with_state("abc<", |state| {
+			let (word, next) = literal.opt().parse(state.clone()).unwrap();
+			let after_word = state.next_state().position;
+			assert_eq!(word, Some("abc"));
+			assert_eq!(next.position, after_word);
+
+			let (word, next) = literal.opt().parse(next.clone()).unwrap();
+			assert_eq!(word, None);
+			assert_eq!(next.position, after_word);
+
+			let failing = specific_symbol('<').preceding(cut(literal)).opt();
+			assert!(matches!(failing.parse(next), Err(Err::Failure(_))));
+		});
+	}
+
+	#[test]
+	fn verify_fails_where_the_parser_started() {
+		with_state("  abc def", |state| {
+			let state = state.next_state().next_state();
+			let start = state.position;
+			let (word, next) = literal
+				.verify(|x| x == &"abc", ParseError::NotMacroStart)
+				.parse(state.clone())
+				.unwrap();
+			assert_eq!(word, "abc");
+			assert_ne!(next.position, start);
+
+			let result = literal
+				.verify(|x| x == &"def", ParseError::NotMacroStart)
+				.parse(state);
+			let Err(Err::Error(error)) = result else {
+				panic!("expected a recoverable error")
+			};
+			assert!(matches!(error.error, ParseError::NotMacroStart));
+			assert_eq!(error.text_position.get_start(), start);
+		});
+	}
+
+	#[test]
+	fn count_matches_zero_or_more() {
+		for src in ["", "x", "|", "|||x", "x|||", "| | |", "||||||||||"] {
+			with_state(src, |state| {
+				let (counted, a) = count(specific_symbol('|')).parse(state.clone()).unwrap();
+				let (found, b) = zero_or_more(specific_symbol('|')).parse(state).unwrap();
+				assert_eq!(counted, found.len(), "{}", src);
+				assert_eq!(a.position, b.position, "{}", src);
+			});
+		}
+		// Failures stop both of them
+		with_state("|a|b", |state| {
+			let parser = || specific_symbol('|').followed_by(cut(specific_symbol('a')));
+			assert!(matches!(
+				count(parser()).parse(state.clone()),
+				Err(Err::Failure(_))
+			));
+			assert!(matches!(
+				zero_or_more(parser()).parse(state),
+				Err(Err::Failure(_))
+			));
+		});
+	}
+
+	#[test]
+	fn zero_or_more_matches_parsing_one_at_a_time() {
+		let src = (0..200)
+			.map(|x| format!("a{}=\"{}\" b{}={{c and d}}", x, x, x))
+			.collect::<Vec<_>>()
+			.join(" ");
+		with_state(&src, |state| {
+			let parser = after_spaces(attribute);
+			let (all, end) = zero_or_more(after_spaces(attribute))
+				.parse(state.clone())
+				.unwrap();
+			let mut one_at_a_time = vec![];
+			let mut state = state;
+			while let Ok((x, next)) = parser.parse(state.clone()) {
+				one_at_a_time.push(x);
+				state = next;
+			}
+			assert_eq!(all.len(), 400);
+			assert_eq!(all, one_at_a_time);
+			assert_eq!(end.position, state.position);
+			assert!(end.remaining().is_empty());
+		});
+	}
+
+	#[test]
+	fn many_till_stops_at_its_end() {
+		with_state("a b c > d", |state| {
+			let words = many_till(literal.followed_by(skip_spaces()), specific_symbol('>'));
+			let (found, next) = words.parse(state).unwrap();
+			assert_eq!(found, ["a", "b", "c"]);
+			// The end is consumed too
+			assert_eq!(tokens_text(next.remaining()), " d");
+		});
+		with_state("> a", |state| {
+			let words = many_till(literal, specific_symbol('>'));
+			let (found, next) = words.parse(state).unwrap();
+			assert!(found.is_empty());
+			assert_eq!(tokens_text(next.remaining()), " a");
+		});
+	}
+
+	#[test]
+	fn many_till_needs_its_end() {
+		with_state("a b c", |state| {
+			let words = many_till(literal.followed_by(skip_spaces()), specific_symbol('>'));
+			let error = words.parse(state).unwrap_err();
+			assert!(matches!(error, Err::Error(_)));
+			let error = error.unpack();
+			assert!(matches!(error.error, ParseError::ReachedEOF));
+			assert_eq!(error.text_position.get_start().get_idx(), 5);
+		});
+		// Errors from the items are given as they are
+		with_state("a , >", |state| {
+			let words = many_till(literal.followed_by(skip_spaces()), specific_symbol('>'));
+			assert!(matches!(
+				words.parse(state).unwrap_err().unpack().error,
+				ParseError::NotLiteral
+			));
+		});
+	}
+
+	#[test]
+	fn recovered_errors_stay_in_their_branch() {
+		with_state("a b", |state| {
+			let mut branch = state.clone();
+			branch.push_error(ParseError::EndlessString.error_at(&state).unpack());
+			let mut other = branch.clone();
+			other.push_error(ParseError::NotLiteral.error_at(&state).unpack());
+			assert!(state.errors.is_empty());
+			assert_eq!(branch.errors.len(), 1);
+			assert_eq!(other.errors.len(), 2);
+		});
+	}
+
+	#[test]
+	fn repeating_keeps_only_the_errors_of_matches() {
+		// Recovers from an error before every word, including the one it
+		// fails on after the last word
+		fn noisy_word(mut state: ParserState) -> ParserResult<&str> {
+			state.push_error(ParseError::NotLiteral.error_at(&state).unpack());
+			literal.followed_by(skip_spaces()).parse(state)
+		}
+		with_state("a b c", |state| {
+			let (found, next) = zero_or_more(noisy_word).parse(state.clone()).unwrap();
+			assert_eq!(found, ["a", "b", "c"]);
+			assert_eq!(next.errors.len(), 3);
+			assert!(next.remaining().is_empty());
+			assert!(state.errors.is_empty());
+			assert!(repeated(noisy_word, 4..).parse(state).is_err());
+		});
+		with_state("", |state| {
+			let (found, next) = zero_or_more(noisy_word).parse(state).unwrap();
+			assert!(found.is_empty());
+			assert!(next.errors.is_empty());
+		});
+	}
+
+	#[test]
+	fn tracing_leaves_results_alone() {
+		with_state("<p class=a | hi <b | there>> <i>", |state| {
+			let (plain, plain_state) = some_tag.parse(state.clone()).unwrap();
+			let (traced, traced_state) = trace(some_tag, "tag").parse(state.clone()).unwrap();
+			assert_eq!(plain, traced);
+			assert_eq!(plain_state.position, traced_state.position);
+			assert!(trace(some_tag, "tag")
+				.parse(state.clone().next_state())
+				.is_err());
+
+			// What gets logged when the debug level is enabled
+			let (result, message) = run_traced(&some_tag, "tag", state.clone());
+			let (traced, traced_state) = result.unwrap();
+			assert_eq!(plain, traced);
+			assert_eq!(plain_state.position, traced_state.position);
+			let end = plain_state.position.get_column() + 1;
+			assert_eq!(message, format!("tag at 1:1: ok, ended at 1:{}", end));
+			let (result, message) = run_traced(&some_tag, "tag", state.next_state());
+			assert!(result.is_err());
+			assert!(message.starts_with("tag at 1:2: error: "));
+		});
+	}
+}
@@ -2,7 +2,7 @@ use std::ops::Bound;
 
 use crate::kismesis::{
 	compiler::{
-		errors::{ErrorKind, ErrorState, StatelessError},
+		errors::{ErrorCategory, ErrorKind, ErrorState, StatelessError},
 		html::ScopedError,
 	},
 	KisID,
@@ -54,6 +54,23 @@ pub enum ParseError {
 	NotAnIndent,
 	ReachedEOF,
 	EndlessString,
+	DanglingInterpolation,
+	MaxDepthExceeded(usize),
+	StepBudgetExceeded,
+	DuplicateId,
+	ExpressionInPath,
+	TemplateNotFound(String),
+	DuplicateExtends,
+	VoidElementWithBody(String),
+	UnknownSetting(String),
+	UndefinedPlugin(String),
+	PluginFailed(String),
+	MissingAttributeValue(String),
+	ExpressionInEnvName,
+	ReservedName(String),
+	ElseWithoutIf,
+	ContentInEmptyExpr,
+	EmptyMacroName,
 }
 
 #[derive(Clone, Debug)]
@@ -82,6 +99,10 @@ impl Err {
 pub enum Hints {
 	ArgumentDefinedHere,
 	ReferenceToThis,
+	AvailableNames(Vec<String>),
+	/// What a parser that reached the end of the file was expecting
+	Expected(String),
+	ExprOpenedHere,
 }
 
 impl ErrorKind for Hints {
@@ -89,6 +110,12 @@ impl ErrorKind for Hints {
 		match self {
 			Self::ArgumentDefinedHere => "Argument defined here".into(),
 			Self::ReferenceToThis => "Value comes from here".into(),
+			Self::AvailableNames(names) if names.is_empty() => {
+				"There are no names available here".into()
+			}
+			Self::AvailableNames(names) => format!("Available names are: {}", names.join(", ")),
+			Self::Expected(text) => text.clone(),
+			Self::ExprOpenedHere => "The expression was opened here".into(),
 		}
 	}
 }
@@ -166,8 +193,8 @@ impl ErrorKind for ParseError {
 				"Expected the file to end, but it didn't. You might have too many `>`".into()
 			}
 			Self::LiteralNotMatch { expected, .. } => format!("Expected the word `{}`", expected),
-			Self::ExpectedExprStart => "Expected `[` to denote the start of an expression".into(),
-			Self::ExpectedExprEnd => "Expected `]`to denote the end of an expression".into(),
+			Self::ExpectedExprStart => "Expected `{` to denote the start of an expression".into(),
+			Self::ExpectedExprEnd => "Expected `}` to denote the end of an expression".into(),
 			Self::ExpectedMacroMark => "Expected `!` to denote a macro call".into(),
 			Self::ExpectedPluginMark => "Expected `?` to denote a plugin call".into(),
 			Self::ExpectedUniFunc => "Expected `not` or some other unary function".into(),
@@ -187,12 +214,101 @@ impl ErrorKind for ParseError {
 			Self::NotSymbol => "Expected a special character".into(),
 			Self::NotMacroStart => "Expected the start of a macro".into(),
 			Self::CharacterNotMatch { expected, .. } => format!("Expected `{}`", expected),
-			Self::NotQuoteMark => "Expected a quotation mark".into(),
+			Self::NotQuoteMark => "Expected a quotation mark (`\"`, `'` or `` ` ``)".into(),
 			Self::NotASpace => "Expected a space".into(),
 			Self::NotAnIndent => "Expected an indent (tab key)".into(),
 			Self::ReachedEOF => "Reached end of file".into(),
 			Self::EndlessString => "String reaches end of file".into(),
 			Self::ExpectedEquals => "Expected an equals sign `=`".into(),
+			Self::DanglingInterpolation => {
+				"Expected an expression after this `@`. Use `@@` or `\\@` to write a literal `@`"
+					.into()
+			}
+			Self::MaxDepthExceeded(max) => {
+				format!(
+					"Tags are nested too deeply. At most {} levels are allowed",
+					max
+				)
+			}
+			Self::StepBudgetExceeded => "Parsing took too many steps and was stopped here".into(),
+			Self::DuplicateId => "A tag can only have one id".into(),
+			Self::ExpressionInPath => "Paths can't contain expressions".into(),
+			Self::TemplateNotFound(path) => format!("There is no template at `{}`", path),
+			Self::DuplicateExtends => "A file can only extend one template".into(),
+			Self::VoidElementWithBody(name) => {
+				format!("`{}` is a void element, so it can't have a body", name)
+			}
+			Self::UnknownSetting(name) => format!("There is no setting called `{}`", name),
+			Self::UndefinedPlugin(name) => format!("There is no plugin called `{}`", name),
+			Self::PluginFailed(reason) => format!("The plugin failed: {}", reason),
+			Self::MissingAttributeValue(name) => format!("`{}` is missing its value", name),
+			Self::ExpressionInEnvName => "Environment names can't contain expressions".into(),
+			Self::ReservedName(name) => format!("`{}` is a reserved word", name),
+			Self::ElseWithoutIf => "An `else` tag has to come right after an `if` tag".into(),
+			Self::ContentInEmptyExpr => "Nothing can come after the `!` in `{!}`".into(),
+			Self::EmptyMacroName => "Expected the name of the macro before the `!`".into(),
+		}
+	}
+
+	fn code(&self) -> &'static str {
+		match self {
+			Self::TriedToParseInvalidID(_) => "E0001",
+			Self::WronglyNestedSection => "E0002",
+			Self::ExpectedLambdaStart => "E0003",
+			Self::ConditionUnmet => "E0004",
+			Self::NotInRange(..) => "E0005",
+			Self::ExpressionInSetStmt => "E0006",
+			Self::ExpectedSetStarter => "E0007",
+			Self::TagOpenerMismatch => "E0008",
+			Self::TagCloserMismatch => "E0009",
+			Self::ExpectedEOF => "E0010",
+			Self::ExpectedEquals => "E0011",
+			Self::LiteralNotMatch { .. } => "E0012",
+			Self::ExpectedExprStart => "E0013",
+			Self::ExpectedExprEnd => "E0014",
+			Self::ExpectedMacroMark => "E0015",
+			Self::ExpectedPluginMark => "E0016",
+			Self::ExpectedUniFunc => "E0017",
+			Self::ExpectedBinFunc => "E0018",
+			Self::ExpectedVarName => "E0019",
+			Self::ExpectedTagNameOrMacroDef => "E0020",
+			Self::ExpectedBodyOpener => "E0021",
+			Self::ExpectedTagName => "E0022",
+			Self::ExpectedTagCloser => "E0023",
+			Self::ExpectedTagOpener => "E0024",
+			Self::NotANewline => "E0025",
+			Self::NotLiteral => "E0026",
+			Self::UnexpectedMacroDef => "E0027",
+			Self::EmptyString => "E0028",
+			Self::NotSymbol => "E0029",
+			Self::NotMacroStart => "E0030",
+			Self::CharacterNotMatch { .. } => "E0031",
+			Self::NotQuoteMark => "E0032",
+			Self::NotASpace => "E0033",
+			Self::NotAnIndent => "E0034",
+			Self::ReachedEOF => "E0035",
+			Self::EndlessString => "E0036",
+			Self::DanglingInterpolation => "E0037",
+			Self::MaxDepthExceeded(_) => "E0038",
+			Self::StepBudgetExceeded => "E0039",
+			Self::DuplicateId => "E0040",
+			Self::ExpressionInPath => "E0041",
+			Self::TemplateNotFound(_) => "E0042",
+			Self::DuplicateExtends => "E0043",
+			Self::VoidElementWithBody(_) => "E0044",
+			Self::UnknownSetting(_) => "E0045",
+			Self::UndefinedPlugin(_) => "E0046",
+			Self::PluginFailed(_) => "E0047",
+			Self::MissingAttributeValue(_) => "E0048",
+			Self::ExpressionInEnvName => "E0049",
+			Self::ReservedName(_) => "E0050",
+			Self::ElseWithoutIf => "E0051",
+			Self::ContentInEmptyExpr => "E0052",
+			Self::EmptyMacroName => "E0053",
 		}
 	}
+
+	fn category(&self) -> ErrorCategory {
+		ErrorCategory::Syntax
+	}
 }
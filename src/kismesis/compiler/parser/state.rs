@@ -1,20 +1,38 @@
-use std::path::PathBuf;
+use std::{cell::Cell, path::PathBuf, rc::Rc};
 
 use crate::kismesis::{
 	compiler::{errors::ErrorState, lexer::Token},
-	Kismesis,
+	KisID, Kismesis,
 };
 
 use super::{errors::ParseError, types::TextPos};
 
+/// Where a parser is in the token stream, along with what it has found out
+/// on the way there.
+///
+/// Parsers backtrack by keeping a clone of the state they started from and
+/// going back to it if they fail. Cloning is cheap and gives a snapshot of
+/// the position, the errors that were recovered from and the tags that are
+/// still open, so none of those leak from a discarded branch. The step
+/// counter is the exception: it's shared between every clone, so steps taken
+/// in discarded branches still count against the budget
 #[derive(Clone, Debug)]
 pub struct ParserState<'a> {
 	pub(crate) tokens: &'a [Token],
 	pub(crate) position: TokenPos,
-	pub(crate) errors: Vec<ErrorState<ParseError>>,
-	pub(crate) tag_openers: Vec<TokenPos>,
+	// States get cloned every time the parser backtracks, so anything that
+	// would need an allocation to be cloned is kept behind an `Rc`
+	pub(crate) errors: Rc<Vec<ErrorState<ParseError>>>,
+	pub(crate) tag_openers: Rc<Vec<TokenPos>>,
 	pub(crate) section_depth: usize,
-	pub(crate) project_path: Option<PathBuf>,
+	/// How many tag bodies the parser is currently inside of
+	pub(crate) depth: usize,
+	pub(crate) max_depth: usize,
+	pub(crate) steps: Rc<StepCounter>,
+	pub(crate) project_path: Option<Rc<PathBuf>>,
+	/// The file the tokens come from, if they come from one, so errors can
+	/// point at other places in it
+	pub(crate) scope: Option<KisID>,
 	pub(crate) engine: &'a Kismesis,
 }
 
@@ -23,14 +41,26 @@ impl<'a> ParserState<'a> {
 		Self {
 			tokens,
 			position: TokenPos::new(),
-			errors: vec![],
-			tag_openers: Vec::new(),
+			errors: Rc::new(Vec::new()),
+			tag_openers: Rc::new(Vec::new()),
 			section_depth: 0,
+			depth: 0,
+			max_depth: engine.max_parse_depth(),
+			steps: Rc::new(StepCounter::new(engine.parse_step_budget())),
 			engine,
-			project_path
+			project_path: project_path.map(Rc::new),
+			scope: None,
 		}
 	}
 	pub(crate) fn next_state(self) -> Self {
+		if !self.steps.step(self.position) {
+			// Out of budget, so everything after this point looks like the
+			// end of the file and the parser winds down quickly
+			return Self {
+				tokens: &[],
+				..self
+			};
+		}
 		let next_token = self.tokens.get(0);
 		let position = match next_token {
 			Some(Token::Newline(_)) => self.position.next_line(),
@@ -47,33 +77,41 @@ impl<'a> ParserState<'a> {
 		self.tokens.first()
 	}
 
+	/// Returns the token `n` positions ahead without advancing the state.
+	/// `nth_token(0)` is the same as `first_token()`.
+	pub(crate) fn nth_token(&self, n: usize) -> Option<&Token> {
+		self.tokens.get(n)
+	}
+
+	/// The tokens that haven't been consumed yet, borrowed from the token
+	/// stream. Useful for parsers that need to scan ahead in bulk
+	pub fn remaining(&self) -> &'a [Token] {
+		self.tokens
+	}
+
 	pub(crate) fn advanced(&self) -> (Option<&'a Token>, ParserState<'a>) {
 		(self.tokens.first(), self.clone().next_state())
 	}
 
+	/// Keeps an error the parser recovered from, to be reported at the end
+	pub(crate) fn push_error(&mut self, error: ErrorState<ParseError>) {
+		Rc::make_mut(&mut self.errors).push(error);
+	}
+
 	pub(crate) fn close_tag(&self) -> Result<Self, ParseError> {
 		if !self.tag_openers.is_empty() {
 			let mut clone = self.clone();
-			clone.tag_openers.pop();
-			Ok(Self {
-				tag_openers: clone.tag_openers,
-				..clone
-			})
+			Rc::make_mut(&mut clone.tag_openers).pop();
+			Ok(clone)
 		} else {
 			Err(ParseError::TagCloserMismatch)
 		}
 	}
 
 	pub(crate) fn open_tag(&self) -> Self {
-		let clone = self.clone();
-		Self {
-			tag_openers: clone
-				.tag_openers
-				.into_iter()
-				.chain(vec![self.position])
-				.collect(),
-			..clone
-		}
+		let mut clone = self.clone();
+		Rc::make_mut(&mut clone.tag_openers).push(self.position);
+		clone
 	}
 
 	pub(crate) fn below_scope(&self) -> Self {
@@ -84,6 +122,39 @@ impl<'a> ParserState<'a> {
 		}
 	}
 
+	pub(crate) fn deeper(&self) -> Result<Self, ParseError> {
+		if self.depth >= self.max_depth {
+			return Err(ParseError::MaxDepthExceeded(self.max_depth));
+		}
+		Ok(Self {
+			depth: self.depth + 1,
+			..self.clone()
+		})
+	}
+
+	pub(crate) fn shallower(self) -> Self {
+		Self {
+			depth: self.depth.saturating_sub(1),
+			..self
+		}
+	}
+
+	/// Moves this state to where `other` is, for loops that keep a state to
+	/// go back to. Only the parts that parsing changes are copied, and the
+	/// shared lists only if they changed, so it's cheaper than a clone
+	pub(crate) fn catch_up(&mut self, other: &Self) {
+		self.tokens = other.tokens;
+		self.position = other.position;
+		self.section_depth = other.section_depth;
+		self.depth = other.depth;
+		if !Rc::ptr_eq(&self.errors, &other.errors) {
+			self.errors = other.errors.clone();
+		}
+		if !Rc::ptr_eq(&self.tag_openers, &other.tag_openers) {
+			self.tag_openers = other.tag_openers.clone();
+		}
+	}
+
 	pub(crate) fn above_scope(&self) -> Self {
 		let clone = self.clone();
 		Self {
@@ -93,13 +164,68 @@ impl<'a> ParserState<'a> {
 	}
 }
 
-#[derive(Clone, Debug, Copy, PartialEq, Default)]
+/// Counts the steps taken by every state derived from the same initial
+/// state, including the ones taken in branches that were later discarded.
+#[derive(Debug)]
+pub(crate) struct StepCounter {
+	steps: Cell<usize>,
+	budget: Option<usize>,
+	exceeded_at: Cell<Option<TokenPos>>,
+}
+
+impl StepCounter {
+	fn new(budget: Option<usize>) -> Self {
+		Self {
+			steps: Cell::new(0),
+			budget,
+			exceeded_at: Cell::new(None),
+		}
+	}
+
+	/// Registers a step, returning false if the budget has been exceeded
+	fn step(&self, position: TokenPos) -> bool {
+		let Some(budget) = self.budget else {
+			return true;
+		};
+		if self.exceeded_at.get().is_some() {
+			return false;
+		}
+		let steps = self.steps.get() + 1;
+		self.steps.set(steps);
+		if steps > budget {
+			self.exceeded_at.set(Some(position));
+			return false;
+		}
+		true
+	}
+
+	/// The position at which the budget ran out, if it did
+	pub(crate) fn exceeded_at(&self) -> Option<TokenPos> {
+		self.exceeded_at.get()
+	}
+}
+
+#[derive(Clone, Debug, Copy, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct TokenPos {
 	idx: usize,
 	line: usize,
 	column: usize,
 }
 
+/// Positions are ordered by line, then by column
+impl Ord for TokenPos {
+	fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+		(self.line, self.column, self.idx).cmp(&(other.line, other.column, other.idx))
+	}
+}
+
+impl PartialOrd for TokenPos {
+	fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+		Some(self.cmp(other))
+	}
+}
+
 impl TokenPos {
 	pub fn new() -> Self {
 		Self {
@@ -162,3 +288,83 @@ impl TokenPos {
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn nth_token_looks_ahead() {
+		let engine = Kismesis::new();
+		let tokens = vec![
+			Token::Symbol('='),
+			Token::Symbol('='),
+			Token::Word("a".into()),
+		];
+		let state = ParserState::new(&tokens, None, &engine);
+		assert_eq!(state.nth_token(0), state.first_token());
+		assert_eq!(state.nth_token(1), Some(&Token::Symbol('=')));
+		assert_eq!(state.nth_token(2), Some(&Token::Word("a".into())));
+		assert_eq!(state.nth_token(3), None);
+		assert_eq!(state.nth_token(usize::MAX), None);
+
+		let state = state.next_state().next_state();
+		assert_eq!(state.nth_token(0), Some(&Token::Word("a".into())));
+		assert_eq!(state.nth_token(1), None);
+	}
+
+	#[test]
+	fn remaining_shrinks_as_the_state_advances() {
+		let engine = Kismesis::new();
+		let tokens = vec![
+			Token::Word("a".into()),
+			Token::Space(' '),
+			Token::Symbol('>'),
+		];
+		let state = ParserState::new(&tokens, None, &engine);
+		assert_eq!(state.remaining(), &tokens[..]);
+		// It borrows the stream itself rather than a copy of it
+		assert!(std::ptr::eq(state.remaining(), &tokens[..]));
+
+		let state = state.next_state();
+		assert_eq!(state.remaining(), &tokens[1..]);
+		let (token, state) = state.advanced();
+		assert_eq!(token, Some(&Token::Space(' ')));
+		assert_eq!(state.remaining(), &tokens[2..]);
+		let state = state.next_state();
+		assert!(state.remaining().is_empty());
+		assert!(state.next_state().remaining().is_empty());
+	}
+
+	#[test]
+	fn clones_are_snapshots_except_for_steps() {
+		let mut engine = Kismesis::new();
+		engine.set_parse_step_budget(Some(3));
+		let tokens = vec![
+			Token::Symbol('<'),
+			Token::Word("a".into()),
+			Token::Symbol('>'),
+		];
+		let state = ParserState::new(&tokens, None, &engine);
+		let snapshot = state.clone();
+		let attempt = |state: ParserState| {
+			let mut state = state.open_tag().next_state().next_state();
+			state.push_error(ParseError::TagCloserMismatch.error_at(&state).unpack());
+			Err::<ParserState, _>(ParseError::EmptyString)
+		};
+		assert!(attempt(state).is_err());
+
+		// Going back to the snapshot undoes everything the attempt did
+		let state = snapshot;
+		assert_eq!(state.position, TokenPos::new());
+		assert_eq!(state.remaining(), &tokens[..]);
+		assert!(state.errors.is_empty());
+		assert!(state.tag_openers.is_empty());
+		// but the two steps it took still count, so only one is left
+		let state = state.next_state();
+		assert_eq!(state.remaining(), &tokens[1..]);
+		let state = state.next_state();
+		assert!(state.remaining().is_empty());
+		assert!(state.steps.exceeded_at().is_some());
+	}
+}
@@ -1,4 +1,12 @@
-use std::{collections::HashMap, path::Path};
+use std::{
+	cell::RefCell,
+	collections::{HashMap, HashSet},
+	fmt,
+	ops::Deref,
+	path::{Path, PathBuf},
+	rc::Rc,
+	str::FromStr,
+};
 
 use rhai::Dynamic;
 
@@ -8,39 +16,153 @@ use super::state::TokenPos;
 
 pub type Scoped<'a, T> = (T, KisID);
 
+/// A tag or attribute name. Symbols that an engine made of the same name
+/// with [`Kismesis::intern`] share their storage, so cloning and comparing
+/// them is cheap.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Symbol(Rc<str>);
+
+impl Symbol {
+	pub fn as_str(&self) -> &str {
+		&self.0
+	}
+
+	/// Whether both symbols share their storage
+	pub fn ptr_eq(&self, other: &Self) -> bool {
+		Rc::ptr_eq(&self.0, &other.0)
+	}
+}
+
+/// The names an engine has made symbols of
+#[derive(Debug, Default)]
+pub(crate) struct Interner(RefCell<HashSet<Rc<str>>>);
+
+impl Interner {
+	pub(crate) fn intern(&self, name: &str) -> Symbol {
+		let mut names = self.0.borrow_mut();
+		if let Some(name) = names.get(name) {
+			return Symbol(name.clone());
+		}
+		let name: Rc<str> = name.into();
+		names.insert(name.clone());
+		Symbol(name)
+	}
+}
+
+impl From<&str> for Symbol {
+	fn from(name: &str) -> Self {
+		Self(name.into())
+	}
+}
+
+impl From<String> for Symbol {
+	fn from(name: String) -> Self {
+		Self(name.into())
+	}
+}
+
+impl Deref for Symbol {
+	type Target = str;
+
+	fn deref(&self) -> &Self::Target {
+		self.as_str()
+	}
+}
+
+impl PartialEq<str> for Symbol {
+	fn eq(&self, other: &str) -> bool {
+		self.as_str() == other
+	}
+}
+
+impl PartialEq<&str> for Symbol {
+	fn eq(&self, other: &&str) -> bool {
+		self.as_str() == *other
+	}
+}
+
+impl fmt::Display for Symbol {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(self.as_str())
+	}
+}
+
+/// Symbols are stored as their text. The ones that are read back don't
+/// share their storage
+#[cfg(feature = "serde")]
+impl serde::Serialize for Symbol {
+	fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+		serializer.serialize_str(self.as_str())
+	}
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Symbol {
+	fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+		let name = String::deserialize(deserializer)?;
+		Ok(Self::from(name))
+	}
+}
+
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum StringParts {
 	String(String),
 	Expression(Ranged<Expression>),
+	Entity(String),
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Attribute {
-	pub(crate) name: Ranged<String>,
+	pub(crate) name: Ranged<Symbol>,
 	pub(crate) value: Ranged<Expression>,
+	/// If set, the attribute is only written when this is truthy
+	pub(crate) condition: Option<Ranged<Expression>>,
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Argument {
 	pub(crate) name: Ranged<String>,
 	pub(crate) value: Option<Ranged<Expression>>,
 }
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct HtmlTag {
-	pub(crate) name: Ranged<String>,
+	/// The `svg` in `svg:rect`
+	pub(crate) namespace: Option<Ranged<Symbol>>,
+	pub(crate) name: Ranged<Symbol>,
+	/// In the order they were written in, which is also the order they're
+	/// generated in. Shorthands come first, since they're written right
+	/// after the name
 	pub(crate) attributes: Vec<Attribute>,
+	/// The expressions of `{...props}`, which give lists of `{name, value}`
+	/// pairs. Attributes written in `attributes` take precedence over spread
+	/// ones, and later spreads take precedence over earlier ones
+	pub(crate) spreads: Vec<Ranged<Expression>>,
 	pub(crate) body: Vec<HtmlNodes>,
 	pub(crate) subtags: Vec<HtmlTag>,
+	/// Set by `<-tag>`, removes the whitespace before the tag
+	pub(crate) trim_before: bool,
+	/// Set by `<tag->`, removes the whitespace after the tag
+	pub(crate) trim_after: bool,
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Macro {
 	pub(crate) name: Ranged<String>,
 	pub(crate) arguments: Vec<Argument>,
 	pub(crate) body: Vec<HtmlNodes>,
+	/// Set by `<macro pure name>`. The output of a pure macro only depends on
+	/// its arguments, so it's generated once for each set of them. Always
+	/// false for macro calls
+	pub(crate) pure: bool,
 }
 
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Section {
 	pub(crate) depth: usize,
 	pub(crate) name: Vec<StringParts>,
@@ -53,33 +175,45 @@ impl Section {
 		let mut tags = Vec::new();
 		let hstr = format!("h{}", self.depth);
 		let title = HtmlTag {
+			namespace: None,
 			name: Ranged {
-				value: hstr,
+				value: Symbol::from(hstr),
 				range: TextPos::Single(TokenPos::new()),
 			},
 			attributes: vec![],
+			spreads: vec![],
 			body: vec![HtmlNodes::String(self.name)],
 			subtags: vec![],
+			trim_before: false,
+			trim_after: false,
 		};
 		let header = match self.subtitle {
 			Some(subtitle) => {
 				let subtitle = HtmlTag {
+					namespace: None,
 					name: Ranged {
-						value: String::from("p"),
+						value: Symbol::from("p"),
 						range: TextPos::Single(TokenPos::new()),
 					},
 					attributes: vec![],
+					spreads: vec![],
 					body: vec![HtmlNodes::String(subtitle)],
 					subtags: vec![],
+					trim_before: false,
+					trim_after: false,
 				};
 				HtmlTag {
+					namespace: None,
 					name: Ranged {
-						value: String::from("hgroup"),
+						value: Symbol::from("hgroup"),
 						range: TextPos::Single(TokenPos::new()),
 					},
 					attributes: vec![],
+					spreads: vec![],
 					body: vec![HtmlNodes::HtmlTag(title), HtmlNodes::HtmlTag(subtitle)],
 					subtags: vec![],
+					trim_before: false,
+					trim_after: false,
 				}
 			}
 			None => title,
@@ -91,13 +225,17 @@ impl Section {
 			match (x.first().to_owned(), x.len()) {
 				(Some(HtmlNodes::String(_)), 1) | (Some(_), 2..) => {
 					let r = HtmlTag {
+						namespace: None,
 						name: Ranged {
-							value: String::from("p"),
+							value: Symbol::from("p"),
 							range: TextPos::Single(TokenPos::new()),
 						},
 						attributes: vec![],
+						spreads: vec![],
 						body: x,
 						subtags: vec![],
+						trim_before: false,
+						trim_after: false,
 					};
 					content.push(HtmlNodes::HtmlTag(r));
 				}
@@ -110,36 +248,69 @@ impl Section {
 		tags.append(&mut content);
 
 		HtmlTag {
+			namespace: None,
 			name: Ranged {
-				value: String::from("section"),
+				value: Symbol::from("section"),
 				range: TextPos::Single(TokenPos::new()),
 			},
 			attributes: vec![],
+			spreads: vec![],
 			body: tags,
 			subtags: vec![],
+			trim_before: false,
+			trim_after: false,
 		}
 	}
 }
 
+/// The literal text in a string, for things like search indexes. Each
+/// expression is replaced with `placeholder`, or left out if it's `None`.
+/// Entities are kept as they're written, like `&amp;`
+pub fn plain_text(parts: &[StringParts], placeholder: Option<&str>) -> String {
+	let mut output = String::new();
+	for part in parts {
+		match part {
+			StringParts::String(x) => output.push_str(x),
+			StringParts::Entity(x) => output.push_str(&format!("&{};", x)),
+			StringParts::Expression(_) => output.push_str(placeholder.unwrap_or_default()),
+		}
+	}
+	output
+}
+
 pub fn paragraph_str_to_p(vec: Vec<HtmlNodes>) -> HtmlTag {
 	HtmlTag {
+		namespace: None,
 		name: Ranged {
-			value: String::from("p"),
+			value: Symbol::from("p"),
 			range: TextPos::Single(TokenPos::new()),
 		},
 		attributes: vec![],
+		spreads: vec![],
 		body: vec,
 		subtags: vec![],
+		trim_before: false,
+		trim_after: false,
 	}
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct PlugCall {
 	pub(crate) name: Ranged<String>,
 	pub(crate) body: Vec<HtmlNodes>,
 }
 
+/// Content given to a named `content:name!` slot of a file's template.
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Slot {
+	pub(crate) name: Ranged<String>,
+	pub(crate) body: Vec<HtmlNodes>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum HtmlNodes {
 	HtmlTag(HtmlTag),
 	MacroCall(Macro),
@@ -147,19 +318,25 @@ pub enum HtmlNodes {
 	PlugCall(Box<PlugCall>),
 	Section(Section),
 	If(IfTag),
+	Env(EnvTag),
 	For(ForTag),
-	Content,
+	Content(Option<String>),
+	Include(Ranged<Vec<StringParts>>),
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum TopNodes {
 	HtmlTag(HtmlTag),
 	MacroCall(Macro),
 	PlugCall(Box<PlugCall>),
 	Section(Section),
-	Content,
+	Content(Option<String>),
+	Slot(Slot),
+	Include(Ranged<Vec<StringParts>>),
 	Doctype(String),
 	If(IfTag),
+	Env(EnvTag),
 	For(ForTag),
 }
 
@@ -170,8 +347,10 @@ pub enum BodyTags {
 	PlugCall(Box<PlugCall>),
 	Section(Section),
 	If(IfTag),
+	Env(EnvTag),
 	For(ForTag),
-	Content,
+	Content(Option<String>),
+	Include(Ranged<Vec<StringParts>>),
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -181,9 +360,12 @@ pub enum Tag {
 	MacroCall(Macro),
 	PlugCall(Box<PlugCall>),
 	Section(Section),
-	Content,
+	Content(Option<String>),
+	Slot(Slot),
+	Include(Ranged<Vec<StringParts>>),
 	Doctype(String),
 	If(IfTag),
+	Env(EnvTag),
 	For(ForTag),
 }
 
@@ -196,15 +378,20 @@ pub enum BodyNodes {
 	String(Vec<StringParts>),
 	LambdaDef(Lambda),
 	VarDef(Variable),
-	Content,
-	SetStmt(String, String),
+	Content(Option<String>),
+	Slot(Slot),
+	Include(Ranged<Vec<StringParts>>),
+	SetStmt(Ranged<String>, Ranged<String>),
+	Extends(Ranged<PathBuf>),
 	Section(Section),
 	Doctype(String),
 	If(IfTag),
+	Env(EnvTag),
 	For(ForTag),
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ParsedFile {
 	pub file_id: KisID,
 	pub body: Vec<TopNodes>,
@@ -214,6 +401,65 @@ pub struct ParsedFile {
 	pub template: Option<KisTemplateID>,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolKind {
+	Macro,
+	Variable,
+	Lambda,
+}
+
+/// A macro, variable or lambda defined in a file
+#[derive(Debug, Clone, PartialEq)]
+pub struct DefinedSymbol {
+	pub name: String,
+	pub kind: SymbolKind,
+	/// The arguments of a macro, empty for variables and lambdas
+	pub arguments: Vec<SymbolArgument>,
+	/// Where the symbol's name was written
+	pub position: TextPos,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SymbolArgument {
+	pub name: String,
+	pub default: Option<Expression>,
+}
+
+/// Identifies something in a file across different versions of it
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum AstNodeId {
+	Macro(String),
+	Variable(String),
+	Lambda(String),
+	/// A node of the file's body, by its index
+	Body(usize),
+	/// The template the file extends
+	Template,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AstChange {
+	Added(AstNodeId),
+	Removed(AstNodeId),
+	Changed(AstNodeId),
+}
+
+/// Every symbol defined in a file, in the order they were defined in
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct SymbolTable {
+	pub symbols: Vec<DefinedSymbol>,
+}
+
+impl SymbolTable {
+	pub fn get(&self, name: &str) -> Option<&DefinedSymbol> {
+		self.symbols.iter().rfind(|x| x.name == name)
+	}
+
+	pub fn of_kind(&self, kind: SymbolKind) -> impl Iterator<Item = &DefinedSymbol> {
+		self.symbols.iter().filter(move |x| x.kind == kind)
+	}
+}
+
 pub enum VariableOption<T> {
 	Some(T),
 	None,  // The variable was not found
@@ -232,27 +478,134 @@ impl ParsedFile {
 		}
 	}
 
+	/// The `extends` statement that gives this file its template, if the
+	/// template came from a file
+	pub(crate) fn extends_statement(&self) -> Option<String> {
+		match self.template {
+			Some(KisTemplateID::File(ref path)) => {
+				let path = path.to_string_lossy().to_string();
+				let path = Expression::Literal(vec![StringParts::String(path)]);
+				Some(format!("extends {}", path))
+			}
+			_ => None,
+		}
+	}
+
+	/// The template this file extends. A template the engine doesn't have is
+	/// treated like no template at all
+	fn get_template<'a>(&self, engine: &'a Kismesis) -> Option<&'a ParsedFile> {
+		engine.get_template(self.template.clone()?)
+	}
+
 	pub fn get_macro_template<'a>(
 		&'a self,
 		engine: &'a Kismesis,
 		predicate: impl Fn(&&Macro) -> bool,
 	) -> Option<&Macro> {
 		self.defined_macros.iter().rfind(&predicate).or(self
-			.template
-			.as_ref()
-			.map(|x| {
-				engine
-					.get_template(x.clone())
-					.unwrap()
-					.get_macro_template(engine, &predicate)
-			})
-			.unwrap_or(None))
+			.get_template(engine)
+			.and_then(|x| x.get_macro_template(engine, &predicate)))
+	}
+
+	/// Lists the symbols defined in this file, without the ones in its
+	/// template
+	pub fn symbols(&self) -> SymbolTable {
+		let macros = self.defined_macros.iter().map(|x| DefinedSymbol {
+			name: x.name.value.clone(),
+			kind: SymbolKind::Macro,
+			arguments: x
+				.arguments
+				.iter()
+				.map(|arg| SymbolArgument {
+					name: arg.name.value.clone(),
+					default: arg.value.as_ref().map(|x| x.value.clone()),
+				})
+				.collect(),
+			position: x.name.range.clone(),
+		});
+		let variables = self.defined_variables.iter().map(|x| DefinedSymbol {
+			name: x.name.value.clone(),
+			kind: SymbolKind::Variable,
+			arguments: vec![],
+			position: x.name.range.clone(),
+		});
+		let lambdas = self.defined_lambdas.iter().map(|x| DefinedSymbol {
+			name: x.name.value.clone(),
+			kind: SymbolKind::Lambda,
+			arguments: vec![],
+			position: x.name.range.clone(),
+		});
+		let mut symbols: Vec<DefinedSymbol> = macros.chain(variables).chain(lambdas).collect();
+		symbols.sort_by_key(|x| x.position.get_start().get_idx());
+		SymbolTable { symbols }
+	}
+
+	/// Lists what changed between this file and a newer version of it.
+	///
+	/// Macros, variables and lambdas are matched by name, using their last
+	/// definition like the generator does. Body nodes are matched by index, so
+	/// inserting a node changes every node after it. Positions aren't
+	/// compared, so moving a definition without changing it isn't a change
+	pub fn diff(&self, other: &ParsedFile) -> Vec<AstChange> {
+		let mut changes = Vec::new();
+		diff_named(
+			&self.defined_macros,
+			&other.defined_macros,
+			|x| &x.name.value,
+			AstNodeId::Macro,
+			&mut changes,
+		);
+		diff_named(
+			&self.defined_variables,
+			&other.defined_variables,
+			|x| &x.name.value,
+			AstNodeId::Variable,
+			&mut changes,
+		);
+		diff_named(
+			&self.defined_lambdas,
+			&other.defined_lambdas,
+			|x| &x.name.value,
+			AstNodeId::Lambda,
+			&mut changes,
+		);
+		for idx in 0..self.body.len().max(other.body.len()) {
+			match (self.body.get(idx), other.body.get(idx)) {
+				(Some(x), Some(y)) if !x.eq_ignoring_span(y) => {
+					changes.push(AstChange::Changed(AstNodeId::Body(idx)))
+				}
+				(Some(_), None) => changes.push(AstChange::Removed(AstNodeId::Body(idx))),
+				(None, Some(_)) => changes.push(AstChange::Added(AstNodeId::Body(idx))),
+				_ => (),
+			}
+		}
+		match (&self.template, &other.template) {
+			(Some(_), None) => changes.push(AstChange::Removed(AstNodeId::Template)),
+			(None, Some(_)) => changes.push(AstChange::Added(AstNodeId::Template)),
+			(Some(x), Some(y)) if x != y => changes.push(AstChange::Changed(AstNodeId::Template)),
+			_ => (),
+		}
+		changes
 	}
 
 	pub fn get_path_slice<'a>(&'a self, engine: &'a Kismesis) -> Option<&Path> {
 		engine.get_file(self.file_id)?.path.as_deref()
 	}
 
+	/// Where a token position is in the file's source, in bytes. The
+	/// position right after the last token is the end of the source. Only
+	/// files registered from source text know their offsets
+	pub fn byte_offset(&self, engine: &Kismesis, pos: &TokenPos) -> Option<usize> {
+		let offsets = engine.get_file(self.file_id)?.offsets.as_ref()?;
+		match offsets.get(pos.get_idx()) {
+			Some(range) => Some(range.start),
+			None if pos.get_idx() == offsets.len() => {
+				Some(offsets.last().map_or(0, |range| range.end))
+			}
+			None => None,
+		}
+	}
+
 	pub fn get_variable_value<'a>(
 		&'a self,
 		engine: &'a Kismesis,
@@ -273,24 +626,16 @@ impl ParsedFile {
 			}
 		}
 
-		match self.template {
-			Some(ref template) => engine
-				.get_template(template.clone())
-				.unwrap()
-				.get_variable_value(engine, predicate),
+		match self.get_template(engine) {
+			Some(template) => template.get_variable_value(engine, predicate),
 			None => VariableOption::None,
 		}
 	}
 
 	pub fn get_macro_scope<'a>(&'a self, engine: &'a Kismesis) -> HashMap<String, Scoped<&Macro>> {
 		let mut output = HashMap::new();
-		if let Some(ref template) = self.template {
-			output.extend(
-				engine
-					.get_template(template.clone())
-					.unwrap()
-					.get_macro_scope(engine),
-			)
+		if let Some(template) = self.get_template(engine) {
+			output.extend(template.get_macro_scope(engine))
 		}
 
 		output.extend(
@@ -309,13 +654,8 @@ impl ParsedFile {
 	) -> HashMap<String, Scoped<(Option<&Ranged<Expression>>, TextPos)>> {
 		let mut out = HashMap::new();
 
-		if let Some(ref template) = self.template {
-			out.extend(
-				engine
-					.get_template(template.clone())
-					.unwrap()
-					.get_variable_scope(&[], engine),
-			)
+		if let Some(template) = self.get_template(engine) {
+			out.extend(template.get_variable_scope(&[], engine))
 		}
 
 		out.extend(self.defined_lambdas.iter().map(|x| {
@@ -355,6 +695,41 @@ impl ParsedFile {
 	}
 }
 
+/// Compares definitions by name, reporting the ones that were removed or
+/// changed in the order of `old`, then the ones that were added in the order
+/// of `new`
+fn diff_named<T: SpanlessEq>(
+	old: &[T],
+	new: &[T],
+	name: impl Fn(&T) -> &String,
+	id: impl Fn(String) -> AstNodeId,
+	changes: &mut Vec<AstChange>,
+) {
+	let mut seen: Vec<&String> = Vec::new();
+	for item in old.iter() {
+		let item_name = name(item);
+		if seen.contains(&item_name) {
+			continue;
+		}
+		seen.push(item_name);
+		let old_item = old.iter().rfind(|x| name(x) == item_name);
+		match (old_item, new.iter().rfind(|x| name(x) == item_name)) {
+			(_, None) => changes.push(AstChange::Removed(id(item_name.clone()))),
+			(Some(x), Some(y)) if !x.eq_ignoring_span(y) => {
+				changes.push(AstChange::Changed(id(item_name.clone())))
+			}
+			_ => (),
+		}
+	}
+	for item in new.iter() {
+		let item_name = name(item);
+		if !seen.contains(&item_name) {
+			seen.push(item_name);
+			changes.push(AstChange::Added(id(item_name.clone())));
+		}
+	}
+}
+
 impl From<Tag> for BodyNodes {
 	fn from(value: Tag) -> Self {
 		match value {
@@ -363,9 +738,12 @@ impl From<Tag> for BodyNodes {
 			Tag::MacroDef(x) => Self::MacroDef(x),
 			Tag::PlugCall(x) => Self::PlugCall(x),
 			Tag::Section(x) => Self::Section(x),
-			Tag::Content => Self::Content,
+			Tag::Content(x) => Self::Content(x),
+			Tag::Slot(x) => Self::Slot(x),
+			Tag::Include(x) => Self::Include(x),
 			Tag::Doctype(x) => Self::Doctype(x),
 			Tag::If(x) => Self::If(x),
+			Tag::Env(x) => Self::Env(x),
 			Tag::For(x) => Self::For(x),
 		}
 	}
@@ -378,8 +756,10 @@ impl From<BodyTags> for BodyNodes {
 			BodyTags::MacroCall(x) => Self::MacroCall(x),
 			BodyTags::PlugCall(x) => Self::PlugCall(x),
 			BodyTags::Section(x) => Self::Section(x),
-			BodyTags::Content => Self::Content,
+			BodyTags::Content(x) => Self::Content(x),
+			BodyTags::Include(x) => Self::Include(x),
 			BodyTags::If(x) => Self::If(x),
+			BodyTags::Env(x) => Self::Env(x),
 			BodyTags::For(x) => Self::For(x),
 		}
 	}
@@ -391,29 +771,57 @@ impl From<BodyTags> for HtmlNodes {
 			BodyTags::HtmlTag(x) => Self::HtmlTag(x),
 			BodyTags::MacroCall(x) => Self::MacroCall(x),
 			BodyTags::PlugCall(x) => Self::PlugCall(x),
-			BodyTags::Content => Self::Content,
+			BodyTags::Content(x) => Self::Content(x),
+			BodyTags::Include(x) => Self::Include(x),
 			BodyTags::Section(x) => Self::Section(x),
 			BodyTags::If(x) => Self::If(x),
+			BodyTags::Env(x) => Self::Env(x),
 			BodyTags::For(x) => Self::For(x),
 		}
 	}
 }
 
+/// Strings aren't tags, so they're given back as they were
+impl TryFrom<HtmlNodes> for BodyTags {
+	type Error = HtmlNodes;
+
+	fn try_from(value: HtmlNodes) -> Result<Self, Self::Error> {
+		match value {
+			HtmlNodes::HtmlTag(x) => Ok(Self::HtmlTag(x)),
+			HtmlNodes::MacroCall(x) => Ok(Self::MacroCall(x)),
+			HtmlNodes::PlugCall(x) => Ok(Self::PlugCall(x)),
+			HtmlNodes::Content(x) => Ok(Self::Content(x)),
+			HtmlNodes::Include(x) => Ok(Self::Include(x)),
+			HtmlNodes::Section(x) => Ok(Self::Section(x)),
+			HtmlNodes::If(x) => Ok(Self::If(x)),
+			HtmlNodes::Env(x) => Ok(Self::Env(x)),
+			HtmlNodes::For(x) => Ok(Self::For(x)),
+			HtmlNodes::String(x) => Err(HtmlNodes::String(x)),
+		}
+	}
+}
+
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum BinFunc {
 	And,
 	Or,
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum UniFunc {
 	Not,
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Expression {
+	/// `{!}`. Interpolating it directly writes nothing
 	None,
 	Variable(String),
+	/// `user.name`, a field of some other value
+	Member(Box<Ranged<Expression>>, String),
 	Literal(Vec<StringParts>),
 	BinFunc(BinFunc, Box<Ranged<Expression>>, Box<Ranged<Expression>>),
 	UniFunc(UniFunc, Box<Ranged<Expression>>),
@@ -421,38 +829,58 @@ pub enum Expression {
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Variable {
 	pub name: Ranged<String>,
 	pub value: Ranged<Expression>,
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Lambda {
 	pub name: Ranged<String>,
 	pub value: Option<Ranged<Expression>>,
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Ranged<T> {
 	pub(crate) value: T,
 	pub(crate) range: TextPos,
 }
 
-impl Ranged<&str> {
-	pub fn to_own(&self) -> Ranged<String> {
+impl<T> Ranged<T> {
+	/// Transforms the value, keeping the range it came from
+	pub fn map<U>(self, f: impl FnOnce(T) -> U) -> Ranged<U> {
 		Ranged {
-			value: self.value.to_owned(),
+			value: f(self.value),
+			range: self.range,
+		}
+	}
+
+	pub fn as_ref(&self) -> Ranged<&T> {
+		Ranged {
+			value: &self.value,
 			range: self.range.clone(),
 		}
 	}
 }
 
+impl Ranged<&str> {
+	pub fn to_own(&self) -> Ranged<String> {
+		self.clone().map(str::to_owned)
+	}
+}
+
+impl<T: AsRef<str>> Ranged<T> {
+	pub fn to_symbol(&self, engine: &Kismesis) -> Ranged<Symbol> {
+		self.as_ref().map(|x| engine.intern(x.as_ref()))
+	}
+}
+
 impl Ranged<Dynamic> {
 	pub fn cast<T: Clone + 'static>(self) -> Ranged<T> {
-		Ranged {
-			value: self.value.cast(),
-			range: self.range.clone(),
-		}
+		self.map(Dynamic::cast)
 	}
 }
 
@@ -534,7 +962,8 @@ impl AstNode for Ranged<Expression> {
 	}
 }
 */
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum TextPos {
 	Single(TokenPos),
 	Range((TokenPos, TokenPos)),
@@ -542,6 +971,14 @@ pub enum TextPos {
 }
 
 impl TextPos {
+	pub fn get_start(&self) -> TokenPos {
+		match self {
+			Self::Single(x) => *x,
+			Self::Range(x) => x.0,
+			Self::Multi(x) => x[0].get_start(),
+		}
+	}
+
 	pub fn get_start_line(&self) -> usize {
 		match self {
 			Self::Single(x) => x.get_line(),
@@ -580,9 +1017,157 @@ impl Macro {
 
 		output
 	}
+
+	pub fn get_argument(&self, name: &str) -> Option<&Argument> {
+		self.arguments.iter().find(|x| x.name.value == name)
+	}
 }
 
+/// The elements that can't have any content, and so are written without a
+/// closing tag
+pub const VOID_ELEMENTS: &[&str] = &[
+	"area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "source", "track",
+	"wbr",
+];
+
+/// Every element in the HTML standard, not counting obsolete ones
+pub const HTML_ELEMENTS: &[&str] = &[
+	"a",
+	"abbr",
+	"address",
+	"area",
+	"article",
+	"aside",
+	"audio",
+	"b",
+	"base",
+	"bdi",
+	"bdo",
+	"blockquote",
+	"body",
+	"br",
+	"button",
+	"canvas",
+	"caption",
+	"cite",
+	"code",
+	"col",
+	"colgroup",
+	"data",
+	"datalist",
+	"dd",
+	"del",
+	"details",
+	"dfn",
+	"dialog",
+	"div",
+	"dl",
+	"dt",
+	"em",
+	"embed",
+	"fieldset",
+	"figcaption",
+	"figure",
+	"footer",
+	"form",
+	"h1",
+	"h2",
+	"h3",
+	"h4",
+	"h5",
+	"h6",
+	"head",
+	"header",
+	"hgroup",
+	"hr",
+	"html",
+	"i",
+	"iframe",
+	"img",
+	"input",
+	"ins",
+	"kbd",
+	"label",
+	"legend",
+	"li",
+	"link",
+	"main",
+	"map",
+	"mark",
+	"menu",
+	"meta",
+	"meter",
+	"nav",
+	"noscript",
+	"object",
+	"ol",
+	"optgroup",
+	"option",
+	"output",
+	"p",
+	"picture",
+	"pre",
+	"progress",
+	"q",
+	"rp",
+	"rt",
+	"ruby",
+	"s",
+	"samp",
+	"script",
+	"search",
+	"section",
+	"select",
+	"slot",
+	"small",
+	"source",
+	"span",
+	"strong",
+	"style",
+	"sub",
+	"summary",
+	"sup",
+	"table",
+	"tbody",
+	"td",
+	"template",
+	"textarea",
+	"tfoot",
+	"th",
+	"thead",
+	"time",
+	"title",
+	"tr",
+	"track",
+	"u",
+	"ul",
+	"var",
+	"video",
+	"wbr",
+];
+
 impl HtmlTag {
+	/// Namespaced tags are never void, since they're not HTML elements
+	pub fn is_void_element(&self) -> bool {
+		self.namespace.is_none() && VOID_ELEMENTS.contains(&self.name.value.as_str())
+	}
+
+	/// The name as it's written in HTML, including the namespace if any
+	pub fn qualified_name(&self) -> String {
+		match self.namespace {
+			Some(ref namespace) => format!("{}:{}", namespace, self.name),
+			None => self.name.value.to_string(),
+		}
+	}
+
+	/// Finds the first attribute with the given name. HTML attribute names
+	/// aren't case-sensitive, so neither is this
+	pub fn get_attribute(&self, name: &str) -> Option<&Attribute> {
+		self.attributes
+			.iter()
+			.find(|x| x.name.value.eq_ignore_ascii_case(name))
+	}
+
 	pub fn merge_subtags(mut self) -> Self {
 		let mut subtag_stack = self.subtags;
 		let Some(top) = subtag_stack.last_mut() else {
@@ -608,14 +1193,911 @@ impl HtmlTag {
 }
 
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct IfTag {
 	pub condition: Ranged<Expression>,
 	pub body: Vec<HtmlNodes>,
+	/// The body of the `<else>` tag right after this one, if there is one
+	pub else_body: Option<Vec<HtmlNodes>>,
+}
+
+/// Content that's only written when building for the environment called
+/// `name`, like `<env "production" | ...>`
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct EnvTag {
+	pub name: Ranged<String>,
+	pub body: Vec<HtmlNodes>,
 }
 
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ForTag {
 	pub variable: Ranged<String>,
 	pub iterator: Ranged<Expression>,
 	pub body: Vec<HtmlNodes>,
 }
+
+fn write_string_parts(
+	f: &mut fmt::Formatter<'_>,
+	parts: &[StringParts],
+	escaped: &[char],
+) -> fmt::Result {
+	for part in parts {
+		match part {
+			StringParts::String(string) => {
+				for chr in string.chars() {
+					if escaped.contains(&chr) {
+						write!(f, "\\")?;
+					}
+					write!(f, "{}", chr)?;
+				}
+			}
+			StringParts::Expression(expr) => write!(f, "@{}", expr)?,
+			StringParts::Entity(name) => write!(f, "&{};", name)?,
+		}
+	}
+	Ok(())
+}
+
+fn write_body(f: &mut fmt::Formatter<'_>, body: &[HtmlNodes]) -> fmt::Result {
+	if body.is_empty() {
+		return Ok(());
+	}
+	write!(f, " | ")?;
+	for node in body {
+		write!(f, "{}", node)?;
+	}
+	Ok(())
+}
+
+fn write_content(f: &mut fmt::Formatter<'_>, slot: &Option<String>) -> fmt::Result {
+	match slot {
+		Some(name) => write!(f, "<content:{}!>", name),
+		None => write!(f, "<content!>"),
+	}
+}
+
+fn write_include(f: &mut fmt::Formatter<'_>, path: &[StringParts]) -> fmt::Result {
+	write!(f, "<include \"")?;
+	write_string_parts(f, path, &['@', '"', '\\'])?;
+	write!(f, "\">")
+}
+
+/// Displays a body the way it's written after a tag's head, including the
+/// leading ` |`
+pub(crate) struct InlineBody<'a>(pub(crate) &'a [HtmlNodes]);
+
+impl fmt::Display for InlineBody<'_> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write_body(f, self.0)
+	}
+}
+
+impl fmt::Display for StringParts {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write_string_parts(f, std::slice::from_ref(self), &['@', '<', '>', '\\'])
+	}
+}
+
+impl fmt::Display for BinFunc {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::And => write!(f, "and"),
+			Self::Or => write!(f, "or"),
+		}
+	}
+}
+
+impl fmt::Display for UniFunc {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Not => write!(f, "not"),
+		}
+	}
+}
+
+impl FromStr for BinFunc {
+	type Err = ();
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"and" => Ok(Self::And),
+			"or" => Ok(Self::Or),
+			_ => Err(()),
+		}
+	}
+}
+
+impl FromStr for UniFunc {
+	type Err = ();
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"not" => Ok(Self::Not),
+			_ => Err(()),
+		}
+	}
+}
+
+impl fmt::Display for Expression {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::None => write!(f, "{{!}}"),
+			Self::Variable(name) => write!(f, "{}", name),
+			Self::Member(object, field) => write!(f, "{}.{}", object, field),
+			Self::Literal(parts) => {
+				write!(f, "\"")?;
+				write_string_parts(f, parts, &['@', '"', '\\'])?;
+				write!(f, "\"")
+			}
+			Self::BinFunc(func, x, y) => write!(f, "{{{} {} {}}}", x, func, y),
+			Self::UniFunc(func, x) => write!(f, "{{{} {}}}", func, x),
+			Self::Array(values) => {
+				write!(f, "{{")?;
+				for (idx, value) in values.iter().enumerate() {
+					if idx != 0 {
+						write!(f, ", ")?;
+					}
+					write!(f, "{}", value)?;
+				}
+				// `{a}` would be just `a`
+				if values.len() == 1 {
+					write!(f, ",")?;
+				}
+				write!(f, "}}")
+			}
+		}
+	}
+}
+
+impl<T: fmt::Display> fmt::Display for Ranged<T> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		self.value.fmt(f)
+	}
+}
+
+impl fmt::Display for Attribute {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}={}", self.name, self.value)?;
+		match self.condition {
+			Some(ref condition) => match condition.value {
+				Expression::Variable(_) | Expression::Member(..) | Expression::Literal(_) => {
+					write!(f, "?{{{}}}", condition)
+				}
+				_ => write!(f, "?{}", condition),
+			},
+			None => Ok(()),
+		}
+	}
+}
+
+impl fmt::Display for Argument {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self.value {
+			Some(ref value) => write!(f, "{}={}", self.name, value),
+			None => write!(f, "{}", self.name),
+		}
+	}
+}
+
+impl fmt::Display for HtmlTag {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "<")?;
+		if self.trim_before {
+			write!(f, "-")?;
+		}
+		write!(f, "{}", self.qualified_name())?;
+		for attribute in self.attributes.iter() {
+			write!(f, " {}", attribute)?;
+		}
+		for spread in self.spreads.iter() {
+			write!(f, " {{...{}}}", spread)?;
+		}
+		for subtag in self.subtags.iter() {
+			write!(f, " +{}", subtag.qualified_name())?;
+			for attribute in subtag.attributes.iter() {
+				write!(f, " {}", attribute)?;
+			}
+		}
+		write_body(f, &self.body)?;
+		if self.trim_after {
+			write!(f, "-")?;
+		}
+		write!(f, ">")
+	}
+}
+
+/// Displays a macro as a call. Use [`Macro::display_definition`] to
+/// display it as a `<macro ...>` definition instead.
+impl fmt::Display for Macro {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "<{}!", self.name)?;
+		for argument in self.arguments.iter() {
+			write!(f, " {}", argument)?;
+		}
+		write!(f, ">")
+	}
+}
+
+impl fmt::Display for Slot {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "<content:{}!", self.name)?;
+		write_body(f, &self.body)?;
+		write!(f, ">")
+	}
+}
+
+pub struct MacroDefinition<'a>(&'a Macro);
+
+impl MacroDefinition<'_> {
+	/// The definition up to its body, from `<macro` to the last argument
+	pub fn head(&self) -> String {
+		let mut head = String::from("<macro ");
+		if self.0.pure {
+			head.push_str("pure ");
+		}
+		head.push_str(&self.0.name.value);
+		for argument in self.0.arguments.iter() {
+			head.push_str(&format!(" {}", argument));
+		}
+		head
+	}
+}
+
+impl fmt::Display for MacroDefinition<'_> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}", self.head())?;
+		write_body(f, &self.0.body)?;
+		write!(f, ">")
+	}
+}
+
+impl Macro {
+	pub fn display_definition(&self) -> MacroDefinition<'_> {
+		MacroDefinition(self)
+	}
+}
+
+impl fmt::Display for PlugCall {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "<{}?>", self.name)
+	}
+}
+
+impl fmt::Display for IfTag {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "<if {}", self.condition)?;
+		write_body(f, &self.body)?;
+		write!(f, ">")?;
+		if let Some(else_body) = &self.else_body {
+			write!(f, " <else")?;
+			write_body(f, else_body)?;
+			write!(f, ">")?;
+		}
+		Ok(())
+	}
+}
+
+impl fmt::Display for EnvTag {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let name = Expression::Literal(vec![StringParts::String(self.name.value.clone())]);
+		write!(f, "<env {}", name)?;
+		write_body(f, &self.body)?;
+		write!(f, ">")
+	}
+}
+
+impl fmt::Display for ForTag {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "<for {} in {}", self.variable, self.iterator)?;
+		write_body(f, &self.body)?;
+		write!(f, ">")
+	}
+}
+
+impl fmt::Display for Section {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{} ", "#".repeat(self.depth))?;
+		write_string_parts(f, &self.name, &['@', '<', '>', '\\'])?;
+		if let Some(ref subtitle) = self.subtitle {
+			write!(f, "\n#: ")?;
+			write_string_parts(f, subtitle, &['@', '<', '>', '\\'])?;
+		}
+		for paragraph in self.content.iter() {
+			writeln!(f)?;
+			for node in paragraph {
+				write!(f, "{}", node)?;
+			}
+		}
+		Ok(())
+	}
+}
+
+impl fmt::Display for HtmlNodes {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::HtmlTag(x) => x.fmt(f),
+			Self::MacroCall(x) => x.fmt(f),
+			Self::String(x) => write_string_parts(f, x, &['@', '<', '>', '\\']),
+			Self::PlugCall(x) => x.fmt(f),
+			Self::Section(x) => x.fmt(f),
+			Self::If(x) => x.fmt(f),
+			Self::Env(x) => x.fmt(f),
+			Self::For(x) => x.fmt(f),
+			Self::Content(x) => write_content(f, x),
+			Self::Include(x) => write_include(f, &x.value),
+		}
+	}
+}
+
+impl fmt::Display for TopNodes {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::HtmlTag(x) => x.fmt(f),
+			Self::MacroCall(x) => x.fmt(f),
+			Self::PlugCall(x) => x.fmt(f),
+			Self::Section(x) => x.fmt(f),
+			Self::Content(x) => write_content(f, x),
+			Self::Include(x) => write_include(f, &x.value),
+			Self::Slot(x) => x.fmt(f),
+			Self::Doctype(x) => write!(f, "<!doctype {}>", x),
+			Self::If(x) => x.fmt(f),
+			Self::Env(x) => x.fmt(f),
+			Self::For(x) => x.fmt(f),
+		}
+	}
+}
+
+impl fmt::Display for BodyTags {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::HtmlTag(x) => x.fmt(f),
+			Self::MacroCall(x) => x.fmt(f),
+			Self::PlugCall(x) => x.fmt(f),
+			Self::Section(x) => x.fmt(f),
+			Self::If(x) => x.fmt(f),
+			Self::Env(x) => x.fmt(f),
+			Self::For(x) => x.fmt(f),
+			Self::Content(x) => write_content(f, x),
+			Self::Include(x) => write_include(f, &x.value),
+		}
+	}
+}
+
+impl fmt::Display for Variable {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "const {} = {}", self.name, self.value)
+	}
+}
+
+impl fmt::Display for Lambda {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self.value {
+			Some(ref value) => write!(f, "mut {} = {}", self.name, value),
+			None => write!(f, "mut {}", self.name),
+		}
+	}
+}
+
+impl fmt::Display for ParsedFile {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		if let Some(extends) = self.extends_statement() {
+			writeln!(f, "{}", extends)?;
+		}
+		for variable in self.defined_variables.iter() {
+			writeln!(f, "{}", variable)?;
+		}
+		for lambda in self.defined_lambdas.iter() {
+			writeln!(f, "{}", lambda)?;
+		}
+		for mac in self.defined_macros.iter() {
+			writeln!(f, "{}", mac.display_definition())?;
+		}
+		for node in self.body.iter() {
+			writeln!(f, "{}", node)?;
+		}
+		Ok(())
+	}
+}
+
+/// Structural equality that doesn't take source positions into account,
+/// so the same code parsed from different places compares as equal.
+pub trait SpanlessEq {
+	fn eq_ignoring_span(&self, other: &Self) -> bool;
+}
+
+impl<T: SpanlessEq> SpanlessEq for Ranged<T> {
+	fn eq_ignoring_span(&self, other: &Self) -> bool {
+		self.value.eq_ignoring_span(&other.value)
+	}
+}
+
+impl<T: SpanlessEq> SpanlessEq for [T] {
+	fn eq_ignoring_span(&self, other: &Self) -> bool {
+		self.len() == other.len()
+			&& self
+				.iter()
+				.zip(other.iter())
+				.all(|(x, y)| x.eq_ignoring_span(y))
+	}
+}
+
+impl<T: SpanlessEq> SpanlessEq for Vec<T> {
+	fn eq_ignoring_span(&self, other: &Self) -> bool {
+		self.as_slice().eq_ignoring_span(other.as_slice())
+	}
+}
+
+impl<T: SpanlessEq> SpanlessEq for Option<T> {
+	fn eq_ignoring_span(&self, other: &Self) -> bool {
+		match (self, other) {
+			(Some(x), Some(y)) => x.eq_ignoring_span(y),
+			(None, None) => true,
+			_ => false,
+		}
+	}
+}
+
+impl<T: SpanlessEq> SpanlessEq for Box<T> {
+	fn eq_ignoring_span(&self, other: &Self) -> bool {
+		self.as_ref().eq_ignoring_span(other.as_ref())
+	}
+}
+
+impl SpanlessEq for String {
+	fn eq_ignoring_span(&self, other: &Self) -> bool {
+		self == other
+	}
+}
+
+impl SpanlessEq for Symbol {
+	fn eq_ignoring_span(&self, other: &Self) -> bool {
+		self == other
+	}
+}
+
+impl SpanlessEq for StringParts {
+	fn eq_ignoring_span(&self, other: &Self) -> bool {
+		match (self, other) {
+			(Self::Expression(x), Self::Expression(y)) => x.eq_ignoring_span(y),
+			_ => self == other,
+		}
+	}
+}
+
+impl SpanlessEq for Expression {
+	fn eq_ignoring_span(&self, other: &Self) -> bool {
+		match (self, other) {
+			(Self::Literal(x), Self::Literal(y)) => x.eq_ignoring_span(y),
+			(Self::BinFunc(f1, x1, y1), Self::BinFunc(f2, x2, y2)) => {
+				f1 == f2 && x1.eq_ignoring_span(x2) && y1.eq_ignoring_span(y2)
+			}
+			(Self::UniFunc(f1, x1), Self::UniFunc(f2, x2)) => f1 == f2 && x1.eq_ignoring_span(x2),
+			(Self::Member(x1, f1), Self::Member(x2, f2)) => f1 == f2 && x1.eq_ignoring_span(x2),
+			(Self::Array(x), Self::Array(y)) => x.eq_ignoring_span(y),
+			_ => self == other,
+		}
+	}
+}
+
+impl SpanlessEq for Attribute {
+	fn eq_ignoring_span(&self, other: &Self) -> bool {
+		self.name.eq_ignoring_span(&other.name)
+			&& self.value.eq_ignoring_span(&other.value)
+			&& self.condition.eq_ignoring_span(&other.condition)
+	}
+}
+
+impl SpanlessEq for Argument {
+	fn eq_ignoring_span(&self, other: &Self) -> bool {
+		self.name.eq_ignoring_span(&other.name) && self.value.eq_ignoring_span(&other.value)
+	}
+}
+
+impl SpanlessEq for HtmlTag {
+	fn eq_ignoring_span(&self, other: &Self) -> bool {
+		self.namespace.eq_ignoring_span(&other.namespace)
+			&& self.name.eq_ignoring_span(&other.name)
+			&& self.attributes.eq_ignoring_span(&other.attributes)
+			&& self.spreads.eq_ignoring_span(&other.spreads)
+			&& self.body.eq_ignoring_span(&other.body)
+			&& self.subtags.eq_ignoring_span(&other.subtags)
+			&& self.trim_before == other.trim_before
+			&& self.trim_after == other.trim_after
+	}
+}
+
+impl SpanlessEq for Macro {
+	fn eq_ignoring_span(&self, other: &Self) -> bool {
+		self.name.eq_ignoring_span(&other.name)
+			&& self.arguments.eq_ignoring_span(&other.arguments)
+			&& self.body.eq_ignoring_span(&other.body)
+			&& self.pure == other.pure
+	}
+}
+
+impl SpanlessEq for Variable {
+	fn eq_ignoring_span(&self, other: &Self) -> bool {
+		self.name.eq_ignoring_span(&other.name) && self.value.eq_ignoring_span(&other.value)
+	}
+}
+
+impl SpanlessEq for Lambda {
+	fn eq_ignoring_span(&self, other: &Self) -> bool {
+		self.name.eq_ignoring_span(&other.name) && self.value.eq_ignoring_span(&other.value)
+	}
+}
+
+impl SpanlessEq for PlugCall {
+	fn eq_ignoring_span(&self, other: &Self) -> bool {
+		self.name.eq_ignoring_span(&other.name) && self.body.eq_ignoring_span(&other.body)
+	}
+}
+
+impl SpanlessEq for IfTag {
+	fn eq_ignoring_span(&self, other: &Self) -> bool {
+		self.condition.eq_ignoring_span(&other.condition)
+			&& self.body.eq_ignoring_span(&other.body)
+			&& self.else_body.eq_ignoring_span(&other.else_body)
+	}
+}
+
+impl SpanlessEq for EnvTag {
+	fn eq_ignoring_span(&self, other: &Self) -> bool {
+		self.name.eq_ignoring_span(&other.name) && self.body.eq_ignoring_span(&other.body)
+	}
+}
+
+impl SpanlessEq for ForTag {
+	fn eq_ignoring_span(&self, other: &Self) -> bool {
+		self.variable.eq_ignoring_span(&other.variable)
+			&& self.iterator.eq_ignoring_span(&other.iterator)
+			&& self.body.eq_ignoring_span(&other.body)
+	}
+}
+
+impl SpanlessEq for Section {
+	fn eq_ignoring_span(&self, other: &Self) -> bool {
+		self.depth == other.depth
+			&& self.name.eq_ignoring_span(&other.name)
+			&& self.subtitle.eq_ignoring_span(&other.subtitle)
+			&& self.content.eq_ignoring_span(&other.content)
+	}
+}
+
+impl SpanlessEq for Slot {
+	fn eq_ignoring_span(&self, other: &Self) -> bool {
+		self.name.eq_ignoring_span(&other.name) && self.body.eq_ignoring_span(&other.body)
+	}
+}
+
+impl SpanlessEq for HtmlNodes {
+	fn eq_ignoring_span(&self, other: &Self) -> bool {
+		match (self, other) {
+			(Self::HtmlTag(x), Self::HtmlTag(y)) => x.eq_ignoring_span(y),
+			(Self::MacroCall(x), Self::MacroCall(y)) => x.eq_ignoring_span(y),
+			(Self::String(x), Self::String(y)) => x.eq_ignoring_span(y),
+			(Self::PlugCall(x), Self::PlugCall(y)) => x.eq_ignoring_span(y),
+			(Self::Section(x), Self::Section(y)) => x.eq_ignoring_span(y),
+			(Self::If(x), Self::If(y)) => x.eq_ignoring_span(y),
+			(Self::Env(x), Self::Env(y)) => x.eq_ignoring_span(y),
+			(Self::For(x), Self::For(y)) => x.eq_ignoring_span(y),
+			(Self::Content(x), Self::Content(y)) => x == y,
+			(Self::Include(x), Self::Include(y)) => x.eq_ignoring_span(y),
+			_ => false,
+		}
+	}
+}
+
+impl SpanlessEq for TopNodes {
+	fn eq_ignoring_span(&self, other: &Self) -> bool {
+		match (self, other) {
+			(Self::HtmlTag(x), Self::HtmlTag(y)) => x.eq_ignoring_span(y),
+			(Self::MacroCall(x), Self::MacroCall(y)) => x.eq_ignoring_span(y),
+			(Self::PlugCall(x), Self::PlugCall(y)) => x.eq_ignoring_span(y),
+			(Self::Section(x), Self::Section(y)) => x.eq_ignoring_span(y),
+			(Self::If(x), Self::If(y)) => x.eq_ignoring_span(y),
+			(Self::Env(x), Self::Env(y)) => x.eq_ignoring_span(y),
+			(Self::For(x), Self::For(y)) => x.eq_ignoring_span(y),
+			(Self::Content(x), Self::Content(y)) => x == y,
+			(Self::Include(x), Self::Include(y)) => x.eq_ignoring_span(y),
+			(Self::Slot(x), Self::Slot(y)) => x.eq_ignoring_span(y),
+			(Self::Doctype(x), Self::Doctype(y)) => x == y,
+			_ => false,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::kismesis::compiler::{lexer, parser};
+
+	fn parse(src: &str) -> ParsedFile {
+		let mut engine = Kismesis::new();
+		let id = engine.register_tokens(lexer::tokenize(src), None);
+		parser::file(id, &engine, None, None).unwrap()
+	}
+
+	#[test]
+	fn functions_round_trip_through_strings() {
+		for func in [BinFunc::And, BinFunc::Or] {
+			assert_eq!(func.to_string().parse::<BinFunc>(), Ok(func));
+		}
+		assert_eq!(
+			UniFunc::Not.to_string().parse::<UniFunc>(),
+			Ok(UniFunc::Not)
+		);
+	}
+
+	#[test]
+	fn unknown_function_names_dont_parse() {
+		for name in ["", "And", "xor", "not", " and", "and "] {
+			assert!(name.parse::<BinFunc>().is_err(), "{:?}", name);
+		}
+		for name in ["", "Not", "and", "not "] {
+			assert!(name.parse::<UniFunc>().is_err(), "{:?}", name);
+		}
+	}
+
+	#[test]
+	fn plain_text_of_a_mixed_string() {
+		let parts = vec![
+			StringParts::String("Hi ".into()),
+			StringParts::Expression(Ranged {
+				value: Expression::Variable("name".into()),
+				range: TextPos::Single(TokenPos::new()),
+			}),
+			StringParts::String(", ".into()),
+			StringParts::Entity("amp".into()),
+			StringParts::String(" bye".into()),
+		];
+		assert_eq!(plain_text(&parts, None), "Hi , &amp; bye");
+		assert_eq!(plain_text(&parts, Some("...")), "Hi ..., &amp; bye");
+		assert_eq!(plain_text(&[], Some("...")), "");
+	}
+
+	#[test]
+	fn token_positions_to_byte_offsets() {
+		let mut engine = Kismesis::new();
+		let src = "<p | ünï>\n<b | ok>\n";
+		let file = engine.compile_str(src, None).unwrap();
+		let TopNodes::HtmlTag(b) = &file.body[1] else {
+			panic!("expected a tag")
+		};
+		let at = |pos: &TokenPos| file.byte_offset(&engine, pos);
+		assert_eq!(at(&b.name.range.get_start()), Some(src.find('b').unwrap()));
+		assert_eq!(at(&TokenPos::new()), Some(0));
+		let count = engine.get_file(file.file_id).unwrap().tokens.len();
+		assert_eq!(at(&TokenPos::new_at(count, 0, 0)), Some(src.len()));
+		assert_eq!(at(&TokenPos::new_at(count + 1, 0, 0)), None);
+
+		// Files registered from tokens don't know where their tokens were
+		let tokens = engine.register_tokens(lexer::tokenize(src), None);
+		let file = parser::file(tokens, &engine, None, None).unwrap();
+		assert_eq!(file.byte_offset(&engine, &TokenPos::new()), None);
+	}
+
+	#[test]
+	fn display_tag() {
+		let file = parse("<div class=\"x\" id=y | hello @name <b | world>>");
+		assert_eq!(
+			file.body[0].to_string(),
+			"<div class=\"x\" id=y | hello @name <b | world>>"
+		);
+	}
+
+	#[test]
+	fn display_round_trips() {
+		let src = "<macro card title | <h1 | @title>>\n<card! title=\"a \\\"b\\\"\">\n";
+		let file = parse(src);
+		assert_eq!(file.to_string(), src);
+		let reparsed = parse(&file.to_string());
+		assert!(reparsed.body.eq_ignoring_span(&file.body));
+		assert!(reparsed
+			.defined_macros
+			.eq_ignoring_span(&file.defined_macros));
+	}
+
+	#[test]
+	fn one_element_arrays_display() {
+		let engine = Kismesis::new();
+		for src in ["{a,}", "{}", "{a, \"b\"}", "{a}"] {
+			let expr = parser::parse_expression(&lexer::tokenize(src), &engine).unwrap();
+			let reparsed =
+				parser::parse_expression(&lexer::tokenize(&expr.to_string()), &engine).unwrap();
+			assert!(reparsed.eq_ignoring_span(&expr), "{} changed", src);
+		}
+		let expr = parser::parse_expression(&lexer::tokenize("{a,}"), &engine).unwrap();
+		assert_eq!(expr.to_string(), "{a,}");
+	}
+
+	#[test]
+	fn ranged_values_map() {
+		let range = TextPos::Range((TokenPos::new_at(2, 0, 2), TokenPos::new_at(5, 1, 0)));
+		let word = Ranged {
+			value: "main",
+			range: range.clone(),
+		};
+		let owned = word.clone().map(str::to_owned);
+		assert_eq!(owned.value, "main");
+		assert_eq!(owned.range, range);
+		assert_eq!(word.to_own(), owned);
+
+		let length = owned.as_ref().map(String::len);
+		assert_eq!(length.value, 4);
+		assert_eq!(length.range, range);
+		assert_eq!(owned.to_symbol(&Kismesis::new()).range, range);
+	}
+
+	#[test]
+	fn attribute_and_argument_lookup() {
+		let file = parse("<macro card Title body | x>\n<a HREF=\"x\" href=\"y\" id=\"z\">\n");
+		let tag = match &file.body[0] {
+			TopNodes::HtmlTag(x) => x,
+			other => panic!("Expected a tag, got {other:?}"),
+		};
+		let href = tag.get_attribute("href").unwrap();
+		assert_eq!(href.name.value, "HREF");
+		assert_eq!(tag.get_attribute("Id").unwrap().name.value, "id");
+		assert!(tag.get_attribute("class").is_none());
+
+		let card = &file.defined_macros[0];
+		assert!(card.get_argument("Title").is_some());
+		assert!(card.get_argument("body").is_some());
+		assert!(card.get_argument("title").is_none());
+	}
+
+	#[test]
+	fn body_tags_convert_to_nodes_and_back() {
+		let file = parse(concat!(
+			"<div | text <p | a> <m!> <content!> <include \"x\"> <if {a} | b> ",
+			"<env \"dev\" | c> <for x in {a} | d>\n# Title\n>\n",
+		));
+		let TopNodes::HtmlTag(tag) = &file.body[0] else {
+			panic!("Expected a tag, got {:?}", file.body[0])
+		};
+		let mut kinds = Vec::new();
+		for node in tag.body.iter().cloned() {
+			match BodyTags::try_from(node.clone()) {
+				Ok(tag) => {
+					kinds.push(std::mem::discriminant(&tag));
+					assert_eq!(HtmlNodes::from(tag), node);
+				}
+				Err(x) => assert!(matches!(x, HtmlNodes::String(_)) && x == node),
+			}
+		}
+		// Every kind but plugin calls, which need a plugin
+		kinds.dedup();
+		assert_eq!(kinds.len(), 8, "{:#?}", tag.body);
+	}
+
+	#[cfg(feature = "serde")]
+	#[test]
+	fn serde_round_trips() {
+		let file = parse(concat!(
+			"const title = \"Home\"\n",
+			"mut user\n",
+			"<macro card name={\"x\"} | <h2.title | @name>>\n",
+			"<main#top data-x={title, \"y\"} | <card! name=\"a @title &amp; b\">\n",
+			"\t<if {not user} | <p | guest>> <for x in {title,} | <li | @x>>>\n",
+		));
+		let json = serde_json::to_string(&file).unwrap();
+		let back: ParsedFile = serde_json::from_str(&json).unwrap();
+		assert_eq!(back, file);
+		assert!(back.body[0].eq_ignoring_span(&file.body[0]));
+	}
+
+	fn tag_name(node: &TopNodes) -> &Symbol {
+		match node {
+			TopNodes::HtmlTag(tag) => &tag.name.value,
+			other => panic!("Expected a tag, got {other:?}"),
+		}
+	}
+
+	#[test]
+	fn tag_names_are_interned() {
+		let file = parse("<div | a>\n<div | b>");
+		let first = tag_name(&file.body[0]);
+		let second = tag_name(&file.body[1]);
+		assert_eq!(first.as_str(), "div");
+		assert!(first.ptr_eq(second));
+		assert_eq!(
+			std::mem::size_of::<Symbol>(),
+			2 * std::mem::size_of::<usize>()
+		);
+	}
+
+	#[test]
+	fn engines_keep_their_own_names() {
+		let first = Kismesis::new();
+		let second = Kismesis::new();
+		assert!(first.intern("div").ptr_eq(&first.intern("div")));
+		assert!(!first.intern("div").ptr_eq(&second.intern("div")));
+		assert_eq!(first.intern("div"), second.intern("div"));
+	}
+
+	#[test]
+	fn parsed_tags_compare_structurally() {
+		let tight = parse("<p class=\"a\" | hi @name>");
+		let spaced = parse("<p  class=\"a\"  |  hi @name>");
+		let other = parse("<p class=\"b\" | hi @name>");
+		assert_eq!(tight.body, parse("<p class=\"a\" | hi @name>").body);
+		assert_ne!(tight.body, spaced.body);
+		assert!(tight.body.eq_ignoring_span(&spaced.body));
+		assert!(!tight.body.eq_ignoring_span(&other.body));
+	}
+
+	#[test]
+	fn symbol_tables() {
+		let file = parse(
+			"const site = \"x\"\n<macro card title sub=\"none\" | <h1 | @title @sub>>\n<macro badge | <b | !>>\n",
+		);
+		let table = file.symbols();
+		let names: Vec<_> = table
+			.symbols
+			.iter()
+			.map(|x| (x.name.as_str(), x.kind))
+			.collect();
+		assert_eq!(
+			names,
+			[
+				("site", SymbolKind::Variable),
+				("card", SymbolKind::Macro),
+				("badge", SymbolKind::Macro)
+			]
+		);
+		let card = table.get("card").unwrap();
+		assert_eq!(card.arguments[0].name, "title");
+		assert_eq!(card.arguments[0].default, None);
+		assert_eq!(card.arguments[1].name, "sub");
+		assert_eq!(
+			card.arguments[1].default.as_ref().unwrap().to_string(),
+			"\"none\""
+		);
+		assert_eq!(card.position.get_start().get_line(), 1);
+		assert_eq!(table.of_kind(SymbolKind::Macro).count(), 2);
+		assert!(table.get("title").is_none());
+	}
+
+	#[test]
+	fn diffs_match_definitions_by_name() {
+		let old = parse("const a = \"x\"\nconst b = \"y\"\n<macro m | <p | @a>>\n<p | hi>\n");
+		let new = parse(
+			"const b = \"y\"\nconst a = \"z\"\n<macro m | <p | @a>>\n<macro n | <b>>\n<p | hi>\n",
+		);
+		assert_eq!(
+			old.diff(&new),
+			[
+				AstChange::Added(AstNodeId::Macro("n".into())),
+				AstChange::Changed(AstNodeId::Variable("a".into())),
+			]
+		);
+		// Moving things around without changing them isn't a change
+		let moved =
+			parse("const b = \"y\"\n\n\nconst a = \"x\"\n<macro m  | <p | @a>>\n<p | hi>\n");
+		assert!(old.diff(&moved).is_empty());
+		assert_eq!(
+			new.diff(&old),
+			[
+				AstChange::Removed(AstNodeId::Macro("n".into())),
+				AstChange::Changed(AstNodeId::Variable("a".into())),
+			]
+		);
+	}
+
+	#[test]
+	fn diffs_match_body_nodes_by_index() {
+		let old = parse("<p | a>\n<p | b>\n");
+		let new = parse("<p | a>\n<p | c>\n<p | d>\n");
+		assert_eq!(
+			old.diff(&new),
+			[
+				AstChange::Changed(AstNodeId::Body(1)),
+				AstChange::Added(AstNodeId::Body(2)),
+			]
+		);
+		assert_eq!(new.diff(&old)[1], AstChange::Removed(AstNodeId::Body(2)));
+	}
+}
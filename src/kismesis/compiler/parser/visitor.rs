@@ -0,0 +1,234 @@
+use super::types::{
+	Argument, Attribute, EnvTag, Expression, ForTag, HtmlNodes, HtmlTag, IfTag, Lambda, Macro,
+	ParsedFile, PlugCall, Ranged, Section, Slot, StringParts, TopNodes, Variable,
+};
+
+/// A pass over the AST. Every method does nothing by default, so a visitor
+/// only needs to implement the nodes it cares about. [`walk`] takes care of
+/// the recursion.
+pub trait Visitor {
+	fn visit_variable(&mut self, _variable: &Variable) {}
+	fn visit_lambda(&mut self, _lambda: &Lambda) {}
+	fn visit_macro_def(&mut self, _mac: &Macro) {}
+	/// Called after the body of a macro definition has been walked
+	fn leave_macro_def(&mut self, _mac: &Macro) {}
+	fn visit_tag(&mut self, _tag: &HtmlTag) {}
+	fn visit_macro_call(&mut self, _mac: &Macro) {}
+	fn visit_plug_call(&mut self, _call: &PlugCall) {}
+	fn visit_section(&mut self, _section: &Section) {}
+	fn visit_if(&mut self, _tag: &IfTag) {}
+	fn visit_env(&mut self, _tag: &EnvTag) {}
+	fn visit_for(&mut self, _tag: &ForTag) {}
+	/// Called after the body of a `for` tag has been walked
+	fn leave_for(&mut self, _tag: &ForTag) {}
+	fn visit_content(&mut self, _slot: Option<&str>) {}
+	fn visit_slot(&mut self, _slot: &Slot) {}
+	fn visit_include(&mut self, _path: &Ranged<Vec<StringParts>>) {}
+	fn visit_doctype(&mut self, _doctype: &str) {}
+	fn visit_attribute(&mut self, _attribute: &Attribute) {}
+	fn visit_argument(&mut self, _argument: &Argument) {}
+	fn visit_string_part(&mut self, _part: &StringParts) {}
+	fn visit_expression(&mut self, _expression: &Ranged<Expression>) {}
+}
+
+/// Walks through every node of a file, definitions first
+pub fn walk(file: &ParsedFile, visitor: &mut impl Visitor) {
+	for variable in file.defined_variables.iter() {
+		visitor.visit_variable(variable);
+		walk_expression(&variable.value, visitor);
+	}
+	for lambda in file.defined_lambdas.iter() {
+		visitor.visit_lambda(lambda);
+		if let Some(ref value) = lambda.value {
+			walk_expression(value, visitor);
+		}
+	}
+	for mac in file.defined_macros.iter() {
+		visitor.visit_macro_def(mac);
+		walk_arguments(&mac.arguments, visitor);
+		walk_nodes(&mac.body, visitor);
+		visitor.leave_macro_def(mac);
+	}
+	for node in file.body.iter() {
+		walk_top_node(node, visitor);
+	}
+}
+
+pub fn walk_top_node(node: &TopNodes, visitor: &mut impl Visitor) {
+	match node {
+		TopNodes::HtmlTag(tag) => walk_tag(tag, visitor),
+		TopNodes::MacroCall(mac) => walk_macro_call(mac, visitor),
+		TopNodes::PlugCall(call) => walk_plug_call(call, visitor),
+		TopNodes::Section(section) => walk_section(section, visitor),
+		TopNodes::Content(slot) => visitor.visit_content(slot.as_deref()),
+		TopNodes::Slot(slot) => walk_slot(slot, visitor),
+		TopNodes::Include(path) => walk_include(path, visitor),
+		TopNodes::Doctype(doctype) => visitor.visit_doctype(doctype),
+		TopNodes::If(tag) => walk_if(tag, visitor),
+		TopNodes::Env(tag) => walk_env(tag, visitor),
+		TopNodes::For(tag) => walk_for(tag, visitor),
+	}
+}
+
+pub fn walk_node(node: &HtmlNodes, visitor: &mut impl Visitor) {
+	match node {
+		HtmlNodes::HtmlTag(tag) => walk_tag(tag, visitor),
+		HtmlNodes::MacroCall(mac) => walk_macro_call(mac, visitor),
+		HtmlNodes::String(parts) => walk_string(parts, visitor),
+		HtmlNodes::PlugCall(call) => walk_plug_call(call, visitor),
+		HtmlNodes::Section(section) => walk_section(section, visitor),
+		HtmlNodes::If(tag) => walk_if(tag, visitor),
+		HtmlNodes::Env(tag) => walk_env(tag, visitor),
+		HtmlNodes::For(tag) => walk_for(tag, visitor),
+		HtmlNodes::Content(slot) => visitor.visit_content(slot.as_deref()),
+		HtmlNodes::Include(path) => walk_include(path, visitor),
+	}
+}
+
+pub fn walk_nodes(nodes: &[HtmlNodes], visitor: &mut impl Visitor) {
+	for node in nodes {
+		walk_node(node, visitor);
+	}
+}
+
+pub fn walk_tag(tag: &HtmlTag, visitor: &mut impl Visitor) {
+	visitor.visit_tag(tag);
+	for attribute in tag.attributes.iter() {
+		walk_attribute(attribute, visitor);
+	}
+	for spread in tag.spreads.iter() {
+		walk_expression(spread, visitor);
+	}
+	for subtag in tag.subtags.iter() {
+		walk_tag(subtag, visitor);
+	}
+	walk_nodes(&tag.body, visitor);
+}
+
+pub fn walk_attribute(attribute: &Attribute, visitor: &mut impl Visitor) {
+	visitor.visit_attribute(attribute);
+	walk_expression(&attribute.value, visitor);
+	if let Some(ref condition) = attribute.condition {
+		walk_expression(condition, visitor);
+	}
+}
+
+fn walk_arguments(arguments: &[Argument], visitor: &mut impl Visitor) {
+	for argument in arguments {
+		visitor.visit_argument(argument);
+		if let Some(ref value) = argument.value {
+			walk_expression(value, visitor);
+		}
+	}
+}
+
+pub fn walk_macro_call(mac: &Macro, visitor: &mut impl Visitor) {
+	visitor.visit_macro_call(mac);
+	walk_arguments(&mac.arguments, visitor);
+	walk_nodes(&mac.body, visitor);
+}
+
+pub fn walk_plug_call(call: &PlugCall, visitor: &mut impl Visitor) {
+	visitor.visit_plug_call(call);
+	walk_nodes(&call.body, visitor);
+}
+
+pub fn walk_slot(slot: &Slot, visitor: &mut impl Visitor) {
+	visitor.visit_slot(slot);
+	walk_nodes(&slot.body, visitor);
+}
+
+pub fn walk_include(path: &Ranged<Vec<StringParts>>, visitor: &mut impl Visitor) {
+	visitor.visit_include(path);
+	walk_string(&path.value, visitor);
+}
+
+pub fn walk_section(section: &Section, visitor: &mut impl Visitor) {
+	visitor.visit_section(section);
+	walk_string(&section.name, visitor);
+	if let Some(ref subtitle) = section.subtitle {
+		walk_string(subtitle, visitor);
+	}
+	for paragraph in section.content.iter() {
+		walk_nodes(paragraph, visitor);
+	}
+}
+
+pub fn walk_if(tag: &IfTag, visitor: &mut impl Visitor) {
+	visitor.visit_if(tag);
+	walk_expression(&tag.condition, visitor);
+	walk_nodes(&tag.body, visitor);
+	if let Some(else_body) = &tag.else_body {
+		walk_nodes(else_body, visitor);
+	}
+}
+
+pub fn walk_env(tag: &EnvTag, visitor: &mut impl Visitor) {
+	visitor.visit_env(tag);
+	walk_nodes(&tag.body, visitor);
+}
+
+pub fn walk_for(tag: &ForTag, visitor: &mut impl Visitor) {
+	visitor.visit_for(tag);
+	walk_expression(&tag.iterator, visitor);
+	walk_nodes(&tag.body, visitor);
+	visitor.leave_for(tag);
+}
+
+pub fn walk_string(parts: &[StringParts], visitor: &mut impl Visitor) {
+	for part in parts {
+		visitor.visit_string_part(part);
+		if let StringParts::Expression(expression) = part {
+			walk_expression(expression, visitor);
+		}
+	}
+}
+
+pub fn walk_expression(expression: &Ranged<Expression>, visitor: &mut impl Visitor) {
+	visitor.visit_expression(expression);
+	match expression.value {
+		Expression::None | Expression::Variable(_) => (),
+		Expression::Literal(ref parts) => walk_string(parts, visitor),
+		Expression::BinFunc(_, ref x, ref y) => {
+			walk_expression(x, visitor);
+			walk_expression(y, visitor);
+		}
+		Expression::UniFunc(_, ref x) | Expression::Member(ref x, _) => walk_expression(x, visitor),
+		Expression::Array(ref values) => {
+			for value in values {
+				walk_expression(value, visitor);
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::kismesis::Kismesis;
+
+	#[derive(Default)]
+	struct VariableCounter {
+		names: Vec<String>,
+	}
+
+	impl Visitor for VariableCounter {
+		fn visit_expression(&mut self, expression: &Ranged<Expression>) {
+			if let Expression::Variable(x) = &expression.value {
+				self.names.push(x.to_string());
+			}
+		}
+	}
+
+	#[test]
+	fn counts_variables_in_nested_trees() {
+		let src = "<macro card title | <h1 | @title>>\n\
+			<div a={x} | <p | @y <b | @x>>>\n\
+			<if {not z} | <card! title={w and x}>>\n";
+		let file = Kismesis::new().compile_str(src, None).unwrap();
+		let mut counter = VariableCounter::default();
+		walk(&file, &mut counter);
+		counter.names.sort();
+		assert_eq!(counter.names, ["title", "w", "x", "x", "x", "y", "z"]);
+	}
+}
@@ -1,39 +1,69 @@
-use std::fmt::Debug;
+use std::{
+	fmt::Debug,
+	io::{self, Write},
+};
 
 use crate::kismesis::{FileRef, KisID, Kismesis};
 
 use super::{
-	errors::{ErrorKind, ErrorState, StatelessError},
+	errors::{ErrorCategory, ErrorKind, ErrorState, Severity, StatelessError},
 	html::ScopedError,
 	lexer::Token,
-	parser::{errors::Hint, state::TokenPos},
+	parser::{errors::Hint, state::TokenPos, types::TextPos},
 };
 use colored::*;
 
+/// Everything needed to draw errors in a file. Building it splits the file
+/// into lines, so it's worth building once and reusing it for every error in
+/// the same file.
+#[derive(Clone)]
 pub struct DrawingInfo<'a> {
 	pub(crate) line_number_length: usize,
+	pub(crate) scope_id: KisID,
 	pub(crate) scope: &'a FileRef,
 	pub(crate) lines: Vec<(usize, &'a [Token])>,
 	pub(crate) line_offset: (usize, usize),
 	pub(crate) hint: bool,
+	/// How many columns a tab (or an `Indent` token) takes up when drawn
+	pub(crate) tab_width: usize,
+	/// How many columns lines are wrapped to. If it's `None`, the terminal's
+	/// width is used, or 40 columns when there's no terminal
+	pub(crate) max_width: Option<usize>,
 }
 
+pub const DEFAULT_TAB_WIDTH: usize = 4;
+
+/// The narrowest a source line is wrapped to, no matter how long the error
+/// message next to it is
+const MINIMUM_WRAP_WIDTH: usize = 20;
+
+/// Hints nested deeper than this are drawn as if they were this deep, so long
+/// chains of hints don't get pushed off the screen
+const MAX_HINT_DEPTH: usize = 4;
+
 #[derive(Debug)]
-enum ReportingError {
-	InvalidKismesisID,
+pub enum ReportingError {
+	InvalidKismesisID(KisID),
 }
 
 impl ErrorKind for ReportingError {
 	fn get_text(&self) -> String {
 		match self {
-			ReportingError::InvalidKismesisID => "Tried to report an error ocurring on a file with an invalid Kismesis ID.\nPlease contact the developer of the engine you're using.".into(),
+			ReportingError::InvalidKismesisID(id) => format!("Tried to report an error ocurring on a file with an invalid Kismesis ID ({:?}).\nPlease contact the developer of the engine you're using.", id),
 		}
 	}
+
+	fn category(&self) -> ErrorCategory {
+		ErrorCategory::Reporting
+	}
 }
 
 impl<'a> DrawingInfo<'a> {
-	pub fn from(scope: KisID, engine: &'a Kismesis, hint: bool) -> Result<Self, ()> {
-		let scope = engine.get_file(scope).ok_or(())?;
+	pub fn from(scope: KisID, engine: &'a Kismesis, hint: bool) -> Result<Self, ReportingError> {
+		let scope_id = scope;
+		let scope = engine
+			.get_file_or_err(scope)
+			.map_err(|_| ReportingError::InvalidKismesisID(scope))?;
 		let lines: Vec<&[Token]> = scope
 			.tokens
 			.split_inclusive(|x| matches!(x, Token::Newline(_)))
@@ -49,51 +79,113 @@ impl<'a> DrawingInfo<'a> {
 		};
 		Ok(Self {
 			line_number_length: 3,
+			scope_id,
 			scope,
 			lines,
 			line_offset: (2, 2),
 			hint,
+			tab_width: engine.tab_width(),
+			max_width: engine.error_width(),
 		})
 	}
+
+	/// Whether errors are drawn as hints rather than as errors
+	pub fn set_hint(&mut self, hint: bool) {
+		self.hint = hint;
+	}
+
+	/// How many lines are drawn before and after the lines of an error
+	pub fn set_line_offset(&mut self, before: usize, after: usize) {
+		self.line_offset = (before, after);
+	}
+
+	/// The minimum width of the line number margin
+	pub fn set_line_number_length(&mut self, length: usize) {
+		self.line_number_length = length;
+	}
+
+	pub fn set_tab_width(&mut self, tab_width: usize) {
+		self.tab_width = tab_width;
+	}
+
+	/// Wraps lines to a fixed width instead of the terminal's, so the output
+	/// is the same everywhere
+	pub fn set_max_width(&mut self, max_width: Option<usize>) {
+		self.max_width = max_width;
+	}
+
+	/// A copy of this info that draws hints, so hints in the same file don't
+	/// have to split it into lines again
+	fn as_hint(&self) -> Self {
+		let mut info = self.clone();
+		info.hint = true;
+		info
+	}
 }
 
 pub fn draw_error<T: ErrorKind + Debug>(
 	err: &ErrorState<T>,
-	info: &Result<DrawingInfo, ()>,
+	info: &Result<DrawingInfo, ReportingError>,
 	engine: &Kismesis,
 ) -> String {
-	let info = match info.as_ref() {
+	drawn(|w| write_error(w, err, info, engine))
+}
+
+/// Draws an error straight into `w`, writing each line as soon as it's
+/// drawn. The colors are written as escape codes, like in `draw_error`
+pub fn write_error<T: ErrorKind + Debug, W: Write>(
+	w: &mut W,
+	err: &ErrorState<T>,
+	info: &Result<DrawingInfo, ReportingError>,
+	engine: &Kismesis,
+) -> io::Result<()> {
+	write_nested_error(w, err, info, engine, 0)
+}
+
+/// Runs one of the `write_*` functions on a buffer and gives back what it
+/// wrote, for the `draw_*` functions
+fn drawn(write: impl FnOnce(&mut Vec<u8>) -> io::Result<()>) -> String {
+	let mut output = Vec::new();
+	write(&mut output).expect("Writing to a Vec can't fail");
+	String::from_utf8(output).expect("Drawn errors are always valid UTF-8")
+}
+
+/// Draws an error that is `depth` hints deep, with a bar to the left of it
+/// for every level
+fn write_nested_error<T: ErrorKind + Debug, W: Write>(
+	w: &mut W,
+	err: &ErrorState<T>,
+	info: &Result<DrawingInfo, ReportingError>,
+	engine: &Kismesis,
+	depth: usize,
+) -> io::Result<()> {
+	let info = match info {
 		Ok(x) => x,
-		Err(_) => {
-			let err = ReportingError::InvalidKismesisID.stateless();
-			return draw_stateless_error(&err, false, engine);
-		}
-	};
-	let minimum_line = {
-		let x = err.text_position.get_start_line();
-		if x < info.line_offset.0 {
-			0
-		} else {
-			x - info.line_offset.0
-		}
-	};
-	let maximum_line = {
-		let x = err.text_position.get_end_line();
-		if x > info.lines.len() - info.line_offset.1 {
-			info.lines.len()
-		} else {
-			x + info.line_offset.1
+		Err(ReportingError::InvalidKismesisID(id)) => {
+			let err = ReportingError::InvalidKismesisID(*id).stateless();
+			return write_nested_stateless_error(w, &err, engine, depth);
 		}
 	};
+	let minimum_line = err
+		.text_position
+		.get_start_line()
+		.saturating_sub(info.line_offset.0);
+	// Errors can be past the last line, and files can have no lines at all,
+	// so the lines after the error are capped rather than counted back from
+	// the end of the file
+	let maximum_line = std::cmp::min(
+		err.text_position.get_end_line() + info.line_offset.1,
+		info.lines.len(),
+	);
 
-	let mut output = String::new();
+	let mut header = String::new();
 
 	if info.hint {
-		output.push_str(&" HINT ".black().on_yellow().to_string());
-		output.push_str(&" in `".black().on_yellow().to_string());
+		header.push_str(&" HINT ".black().on_yellow().to_string());
+		header.push_str(&" in `".black().on_yellow().to_string());
 		match info.scope.path {
 			Some(ref path) => {
-				output.push_str(
+				header.push_str(
 					&path
 						.to_string_lossy()
 						.to_string()
@@ -101,83 +193,298 @@ pub fn draw_error<T: ErrorKind + Debug>(
 						.on_yellow()
 						.to_string(),
 				);
-				output.push_str(&"` ".black().on_yellow().to_string());
+				header.push_str(&"` ".black().on_yellow().to_string());
 			}
-			None => output.push_str(&"input` ".black().on_yellow().to_string()),
+			None => header.push_str(&"input` ".black().on_yellow().to_string()),
 		}
 	} else {
-		output.push_str(&" ERROR ".black().on_red().to_string());
-		output.push_str(&" in `".black().on_red().to_string());
+		let color = label_color(&err.error);
+		header.push_str(&error_label(&err.error).black().on_color(color).to_string());
+		header.push_str(&" in `".black().on_color(color).to_string());
 		match info.scope.path {
 			Some(ref path) => {
-				output.push_str(
+				header.push_str(
 					&path
 						.to_string_lossy()
 						.to_string()
 						.black()
-						.on_red()
+						.on_color(color)
 						.to_string(),
 				);
-				output.push_str(&"` ".black().on_red().to_string());
+				header.push_str(&"` ".black().on_color(color).to_string());
 			}
-			None => output.push_str(&"input` ".black().on_red().to_string()),
+			None => header.push_str(&"input` ".black().on_color(color).to_string()),
 		}
 	}
-	output.push('\n');
+	header.push('\n');
+	w.write_all(indent_hint(&header, depth).as_bytes())?;
 
 	for line_number in minimum_line..=maximum_line {
 		if let Some(string) = draw_line(line_number, err, info) {
-			output.push_str(&string);
-			output.push('\n');
+			w.write_all(indent_hint(&format!("{}\n", string), depth).as_bytes())?;
 		}
 	}
-
-	output.push('\n');
+	w.write_all(indent_hint("\n", depth).as_bytes())?;
 
 	for x in err.hints.iter() {
-		let hint = match x {
-			Hint::Stateful(x) => {
-				draw_error(&x.error, &DrawingInfo::from(x.scope, engine, true), engine)
+		match x {
+			Hint::Stateful(x) if x.scope == info.scope_id => {
+				write_nested_error(w, &x.error, &Ok(info.as_hint()), engine, depth + 1)?
 			}
-			Hint::Stateless(x) => draw_stateless_error(x, true, engine),
-		};
-		output.push_str(&hint);
+			Hint::Stateful(x) => write_nested_error(
+				w,
+				&x.error,
+				&DrawingInfo::from(x.scope, engine, true).map(|mut hint_info| {
+					hint_info.max_width = info.max_width;
+					hint_info
+				}),
+				engine,
+				depth + 1,
+			)?,
+			Hint::Stateless(x) => write_nested_stateless_error(w, x, engine, depth + 1)?,
+		}
 	}
 
-	if !err.text_position.is_one_line() {
-		output.push_str(&format!("\n{}", err.error.get_text()));
+	// The message of a one-line error goes after its last token, so errors
+	// past the end of the file, like in empty files, need it written here
+	if !err.text_position.is_one_line() || !ends_in_file(&err.text_position, info) {
+		let message = indent_hint(&format!("\n{}", err.error.get_text()), depth);
+		w.write_all(message.as_bytes())?;
 	}
 
+	Ok(())
+}
+
+/// Whether the last token of the position is in the file
+fn ends_in_file(position: &TextPos, info: &DrawingInfo) -> bool {
+	let token_count = info
+		.lines
+		.last()
+		.map_or(0, |(start, line)| start + line.len());
+	match position {
+		TextPos::Single(x) => x.get_idx() < token_count,
+		TextPos::Range((_, end)) => end.get_idx() <= token_count,
+		TextPos::Multi(_) => true,
+	}
+}
+
+/// Puts a bar before every line of a hint for every level of depth it's at
+fn indent_hint(string: &str, depth: usize) -> String {
+	let bar = "  │ ".repeat(depth.min(MAX_HINT_DEPTH));
+	if bar.is_empty() {
+		return string.to_string();
+	}
+	string
+		.split_inclusive('\n')
+		.map(|line| match line {
+			"\n" => format!("{}\n", bar.trim_end()),
+			line => format!("{}{}", bar, line),
+		})
+		.collect()
+}
+
+/// Formats an error as a single `path:line:col: message` line, without
+/// drawing the source around it. Hints get a line each, indented below the
+/// error they belong to.
+pub fn draw_error_summary<T: ErrorKind>(
+	err: &ErrorState<T>,
+	info: &Result<DrawingInfo, ReportingError>,
+	engine: &Kismesis,
+) -> String {
+	let mut output = String::new();
+	summarize_error(err, info, engine, 0, &mut output);
 	output
 }
 
+fn summarize_error<T: ErrorKind>(
+	err: &ErrorState<T>,
+	info: &Result<DrawingInfo, ReportingError>,
+	engine: &Kismesis,
+	depth: usize,
+	output: &mut String,
+) {
+	let info = match info {
+		Ok(x) => x,
+		Err(ReportingError::InvalidKismesisID(id)) => {
+			let err = ReportingError::InvalidKismesisID(*id).stateless();
+			summarize_stateless_error(&err, engine, depth, output);
+			return;
+		}
+	};
+	let path = match info.scope.path {
+		Some(ref path) => path.to_string_lossy().to_string(),
+		None => "input".to_string(),
+	};
+	let start = err.text_position.get_start();
+	let (line, column) = engine
+		.resolve_position(info.scope_id, &start, info.tab_width)
+		.unwrap_or((start.get_line() + 1, start.get_column() + 1));
+	output.push_str(&format!(
+		"{}{}:{}:{}: {}\n",
+		"  ".repeat(depth),
+		path,
+		line,
+		column,
+		err.error.get_text().replace('\n', " ")
+	));
+	for hint in err.hints.iter() {
+		match hint {
+			Hint::Stateful(x) => summarize_error(
+				&x.error,
+				&DrawingInfo::from(x.scope, engine, true),
+				engine,
+				depth + 1,
+				output,
+			),
+			Hint::Stateless(x) => summarize_stateless_error(x, engine, depth + 1, output),
+		}
+	}
+}
+
+fn summarize_stateless_error<T: ErrorKind>(
+	err: &StatelessError<T>,
+	engine: &Kismesis,
+	depth: usize,
+	output: &mut String,
+) {
+	output.push_str(&format!(
+		"{}{}\n",
+		"  ".repeat(depth),
+		err.error.get_text().replace('\n', " ")
+	));
+	for hint in err.hints.iter() {
+		match hint {
+			Hint::Stateful(x) => summarize_error(
+				&x.error,
+				&DrawingInfo::from(x.scope, engine, true),
+				engine,
+				depth + 1,
+				output,
+			),
+			Hint::Stateless(x) => summarize_stateless_error(x, engine, depth + 1, output),
+		}
+	}
+}
+
+/// The label an error is shown with, including its code if it has one
+fn error_label<T: ErrorKind>(error: &T) -> String {
+	let label = match error.severity() {
+		Severity::Error => "ERROR",
+		Severity::Warning => "WARNING",
+	};
+	match error.code() {
+		"" => format!(" {} ", label),
+		code => format!(" {} {} ", label, code),
+	}
+}
+
+fn label_color<T: ErrorKind>(error: &T) -> Color {
+	match error.severity() {
+		Severity::Error => Color::Red,
+		Severity::Warning => Color::Yellow,
+	}
+}
+
+/// Formats an error as a GitHub Actions `::error` or `::warning` workflow
+/// command, so it shows up as an annotation on the offending line
+pub fn draw_github_annotation<T: ErrorKind>(err: &ErrorState<T>, info: &DrawingInfo) -> String {
+	let file = match info.scope.path {
+		Some(ref path) => path.to_string_lossy().to_string(),
+		None => "input".to_string(),
+	};
+	let mut properties = format!(
+		"file={},line={}",
+		escape_annotation_property(&file),
+		err.text_position.get_start_line() + 1
+	);
+	if !err.text_position.is_one_line() {
+		properties.push_str(&format!(
+			",endLine={}",
+			err.text_position.get_end_line() + 1
+		));
+	}
+	let kind = match err.error.category() {
+		ErrorCategory::Syntax => "Syntax error",
+		ErrorCategory::Semantic => "Error",
+		ErrorCategory::Reporting => "Reporting error",
+	};
+	let title = match err.error.code() {
+		"" => kind.to_string(),
+		code => format!("{} {}", kind, code),
+	};
+	properties.push_str(&format!(",title={}", escape_annotation_property(&title)));
+	let command = match err.error.severity() {
+		Severity::Error => "error",
+		Severity::Warning => "warning",
+	};
+	format!(
+		"::{} {}::{}",
+		command,
+		properties,
+		escape_annotation_data(&err.error.get_text())
+	)
+}
+
+fn escape_annotation_data(string: &str) -> String {
+	string
+		.replace('%', "%25")
+		.replace('\r', "%0D")
+		.replace('\n', "%0A")
+}
+
+fn escape_annotation_property(string: &str) -> String {
+	escape_annotation_data(string)
+		.replace(':', "%3A")
+		.replace(',', "%2C")
+}
+
 pub fn draw_stateless_error<T: ErrorKind + Debug>(
 	err: &StatelessError<T>,
 	hint: bool,
 	engine: &Kismesis,
 ) -> String {
+	drawn(|w| write_nested_stateless_error(w, err, engine, usize::from(hint)))
+}
+
+fn write_nested_stateless_error<T: ErrorKind + Debug, W: Write>(
+	w: &mut W,
+	err: &StatelessError<T>,
+	engine: &Kismesis,
+	depth: usize,
+) -> io::Result<()> {
 	let mut output = String::new();
 
-	if hint {
+	if depth > 0 {
 		output.push_str(&" HINT ".black().on_yellow().to_string());
 	} else {
-		output.push_str(&" ERROR ".black().on_red().to_string());
+		output.push_str(
+			&error_label(&err.error)
+				.black()
+				.on_color(label_color(&err.error))
+				.to_string(),
+		);
 	}
 	output.push('\n');
 
 	output.push_str(&format!("\n{}", err.error.get_text()));
+	w.write_all(indent_hint(&output, depth).as_bytes())?;
 
 	for x in err.hints.iter() {
-		let hint = match x {
-			Hint::Stateful(x) => {
-				draw_error(&x.error, &DrawingInfo::from(x.scope, engine, true), engine)
-			}
-			Hint::Stateless(x) => draw_stateless_error(x, true, engine),
-		};
-		output.push_str(&hint);
+		// The message doesn't end its line, so hints need to start one
+		w.write_all(b"\n")?;
+		match x {
+			Hint::Stateful(x) => write_nested_error(
+				w,
+				&x.error,
+				&DrawingInfo::from(x.scope, engine, true),
+				engine,
+				depth + 1,
+			)?,
+			Hint::Stateless(x) => write_nested_stateless_error(w, x, engine, depth + 1)?,
+		}
 	}
 
-	output
+	Ok(())
 }
 
 fn draw_line<T: ErrorKind>(
@@ -186,41 +493,47 @@ fn draw_line<T: ErrorKind>(
 	info: &DrawingInfo,
 ) -> Option<String> {
 	let mut output = draw_line_number(line_number, info).white().to_string();
-	let mut error_line = turn_to_chars(draw_line_number(line_number, info), ' ');
-	let termsize = termsize::get().map(|size| size.cols).unwrap_or(40) as usize;
-	let termsize = std::cmp::min(termsize, termsize - err.error.get_text().len());
+	let blank_margin = turn_to_chars(draw_line_number(line_number, info), ' ', info.tab_width);
+	let mut error_line = blank_margin.clone();
+	let termsize = info
+		.max_width
+		.unwrap_or_else(|| termsize::get().map(|size| size.cols).unwrap_or(40) as usize);
+	// Leave room for the margin and for the message that follows the carets,
+	// but never wrap so tightly that every token ends up on its own line
+	let width = termsize
+		.saturating_sub(blank_margin.chars().count())
+		.saturating_sub(err.error.get_text().chars().count() + 1)
+		.max(MINIMUM_WRAP_WIDTH);
 	if let Some(line) = info.lines.get(line_number) {
-		let mut char_idx: usize = 0;
+		let mut line_width: usize = 0;
 		for (token_idx, token) in line.1.iter().enumerate() {
 			let token_pos = TokenPos::new_at(line.0 + token_idx, line_number, token_idx);
 			let tkstr = match token {
 				Token::Newline(_) if token_pos.is_in(&err.text_position) => "~".to_string(),
 				Token::Newline(_) => "".to_string(),
-				Token::Indent(_) => " ".repeat(4),
+				Token::Indent(_) => " ".repeat(info.tab_width),
 				x => x.get_as_string(),
 			};
-			char_idx += tkstr.len();
-			if char_idx + tkstr.len() >= termsize && token_idx != 0 {
+			let token_width = tkstr.chars().count();
+			// Lines are only ever broken between tokens, so words stay whole
+			if line_width + token_width > width && line_width != 0 {
 				if error_line.chars().any(|x| !x.is_whitespace()) {
 					output.push('\n');
-					output.push_str(error_line.yellow().to_string().trim_end());
-					output.push('\n');
-					output.push_str(&turn_to_chars(draw_line_number(line_number, info), ' '));
-					error_line = turn_to_chars(draw_line_number(line_number, info), ' ');
-				} else {
-					output.push('\n');
-					output.push_str(&turn_to_chars(draw_line_number(line_number, info), ' '));
-					error_line = turn_to_chars(draw_line_number(line_number, info), ' ');
+					output.push_str(&error_line.trim_end().yellow().to_string());
 				}
-				char_idx = tkstr.len();
+				output.push('\n');
+				output.push_str(&blank_margin);
+				error_line = blank_margin.clone();
+				line_width = 0;
 			}
+			line_width += token_width;
 			output.push_str(&tkstr);
 			let char = if token_pos.is_in(&err.text_position) {
 				'^'
 			} else {
 				' '
 			};
-			error_line.push_str(&turn_to_chars(tkstr, char));
+			error_line.push_str(&turn_to_chars(tkstr, char, info.tab_width));
 			if token_pos.is_at_an_end(&err.text_position) {
 				if err.text_position.is_one_line() {
 					error_line.push_str(&format!(" {}", err.error.get_text()));
@@ -241,11 +554,11 @@ fn draw_line<T: ErrorKind>(
 	}
 }
 
-fn turn_to_chars(string: String, chr: char) -> String {
+fn turn_to_chars(string: String, chr: char, tab_width: usize) -> String {
 	string
 		.chars()
 		.map(|x| match x {
-			'\t' => chr.to_string().repeat(4),
+			'\t' => chr.to_string().repeat(tab_width),
 			_ => chr.to_string(),
 		})
 		.collect()
@@ -261,9 +574,409 @@ fn draw_line_number(line: usize, info: &DrawingInfo) -> String {
 }
 
 pub fn draw_scoped_error<T: ErrorKind + Debug>(err: &ScopedError<T>, engine: &Kismesis) -> String {
-	draw_error(
+	drawn(|w| write_scoped_error(w, err, engine))
+}
+
+pub fn write_scoped_error<T: ErrorKind + Debug, W: Write>(
+	w: &mut W,
+	err: &ScopedError<T>,
+	engine: &Kismesis,
+) -> io::Result<()> {
+	write_error(
+		w,
 		&err.error,
 		&DrawingInfo::from(err.scope, engine, false),
 		engine,
 	)
 }
+
+/// Draws every error, grouped by the file they're in. Files are drawn in the
+/// order their first error appears in, each one under a header with its
+/// path, and the errors in a file are drawn from the first one in the source
+/// to the last one.
+pub fn draw_all<T: ErrorKind + Debug>(errors: &[ScopedError<T>], engine: &Kismesis) -> String {
+	drawn(|w| write_all(w, errors, engine))
+}
+
+/// Draws every error into `w` the way `draw_all` does, writing each one as
+/// soon as it's drawn
+pub fn write_all<T: ErrorKind + Debug, W: Write>(
+	w: &mut W,
+	errors: &[ScopedError<T>],
+	engine: &Kismesis,
+) -> io::Result<()> {
+	let mut files: Vec<(KisID, Vec<&ErrorState<T>>)> = vec![];
+	for err in errors {
+		match files.iter_mut().find(|(scope, _)| *scope == err.scope) {
+			Some((_, file_errors)) => file_errors.push(&err.error),
+			None => files.push((err.scope, vec![&err.error])),
+		}
+	}
+
+	for (scope, mut file_errors) in files {
+		file_errors.sort();
+		let info = DrawingInfo::from(scope, engine, false);
+		let header = match info.as_ref().map(|x| &x.scope.path) {
+			Ok(Some(path)) => path.to_string_lossy().to_string(),
+			Ok(None) => "input".to_string(),
+			Err(_) => format!("file {}", scope),
+		};
+		write!(w, "{}\n\n", header.bold().underline())?;
+		for err in file_errors {
+			write_error(w, err, &info, engine)?;
+		}
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Drawn text without its colors
+	fn plain(text: &str) -> String {
+		let mut output = String::new();
+		let mut chars = text.chars();
+		while let Some(x) = chars.next() {
+			if x == '\x1b' {
+				chars.by_ref().find(|x| *x == 'm');
+			} else {
+				output.push(x);
+			}
+		}
+		output
+	}
+
+	/// The source line with `marker` in it and the caret line under it
+	fn marked_lines(text: &str, marker: char) -> (String, String) {
+		let lines: Vec<&str> = text.lines().collect();
+		let idx = lines
+			.iter()
+			.position(|x| x.contains(marker) && !x.contains('^'))
+			.unwrap();
+		(lines[idx].to_string(), lines[idx + 1].to_string())
+	}
+
+	#[test]
+	fn carets_line_up_with_tabs() {
+		for tab_width in [2, 4, 8] {
+			let mut engine = Kismesis::new();
+			engine.set_tab_width(tab_width);
+			let error = engine.compile_str("<p |\n\t x\t@>\n", None).unwrap_err();
+			let (source, carets) = marked_lines(&plain(&error.text), '@');
+			assert!(!source.contains('\t'));
+			assert!(source.contains(&format!(
+				"{} x{}@",
+				" ".repeat(tab_width),
+				" ".repeat(tab_width)
+			)));
+			assert_eq!(
+				source.chars().position(|x| x == '@'),
+				carets.chars().position(|x| x == '^')
+			);
+		}
+	}
+
+	#[test]
+	fn github_annotations() {
+		use crate::kismesis::compiler::parser::errors::ParseError;
+		use std::path::PathBuf;
+
+		let mut engine = Kismesis::new();
+		let id = engine.register_source("<p | a>\n<p | b\n", Some(PathBuf::from("a,b:c%.ks")));
+		let info = DrawingInfo::from(id, &engine, false).unwrap();
+		let error = ErrorState {
+			error: ParseError::EndlessString,
+			text_position: TextPos::Single(TokenPos::new_at(9, 1, 0)),
+			hints: vec![],
+		};
+		assert_eq!(
+			draw_github_annotation(&error, &info),
+			"::error file=a%2Cb%3Ac%25.ks,line=2,title=Syntax error E0036::String reaches end of file"
+		);
+		assert_eq!(escape_annotation_data("50%\r\nx"), "50%25%0D%0Ax");
+	}
+
+	#[test]
+	fn drawing_info_can_be_reused() {
+		use crate::kismesis::compiler::parser::errors::{Hints, ParseError};
+
+		let mut engine = Kismesis::new();
+		let id = engine.register_source("<p | a>\n<p | b>\n<p | c>\n<p | d\n", None);
+		let at = |idx, line| TextPos::Single(TokenPos::new_at(idx, line, 0));
+		let errors = [
+			ParseError::EndlessString.with_state_at(at(0, 0)),
+			ParseError::ExpectedTagName.with_state_at(at(8, 1)),
+			ErrorState {
+				error: ParseError::ExpectedTagCloser,
+				text_position: at(24, 3),
+				hints: vec![Hints::ExprOpenedHere.with_state_at(at(16, 2), id)],
+			},
+		];
+		let cached = DrawingInfo::from(id, &engine, false);
+		for error in errors.iter() {
+			assert_eq!(
+				draw_error(error, &cached, &engine),
+				draw_error(error, &DrawingInfo::from(id, &engine, false), &engine)
+			);
+		}
+	}
+
+	#[test]
+	fn long_lines_wrap_between_tokens() {
+		use crate::kismesis::compiler::parser::errors::{Hints, ParseError};
+
+		let words: Vec<String> = (0..30).map(|x| format!("word{:02}", x)).collect();
+		let mut engine = Kismesis::new();
+		let id = engine.register_source(&format!("<p | {}>\n", words.join(" ")), None);
+		let mut info = DrawingInfo::from(id, &engine, false).unwrap();
+		info.set_max_width(Some(60));
+		// `word17` is the 17th word after `<`, `p`, ` `, `|` and ` `
+		let idx = 5 + 2 * 17;
+		let position = TextPos::Single(TokenPos::new_at(idx, 0, idx));
+		let short = ParseError::EndlessString.with_state_at(position.clone());
+		let long = ErrorKind::with_state_at(Hints::Expected("x".repeat(500)), position);
+		for text in [
+			draw_error(&short, &Ok(info.clone()), &engine),
+			draw_error(&long, &Ok(info.clone()), &engine),
+		] {
+			let text = plain(&text);
+			let lines: Vec<&str> = text.lines().collect();
+			let carets = lines.iter().position(|x| x.contains('^')).unwrap();
+			let source = lines[carets - 1];
+			let start = lines[carets].find('^').unwrap();
+			assert_eq!(source.get(start..start + 6), Some("word17"));
+			assert_eq!(lines[carets].matches('^').count(), 6);
+			// Every word is drawn whole on some line
+			for word in words.iter() {
+				assert!(lines.iter().any(|x| x.contains(word.as_str())), "{}", word);
+			}
+		}
+	}
+
+	#[test]
+	fn fixed_widths_wrap_the_same_everywhere() {
+		use crate::kismesis::compiler::parser::errors::{Hints, ParseError};
+
+		let words: Vec<String> = (0..40).map(|x| format!("word{:02}", x)).collect();
+		let mut engine = Kismesis::new();
+		let id = engine.register_source(&format!("<p | {}>\n", words.join(" ")), None);
+		let position = TextPos::Single(TokenPos::new_at(7, 0, 7));
+		let error = ErrorState {
+			error: ParseError::EndlessString,
+			text_position: position.clone(),
+			hints: vec![Hints::ExprOpenedHere.with_state_at(position, id)],
+		};
+		let mut drawn = vec![];
+		for width in [60, 80, 120] {
+			engine.set_error_width(Some(width));
+			let text = plain(&draw_error(
+				&error,
+				&DrawingInfo::from(id, &engine, false),
+				&engine,
+			));
+			// The hint is drawn at the same width as the error it belongs to
+			for line in text.lines() {
+				assert!(line.chars().count() <= width, "{}: {:?}", width, line);
+			}
+			let again = draw_error(&error, &DrawingInfo::from(id, &engine, false), &engine);
+			assert_eq!(text, plain(&again));
+			drawn.push(text);
+		}
+		assert_ne!(drawn[0], drawn[1]);
+		assert_ne!(drawn[1], drawn[2]);
+	}
+
+	#[test]
+	fn writing_matches_drawing() {
+		use crate::kismesis::compiler::parser::errors::{Hints, ParseError};
+		use std::path::PathBuf;
+
+		let mut engine = Kismesis::new();
+		let id = engine.register_source("<p | a>\n\t<p | b\n", None);
+		let other = engine.register_source("<p | c>\n", Some(PathBuf::from("c.ks")));
+		let at = |idx, line, column| TextPos::Single(TokenPos::new_at(idx, line, column));
+		let error = ErrorState {
+			error: ParseError::EndlessString,
+			text_position: at(12, 1, 4),
+			hints: vec![
+				Hints::ExprOpenedHere.with_state_at(at(2, 0, 2), id),
+				Hints::ReferenceToThis.with_state_at(at(4, 0, 4), other),
+				Hints::AvailableNames(vec!["a".into()]).stateless(),
+			],
+		};
+		let errors = [
+			ScopedError {
+				error: error.clone(),
+				scope: id,
+			},
+			ParseError::ExpectedTagName.with_scope_at(other, at(1, 0, 1)),
+			ParseError::ExpectedTagName.with_scope_at(KisID(99), at(0, 0, 0)),
+		];
+		for err in errors.iter() {
+			let mut written = Vec::new();
+			write_scoped_error(&mut written, err, &engine).unwrap();
+			assert_eq!(
+				String::from_utf8(written).unwrap(),
+				draw_scoped_error(err, &engine)
+			);
+		}
+		let mut written = Vec::new();
+		write_all(&mut written, &errors, &engine).unwrap();
+		let drawn = draw_all(&errors, &engine);
+		assert_eq!(String::from_utf8(written).unwrap(), drawn);
+		for text in [
+			ParseError::EndlessString.get_text(),
+			Hints::ReferenceToThis.get_text(),
+		] {
+			assert!(plain(&drawn).contains(&text));
+		}
+	}
+
+	#[test]
+	fn warnings_have_a_yellow_banner() {
+		use crate::kismesis::compiler::{analysis::Warning, parser::errors::ParseError};
+
+		let mut engine = Kismesis::new();
+		let id = engine.register_source("const x = \"a\"\n", None);
+		let info = DrawingInfo::from(id, &engine, false);
+		let at = TextPos::Single(TokenPos::new_at(6, 0, 6));
+		let warning = Warning::UnusedVariable("x".into()).with_state_at(at.clone());
+		let error = ParseError::EndlessString.with_state_at(at);
+		assert_eq!(warning.error.severity(), Severity::Warning);
+		assert_eq!(error.error.severity(), Severity::Error);
+
+		let banner = |label: &str, color| label.black().on_color(color).to_string();
+		let warning_text = draw_error(&warning, &info, &engine);
+		assert!(warning_text.contains(&banner(&error_label(&warning.error), Color::Yellow)));
+		assert!(plain(&warning_text).contains(" WARNING "));
+		assert!(!plain(&warning_text).contains(" ERROR "));
+		let error_text = draw_error(&error, &info, &engine);
+		assert!(error_text.contains(&banner(&error_label(&error.error), Color::Red)));
+		assert!(!plain(&error_text).contains(" WARNING "));
+	}
+
+	#[test]
+	fn error_codes_are_drawn() {
+		let mut codes = vec![];
+		for src in ["<p | a\n", "<>\n"] {
+			let mut engine = Kismesis::new();
+			let error = engine.compile_str(src, None).unwrap_err();
+			let code = error.errors[0].error.code();
+			assert!(code.starts_with('E'));
+			assert!(plain(&error.text).contains(&format!(" ERROR {} ", code)));
+			codes.push(code);
+		}
+		assert_ne!(codes[0], codes[1]);
+	}
+
+	#[test]
+	fn summaries_take_a_line_each() {
+		use crate::kismesis::compiler::parser::errors::{Hints, ParseError};
+		use std::path::PathBuf;
+
+		let mut engine = Kismesis::new();
+		let id = engine.register_source("<p | a>\n\t<p | b\n", None);
+		let named = engine.register_source("<p | a>\n", Some(PathBuf::from("a.ks")));
+		let error = ErrorState {
+			error: ParseError::EndlessString,
+			text_position: TextPos::Single(TokenPos::new_at(12, 1, 4)),
+			hints: vec![Hints::ExprOpenedHere
+				.with_state_at(TextPos::Single(TokenPos::new_at(2, 0, 2)), named)],
+		};
+		let info = DrawingInfo::from(id, &engine, false);
+		assert_eq!(
+			draw_error_summary(&error, &info, &engine),
+			format!(
+				"input:2:8: {}\n  a.ks:1:3: {}\n",
+				ParseError::EndlessString.get_text(),
+				Hints::ExprOpenedHere.get_text()
+			)
+		);
+	}
+
+	#[test]
+	fn errors_are_grouped_by_file() {
+		use crate::kismesis::compiler::parser::errors::ParseError;
+		use std::path::PathBuf;
+
+		let mut engine = Kismesis::new();
+		let a = engine.register_source("<p | a>\n<p | b>\n", Some(PathBuf::from("a.ks")));
+		let b = engine.register_source("<p | c>\n<p | d>\n", Some(PathBuf::from("b.ks")));
+		let at = |line| TextPos::Single(TokenPos::new_at(line * 8 + 5, line, 5));
+		let errors = [
+			ParseError::EndlessString.with_scope_at(b, at(1)),
+			ParseError::ExpectedTagName.with_scope_at(a, at(0)),
+			ParseError::ExpectedTagCloser.with_scope_at(b, at(0)),
+		];
+		let text = plain(&draw_all(&errors, &engine));
+		let find = |x: &str| {
+			assert_eq!(text.matches(x).count(), 1, "{}", text);
+			text.find(x).unwrap()
+		};
+		let headers = (find("b.ks\n"), find("a.ks\n"));
+		assert!(headers.0 < headers.1);
+		let messages = [
+			ParseError::ExpectedTagCloser,
+			ParseError::EndlessString,
+			ParseError::ExpectedTagName,
+		]
+		.map(|x| find(&x.get_text()));
+		assert!(headers.0 < messages[0] && messages[0] < messages[1]);
+		assert!(messages[1] < headers.1 && headers.1 < messages[2]);
+		assert_eq!(draw_all::<ParseError>(&[], &engine), "");
+	}
+
+	#[test]
+	fn hints_are_indented_by_depth() {
+		use crate::kismesis::compiler::parser::errors::{Hint, Hints, ParseError};
+
+		// Every hint is a hint of the one before it
+		let mut hint = ErrorKind::stateless(Hints::Expected("deepest".into()));
+		for depth in (1..=6).rev() {
+			let mut outer = ErrorKind::stateless(Hints::Expected(format!("depth {}", depth)));
+			outer.hints.push(Hint::Stateless(hint));
+			hint = outer;
+		}
+		let mut error = ParseError::EndlessString.stateless();
+		error.hints.push(Hint::Stateless(hint));
+
+		let text = plain(&draw_stateless_error(&error, false, &Kismesis::new()));
+		let prefix = |message: &str| {
+			let line = text.lines().find(|x| x.ends_with(message)).unwrap();
+			line[..line.len() - message.len()].to_string()
+		};
+		assert_eq!(prefix(&ParseError::EndlessString.get_text()), "");
+		assert_eq!(prefix("depth 1"), "  │ ");
+		assert_eq!(prefix("depth 2"), "  │   │ ");
+		assert_eq!(prefix("depth 6"), "  │ ".repeat(MAX_HINT_DEPTH));
+		assert_eq!(prefix("deepest"), "  │ ".repeat(MAX_HINT_DEPTH));
+	}
+
+	#[test]
+	fn errors_in_empty_files() {
+		use crate::kismesis::compiler::parser::errors::ParseError;
+
+		for src in ["", "\n", "\n\n\n"] {
+			let mut engine = Kismesis::new();
+			let id = engine.register_source(src, None);
+			for position in [
+				TextPos::Single(TokenPos::new()),
+				TextPos::Single(TokenPos::new_at(4, 4, 0)),
+				TextPos::Range((TokenPos::new(), TokenPos::new_at(9, 9, 0))),
+			] {
+				let error = ParseError::ExpectedTagName.with_scope_at(id, position);
+				let mut info = DrawingInfo::from(id, &engine, false);
+				if let Ok(info) = info.as_mut() {
+					info.set_line_offset(5, 5);
+				}
+				let text = plain(&draw_error(&error.error, &info, &engine));
+				assert!(
+					text.contains(&ParseError::ExpectedTagName.get_text()),
+					"{}",
+					text
+				);
+			}
+		}
+	}
+}
@@ -6,9 +6,10 @@ use rhai::{plugin::*, FnPtr};
 use rhai::{export_module, exported_module, Module};
 use rhai::{Array, Dynamic, Engine};
 
+use super::Kismesis;
 use super::compiler::{
 	lexer::Token,
-	parser::types::{Attribute, Expression, HtmlNodes, HtmlTag, Ranged, TextPos},
+	parser::types::{Attribute, Expression, HtmlNodes, HtmlTag, Ranged, Symbol, TextPos},
 };
 
 #[derive(Clone)]
@@ -30,6 +31,17 @@ pub struct Parser {
 
 type PluginParseResult = Result<Parser, PluginParseError>;
 
+impl PluginParseError {
+	/// For when a function given to a parser couldn't be called, or failed
+	fn call_failed(error: Box<EvalAltResult>, line: usize, column: usize) -> Self {
+		Self {
+			message: error.to_string(),
+			line,
+			column,
+		}
+	}
+}
+
 impl Parser {
 	fn any(this: PluginParseResult) -> PluginParseResult {
 		let this = this?;
@@ -73,7 +85,13 @@ impl Parser {
 		}
 	}
 	fn parse(ctx: NativeCallContext, this: PluginParseResult, predicate: FnPtr) -> PluginParseResult {
-		predicate.call_within_context::<PluginParseResult>(&ctx, (this, )).unwrap()
+		let (line, column) = match this {
+			Ok(ref x) => (x.line, x.column),
+			Err(ref x) => (x.line, x.column),
+		};
+		predicate
+			.call_within_context::<PluginParseResult>(&ctx, (this, ))
+			.unwrap_or_else(|x| Err(PluginParseError::call_failed(x, line, column)))
 	}
 	fn sequence(ctx: NativeCallContext, this: PluginParseResult, sequence: Vec<Dynamic>) -> PluginParseResult {
 		let this = this?;
@@ -82,7 +100,9 @@ impl Parser {
 		let mut parser = this;
 		for fun in sequence.into_iter() {
 			let clone: PluginParseResult = Ok(parser.clone());
-			let result = fun.call_within_context::<PluginParseResult>(&ctx, (clone, )).unwrap();
+			let result = fun
+				.call_within_context::<PluginParseResult>(&ctx, (clone, ))
+				.unwrap_or_else(|x| Err(PluginParseError::call_failed(x, parser.line, parser.column)));
 			match result {
 				Ok(x) => {
 					values.push(x.output.clone());
@@ -111,14 +131,21 @@ impl Parser {
 	fn map(ctx: NativeCallContext, this: PluginParseResult, predicate: FnPtr) -> PluginParseResult {
 		let this = this?; 
 		let value: Dynamic = this.output.cast();
-		let output: Dynamic = predicate.call_within_context(&ctx, (value, )).unwrap();
+		let output: Dynamic = predicate
+			.call_within_context(&ctx, (value, ))
+			.map_err(|x| PluginParseError::call_failed(x, this.line, this.column))?;
 		Ok(Parser {
 			output,
 			..this
 		})
 	}
 	fn map_err(ctx: NativeCallContext, this: PluginParseResult, predicate: FnPtr) -> PluginParseResult {
-			this.map_err(|x| predicate.call_within_context(&ctx, (x, )).unwrap())
+		this.map_err(|x| {
+			let (line, column) = (x.line, x.column);
+			predicate
+				.call_within_context(&ctx, (x, ))
+				.unwrap_or_else(|x| PluginParseError::call_failed(x, line, column))
+		})
 	}
 	fn is_ok(this: PluginParseResult) -> bool {
 		this.is_ok()
@@ -204,7 +231,11 @@ pub(crate) fn new_engine() -> Engine {
 
 	plugin_engine
 		.register_type::<Attribute>()
-		.register_fn("new_attribute", |name, value| Attribute { value, name });
+		.register_fn("new_attribute", |name: Ranged<String>, value| Attribute {
+			value,
+			name: name.map(Symbol::from),
+			condition: None,
+		});
 
 	plugin_engine.register_type::<TextPos>();
 
@@ -228,18 +259,20 @@ pub fn dyn_htmltag(
 	subtags: Array,
 ) -> HtmlTag {
 	HtmlTag {
-		name,
+		namespace: None,
+		name: name.map(Symbol::from),
 		attributes: attributes.into_iter().map(|x| x.cast()).collect(),
+		spreads: vec![],
 		body: body.into_iter().map(|x| x.cast()).collect(),
 		subtags: subtags.into_iter().map(|x| x.cast()).collect(),
+		trim_before: false,
+		trim_after: false,
 	}
 }
 
-pub fn into_html_nodes(output: Array) -> Vec<HtmlNodes> {
-	output
-		.into_iter()
-		.map(|x| dyn_try_html_node(x).unwrap())
-		.collect()
+/// Gives `None` if any of the values isn't a tag
+pub fn into_html_nodes(output: Array) -> Option<Vec<HtmlNodes>> {
+	output.into_iter().map(dyn_try_html_node).collect()
 }
 
 pub fn dyn_try_html_node(object: Dynamic) -> Option<HtmlNodes> {
@@ -257,23 +290,38 @@ mod exprs {
 	}
 }
 
+thread_local! {
+	/// The parser needs an engine for its settings, and making one makes a
+	/// whole rhai engine too, so plugins share this one instead of making one
+	/// on every call
+	static PARSING_ENGINE: Kismesis = Kismesis::new();
+}
+
 #[export_module]
 mod parsers {
-	use crate::kismesis::{
-		compiler::{
-			lexer::{Token, self},
-			parser::{self, state::ParserState},
-		},
-		Kismesis,
+	use super::PARSING_ENGINE;
+	use crate::kismesis::compiler::{
+		lexer::{Token, self},
+		parser::{self, state::ParserState},
 	};
 
-	pub fn get_attributes(ctx: NativeCallContext, params: Vec<Token>) -> Array {
+	/// Gives `()` if the tokens aren't attributes
+	pub fn get_attributes(ctx: NativeCallContext, params: Vec<Token>) -> Dynamic {
 		let tag: Rc<RefCell<EngineTag>> = ctx.engine().default_tag().clone_cast();
 		let project_path = tag.borrow().clone().project_path;
-		let x = parser::multiple_attributes(ParserState::new(&params, project_path, &Kismesis::new()))
-			.unwrap()
-			.0;
-		x.into_iter().map(|x| Dynamic::from(x)).collect()
+		PARSING_ENGINE.with(|engine| {
+			match parser::multiple_attributes(ParserState::new(&params, project_path, engine)) {
+				Ok((x, _)) => Dynamic::from_array(x.into_iter().map(|x| Dynamic::from(x)).collect()),
+				Err(_) => Dynamic::UNIT,
+			}
+		})
+	}
+
+	/// Gives `()` if the tokens aren't a single expression
+	pub fn get_expression(params: Vec<Token>) -> Dynamic {
+		PARSING_ENGINE.with(|engine| {
+			parser::parse_expression(&params, engine).map_or(Dynamic::UNIT, Dynamic::from)
+		})
 	}
 
 	pub fn tokenize(tokens: String) -> Vec<Token> {
@@ -285,14 +333,21 @@ mod parsers {
 mod io {
     use std::fs;
 
+	/// Gives `()` if the file can't be read, or if it isn't inside of the
+	/// project
 	pub fn read_to_string(ctx: NativeCallContext, path: String) -> Dynamic {
 		let path = PathBuf::from(path);
 		let tag: Rc<RefCell<EngineTag>> = ctx.engine().default_tag().clone_cast();
-		let project_path = tag.borrow().clone().project_path.clone().unwrap();
-		if path.canonicalize().unwrap().starts_with(project_path.canonicalize().unwrap()) {
-			Dynamic::from(fs::read_to_string(&path).map(|x| Dynamic::from(x)).unwrap_or(Dynamic::UNIT))
+		let Some(project_path) = tag.borrow().project_path.clone() else {
+			return Dynamic::UNIT;
+		};
+		let (Ok(path), Ok(project_path)) = (path.canonicalize(), project_path.canonicalize()) else {
+			return Dynamic::UNIT;
+		};
+		if path.starts_with(project_path) {
+			fs::read_to_string(&path).map(Dynamic::from).unwrap_or(Dynamic::UNIT)
 		} else {
-			panic!("Tried to access illegal path")
+			Dynamic::UNIT
 		}
 	}
 }
@@ -0,0 +1,5 @@
+//! The Kismesis templating engine. The command line tool in `main.rs` and the
+//! benchmarks use it from here.
+pub mod kismesis;
+
+pub use kismesis::*;
@@ -1,5 +1,8 @@
-pub mod kismesis;
+use kismesis::compiler;
 
 fn main() {
-	kismesis::compiler::compile_project();
+	match std::env::args().nth(1).as_deref() {
+		Some("fmt") => compiler::format_files(std::env::args().skip(2)),
+		_ => compiler::compile_project(),
+	}
 }